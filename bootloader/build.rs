@@ -1,3 +1,6 @@
 fn main() {
     built::write_built_file().expect("Failed to acquire build-time information");
+
+    #[cfg(feature = "recovery")]
+    env_config::generate_env_config_constants();
 }
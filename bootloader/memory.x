@@ -8,7 +8,7 @@ MEMORY
     /* Bootloader is given the first 3 sectors (16K * 3 = 48K) */
     FLASH : ORIGIN = 0x08000000, LENGTH = 48K
     
-    /* First 16 bytes are reserved for the UCS words */
-    /* LENGTH = (128K - 16) = 131056 */
-    RAM : ORIGIN = 0x20000010, LENGTH = 131056
+    /* First 48 bytes are reserved for the UCS words */
+    /* LENGTH = (128K - 48) = 131024 */
+    RAM : ORIGIN = 0x20000030, LENGTH = 131024
 }
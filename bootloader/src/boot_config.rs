@@ -1,28 +1,40 @@
-use bootloader_support::{BootSlot, FLASH_BASE_ADDRESS};
-use core::{mem, ptr};
+use bootloader_support::{
+    boot_config_erase_is_safe, BootHistory, BootHistoryEntry, BootSlot, FLASH_BASE_ADDRESS,
+};
+use core::ptr;
 use log::debug;
-use static_assertions::const_assert_eq;
+use static_assertions::const_assert;
 use stm32f4xx_hal::{crc32::Crc32, flash::FlashExt};
+use wire_protocols::FirmwareVersion;
 
-const_assert_eq!(mem::align_of::<BootConfig>(), 4);
-const_assert_eq!(mem::size_of::<BootConfig>(), BootConfig::SIZE_IN_FLASH);
+const_assert!(BootConfig::SIZE_IN_FLASH % 4 == 0);
+const_assert!(BootConfig::RECORDS_PER_SECTOR >= 2);
 
 pub static DEFAULT_CONFIG: BootConfig = BootConfig {
     magic: 0,
-    version: 0,
+    version: BootConfig::VERSION,
     firmware_boot_slot: BootSlot::Slot0,
+    history: BootHistory::new(),
     checksum: 0,
 };
 
 /// Boot configuration.
 /// Lives in flash sector 3 (0x0800_C000).
+/// Each write appends a new record to the sector, the sector is only erased
+/// once it's full. The last valid record is the active configuration.
+/// Erasing is only done when losing power before the new record is programmed
+/// still boots either the previous or the new slot, see `BootConfig::write`.
 /// `magic` is set to `BootConfig::MAGIC`
+/// `version` is set to `BootConfig::VERSION`, the version 0 layout
+/// (magic, version, slot, checksum) is migrated on read.
+/// `history` holds the most recent boots, newest first.
 /// `checksum` is the CRC32 of the preceeding bytes.
 #[derive(Copy, Clone)]
 pub struct BootConfig {
     magic: u32,
     version: u32,
     firmware_boot_slot: BootSlot,
+    history: BootHistory,
     checksum: u32,
 }
 
@@ -31,10 +43,15 @@ impl BootConfig {
     const FLASH_ADDRESS: u32 = FLASH_BASE_ADDRESS + Self::FLASH_SECTOR_OFFSET;
     const FLASH_SECTOR: usize = 3;
     const FLASH_SECTOR_OFFSET: u32 = 0xC000;
+    const FLASH_SECTOR_SIZE: usize = 16 * 1024;
+
+    const SIZE_IN_FLASH: usize = 12 + BootHistory::WIRE_SIZE + 4;
+    const RECORDS_PER_SECTOR: usize = Self::FLASH_SECTOR_SIZE / Self::SIZE_IN_FLASH;
 
-    const SIZE_IN_FLASH: usize = 16;
+    const V0_SIZE_IN_FLASH: usize = 16;
 
     const MAGIC: u32 = 0xFEEDC0DE;
+    const VERSION: u32 = 1;
 
     pub fn read<F: FlashExt>(flash: &F, crc: &mut Crc32) -> Option<Self> {
         debug!(
@@ -43,49 +60,63 @@ impl BootConfig {
             Self::FLASH_SECTOR_OFFSET
         );
 
-        let cfg_bytes = &flash.read()[Self::FLASH_SECTOR_OFFSET as usize..];
-        let cfg = BootConfig {
-            magic: u32::from_le_bytes(cfg_bytes[0..4].try_into().unwrap()),
-            version: u32::from_le_bytes(cfg_bytes[4..8].try_into().unwrap()),
-            firmware_boot_slot: BootSlot::from_u32(u32::from_le_bytes(
-                cfg_bytes[8..12].try_into().unwrap(),
-            )),
-            checksum: u32::from_le_bytes(cfg_bytes[12..16].try_into().unwrap()),
-        };
+        let sector = Self::sector_bytes(flash);
 
-        if cfg.magic != Self::MAGIC {
-            debug!("Config has invalid magic 0x{:X}", cfg.magic);
-            None
-        } else {
-            crc.init();
-            // -4 to exclude the checksum field
-            let expected_crc = crc.update_bytes(&cfg_bytes[..Self::SIZE_IN_FLASH - 4]);
-            if cfg.checksum != expected_crc {
-                debug!(
-                    "Config has invalid checksum 0x{:X} (expected 0x{expected_crc:X})",
-                    cfg.checksum
-                );
-                None
-            } else {
-                Some(cfg)
+        // Records are appended in order, stop at the first unused one
+        let mut cfg = None;
+        for record in sector
+            .chunks_exact(Self::SIZE_IN_FLASH)
+            .take_while(|r| !is_erased(r))
+        {
+            if let Some(c) = Self::parse_record(record, crc) {
+                cfg = Some(c);
             }
         }
+
+        if cfg.is_none() {
+            cfg = Self::parse_v0_record(&sector[..Self::V0_SIZE_IN_FLASH], crc);
+        }
+
+        cfg
     }
 
-    pub fn write<F: FlashExt>(&mut self, flash: &mut F, crc: &mut Crc32) {
-        crc.init();
+    /// Appends the config to the sector, erasing it first when it's full.
+    ///
+    /// There's no other sector to stage the record in, so the sector is only erased
+    /// when that can't change the boot slot (see `boot_config_erase_is_safe`).
+    /// Otherwise the config isn't written and false is returned. The record on flash,
+    /// including its boot history, then stays as is until the next slot change.
+    pub fn write<F: FlashExt>(&mut self, flash: &mut F, crc: &mut Crc32) -> bool {
         self.magic = Self::MAGIC;
-        crc.update(&[self.magic]);
-        crc.update(&[self.version]);
-        let crc = crc.update(&[self.firmware_boot_slot.into_u32()]);
-        self.checksum = crc;
-
-        let bytes = self.convert_to_le_bytes();
-        let mut unlocked_flash = flash.unlocked();
-        unlocked_flash.erase(Self::FLASH_SECTOR as u8).unwrap();
-        unlocked_flash
-            .program(Self::FLASH_SECTOR_OFFSET as usize, bytes.iter())
-            .unwrap()
+        self.version = Self::VERSION;
+        let mut bytes = self.convert_to_le_bytes();
+        crc.init();
+        // -4 to exclude the checksum field
+        self.checksum = crc.update_bytes(&bytes[..Self::SIZE_IN_FLASH - 4]);
+        bytes[Self::SIZE_IN_FLASH - 4..].copy_from_slice(&self.checksum.to_le_bytes());
+
+        let next_record = Self::sector_bytes(flash)
+            .chunks_exact(Self::SIZE_IN_FLASH)
+            .position(is_erased);
+
+        let record_idx = match next_record {
+            Some(idx) => idx,
+            None => {
+                let default_slot = DEFAULT_CONFIG.firmware_boot_slot;
+                let stored_slot = Self::read(flash, crc).map(|cfg| cfg.firmware_boot_slot);
+                if !boot_config_erase_is_safe(default_slot, stored_slot, self.firmware_boot_slot) {
+                    debug!("Boot config sector is full, erasing it now could change the boot slot");
+                    return false;
+                }
+                debug!("Boot config sector is full, erasing");
+                flash.unlocked().erase(Self::FLASH_SECTOR as u8).unwrap();
+                0
+            }
+        };
+        let offset = Self::FLASH_SECTOR_OFFSET as usize + (record_idx * Self::SIZE_IN_FLASH);
+        debug!("Writing boot config record {record_idx} at offset 0x{offset:X}");
+        flash.unlocked().program(offset, bytes.iter()).unwrap();
+        true
     }
 
     pub fn firmware_boot_slot(&self) -> BootSlot {
@@ -96,18 +127,109 @@ impl BootConfig {
         self.firmware_boot_slot = self.firmware_boot_slot.other();
     }
 
+    pub fn history(&self) -> &BootHistory {
+        &self.history
+    }
+
+    /// Record a new boot in the history.
+    pub fn push_boot_history_entry(&mut self, entry: BootHistoryEntry) {
+        self.history.push(entry);
+    }
+
+    /// Set the firmware version of the most recent boot.
+    /// Returns true if the config was modified and needs to be written.
+    pub fn set_firmware_version(&mut self, version: FirmwareVersion) -> bool {
+        match self.history.latest_mut() {
+            Some(entry) if entry.firmware_version != Some(version) => {
+                entry.firmware_version = Some(version);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn sector_bytes<F: FlashExt>(flash: &F) -> &[u8] {
+        let start = Self::FLASH_SECTOR_OFFSET as usize;
+        &flash.read()[start..start + Self::FLASH_SECTOR_SIZE]
+    }
+
+    fn parse_record(bytes: &[u8], crc: &mut Crc32) -> Option<Self> {
+        let magic = read_u32(bytes, 0);
+        let version = read_u32(bytes, 4);
+        let checksum = read_u32(bytes, Self::SIZE_IN_FLASH - 4);
+        if magic != Self::MAGIC || version != Self::VERSION {
+            return None;
+        }
+
+        crc.init();
+        let expected_crc = crc.update_bytes(&bytes[..Self::SIZE_IN_FLASH - 4]);
+        if checksum != expected_crc {
+            debug!(
+                "Config record has invalid checksum 0x{checksum:X} (expected 0x{expected_crc:X})"
+            );
+            return None;
+        }
+
+        Some(BootConfig {
+            magic,
+            version,
+            firmware_boot_slot: BootSlot::from_u32(read_u32(bytes, 8)),
+            history: BootHistory::from_le_bytes(&bytes[12..Self::SIZE_IN_FLASH - 4]),
+            checksum,
+        })
+    }
+
+    /// Version 0 configs are a single record at the start of the sector
+    fn parse_v0_record(bytes: &[u8], crc: &mut Crc32) -> Option<Self> {
+        let magic = read_u32(bytes, 0);
+        let version = read_u32(bytes, 4);
+        let checksum = read_u32(bytes, 12);
+        if magic != Self::MAGIC {
+            debug!("Config has invalid magic 0x{magic:X}");
+            return None;
+        }
+        if version != 0 {
+            debug!("Config has unsupported version {version}");
+            return None;
+        }
+
+        crc.init();
+        // -4 to exclude the checksum field
+        let expected_crc = crc.update_bytes(&bytes[..Self::V0_SIZE_IN_FLASH - 4]);
+        if checksum != expected_crc {
+            debug!("Config has invalid checksum 0x{checksum:X} (expected 0x{expected_crc:X})");
+            return None;
+        }
+
+        debug!("Migrating version 0 config");
+        Some(BootConfig {
+            magic,
+            version: Self::VERSION,
+            firmware_boot_slot: BootSlot::from_u32(read_u32(bytes, 8)),
+            history: BootHistory::new(),
+            checksum,
+        })
+    }
+
     fn convert_to_le_bytes(&self) -> [u8; Self::SIZE_IN_FLASH] {
-        let a = self.magic.to_le_bytes();
-        let b = self.version.to_le_bytes();
-        let c = self.firmware_boot_slot.into_u32().to_le_bytes();
-        let d = self.checksum.to_le_bytes();
-        [
-            a[0], a[1], a[2], a[3], b[0], b[1], b[2], b[3], c[0], c[1], c[2], c[3], d[0], d[1],
-            d[2], d[3],
-        ]
+        let mut bytes = [0_u8; Self::SIZE_IN_FLASH];
+        bytes[0..4].copy_from_slice(&self.magic.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.firmware_boot_slot.into_u32().to_le_bytes());
+        bytes[12..Self::SIZE_IN_FLASH - 4].copy_from_slice(&self.history.to_le_bytes());
+        bytes[Self::SIZE_IN_FLASH - 4..].copy_from_slice(&self.checksum.to_le_bytes());
+        bytes
     }
 }
 
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn is_erased(bytes: &[u8]) -> bool {
+    bytes.iter().all(|b| *b == 0xFF)
+}
+
 pub trait BootSlotExt {
     fn application_flash_address(&self) -> Option<u32>;
 
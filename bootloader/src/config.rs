@@ -1 +1,32 @@
+use wire_protocols::FirmwareVersion;
+
 pub const WATCHDOG_RESET_PERIOD_MS: u32 = 8000;
+
+/// Reported to the application through the UCS
+pub const BOOTLOADER_VERSION: FirmwareVersion =
+    match FirmwareVersion::parse(env!("CARGO_PKG_VERSION")) {
+        Some(v) => v,
+        None => panic!("Invalid package version"),
+    };
+
+#[cfg(feature = "recovery")]
+pub use self::recovery::*;
+
+#[cfg(feature = "recovery")]
+mod recovery {
+    use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+    pub use self::generated_confg::*;
+    #[allow(dead_code)]
+    mod generated_confg {
+        include!(concat!(env!("OUT_DIR"), "/env_config.rs"));
+    }
+
+    pub const IP_CIDR: Ipv4Cidr = Ipv4Cidr::new(Ipv4Address(IP_ADDRESS), 24);
+
+    pub const DEVICE_PROTO_SOCKET_BUFFER_LEN: usize = wire_protocols::device::SOCKET_BUFFER_LEN;
+
+    pub const UPDATE_MANAGER_POLL_INTERVAL_MS: u32 = 100;
+
+    pub const LED_BLINK_INTERVAL_MS: u32 = 250;
+}
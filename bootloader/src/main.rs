@@ -4,11 +4,13 @@
 mod config;
 mod logger;
 mod panic_handler;
+#[cfg(feature = "recovery")]
+mod recovery;
 
 use bootloader_lib::{
     BootConfig, BootSlotExt, ResetReasonExt, UpdateConfigAndStatus, DEFAULT_CONFIG,
 };
-use bootloader_support::ResetReason;
+use bootloader_support::{BootHistoryEntry, ResetReason};
 use core::sync::atomic::{compiler_fence, Ordering::SeqCst};
 use cortex_m_rt::entry;
 use log::{debug, error, info, warn};
@@ -46,7 +48,8 @@ fn main() -> ! {
     let gpioc = dp.GPIOC.split();
 
     // Turn it off, active-low
-    let _led: LedPin = gpioc.pc13.into_push_pull_output_in_state(true.into());
+    #[allow(unused_variables)]
+    let led: LedPin = gpioc.pc13.into_push_pull_output_in_state(true.into());
 
     // Setup logging impl via USART6, Rx on PA12, Tx on PA11
     // This is also the virtual com port on the nucleo boards: stty -F /dev/ttyACM0 115200
@@ -54,6 +57,15 @@ fn main() -> ! {
     let log_tx = dp.USART6.tx(log_tx_pin, 115_200.bps(), &clocks).unwrap();
     unsafe { crate::logger::init_logging(log_tx) };
 
+    // Recovery button (KEY on PA0), active-low
+    #[cfg(feature = "recovery")]
+    let recovery_pin_held = {
+        let pin = gpioa.pa0.into_pull_up_input();
+        // Give the internal pull-up a moment to settle
+        cortex_m::asm::delay(1_000);
+        pin.is_low()
+    };
+
     let mut flash = dp.FLASH;
     let mut crc = Crc32::new(dp.CRC);
     let mut boot_cfg = match BootConfig::read(&flash, &mut crc) {
@@ -67,10 +79,8 @@ fn main() -> ! {
             // TODO clear the UCS RAM words too?
             UpdateConfigAndStatus::clear();
 
-            debug!("Writing config to flash");
-            let mut cfg = DEFAULT_CONFIG;
-            cfg.write(&mut flash, &mut crc);
-            cfg
+            // Written to flash along with the boot history entry below
+            DEFAULT_CONFIG
         }
     };
 
@@ -79,6 +89,10 @@ fn main() -> ! {
     // Read and clear UCS flags
     let update_pending = UpdateConfigAndStatus::update_pending();
     let update_valid = UpdateConfigAndStatus::update_valid();
+    let recovery_requested = UpdateConfigAndStatus::recovery_requested();
+    let rollback_requested = UpdateConfigAndStatus::rollback_requested();
+    let boot_other_slot_once = UpdateConfigAndStatus::boot_other_slot_once();
+    let firmware_version = UpdateConfigAndStatus::firmware_version();
 
     info!("************************************************************");
     info!(
@@ -94,14 +108,27 @@ fn main() -> ! {
     }
     info!("Reset reason: {reset_reason}");
     info!("Boot config slot: {}", boot_cfg.firmware_boot_slot());
+    if let Some(prev_boot) = boot_cfg.history().latest() {
+        info!("Previous boot: {prev_boot}");
+    }
     info!("Update pending: {update_pending}");
     info!("Update valid: {update_valid}");
+    info!("Recovery requested: {recovery_requested}");
+    info!("Rollback requested: {rollback_requested}");
+    info!("Boot other slot once: {boot_other_slot_once}");
+    if let Some(v) = firmware_version {
+        info!("Previous firmware version: {v}");
+    }
     info!("************************************************************");
 
     const NOT_PENDING: bool = false;
     const IS_PENDING: bool = true;
     const NOT_VALID: bool = false;
     const IS_VALID: bool = true;
+    let mut update_attempted = false;
+    let mut update_committed = false;
+    let mut rolled_back = false;
+    let mut booted_once = false;
     let boot_slot = match (update_pending, update_valid, reset_reason) {
         (IS_PENDING, IS_VALID, ResetReason::SoftwareReset) => {
             // The newly booted updated application marked the update
@@ -109,8 +136,8 @@ fn main() -> ! {
             debug!("Pending update now complete");
             UpdateConfigAndStatus::clear();
             boot_cfg.swap_firmware_boot_slot();
-            debug!("Writing new config slot: {}", boot_cfg.firmware_boot_slot());
-            boot_cfg.write(&mut flash, &mut crc);
+            debug!("New config slot: {}", boot_cfg.firmware_boot_slot());
+            update_committed = true;
             boot_cfg.firmware_boot_slot()
         }
         (IS_PENDING, IS_VALID, _) => {
@@ -124,6 +151,7 @@ fn main() -> ! {
 
             // Keep it set, doing the read clears it by default
             UpdateConfigAndStatus::set_update_pending();
+            update_attempted = true;
 
             let current_slot = boot_cfg.firmware_boot_slot();
             current_slot.other()
@@ -138,6 +166,35 @@ fn main() -> ! {
             UpdateConfigAndStatus::clear();
             boot_cfg.firmware_boot_slot()
         }
+        (NOT_PENDING, NOT_VALID, ResetReason::SoftwareReset) if rollback_requested => {
+            UpdateConfigAndStatus::clear();
+            let other_slot = boot_cfg.firmware_boot_slot().other();
+            if other_slot.application_flash_address().is_some() {
+                // Same as an update, the other slot is trial booted and only
+                // becomes the active slot once its application marks itself valid
+                info!("Rolling back to slot {other_slot}, selecting it for a trial boot");
+                UpdateConfigAndStatus::set_update_pending();
+                update_attempted = true;
+                rolled_back = true;
+                other_slot
+            } else {
+                warn!("Rollback requested, but the application at slot {other_slot} is invalid, ignoring");
+                boot_cfg.firmware_boot_slot()
+            }
+        }
+        (NOT_PENDING, NOT_VALID, ResetReason::SoftwareReset) if boot_other_slot_once => {
+            // The active slot is left as is, so the next reset goes back to it
+            UpdateConfigAndStatus::clear();
+            let other_slot = boot_cfg.firmware_boot_slot().other();
+            if other_slot.application_flash_address().is_some() {
+                info!("Booting slot {other_slot} once");
+                booted_once = true;
+                other_slot
+            } else {
+                warn!("Booting slot {other_slot} once was requested, but its application is invalid, ignoring");
+                boot_cfg.firmware_boot_slot()
+            }
+        }
         (NOT_PENDING, NOT_VALID, _) => {
             debug!("Normal boot");
             UpdateConfigAndStatus::clear();
@@ -145,9 +202,61 @@ fn main() -> ! {
         }
     };
 
-    if let Some(valid_app_address) = boot_slot.application_flash_address() {
+    // The version reported by the application of the previous boot goes into its entry,
+    // so there's a single write per boot
+    if let Some(v) = firmware_version {
+        boot_cfg.set_firmware_version(v);
+    }
+
+    let mut boot_entry = BootHistoryEntry::new(boot_slot, reset_reason);
+    boot_entry.update_attempted = update_attempted;
+    boot_entry.update_committed = update_committed;
+    boot_entry.rolled_back = rolled_back;
+    boot_entry.booted_once = booted_once;
+    boot_cfg.push_boot_history_entry(boot_entry);
+    debug!("Writing config to flash");
+    if !boot_cfg.write(&mut flash, &mut crc) {
+        warn!("Boot config sector is full, this boot isn't recorded, the boot history on flash stays as is until the next slot change");
+    }
+
+    let app_address = boot_slot.application_flash_address();
+
+    #[cfg(feature = "recovery")]
+    if recovery_requested || recovery_pin_held || app_address.is_none() {
+        if app_address.is_none() {
+            error!("The application at boot slot {boot_slot} is invalid!");
+        }
+        if recovery_pin_held {
+            info!("Recovery button held");
+        }
+        // Abort any trial boot, a plain reboot out of recovery goes back to the config slot
+        UpdateConfigAndStatus::clear();
+        recovery::run(recovery::Resources {
+            spi2: dp.SPI2,
+            tim4: dp.TIM4,
+            tim5: dp.TIM5,
+            eth_int: gpioa.pa8,
+            gpiob: dp.GPIOB.split(),
+            clocks,
+            led,
+            watchdog,
+            flash,
+            active_boot_slot: boot_cfg.firmware_boot_slot(),
+            reset_reason,
+            boot_history: *boot_cfg.history(),
+        });
+    }
+
+    #[cfg(not(feature = "recovery"))]
+    if recovery_requested {
+        warn!("Recovery mode was requested but isn't supported by this bootloader");
+    }
+
+    if let Some(valid_app_address) = app_address {
         debug!("Booting firmware at slot {boot_slot} address 0x{valid_app_address:X}");
 
+        UpdateConfigAndStatus::set_bootloader_version(config::BOOTLOADER_VERSION);
+
         watchdog.feed();
 
         // de-init
@@ -0,0 +1,185 @@
+use bootloader_lib::UpdateConfigAndStatus;
+use bootloader_support::{BootHistory, BootSlot, ResetReason, FLASH_BASE_ADDRESS};
+use log::{debug, info, warn};
+use stm32f4xx_hal::{
+    flash::FlashExt,
+    pac::{self, FLASH},
+    rcc::Enable,
+};
+use update_manager::{Device, DeviceInfo, FirmwareUpdateStatus, StatusCodeResult};
+use wire_protocols::{
+    device::{MemoryEraseRequest, MemoryReadRequest, MemoryWriteRequest, RebootTarget, StatusCode},
+    DeviceSerialNumber, ProtocolVersion,
+};
+
+const NA: &str = "NA";
+
+pub(crate) fn read_device_serial_number() -> DeviceSerialNumber {
+    let word0 = unsafe { *(0x1FFF_7A10 as *const u32) };
+    let word1 = unsafe { *(0x1FFF_7A14 as *const u32) };
+    let word2 = unsafe { *(0x1FFF_7A18 as *const u32) };
+    DeviceSerialNumber::new(word0, word1, word2)
+}
+
+/// The device info reported while in recovery mode.
+/// No firmware is running, so there's no firmware version.
+pub(crate) fn device_info(active_boot_slot: BootSlot, reset_reason: ResetReason) -> DeviceInfo {
+    DeviceInfo {
+        protocol_version: ProtocolVersion::v1(),
+        firmware_version: None,
+        bootloader_version: Some(crate::config::BOOTLOADER_VERSION),
+        hardware: bootloader_support::HARDWARE,
+        device_id: crate::config::DEVICE_ID,
+        device_serial_number: read_device_serial_number(),
+        mac_address: crate::config::MAC_ADDRESS,
+        active_boot_slot,
+        reset_reason,
+        built_time_utc: crate::built_info::BUILT_TIME_UTC,
+        git_commit: crate::built_info::GIT_COMMIT_HASH.unwrap_or(NA),
+    }
+}
+
+/// Same semantics as the application's update manager device, updates are
+/// written to the slot that isn't the active boot slot and completed through
+/// the regular pending update sequence.
+pub(crate) struct RecoveryDevice<'a> {
+    pub info: &'a DeviceInfo,
+    pub boot_history: &'a BootHistory,
+    pub flash: &'a mut FLASH,
+}
+
+impl<'a> Device for RecoveryDevice<'a> {
+    fn info(&self) -> &DeviceInfo {
+        self.info
+    }
+
+    fn boot_history(&self) -> &BootHistory {
+        self.boot_history
+    }
+
+    fn perform_reboot(&mut self) -> ! {
+        warn!("Rebooting now");
+        unsafe { reset() }
+    }
+
+    fn perform_reboot_into(&mut self, target: RebootTarget) -> ! {
+        match target {
+            RebootTarget::OtherSlot => {
+                warn!(
+                    "Rebooting into slot {} once now",
+                    self.info.active_boot_slot.other()
+                );
+                UpdateConfigAndStatus::set_boot_other_slot_once();
+            }
+            RebootTarget::TrialOtherSlot => {
+                warn!(
+                    "Rebooting into slot {} now",
+                    self.info.active_boot_slot.other()
+                );
+                // Same as an update, the bootloader trial boots the other slot
+                UpdateConfigAndStatus::set_update_pending();
+            }
+            RebootTarget::Recovery => {
+                warn!("Rebooting into recovery mode now");
+                UpdateConfigAndStatus::set_recovery_requested();
+            }
+            RebootTarget::CurrentSlot | RebootTarget::Unknown(_) => {
+                warn!("Rebooting now");
+            }
+        }
+        unsafe { reset() }
+    }
+
+    fn perform_rollback(&mut self) -> ! {
+        warn!(
+            "Rolling back to slot {}, rebooting now",
+            self.info.active_boot_slot.other()
+        );
+        UpdateConfigAndStatus::set_rollback_requested();
+        unsafe { reset() }
+    }
+
+    fn complete_update_and_perform_reboot(&mut self) -> ! {
+        warn!("Update complete, rebooting now");
+        UpdateConfigAndStatus::set_update_pending();
+        unsafe { reset() }
+    }
+
+    fn update_progress_changed(&mut self, status: FirmwareUpdateStatus, bytes_written: usize) {
+        if !matches!(status, FirmwareUpdateStatus::InProgress) {
+            info!("Update {status:?}, {bytes_written} bytes written");
+        }
+    }
+
+    fn read_memory(&mut self, req: MemoryReadRequest) -> StatusCodeResult<&[u8]> {
+        // Either slot can be read, the active slot is the source for delta updates
+        let active_slot = self.info.active_boot_slot;
+        let slot = if active_slot.contains(req.address) {
+            active_slot
+        } else {
+            active_slot.other()
+        };
+        if !slot.contains(req.address) {
+            Err(StatusCode::InvalidAddress)
+        } else if !slot.contains(req.address + req.length - 1) {
+            Err(StatusCode::DataLengthIncorrect)
+        } else {
+            req.check_length()?;
+            let a = (req.address - FLASH_BASE_ADDRESS) as usize;
+            let b = a + (req.length as usize);
+            debug!("Reading FLASH at offset 0x{a:X} len=0x{:X}", req.length);
+            let mem = self.flash.read();
+            Ok(&mem[a..b])
+        }
+    }
+
+    fn write_memory(&mut self, req: MemoryWriteRequest, data: &[u8]) -> StatusCodeResult<()> {
+        let other_slot = self.info.active_boot_slot.other();
+        if !other_slot.contains(req.address) {
+            Err(StatusCode::InvalidAddress)
+        } else if !other_slot.contains(req.address + req.length - 1) {
+            Err(StatusCode::DataLengthIncorrect)
+        } else {
+            req.check_write_length()?;
+            let offset = req.address - FLASH_BASE_ADDRESS;
+            debug!(
+                "Writing to FLASH at offset 0x{offset:X} len=0x{:X}",
+                req.length
+            );
+            let mut unlocked_flash = self.flash.unlocked();
+            unlocked_flash
+                .program(offset as usize, data.iter())
+                .map_err(|e| {
+                    warn!("Flash write error: {e:?}");
+                    StatusCode::WriteError
+                })?;
+            Ok(())
+        }
+    }
+
+    fn erase_memory(&mut self, req: MemoryEraseRequest) -> StatusCodeResult<()> {
+        let other_slot = self.info.active_boot_slot.other();
+        if req.address != other_slot.address() {
+            Err(StatusCode::InvalidAddress)
+        } else if req.length != other_slot.size() {
+            Err(StatusCode::DataLengthIncorrect)
+        } else {
+            let mut unlocked_flash = self.flash.unlocked();
+            for sector in other_slot.sectors() {
+                unlocked_flash.erase(*sector).map_err(|e| {
+                    warn!("Flash sector {sector} erase error: {e:?}");
+                    StatusCode::EraseError
+                })?;
+            }
+            Ok(())
+        }
+    }
+}
+
+unsafe fn reset() -> ! {
+    crate::logger::flush_logger();
+    let rcc = &(*pac::RCC::ptr());
+    pac::USART6::disable(rcc);
+
+    bootloader_lib::sw_reset();
+}
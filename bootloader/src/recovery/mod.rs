@@ -0,0 +1,178 @@
+//! Network recovery mode.
+//!
+//! Brings up the ENC28J60 and a minimal TCP/IP stack and serves the device
+//! protocol so a firmware slot can be reflashed with `air-gradient device update`
+//! when there's no bootable application.
+
+use crate::{config, LedPin};
+use bootloader_support::{BootHistory, BootSlot, ResetReason};
+use eth_device::{Eth, SpiPins};
+use log::{debug, info, warn};
+use smoltcp::{
+    iface::{Config, Interface, SocketSet, SocketStorage},
+    socket::tcp::{Socket as TcpSocket, SocketBuffer as TcpSocketBuffer},
+    time::Instant,
+    wire::EthernetAddress,
+};
+use stm32f4xx_hal::{
+    gpio::{gpiob, Speed as GpioSpeed, PA8},
+    pac::{FLASH, SPI2, TIM4, TIM5},
+    prelude::*,
+    rcc::Clocks,
+    spi::Spi,
+    watchdog::IndependentWatchdog,
+};
+use update_manager::UpdateManager;
+
+mod device;
+
+use self::device::RecoveryDevice;
+
+/// Everything recovery mode needs, handed over by main once the boot config is written.
+pub struct Resources {
+    pub spi2: SPI2,
+    pub tim4: TIM4,
+    pub tim5: TIM5,
+    pub eth_int: PA8,
+    pub gpiob: gpiob::Parts,
+    pub clocks: Clocks,
+    pub led: LedPin,
+    pub watchdog: IndependentWatchdog,
+    pub flash: FLASH,
+    pub active_boot_slot: BootSlot,
+    pub reset_reason: ResetReason,
+    pub boot_history: BootHistory,
+}
+
+pub fn run(res: Resources) -> ! {
+    let Resources {
+        spi2,
+        tim4,
+        tim5,
+        eth_int,
+        gpiob,
+        clocks,
+        mut led,
+        mut watchdog,
+        mut flash,
+        active_boot_slot,
+        reset_reason,
+        boot_history,
+    } = res;
+
+    watchdog.feed();
+
+    let device_info = device::device_info(active_boot_slot, reset_reason);
+
+    info!("============================================================");
+    info!("Recovery mode");
+    info!("Serial number: {:X}", device_info.device_serial_number);
+    info!("IP address: {}", config::IP_CIDR.address());
+    info!(
+        "MAC address: {}",
+        EthernetAddress::from_bytes(&config::MAC_ADDRESS)
+    );
+    info!("Device protocol port: {}", config::DEVICE_PORT);
+    info!("Update slot: {}", active_boot_slot.other());
+    info!("============================================================");
+
+    let mut delay = tim4.delay_ms(&clocks);
+
+    let mut clock = tim5.counter_ms(&clocks);
+    clock.start(u32::MAX.millis()).unwrap();
+
+    debug!("Recovery: ETH");
+    let eth_spi = {
+        let sck = gpiob.pb13.into_alternate().speed(GpioSpeed::VeryHigh);
+        let miso = gpiob.pb14.into_alternate().speed(GpioSpeed::VeryHigh);
+        let mosi = gpiob
+            .pb15
+            .into_alternate()
+            .speed(GpioSpeed::VeryHigh)
+            .internal_pull_up(true);
+        let pins: SpiPins = (sck, miso, mosi);
+
+        Spi::new(spi2, pins, enc28j60::MODE, 1.MHz(), &clocks)
+    };
+
+    let mut eth_rx_buffer = [0_u8; Eth::MTU];
+    let mut eth_tx_buffer = [0_u8; Eth::MTU];
+    let mut eth = {
+        let ncs = gpiob.pb12.into_push_pull_output_in_state(true.into());
+        let int = eth_int.into_pull_up_input();
+        let mut reset = gpiob.pb1.into_push_pull_output_in_state(true.into());
+
+        // Perform a hard reset first, then let the driver
+        // perform a soft reset by providing enc28j60::Unconnected
+        // instead of the actual reset pin
+        reset.set_low();
+        delay.delay_ms(5_u8);
+        reset.set_high();
+        delay.delay_ms(5_u8);
+
+        let mut enc = enc28j60::Enc28j60::new(
+            eth_spi,
+            ncs,
+            int,
+            enc28j60::Unconnected,
+            &mut delay,
+            6 * 1024, // 8KB buffer: 6 rx packets 2 tx packet
+            config::MAC_ADDRESS,
+        )
+        .unwrap();
+
+        debug!("ENC28J60: EREVID {:#08b}", enc.erevid().unwrap());
+
+        Eth::new(enc, &mut eth_rx_buffer[..], &mut eth_tx_buffer[..])
+    };
+
+    debug!("Recovery: TCP/IP");
+    let mac = EthernetAddress::from_bytes(&config::MAC_ADDRESS);
+    let net_config = Config::new(mac.into());
+    let mut iface = Interface::new(net_config, &mut eth, Instant::ZERO);
+    iface.update_ip_addrs(|addr| {
+        addr.push(config::IP_CIDR.into()).unwrap();
+    });
+
+    let mut socket_storage = [SocketStorage::EMPTY; 1];
+    let mut sockets = SocketSet::new(&mut socket_storage[..]);
+    let mut tcp_rx_buffer = [0_u8; config::DEVICE_PROTO_SOCKET_BUFFER_LEN];
+    let mut tcp_tx_buffer = [0_u8; config::DEVICE_PROTO_SOCKET_BUFFER_LEN];
+    let tcp_socket = TcpSocket::new(
+        TcpSocketBuffer::new(&mut tcp_rx_buffer[..]),
+        TcpSocketBuffer::new(&mut tcp_tx_buffer[..]),
+    );
+    let device_socket = sockets.add(tcp_socket);
+
+    let mut um = UpdateManager::new(config::DEVICE_PORT);
+    let mut dev = RecoveryDevice {
+        info: &device_info,
+        boot_history: &boot_history,
+        flash: &mut flash,
+    };
+
+    info!(">>> Waiting for the device protocol client <<<");
+
+    let mut last_um_poll = 0;
+    let mut last_led_toggle = 0;
+    loop {
+        watchdog.feed();
+
+        let now = clock.now().ticks();
+        iface.poll(Instant::from_millis(now), &mut eth, &mut sockets);
+
+        if now.wrapping_sub(last_um_poll) >= config::UPDATE_MANAGER_POLL_INTERVAL_MS {
+            last_um_poll = now;
+            let socket = sockets.get_mut::<TcpSocket>(device_socket);
+            if let Err(e) = um.update(&mut dev, socket) {
+                warn!("UM: returned an error. {e:?}");
+                um.reset(socket);
+            }
+        }
+
+        if now.wrapping_sub(last_led_toggle) >= config::LED_BLINK_INTERVAL_MS {
+            last_led_toggle = now;
+            led.toggle();
+        }
+    }
+}
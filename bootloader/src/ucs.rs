@@ -1,4 +1,5 @@
 use core::ptr;
+use wire_protocols::FirmwareVersion;
 
 /// The UCS (Update Configuration and Status) in-memory state.
 pub struct UpdateConfigAndStatus {}
@@ -7,6 +8,15 @@ impl UpdateConfigAndStatus {
     /// Address in RAM where the UCS lives.
     /// word0 == updating_pending
     /// word1 = update_valid
+    /// word2 = recovery_requested
+    /// word3 = rollback_requested
+    /// word4 = bootloader_version_valid
+    /// word5 = bootloader_version (major << 16 | minor)
+    /// word6 = bootloader_version (patch)
+    /// word7 = boot_other_slot_once
+    /// word8 = firmware_version_valid
+    /// word9 = firmware_version (major << 16 | minor)
+    /// word10 = firmware_version (patch)
     const RAM_ADDRESS: u32 = 0x2000_0000;
     const MAGIC_TRUE: u32 = 0xACAD_B0FC;
 
@@ -50,6 +60,105 @@ impl UpdateConfigAndStatus {
         });
     }
 
+    /// Retrieves and clears the UCS.recovery_requested flag.
+    pub fn recovery_requested() -> bool {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            let word = ptr::read_volatile(Self::base_ptr().offset(2));
+            ptr::write_volatile(Self::base_ptr_mut().offset(2), 0);
+            word == Self::MAGIC_TRUE
+        })
+    }
+
+    /// Sets the UCS.recovery_requested flag, the bootloader will enter
+    /// recovery mode on the next reset (when supported).
+    pub fn set_recovery_requested() {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            ptr::write_volatile(Self::base_ptr_mut().offset(2), Self::MAGIC_TRUE);
+        });
+    }
+
+    /// Retrieves and clears the UCS.rollback_requested flag.
+    pub fn rollback_requested() -> bool {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            let word = ptr::read_volatile(Self::base_ptr().offset(3));
+            ptr::write_volatile(Self::base_ptr_mut().offset(3), 0);
+            word == Self::MAGIC_TRUE
+        })
+    }
+
+    /// Sets the UCS.rollback_requested flag, the bootloader will trial boot the
+    /// other slot on the next reset if its image is valid.
+    pub fn set_rollback_requested() {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            ptr::write_volatile(Self::base_ptr_mut().offset(3), Self::MAGIC_TRUE);
+        });
+    }
+
+    /// Retrieves and clears the UCS.boot_other_slot_once flag.
+    pub fn boot_other_slot_once() -> bool {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            let word = ptr::read_volatile(Self::base_ptr().offset(7));
+            ptr::write_volatile(Self::base_ptr_mut().offset(7), 0);
+            word == Self::MAGIC_TRUE
+        })
+    }
+
+    /// Sets the UCS.boot_other_slot_once flag, the bootloader will boot the
+    /// other slot on the next reset without changing the active slot.
+    pub fn set_boot_other_slot_once() {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            ptr::write_volatile(Self::base_ptr_mut().offset(7), Self::MAGIC_TRUE);
+        });
+    }
+
+    /// Retrieves the bootloader version, if the bootloader recorded it.
+    /// Unlike the flags, it is left intact so it can be read at any time.
+    pub fn bootloader_version() -> Option<FirmwareVersion> {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            let valid = ptr::read_volatile(Self::base_ptr().offset(4));
+            let major_minor = ptr::read_volatile(Self::base_ptr().offset(5));
+            let patch = ptr::read_volatile(Self::base_ptr().offset(6));
+            (valid == Self::MAGIC_TRUE).then(|| {
+                FirmwareVersion::new((major_minor >> 16) as u16, major_minor as u16, patch as u16)
+            })
+        })
+    }
+
+    /// Sets the UCS.bootloader_version words, done by the bootloader
+    /// before booting the application.
+    pub fn set_bootloader_version(version: FirmwareVersion) {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            let major_minor = ((version.major as u32) << 16) | version.minor as u32;
+            ptr::write_volatile(Self::base_ptr_mut().offset(5), major_minor);
+            ptr::write_volatile(Self::base_ptr_mut().offset(6), version.patch as u32);
+            ptr::write_volatile(Self::base_ptr_mut().offset(4), Self::MAGIC_TRUE);
+        });
+    }
+
+    /// Retrieves and clears the firmware version, if the application recorded it.
+    pub fn firmware_version() -> Option<FirmwareVersion> {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            let valid = ptr::read_volatile(Self::base_ptr().offset(8));
+            let major_minor = ptr::read_volatile(Self::base_ptr().offset(9));
+            let patch = ptr::read_volatile(Self::base_ptr().offset(10));
+            ptr::write_volatile(Self::base_ptr_mut().offset(8), 0);
+            (valid == Self::MAGIC_TRUE).then(|| {
+                FirmwareVersion::new((major_minor >> 16) as u16, major_minor as u16, patch as u16)
+            })
+        })
+    }
+
+    /// Sets the UCS.firmware_version words, done by the application once it has
+    /// started. The bootloader records it in the boot history on the next reset.
+    pub fn set_firmware_version(version: FirmwareVersion) {
+        cortex_m::interrupt::free(|_cs| unsafe {
+            let major_minor = ((version.major as u32) << 16) | version.minor as u32;
+            ptr::write_volatile(Self::base_ptr_mut().offset(9), major_minor);
+            ptr::write_volatile(Self::base_ptr_mut().offset(10), version.patch as u32);
+            ptr::write_volatile(Self::base_ptr_mut().offset(8), Self::MAGIC_TRUE);
+        });
+    }
+
     const fn base_ptr() -> *const u32 {
         Self::RAM_ADDRESS as *const _
     }
@@ -11,7 +11,7 @@ MEMORY
     /* Slot 1 is bigger (sectors 6 and 7, but we use the min of the two) */
     FLASH : ORIGIN = 0x08010000, LENGTH = 194K
 
-    /* First 16 bytes are reserved for the UCS words */
-    /* LENGTH = (128K - 16) = 131056 */
-    RAM : ORIGIN = 0x20000010, LENGTH = 131056
+    /* First 48 bytes are reserved for the UCS words */
+    /* LENGTH = (128K - 48) = 131024 */
+    RAM : ORIGIN = 0x20000030, LENGTH = 131024
 }
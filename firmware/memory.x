@@ -12,7 +12,7 @@ MEMORY
     /* Use slot 0 here since initial programming must write to slot 0 */
     FLASH : ORIGIN = 0x08010000, LENGTH = 194K
 
-    /* First 16 bytes are reserved for the UCS words */
-    /* LENGTH = (128K - 16) = 131056 */
-    RAM : ORIGIN = 0x20000010, LENGTH = 131056
+    /* First 48 bytes are reserved for the UCS words */
+    /* LENGTH = (128K - 48) = 131024 */
+    RAM : ORIGIN = 0x20000030, LENGTH = 131024
 }
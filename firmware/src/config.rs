@@ -8,6 +8,7 @@ mod generated_confg {
 pub const IP_CIDR: Ipv4Cidr = Ipv4Cidr::new(Ipv4Address(IP_ADDRESS), 24);
 
 pub const BCAST_PROTO_SOCKET_BUFFER_LEN: usize = wire_protocols::broadcast::MESSAGE_LEN * 4;
+pub const DISC_PROTO_SOCKET_BUFFER_LEN: usize = wire_protocols::discovery::RESPONSE_LEN * 2;
 pub const DEVICE_PROTO_SOCKET_BUFFER_LEN: usize = wire_protocols::device::SOCKET_BUFFER_LEN;
 
 pub const STARTUP_DELAY_SECONDS: u8 = 5;
@@ -40,3 +41,5 @@ pub const DATA_MANAGER_WARM_UP_PERIOD_CYCLES: u32 = 12;
 pub const BCAST_INTERVAL_SEC: u32 = 5;
 
 pub const UPDATE_MANAGER_POLL_INTERVAL_MS: u32 = 100;
+
+pub const DISCOVERY_POLL_INTERVAL_MS: u32 = 100;
@@ -26,7 +26,7 @@ mod app {
     use crate::shared_i2c::{I2cDevices, I2cPins};
     use crate::tasks::{
         data_manager::{SpawnArg as DataManagerSpawnArg, TaskState as DataManagerTaskState},
-        data_manager_task,
+        data_manager_task, discovery_task,
         display::{SpawnArg as DisplaySpawnArg, TaskState as DisplayTaskState},
         display_task, eth_gpio_interrupt_handler_task, ipstack_clock_timer_task, ipstack_poll_task,
         ipstack_poll_timer_task,
@@ -39,7 +39,7 @@ mod app {
     };
     use crate::{config, util};
     use bootloader_lib::{BootConfig, ResetReasonExt, UpdateConfigAndStatus};
-    use bootloader_support::ResetReason;
+    use bootloader_support::{BootHistory, ResetReason};
     use log::{debug, error, info};
     use smoltcp::{
         iface::{Config, Interface, SocketHandle, SocketSet},
@@ -59,6 +59,7 @@ mod app {
         watchdog::IndependentWatchdog,
     };
     use update_manager::DeviceInfo;
+    use wire_protocols::discovery::Response as DiscoveryResponse;
 
     type LedPin = PC13<Output<PushPull>>;
 
@@ -75,6 +76,8 @@ mod app {
         #[lock_free]
         device_socket: SocketHandle,
         #[lock_free]
+        disc_socket: SocketHandle,
+        #[lock_free]
         i2c_devices: I2cDevices<DelayUs<TIM10>, DelayUs<TIM11>>,
     }
 
@@ -87,6 +90,8 @@ mod app {
         led: LedPin,
         watchdog: IndependentWatchdog,
         device_info: DeviceInfo,
+        discovery_response: DiscoveryResponse,
+        boot_history: BootHistory,
         flash: FLASH,
     }
 
@@ -97,8 +102,9 @@ mod app {
 
     #[init(local = [
         eth_storage: EthernetStorage<{Eth::MTU}> = EthernetStorage::new(),
-        net_storage: NetworkStorage<2> = NetworkStorage::new(),
+        net_storage: NetworkStorage<3> = NetworkStorage::new(),
         udp_socket_storage: UdpSocketStorage<{config::BCAST_PROTO_SOCKET_BUFFER_LEN}> = UdpSocketStorage::new(),
+        disc_socket_storage: UdpSocketStorage<{config::DISC_PROTO_SOCKET_BUFFER_LEN}> = UdpSocketStorage::new(),
         tcp_socket_storage: TcpSocketStorage<{config::DEVICE_PROTO_SOCKET_BUFFER_LEN}> = TcpSocketStorage::new(),
     ])]
     fn init(mut ctx: init::Context) -> (Shared, Local, init::Monotonics) {
@@ -163,6 +169,7 @@ mod app {
             Ipv4Address(config::BROADCAST_ADDRESS)
         );
         info!("Device protocol port: {}", config::DEVICE_PORT);
+        info!("Discovery protocol port: {}", config::DISCOVERY_PORT);
         info!("Reset reason: {reset_reason}");
         info!("Update pending: {update_pending}");
         info!("############################################################");
@@ -199,7 +206,12 @@ mod app {
         info!("Setup: boot config");
         let flash = ctx.device.FLASH;
         let mut crc = Crc32::new(ctx.device.CRC);
-        let boot_cfg = BootConfig::read(&flash, &mut crc).unwrap();
+        let mut boot_cfg = BootConfig::read(&flash, &mut crc).unwrap();
+        // The bootloader records the version in this boot's history entry on the next
+        // reset, the reported history has it already
+        UpdateConfigAndStatus::set_firmware_version(config::FIRMWARE_VERSION);
+        boot_cfg.set_firmware_version(config::FIRMWARE_VERSION);
+        let boot_history = *boot_cfg.history();
 
         info!("Setup: S8 LP");
         let tx = gpioa.pa9.into_alternate();
@@ -326,6 +338,16 @@ mod app {
         let tcp_socket = TcpSocket::new(tcp_rx_buf, tcp_tx_buf);
         let device_socket = sockets.add(tcp_socket);
 
+        let disc_rx_buf = UdpPacketBuffer::new(
+            &mut ctx.local.disc_socket_storage.rx_metadata[..],
+            &mut ctx.local.disc_socket_storage.rx_buffer[..],
+        );
+        let disc_tx_buf = UdpPacketBuffer::new(
+            &mut ctx.local.disc_socket_storage.tx_metadata[..],
+            &mut ctx.local.disc_socket_storage.tx_buffer[..],
+        );
+        let disc_socket = sockets.add(UdpSocket::new(disc_rx_buf, disc_tx_buf));
+
         info!("Setup: net clock timer");
         let mut net_clock_timer = ctx.core.SYST.counter_us(&clocks);
         net_clock_timer.start(1.millis()).unwrap();
@@ -359,6 +381,7 @@ mod app {
         }
 
         let device_info = util::device_info(boot_cfg.firmware_boot_slot(), reset_reason);
+        let discovery_response = util::discovery_response(&device_info);
 
         watchdog_task::spawn().unwrap();
         display_task::spawn(DisplaySpawnArg::Startup).unwrap();
@@ -375,6 +398,8 @@ mod app {
 
         update_manager_task::spawn_after(config::UPDATE_MANAGER_POLL_INTERVAL_MS.millis()).unwrap();
 
+        discovery_task::spawn_after(config::DISCOVERY_POLL_INTERVAL_MS.millis()).unwrap();
+
         (
             Shared {
                 eth,
@@ -382,6 +407,7 @@ mod app {
                 sockets,
                 bcast_socket,
                 device_socket,
+                disc_socket,
                 i2c_devices,
             },
             Local {
@@ -392,6 +418,8 @@ mod app {
                 led,
                 watchdog,
                 device_info,
+                discovery_response,
+                boot_history,
                 flash,
             },
             init::Monotonics(mono),
@@ -435,12 +463,17 @@ mod app {
 
     extern "Rust" {
         #[task(
-              local = [state: UpdateManagerTaskState = UpdateManagerTaskState::new(), device_info, flash],
+              local = [state: UpdateManagerTaskState = UpdateManagerTaskState::new(), device_info, boot_history, flash],
               shared = [sockets, device_socket])
           ]
         fn update_manager_task(ctx: update_manager_task::Context);
     }
 
+    extern "Rust" {
+        #[task(local = [discovery_response], shared = [sockets, disc_socket])]
+        fn discovery_task(ctx: discovery_task::Context);
+    }
+
     extern "Rust" {
         #[task(binds = SysTick, local = [net_clock_timer])]
         fn ipstack_clock_timer_task(ctx: ipstack_clock_timer_task::Context);
@@ -1,5 +1,4 @@
-pub mod eth;
 pub mod storage;
 
-pub use eth::{Eth, SpiPins};
+pub use eth_device::{Eth, SpiPins};
 pub use storage::{EthernetStorage, NetworkStorage, TcpSocketStorage, UdpSocketStorage};
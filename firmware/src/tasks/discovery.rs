@@ -0,0 +1,59 @@
+use crate::{app::discovery_task, config};
+use log::{debug, warn};
+use smoltcp::socket::udp::{Socket as UdpSocket, UdpMetadata};
+use stm32f4xx_hal::prelude::*;
+use wire_protocols::discovery::{Message as WireMessage, Request};
+
+/// Answers discovery requests with the response built at init, only the
+/// request ID changes between responses
+pub(crate) fn discovery_task(ctx: discovery_task::Context) {
+    let response = ctx.local.discovery_response;
+    let sockets = ctx.shared.sockets;
+    let socket = sockets.get_mut::<UdpSocket>(*ctx.shared.disc_socket);
+
+    if !socket.is_open() {
+        socket.bind(config::DISCOVERY_PORT).unwrap();
+    }
+
+    while socket.can_recv() {
+        let (req, meta) = match socket.recv() {
+            Ok((data, meta)) => (
+                WireMessage::new_checked(data).and_then(|wire| Request::parse(&wire)),
+                meta,
+            ),
+            Err(e) => {
+                warn!("Disc: failed to receive. {e:?}");
+                break;
+            }
+        };
+
+        let req = match req {
+            Ok(req) => req,
+            Err(_) => {
+                debug!("Disc: ignoring invalid request from {}", meta.endpoint);
+                continue;
+            }
+        };
+
+        if !socket.can_send() {
+            warn!("Disc: socket cannot send, dropping request");
+            break;
+        }
+
+        debug!("Disc: request ID {} from {}", req.request_id, meta.endpoint);
+        response.request_id = req.request_id;
+        let meta = UdpMetadata {
+            endpoint: meta.endpoint,
+            meta: Default::default(),
+        };
+        match socket.send(response.message_len(), meta) {
+            Err(e) => warn!("Disc: failed to send. {e:?}"),
+            Ok(buf) => {
+                let mut wire = WireMessage::new_unchecked(buf);
+                response.emit(&mut wire);
+            }
+        }
+    }
+
+    discovery_task::spawn_after(config::DISCOVERY_POLL_INTERVAL_MS.millis()).unwrap();
+}
@@ -5,7 +5,7 @@ use crate::{
     tasks::display::SpawnArg as DisplaySpawnArg,
 };
 use bootloader_lib::UpdateConfigAndStatus;
-use bootloader_support::FLASH_BASE_ADDRESS;
+use bootloader_support::{BootHistory, FLASH_BASE_ADDRESS};
 use log::{debug, warn};
 use smoltcp::socket::tcp::Socket as TcpSocket;
 use stm32f4xx_hal::{
@@ -16,7 +16,7 @@ use stm32f4xx_hal::{
 };
 use update_manager::{Device, DeviceInfo, FirmwareUpdateStatus, StatusCodeResult, UpdateManager};
 use wire_protocols::device::{
-    MemoryEraseRequest, MemoryReadRequest, MemoryWriteRequest, StatusCode,
+    MemoryEraseRequest, MemoryReadRequest, MemoryWriteRequest, RebootTarget, StatusCode,
 };
 
 pub struct TaskState {
@@ -34,6 +34,7 @@ impl TaskState {
 pub(crate) fn update_manager_task(ctx: update_manager_task::Context) {
     let state = ctx.local.state;
     let device_info = ctx.local.device_info;
+    let boot_history = ctx.local.boot_history;
     let flash = ctx.local.flash;
     let sockets = ctx.shared.sockets;
     let socket_handle = ctx.shared.device_socket;
@@ -44,6 +45,7 @@ pub(crate) fn update_manager_task(ctx: update_manager_task::Context) {
 
     let mut dev = UmDevice {
         info: device_info,
+        boot_history,
         flash,
     };
     if let Err(e) = state.um.update(&mut dev, socket) {
@@ -56,6 +58,7 @@ pub(crate) fn update_manager_task(ctx: update_manager_task::Context) {
 
 struct UmDevice<'a> {
     info: &'a DeviceInfo,
+    boot_history: &'a BootHistory,
     flash: &'a mut FLASH,
 }
 
@@ -64,29 +67,56 @@ impl<'a> Device for UmDevice<'a> {
         self.info
     }
 
+    fn boot_history(&self) -> &BootHistory {
+        self.boot_history
+    }
+
     fn perform_reboot(&mut self) -> ! {
         warn!("Rebooting now");
-        unsafe {
-            // TODO - this is common in several places, put into a fn (see main.rs)
-            crate::logger::flush_logger();
-            let rcc = &(*pac::RCC::ptr());
-            pac::USART6::disable(rcc);
+        unsafe { reset() }
+    }
 
-            bootloader_lib::sw_reset();
+    fn perform_reboot_into(&mut self, target: RebootTarget) -> ! {
+        match target {
+            RebootTarget::OtherSlot => {
+                warn!(
+                    "Rebooting into slot {} once now",
+                    self.info.active_boot_slot.other()
+                );
+                UpdateConfigAndStatus::set_boot_other_slot_once();
+            }
+            RebootTarget::TrialOtherSlot => {
+                warn!(
+                    "Rebooting into slot {} now",
+                    self.info.active_boot_slot.other()
+                );
+                // Same as an update, the bootloader trial boots the other slot
+                UpdateConfigAndStatus::set_update_pending();
+            }
+            RebootTarget::Recovery => {
+                warn!("Rebooting into recovery mode now");
+                UpdateConfigAndStatus::set_recovery_requested();
+            }
+            RebootTarget::CurrentSlot | RebootTarget::Unknown(_) => {
+                warn!("Rebooting now");
+            }
         }
+        unsafe { reset() }
+    }
+
+    fn perform_rollback(&mut self) -> ! {
+        warn!(
+            "Rolling back to slot {}, rebooting now",
+            self.info.active_boot_slot.other()
+        );
+        UpdateConfigAndStatus::set_rollback_requested();
+        unsafe { reset() }
     }
 
     fn complete_update_and_perform_reboot(&mut self) -> ! {
         warn!("Update complete, rebooting now");
         UpdateConfigAndStatus::set_update_pending();
-        unsafe {
-            // TODO - this is common in several places, put into a fn (see main.rs)
-            crate::logger::flush_logger();
-            let rcc = &(*pac::RCC::ptr());
-            pac::USART6::disable(rcc);
-
-            bootloader_lib::sw_reset();
-        }
+        unsafe { reset() }
     }
 
     fn update_progress_changed(&mut self, status: FirmwareUpdateStatus, bytes_written: usize) {
@@ -107,10 +137,16 @@ impl<'a> Device for UmDevice<'a> {
     }
 
     fn read_memory(&mut self, req: MemoryReadRequest) -> StatusCodeResult<&[u8]> {
-        let other_slot = self.info.active_boot_slot.other();
-        if !other_slot.contains(req.address) {
+        // Either slot can be read, the active slot is the source for delta updates
+        let active_slot = self.info.active_boot_slot;
+        let slot = if active_slot.contains(req.address) {
+            active_slot
+        } else {
+            active_slot.other()
+        };
+        if !slot.contains(req.address) {
             Err(StatusCode::InvalidAddress)
-        } else if !other_slot.contains(req.address + req.length - 1) {
+        } else if !slot.contains(req.address + req.length - 1) {
             Err(StatusCode::DataLengthIncorrect)
         } else {
             req.check_length()?;
@@ -129,7 +165,7 @@ impl<'a> Device for UmDevice<'a> {
         } else if !other_slot.contains(req.address + req.length - 1) {
             Err(StatusCode::DataLengthIncorrect)
         } else {
-            req.check_length()?;
+            req.check_write_length()?;
             let offset = req.address - FLASH_BASE_ADDRESS;
             debug!(
                 "Writing to FLASH at offset 0x{offset:X} len=0x{:X}",
@@ -164,3 +200,12 @@ impl<'a> Device for UmDevice<'a> {
         }
     }
 }
+
+// TODO - this is common in several places (see main.rs)
+unsafe fn reset() -> ! {
+    crate::logger::flush_logger();
+    let rcc = &(*pac::RCC::ptr());
+    pac::USART6::disable(rcc);
+
+    bootloader_lib::sw_reset();
+}
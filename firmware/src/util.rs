@@ -1,7 +1,8 @@
 use crate::{built_info, config};
-use bootloader_support::{BootSlot, ResetReason};
+use bootloader_lib::UpdateConfigAndStatus;
+use bootloader_support::{BootSlot, ResetReason, HARDWARE};
 use update_manager::DeviceInfo;
-use wire_protocols::{DeviceSerialNumber, ProtocolVersion};
+use wire_protocols::{discovery, DeviceSerialNumber, ProtocolVersion};
 
 const NA: &str = "NA";
 
@@ -15,7 +16,9 @@ pub(crate) fn read_device_serial_number() -> DeviceSerialNumber {
 pub(crate) fn device_info(active_boot_slot: BootSlot, reset_reason: ResetReason) -> DeviceInfo {
     DeviceInfo {
         protocol_version: ProtocolVersion::v1(),
-        firmware_version: config::FIRMWARE_VERSION,
+        firmware_version: Some(config::FIRMWARE_VERSION),
+        bootloader_version: UpdateConfigAndStatus::bootloader_version(),
+        hardware: HARDWARE,
         device_id: config::DEVICE_ID,
         device_serial_number: read_device_serial_number(),
         mac_address: config::MAC_ADDRESS,
@@ -25,3 +28,22 @@ pub(crate) fn device_info(active_boot_slot: BootSlot, reset_reason: ResetReason)
         git_commit: built_info::GIT_COMMIT_HASH.unwrap_or(NA),
     }
 }
+
+/// The discovery protocol response, the request ID is filled in per request
+pub(crate) fn discovery_response(info: &DeviceInfo) -> discovery::Response {
+    discovery::Response {
+        protocol_version: info.protocol_version,
+        request_id: 0,
+        firmware_version: config::FIRMWARE_VERSION,
+        bootloader_version: info.bootloader_version,
+        device_id: info.device_id,
+        device_serial_number: info.device_serial_number,
+        mac_address: info.mac_address,
+        ip_address: config::IP_ADDRESS,
+        device_port: config::DEVICE_PORT,
+        active_boot_slot: match info.active_boot_slot {
+            BootSlot::Slot0 => 0,
+            BootSlot::Slot1 => 1,
+        },
+    }
+}
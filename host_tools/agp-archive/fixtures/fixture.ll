@@ -0,0 +1,48 @@
+; Minimal application for the elf2bin fixtures, see generate.sh
+target datalayout = "e-m:e-p:32:32-Fi8-i64:64-v128:64:128-a:0:32-n32-S64"
+target triple = "thumbv7em-none-eabihf"
+
+@MESSAGE = internal constant [20 x i8] c"agp-archive fixture\00", section ".rodata.MESSAGE", align 4
+@TABLE = internal constant [6 x i32] [i32 1, i32 1, i32 2, i32 3, i32 5, i32 8], section ".rodata.TABLE", align 4
+@COUNTERS = internal global [3 x i32] [i32 10, i32 20, i32 30], section ".data.COUNTERS", align 4
+@BYTE = internal global i8 7, section ".data.BYTE", align 1
+@ZEROS = internal global [8 x i32] zeroinitializer, section ".bss.ZEROS", align 4
+@UNINIT = internal global [16 x i8] undef, section ".uninit.UNINIT", align 4
+@CONFIG_TABLE = internal constant [4 x i32] [i32 u0x4147500A, i32 2, i32 32100, i32 32101], section ".agp_config", align 4
+
+@__RESET_VECTOR = constant ptr @Reset, section ".vector_table.reset_vector", align 4
+@__EXCEPTIONS = constant [14 x ptr] [ptr @DefaultHandler, ptr @DefaultHandler, ptr @DefaultHandler, ptr @DefaultHandler, ptr @DefaultHandler, ptr null, ptr null, ptr null, ptr null, ptr @DefaultHandler, ptr @DefaultHandler, ptr null, ptr @DefaultHandler, ptr @DefaultHandler], section ".vector_table.exceptions", align 4
+
+@llvm.used = appending global [4 x ptr] [ptr @__RESET_VECTOR, ptr @__EXCEPTIONS, ptr @UNINIT, ptr @CONFIG_TABLE], section "llvm.metadata"
+
+define void @Reset() noreturn section ".text.Reset" {
+entry:
+  br label %loop
+loop:
+  %i = phi i32 [ 0, %entry ], [ %next, %loop ]
+  %idx = urem i32 %i, 6
+  %tp = getelementptr [6 x i32], ptr @TABLE, i32 0, i32 %idx
+  %t = load volatile i32, ptr %tp
+  %cidx = urem i32 %i, 3
+  %cp = getelementptr [3 x i32], ptr @COUNTERS, i32 0, i32 %cidx
+  %c = load volatile i32, ptr %cp
+  %sum = add i32 %c, %t
+  store volatile i32 %sum, ptr %cp
+  %zidx = urem i32 %i, 8
+  %zp = getelementptr [8 x i32], ptr @ZEROS, i32 0, i32 %zidx
+  store volatile i32 %sum, ptr %zp
+  %mp = getelementptr [20 x i8], ptr @MESSAGE, i32 0, i32 %idx
+  %m = load volatile i8, ptr %mp
+  %b = load volatile i8, ptr @BYTE
+  %bm = add i8 %b, %m
+  store volatile i8 %bm, ptr @BYTE
+  %next = add i32 %i, 1
+  br label %loop
+}
+
+define void @DefaultHandler() section ".text.DefaultHandler" {
+entry:
+  br label %loop
+loop:
+  br label %loop
+}
@@ -0,0 +1,19 @@
+#!/usr/bin/env bash
+
+# Regenerates the elf2bin test fixtures: a small application linked for slot 0
+# with the cortex-m-rt section layout plus a flash-resident table, and the
+# reference binary from objcopy.
+# Requires the llvm-tools rustup component.
+
+set -euo pipefail
+
+cd "$(dirname "$0")"
+
+bin_dir="$(rustc --print sysroot)/lib/rustlib/$(rustc -vV | sed -n 's/^host: //p')/bin"
+
+"${bin_dir}/llc" -O1 -mtriple=thumbv7em-none-eabihf -mcpu=cortex-m4 -filetype=obj fixture.ll -o fixture.o
+"${bin_dir}/rust-lld" -flavor gnu -z max-page-size=4 -T fixture.ld fixture.o -o agp0.elf
+"${bin_dir}/llvm-objcopy" -O binary --gap-fill=0xff agp0.elf agp0.bin
+rm fixture.o
+
+exit 0
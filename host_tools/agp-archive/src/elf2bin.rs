@@ -0,0 +1,173 @@
+use crate::{Error, Result};
+use bootloader_support::BootSlot;
+use elf::{
+    abi,
+    endian::LittleEndian,
+    file::{Class, FileHeader},
+    segment::ProgramHeader,
+    ElfBytes,
+};
+
+pub fn sanity_check_elf(slot: BootSlot, ehdr: &FileHeader<LittleEndian>) -> Result<()> {
+    if ehdr.class != Class::ELF32 {
+        return Err(Error::BadClass);
+    }
+    if ehdr.e_machine != abi::EM_ARM {
+        return Err(Error::BadMachine);
+    }
+    if !slot.contains(ehdr.e_entry as u32) {
+        return Err(Error::BadEntry(ehdr.e_entry));
+    }
+    Ok(())
+}
+
+/// The value of erased FLASH, used to fill the gaps between segments
+const FILL: u8 = 0xFF;
+
+/// Converts the ELF linked for the given slot into the binary image written to FLASH,
+/// the same output as `arm-none-eabi-objcopy -O binary --gap-fill=0xff`.
+///
+/// Every `PT_LOAD` segment with data in the file is placed at its physical (load) address
+/// relative to the start of the slot, gaps between segments are filled with 0xFF.
+/// The image must start with the vector table at the start of the slot.
+pub fn elf2bin(slot: BootSlot, elf: &ElfBytes<LittleEndian>) -> Result<Vec<u8>> {
+    let slot_start = slot.address() as u64;
+    let slot_end = slot_start + slot.size() as u64;
+
+    let mut segments: Vec<(usize, ProgramHeader)> = elf
+        .segments()
+        .ok_or(Error::MissingProgramHeaders)?
+        .into_iter()
+        .enumerate()
+        .filter(|(_, ph)| ph.p_type == abi::PT_LOAD && ph.p_filesz != 0)
+        .collect();
+    if segments.is_empty() {
+        return Err(Error::NoLoadableSegments);
+    }
+
+    for (index, ph) in segments.iter() {
+        if ph.p_paddr < slot_start || ph.p_paddr + ph.p_filesz > slot_end {
+            return Err(Error::SegmentOutsideSlot(
+                segment_name(elf, *index, ph)?,
+                ph.p_paddr,
+            ));
+        }
+    }
+
+    segments.sort_by_key(|(_, ph)| ph.p_paddr);
+
+    let (first_index, first_ph) = &segments[0];
+    if first_ph.p_paddr != slot_start {
+        return Err(Error::BadImageStart(
+            segment_name(elf, *first_index, first_ph)?,
+            first_ph.p_paddr,
+        ));
+    }
+
+    let mut bin = Vec::new();
+    let mut prev: Option<(usize, &ProgramHeader)> = None;
+    for (index, ph) in segments.iter() {
+        let bin_offset = (ph.p_paddr - slot_start) as usize;
+        if bin_offset < bin.len() {
+            let (prev_index, prev_ph) = prev.expect("The first segment starts at offset 0");
+            return Err(Error::SegmentOverlap(
+                segment_name(elf, *index, ph)?,
+                segment_name(elf, prev_index, prev_ph)?,
+            ));
+        }
+
+        let seg_data = elf.segment_data(ph)?;
+        bin.resize(bin_offset, FILL);
+        bin.extend_from_slice(seg_data);
+        prev = Some((*index, ph));
+    }
+
+    Ok(bin)
+}
+
+/// Names a segment by its program header index and the sections it contains,
+/// e.g. `segment 3 (.data)`
+fn segment_name(elf: &ElfBytes<LittleEndian>, index: usize, ph: &ProgramHeader) -> Result<String> {
+    let mut name = format!("segment {index}");
+    let (shdrs, strtab) = elf.section_headers_with_strtab()?;
+    let (Some(shdrs), Some(strtab)) = (shdrs, strtab) else {
+        return Ok(name);
+    };
+    let mut sections = Vec::new();
+    for sh in shdrs.iter() {
+        let in_segment = sh.sh_flags & abi::SHF_ALLOC as u64 != 0
+            && sh.sh_type != abi::SHT_NOBITS
+            && sh.sh_size != 0
+            && sh.sh_offset >= ph.p_offset
+            && sh.sh_offset + sh.sh_size <= ph.p_offset + ph.p_filesz;
+        if in_segment {
+            sections.push(strtab.get(sh.sh_name as usize)?);
+        }
+    }
+    if !sections.is_empty() {
+        name.push_str(&format!(" ({})", sections.join(" ")));
+    }
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // See fixtures/generate.sh
+    const FIXTURE_ELF: &[u8] = include_bytes!("../fixtures/agp0.elf");
+    const FIXTURE_OBJCOPY_BIN: &[u8] = include_bytes!("../fixtures/agp0.bin");
+
+    #[test]
+    fn matches_objcopy() {
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(FIXTURE_ELF).unwrap();
+        sanity_check_elf(BootSlot::Slot0, &elf.ehdr).unwrap();
+        let bin = elf2bin(BootSlot::Slot0, &elf).unwrap();
+        assert_eq!(bin, FIXTURE_OBJCOPY_BIN);
+    }
+
+    #[test]
+    fn wrong_slot() {
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(FIXTURE_ELF).unwrap();
+        assert!(matches!(
+            sanity_check_elf(BootSlot::Slot1, &elf.ehdr),
+            Err(Error::BadEntry(_))
+        ));
+        let err = elf2bin(BootSlot::Slot1, &elf).unwrap_err();
+        assert!(matches!(err, Error::SegmentOutsideSlot(_, 0x0801_0000)));
+        assert_eq!(
+            err.to_string(),
+            "segment 0 (.vector_table) at p_paddr 0x8010000 is outside the slot"
+        );
+    }
+
+    #[test]
+    fn gaps_filled_with_erased_value() {
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(FIXTURE_ELF).unwrap();
+        let bin = elf2bin(BootSlot::Slot0, &elf).unwrap();
+        let config_sh = elf.section_header_by_name(".agp_config").unwrap().unwrap();
+        let config_offset = (config_sh.sh_addr - BootSlot::Slot0.address() as u64) as usize;
+        assert_eq!(bin[config_offset - 1], FILL);
+        assert_eq!(&bin[config_offset..config_offset + 4], b"\nPGA");
+    }
+
+    #[test]
+    fn overlapping_segments() {
+        // Move the .agp_config segment (index 5) on top of .rodata (index 2)
+        const PHDR_SIZE: usize = 32;
+        const P_PADDR_OFFSET: usize = 12;
+        let mut data = FIXTURE_ELF.to_vec();
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(FIXTURE_ELF).unwrap();
+        let phoff = elf.ehdr.e_phoff as usize;
+        let rodata_paddr = elf.segments().unwrap().get(2).unwrap().p_paddr as u32;
+        let p_paddr = phoff + 5 * PHDR_SIZE + P_PADDR_OFFSET;
+        data[p_paddr..p_paddr + 4].copy_from_slice(&(rodata_paddr + 4).to_le_bytes());
+
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(&data).unwrap();
+        let err = elf2bin(BootSlot::Slot0, &elf).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "segment 5 (.agp_config) overlaps segment 2 (.rodata)"
+        );
+    }
+}
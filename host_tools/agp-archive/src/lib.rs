@@ -0,0 +1,110 @@
+//! Firmware image archive (`agp_images.cpio`) support shared by agp-linker and the CLI.
+//!
+//! The archive contains the application linked for each boot slot, as ELF and
+//! as the raw binary that gets written to FLASH, and a manifest describing them.
+
+#![forbid(unsafe_code)]
+
+use bootloader_support::BootSlot;
+use std::{fmt, path::PathBuf};
+
+mod elf2bin;
+mod manifest;
+
+pub use self::elf2bin::{elf2bin, sanity_check_elf};
+pub use self::manifest::{sha256_hex, ArchiveManifest, ImageDigest};
+
+pub const ARCHIVE_FILE_NAME: &str = "agp_images.cpio";
+pub const MANIFEST_FILE_NAME: &str = "agp_manifest.json";
+
+const SLOT0_FILE_NAME: &str = "agp0";
+const SLOT1_FILE_NAME: &str = "agp1";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    Elf(elf::ParseError),
+    BadClass,
+    BadMachine,
+    BadEntry(u64),
+    MissingProgramHeaders,
+    NoLoadableSegments,
+    BadImageStart(String, u64),
+    SegmentOutsideSlot(String, u64),
+    SegmentOverlap(String, String),
+    MissingImage(String),
+    ImageSizeMismatch(String, u64, u64),
+    ImageDigestMismatch(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            Elf(e) => write!(f, "Failed to parse ELF. {e}"),
+            BadClass => f.write_str("Bad class"),
+            BadMachine => f.write_str("Bad e_machine"),
+            BadEntry(addr) => write!(f, "Bad e_entry 0x{addr:X}"),
+            MissingProgramHeaders => f.write_str("Missing program headers"),
+            NoLoadableSegments => f.write_str("No loadable segments"),
+            BadImageStart(s, addr) => write!(
+                f,
+                "The image must start at the slot address, {s} at p_paddr 0x{addr:X} is first"
+            ),
+            SegmentOutsideSlot(s, addr) => {
+                write!(f, "{s} at p_paddr 0x{addr:X} is outside the slot")
+            }
+            SegmentOverlap(s, other) => write!(f, "{s} overlaps {other}"),
+            MissingImage(name) => write!(f, "The manifest has no entry for '{name}'"),
+            ImageSizeMismatch(name, size, expected) => write!(
+                f,
+                "'{name}' is {size} bytes but the manifest says {expected}"
+            ),
+            ImageDigestMismatch(name) => {
+                write!(f, "'{name}' doesn't match the manifest's SHA-256")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Elf(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<elf::ParseError> for Error {
+    fn from(value: elf::ParseError) -> Self {
+        Error::Elf(value)
+    }
+}
+
+/// Archive entry file names for a boot slot
+pub trait BootSlotExt {
+    fn file_name(&self) -> &'static str;
+
+    fn elf_file_name_and_ext(&self) -> PathBuf {
+        let mut f = PathBuf::from(self.file_name());
+        f.set_extension("elf");
+        f
+    }
+
+    fn bin_file_name_and_ext(&self) -> PathBuf {
+        let mut f = PathBuf::from(self.file_name());
+        f.set_extension("bin");
+        f
+    }
+}
+
+impl BootSlotExt for BootSlot {
+    fn file_name(&self) -> &'static str {
+        match self {
+            BootSlot::Slot0 => SLOT0_FILE_NAME,
+            BootSlot::Slot1 => SLOT1_FILE_NAME,
+        }
+    }
+}
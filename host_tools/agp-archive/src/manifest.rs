@@ -0,0 +1,144 @@
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use wire_protocols::FirmwareVersion;
+
+/// Build information recorded in the archive by agp-linker
+#[serde_as]
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Deserialize, Serialize)]
+pub struct ArchiveManifest {
+    #[serde_as(as = "DisplayFromStr")]
+    pub firmware_version: FirmwareVersion,
+    /// Oldest bootloader the firmware can be booted by
+    #[serde_as(as = "DisplayFromStr")]
+    pub min_bootloader_version: FirmwareVersion,
+    /// Device protocol version the firmware speaks
+    pub protocol_version: u8,
+    pub hardware: String,
+    /// None when the firmware wasn't built from a git checkout
+    pub git_commit: Option<String>,
+    pub built_time_utc: String,
+    /// Cargo profile the firmware was built with
+    pub profile: Option<String>,
+    /// Archive entries by file name
+    pub images: BTreeMap<String, ImageDigest>,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Deserialize, Serialize)]
+pub struct ImageDigest {
+    pub size: u64,
+    /// Lowercase hex
+    pub sha256: String,
+}
+
+impl ArchiveManifest {
+    /// Checks an archive entry is the one the manifest was created for
+    pub fn check_image(&self, name: &str, data: &[u8]) -> Result<()> {
+        let digest = self
+            .images
+            .get(name)
+            .ok_or_else(|| Error::MissingImage(name.to_owned()))?;
+        if digest.size != data.len() as u64 {
+            return Err(Error::ImageSizeMismatch(
+                name.to_owned(),
+                data.len() as u64,
+                digest.size,
+            ));
+        }
+        if !digest.sha256.eq_ignore_ascii_case(&sha256_hex(data)) {
+            return Err(Error::ImageDigestMismatch(name.to_owned()));
+        }
+        Ok(())
+    }
+}
+
+impl ImageDigest {
+    pub fn new(data: &[u8]) -> Self {
+        ImageDigest {
+            size: data.len() as u64,
+            sha256: sha256_hex(data),
+        }
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(images: &[(&str, &[u8])]) -> ArchiveManifest {
+        ArchiveManifest {
+            firmware_version: FirmwareVersion::new(0, 4, 2),
+            min_bootloader_version: FirmwareVersion::new(0, 4, 1),
+            protocol_version: 1,
+            hardware: "air-gradient-pro-stm32f411".to_owned(),
+            git_commit: None,
+            built_time_utc: "Mon, 24 Apr 2023 15:06:18 +0000".to_owned(),
+            profile: None,
+            images: images
+                .iter()
+                .map(|(name, data)| (name.to_string(), ImageDigest::new(data)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn digest() {
+        let d = ImageDigest::new(b"abc");
+        assert_eq!(d.size, 3);
+        assert_eq!(
+            d.sha256,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn check_image() {
+        let m = manifest(&[("agp0.bin", b"slot0"), ("agp1.bin", b"slot1")]);
+        m.check_image("agp0.bin", b"slot0").unwrap();
+        m.check_image("agp1.bin", b"slot1").unwrap();
+    }
+
+    #[test]
+    fn check_image_ignores_digest_case() {
+        let mut m = manifest(&[("agp0.bin", b"slot0")]);
+        let digest = m.images.get_mut("agp0.bin").unwrap();
+        digest.sha256 = digest.sha256.to_uppercase();
+        m.check_image("agp0.bin", b"slot0").unwrap();
+    }
+
+    #[test]
+    fn unlisted_image() {
+        let m = manifest(&[("agp0.bin", b"slot0")]);
+        assert!(matches!(
+            m.check_image("agp1.bin", b"slot1"),
+            Err(Error::MissingImage(name)) if name == "agp1.bin"
+        ));
+    }
+
+    #[test]
+    fn size_mismatch() {
+        let m = manifest(&[("agp0.bin", b"slot0")]);
+        assert!(matches!(
+            m.check_image("agp0.bin", b"slot"),
+            Err(Error::ImageSizeMismatch(_, 4, 5))
+        ));
+    }
+
+    #[test]
+    fn digest_mismatch() {
+        let m = manifest(&[("agp0.bin", b"slot0")]);
+        assert!(matches!(
+            m.check_image("agp0.bin", b"slotX"),
+            Err(Error::ImageDigestMismatch(name)) if name == "agp0.bin"
+        ));
+    }
+}
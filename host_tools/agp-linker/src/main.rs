@@ -1,9 +1,18 @@
+use agp_archive::{
+    ArchiveManifest, BootSlotExt, ImageDigest, ARCHIVE_FILE_NAME, MANIFEST_FILE_NAME,
+};
+use bootloader_support::{BootSlot, HARDWARE};
 use cpio::{write_cpio, NewcBuilder};
+use elf::{endian::LittleEndian, ElfBytes};
 use std::{
-    env, fs, io,
-    path::Path,
+    collections::BTreeMap,
+    env, fs,
+    io::{self, Cursor},
+    path::{Path, PathBuf},
     process::{self, Command, ExitStatus},
+    time::SystemTime,
 };
+use wire_protocols::{FirmwareVersion, ProtocolVersion};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -13,15 +22,11 @@ const LINKER: &str = "flip-link";
 
 const NO_ARCHIVE_ENV_VAR: &str = "AGP_LINKER_NO_ARCHIVE";
 
+const CARGO_MANIFEST_FILE_NAME: &str = "Cargo.toml";
 const MEMORY_FILE_NAME: &str = "memory.x";
 const FLASH_SLOT0_MEMORY_FILE_NAME: &str = "agp_memory_slot_0.x";
 const FLASH_SLOT1_MEMORY_FILE_NAME: &str = "agp_memory_slot_1.x";
 
-const ELF_SLOT0: &str = "agp0.elf";
-const ELF_SLOT1: &str = "agp1.elf";
-
-const CPIO_ARCHIVE: &str = "agp_images.cpio";
-
 fn main() -> Result<()> {
     notmain().map(|code| process::exit(code))
 }
@@ -44,7 +49,7 @@ fn notmain() -> Result<i32> {
     if env::var_os(NO_ARCHIVE_ENV_VAR).is_none() {
         let current_dir = env::current_dir()?;
         let target_dir = current_dir.join("target");
-        let _output_elf_path = get_output_path(&args)?;
+        let output_elf_path = get_output_path(&args)?;
 
         let memory_x_path = current_dir.join(MEMORY_FILE_NAME);
         let memory_x_content = fs::read_to_string(&memory_x_path)?;
@@ -68,7 +73,7 @@ fn notmain() -> Result<i32> {
         // Link with slot 0 memory config
         let agp_memory_slot0_content = fs::read_to_string(&agp_memory_slot0_path)?;
         fs::write(&memory_x_path, agp_memory_slot0_content)?;
-        let agp_slot0_elf = target_dir.join(ELF_SLOT0);
+        let agp_slot0_elf = target_dir.join(BootSlot::Slot0.elf_file_name_and_ext());
         let slot0_linker_args = replace_output_path(&args, &agp_slot0_elf)?;
 
         let exit_status = link_normally(&slot0_linker_args)?;
@@ -79,7 +84,7 @@ fn notmain() -> Result<i32> {
         // Link with slot 1 memory config
         let agp_memory_slot1_content = fs::read_to_string(&agp_memory_slot1_path)?;
         fs::write(&memory_x_path, agp_memory_slot1_content)?;
-        let agp_slot1_elf = target_dir.join(ELF_SLOT1);
+        let agp_slot1_elf = target_dir.join(BootSlot::Slot1.elf_file_name_and_ext());
         let slot1_linker_args = replace_output_path(&args, &agp_slot1_elf)?;
 
         let exit_status = link_normally(&slot1_linker_args)?;
@@ -90,21 +95,119 @@ fn notmain() -> Result<i32> {
         // Restore user's memory.x
         fs::write(memory_x_path, memory_x_content)?;
 
-        // Create a CPIO archive with the two ELF files
-        let cpio_archive_path = target_dir.join(CPIO_ARCHIVE);
+        // Convert each slot's ELF to the binary that gets written to FLASH
+        let mut images = Vec::new();
+        for (slot, elf_path) in [
+            (BootSlot::Slot0, agp_slot0_elf),
+            (BootSlot::Slot1, agp_slot1_elf),
+        ] {
+            let elf_data = fs::read(elf_path)?;
+            let elf = ElfBytes::<LittleEndian>::minimal_parse(&elf_data)?;
+            agp_archive::sanity_check_elf(slot, &elf.ehdr)?;
+            let bin_data = agp_archive::elf2bin(slot, &elf)?;
+            fs::write(target_dir.join(slot.bin_file_name_and_ext()), &bin_data)?;
+            images.push((slot.elf_file_name_and_ext(), elf_data));
+            images.push((slot.bin_file_name_and_ext(), bin_data));
+        }
+
+        let manifest = build_manifest(
+            &current_dir.join(CARGO_MANIFEST_FILE_NAME),
+            Path::new(output_elf_path),
+            &images,
+        )?;
+        let manifest_data = serde_json::to_vec_pretty(&manifest)?;
+        fs::write(target_dir.join(MANIFEST_FILE_NAME), &manifest_data)?;
+
+        // Create a CPIO archive with the manifest, the two ELF files and the two bin files
+        let cpio_archive_path = target_dir.join(ARCHIVE_FILE_NAME);
         let mut cpio_archive_file = fs::File::create(cpio_archive_path)?;
-        let agp_slot0_elf = fs::File::open(agp_slot0_elf)?;
-        let agp_slot1_elf = fs::File::open(agp_slot1_elf)?;
-        let cpio_inputs = vec![
-            (NewcBuilder::new(ELF_SLOT0), agp_slot0_elf),
-            (NewcBuilder::new(ELF_SLOT1), agp_slot1_elf),
-        ];
+        let mut cpio_inputs = vec![(
+            NewcBuilder::new(MANIFEST_FILE_NAME),
+            Cursor::new(manifest_data),
+        )];
+        for (name, data) in images.into_iter() {
+            cpio_inputs.push((
+                NewcBuilder::new(&name.display().to_string()),
+                Cursor::new(data),
+            ));
+        }
         let _ = write_cpio(cpio_inputs.into_iter(), &mut cpio_archive_file)?;
     }
 
     Ok(0)
 }
 
+/// Build the archive manifest from the firmware package's Cargo.toml and the linked images.
+/// `cargo run` sets the CARGO_PKG_* variables to the linker's own package,
+/// so the firmware version is read from the manifest file instead.
+fn build_manifest(
+    cargo_toml_path: &Path,
+    output_elf_path: &Path,
+    images: &[(PathBuf, Vec<u8>)],
+) -> Result<ArchiveManifest> {
+    let cargo_toml: toml::Table = fs::read_to_string(cargo_toml_path)?.parse()?;
+    let package = cargo_toml
+        .get("package")
+        .ok_or_else(|| format!("Missing [package] in {}", cargo_toml_path.display()))?;
+
+    let firmware_version = package
+        .get("version")
+        .and_then(toml::Value::as_str)
+        .ok_or_else(|| format!("Missing package version in {}", cargo_toml_path.display()))?;
+    let min_bootloader_version = package
+        .get("metadata")
+        .and_then(|m| m.get("agp"))
+        .and_then(|m| m.get("min-bootloader-version"))
+        .and_then(toml::Value::as_str)
+        .ok_or_else(|| {
+            format!(
+                "Missing [package.metadata.agp] min-bootloader-version in {}",
+                cargo_toml_path.display()
+            )
+        })?;
+    let parse_version = |v: &str| {
+        FirmwareVersion::parse(v)
+            .ok_or_else(|| format!("Invalid version '{v}', expected 'major.minor.patch'"))
+    };
+
+    Ok(ArchiveManifest {
+        firmware_version: parse_version(firmware_version)?,
+        min_bootloader_version: parse_version(min_bootloader_version)?,
+        protocol_version: ProtocolVersion::default().0,
+        hardware: HARDWARE.to_owned(),
+        git_commit: git_commit(),
+        built_time_utc: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        profile: profile(output_elf_path),
+        images: images
+            .iter()
+            .map(|(name, data)| (name.display().to_string(), ImageDigest::new(data)))
+            .collect::<BTreeMap<_, _>>(),
+    })
+}
+
+/// The commit HEAD points to, if the firmware is built from a git checkout
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Cargo profile directory name, the output path looks like
+/// `target/<triple>/<profile>/deps/<name>-<hash>`
+fn profile(output_elf_path: &Path) -> Option<String> {
+    output_elf_path
+        .parent()
+        .filter(|p| p.ends_with("deps"))
+        .and_then(Path::parent)
+        .and_then(Path::file_name)
+        .map(|p| p.to_string_lossy().into_owned())
+}
+
 /// Normal linking with just the arguments the user provides
 fn link_normally(args: &[String]) -> io::Result<ExitStatus> {
     let mut c = Command::new(LINKER);
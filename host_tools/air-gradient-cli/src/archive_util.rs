@@ -1,220 +1,269 @@
+use agp_archive::{ArchiveManifest, BootSlotExt, MANIFEST_FILE_NAME};
 use anyhow::{anyhow, bail, Result};
 use bootloader_support::BootSlot;
 use cpio::NewcReader;
-use elf::{
-    abi,
-    endian::LittleEndian,
-    file::{Class, FileHeader},
-    segment::ProgramHeader,
-    ElfBytes,
-};
+use elf::{endian::LittleEndian, ElfBytes};
 use std::{
     collections::BTreeMap,
     fs::File,
-    io,
-    path::{Path, PathBuf},
+    io::{self, Read},
+    path::Path,
 };
 use tracing::debug;
 
-const ELF_SLOT0_FILE_NAME: &str = "agp0";
-const ELF_SLOT1_FILE_NAME: &str = "agp1";
-
-pub trait BootSlotExt {
-    fn file_name(&self) -> &'static str;
+/// The contents of an image archive
+#[derive(Clone, Debug)]
+pub struct Archive {
+    /// Archives built before the manifest was added don't have one
+    pub manifest: Option<ArchiveManifest>,
+    pub elf_slot0: Vec<u8>,
+    pub elf_slot1: Vec<u8>,
+    /// Archives built before agp-linker generated the binaries don't have them
+    pub bin_slot0: Option<Vec<u8>>,
+    pub bin_slot1: Option<Vec<u8>>,
+}
 
-    fn elf_file_name_and_ext(&self) -> PathBuf {
-        let mut f = PathBuf::from(self.file_name());
-        f.set_extension("elf");
-        f
+impl Archive {
+    pub fn elf(&self, slot: BootSlot) -> &[u8] {
+        match slot {
+            BootSlot::Slot0 => &self.elf_slot0,
+            BootSlot::Slot1 => &self.elf_slot1,
+        }
     }
 
-    fn bin_file_name_and_ext(&self) -> PathBuf {
-        let mut f = PathBuf::from(self.file_name());
-        f.set_extension("bin");
-        f
+    /// The binary image for the slot as it was built, older archives get theirs
+    /// converted from the ELF
+    pub fn bin(&self, slot: BootSlot) -> Result<Vec<u8>> {
+        let bin = match slot {
+            BootSlot::Slot0 => self.bin_slot0.as_ref(),
+            BootSlot::Slot1 => self.bin_slot1.as_ref(),
+        };
+        match bin {
+            Some(data) => Ok(data.clone()),
+            None => {
+                debug!("Converting boot slot {slot} ELF to bin");
+                let elf = ElfBytes::<LittleEndian>::minimal_parse(self.elf(slot))?;
+                Ok(agp_archive::elf2bin(slot, &elf)?)
+            }
+        }
     }
 }
 
-impl BootSlotExt for BootSlot {
-    fn file_name(&self) -> &'static str {
-        match self {
-            BootSlot::Slot0 => ELF_SLOT0_FILE_NAME,
-            BootSlot::Slot1 => ELF_SLOT1_FILE_NAME,
-        }
-    }
+/// Reads the archive entries, in any order.
+/// Every entry listed in the manifest is checked against it, and both ELF files
+/// are already ran through sanity_check_elf.
+pub fn read_archive<P: AsRef<Path>>(cpio_path: P) -> Result<Archive> {
+    parse_archive(File::open(cpio_path.as_ref())?)
 }
 
-/// Returns (slot0 ELF data , slot1 ELF data), both are already ran through sanity_check_elf
-pub fn extract_elf_files_from_archive<P: AsRef<Path>>(cpio_path: P) -> Result<(Vec<u8>, Vec<u8>)> {
-    let archive_file = File::open(cpio_path.as_ref())?;
-    let mut archive_reader = NewcReader::new(&archive_file)?;
+fn parse_archive<R: Read>(reader: R) -> Result<Archive> {
+    let mut entries = read_entries(reader)?;
 
-    let elf_slot0_data = load_elf_data(BootSlot::Slot0, &mut archive_reader)?;
-    let elf_slot0 = ElfBytes::<LittleEndian>::minimal_parse(&elf_slot0_data)?;
-    sanity_check_elf(BootSlot::Slot0, &elf_slot0.ehdr)?;
+    let manifest: Option<ArchiveManifest> = entries
+        .remove(MANIFEST_FILE_NAME)
+        .map(|data| serde_json::from_slice(&data))
+        .transpose()
+        .map_err(|e| anyhow!("Bad archive, invalid manifest. {e}"))?;
 
-    let mut archive_reader = NewcReader::new(archive_reader.finish()?)?;
+    if let Some(m) = manifest.as_ref() {
+        for name in m.images.keys() {
+            if !entries.contains_key(name) {
+                bail!("Bad archive, missing entry '{name}' listed in the manifest");
+            }
+        }
+        for (name, data) in entries.iter() {
+            if m.images.contains_key(name) {
+                m.check_image(name, data)
+                    .map_err(|e| anyhow!("Bad archive, {e}"))?;
+            }
+        }
+    }
 
-    let elf_slot1_data = load_elf_data(BootSlot::Slot1, &mut archive_reader)?;
-    let elf_slot1 = ElfBytes::<LittleEndian>::minimal_parse(&elf_slot1_data)?;
-    sanity_check_elf(BootSlot::Slot1, &elf_slot1.ehdr)?;
+    let mut take_entry = |name: &Path| entries.remove(name.to_str().unwrap());
+    let mut take_elf = |slot: BootSlot| -> Result<Vec<u8>> {
+        let name = slot.elf_file_name_and_ext();
+        let data = take_entry(&name)
+            .ok_or_else(|| anyhow!("Bad archive, missing entry '{}'", name.display()))?;
+        let elf = ElfBytes::<LittleEndian>::minimal_parse(&data)?;
+        agp_archive::sanity_check_elf(slot, &elf.ehdr)?;
+        Ok(data)
+    };
+    let elf_slot0 = take_elf(BootSlot::Slot0)?;
+    let elf_slot1 = take_elf(BootSlot::Slot1)?;
+    let bin_slot0 = take_entry(&BootSlot::Slot0.bin_file_name_and_ext());
+    let bin_slot1 = take_entry(&BootSlot::Slot1.bin_file_name_and_ext());
+
+    for name in entries.keys() {
+        debug!("Ignoring entry '{name}' in archive");
+    }
 
-    Ok((elf_slot0_data, elf_slot1_data))
+    Ok(Archive {
+        manifest,
+        elf_slot0,
+        elf_slot1,
+        bin_slot0,
+        bin_slot1,
+    })
 }
 
-pub fn load_elf_data<R: io::Read>(slot: BootSlot, reader: &mut NewcReader<R>) -> Result<Vec<u8>> {
-    let entry = reader.entry();
-    debug!(
-        "Found entry '{}' with size {} in archive",
-        entry.name(),
-        entry.file_size()
-    );
-    if entry.name() != slot.elf_file_name_and_ext().to_str().unwrap() {
-        bail!(
-            "Bad archive, first entry should be {}, found '{}'",
-            slot.elf_file_name_and_ext().to_str().unwrap(),
-            entry.name()
+/// Returns the data of each archive entry by name
+fn read_entries<R: Read>(mut reader: R) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut entries = BTreeMap::new();
+    loop {
+        let mut archive_reader = NewcReader::new(reader)?;
+        let entry = archive_reader.entry();
+        if entry.is_trailer() {
+            break;
+        }
+        let name = entry.name().to_owned();
+        debug!(
+            "Found entry '{name}' with size {} in archive",
+            entry.file_size()
         );
+        let mut data = Vec::new();
+        io::copy(&mut archive_reader, &mut data)?;
+        if entries.insert(name.clone(), data).is_some() {
+            bail!("Bad archive, duplicate entry '{name}'");
+        }
+        reader = archive_reader.finish()?;
     }
-    let mut data = Vec::new();
-    io::copy(reader, &mut data)?;
-    Ok(data)
+    Ok(entries)
 }
 
-pub fn sanity_check_elf(slot: BootSlot, ehdr: &FileHeader<LittleEndian>) -> Result<()> {
-    if ehdr.class != Class::ELF32 {
-        bail!("Bad class");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agp_archive::ImageDigest;
+    use cpio::{write_cpio, NewcBuilder};
+    use std::io::Cursor;
+    use wire_protocols::FirmwareVersion;
+
+    // See agp-archive's fixtures/generate.sh
+    const FIXTURE_ELF: &[u8] = include_bytes!("../../agp-archive/fixtures/agp0.elf");
+
+    /// The fixture is linked for slot 0, only the entry point is checked when reading
+    fn elf(slot: BootSlot) -> Vec<u8> {
+        let mut data = FIXTURE_ELF.to_vec();
+        let entry = u32::from_le_bytes(data[24..28].try_into().unwrap());
+        let entry = entry - BootSlot::Slot0.address() + slot.address();
+        data[24..28].copy_from_slice(&entry.to_le_bytes());
+        data
     }
-    if ehdr.e_machine != abi::EM_ARM {
-        bail!("Bad e_machine");
+
+    fn bin(slot: BootSlot) -> Vec<u8> {
+        vec![slot as u8; 64]
     }
-    if !slot.contains(ehdr.e_entry as u32) {
-        bail!("Bad e_entry 0x{:X}", ehdr.e_entry);
+
+    fn images() -> Vec<(String, Vec<u8>)> {
+        [BootSlot::Slot0, BootSlot::Slot1]
+            .into_iter()
+            .flat_map(|slot| {
+                [
+                    (slot.elf_file_name_and_ext(), elf(slot)),
+                    (slot.bin_file_name_and_ext(), bin(slot)),
+                ]
+            })
+            .map(|(name, data)| (name.display().to_string(), data))
+            .collect()
     }
-    Ok(())
-}
 
-const SH_VECTOR_TABLE: &str = ".vector_table";
-const SH_TEXT: &str = ".text";
-const SH_RODATA: &str = ".rodata";
-const SH_DATA: &str = ".data";
-
-// TODO
-// - better error handling/reporting
-// - maybe add a dry-run or w/e and generate the bin
-//   in CI, sanity check matches what arm-none-eabi-objcopy -O binary would do
-pub fn elf2bin(slot: BootSlot, elf: &ElfBytes<LittleEndian>) -> Result<Vec<u8>> {
-    debug!("Converting boot slot {slot} ELF to bin");
-
-    let vector_table_sh = elf
-        .section_header_by_name(SH_VECTOR_TABLE)?
-        .ok_or_else(|| anyhow!("Missing {SH_VECTOR_TABLE} section header"))?;
-    let text_sh = elf
-        .section_header_by_name(SH_TEXT)?
-        .ok_or_else(|| anyhow!("Missing {SH_TEXT} section header"))?;
-    let rodata_sh = elf
-        .section_header_by_name(SH_RODATA)?
-        .ok_or_else(|| anyhow!("Missing {SH_RODATA} section header"))?;
-    let data_sh = elf
-        .section_header_by_name(SH_DATA)?
-        .ok_or_else(|| anyhow!("Missing {SH_DATA} section header"))?;
-
-    if vector_table_sh.sh_addr != slot.address() as u64 {
-        bail!(
-            "Bad {SH_VECTOR_TABLE} address 0x{:X}",
-            vector_table_sh.sh_addr
-        );
+    fn manifest(images: &[(String, Vec<u8>)]) -> Vec<u8> {
+        let manifest = ArchiveManifest {
+            firmware_version: FirmwareVersion::new(0, 4, 2),
+            min_bootloader_version: FirmwareVersion::new(0, 4, 1),
+            protocol_version: 1,
+            hardware: "air-gradient-pro-stm32f411".to_owned(),
+            git_commit: None,
+            built_time_utc: "Mon, 24 Apr 2023 15:06:18 +0000".to_owned(),
+            profile: Some("release".to_owned()),
+            images: images
+                .iter()
+                .map(|(name, data)| (name.clone(), ImageDigest::new(data)))
+                .collect(),
+        };
+        serde_json::to_vec(&manifest).unwrap()
     }
-    if !slot.contains(text_sh.sh_addr as u32) {
-        bail!("Bad {SH_TEXT} address 0x{:X}", text_sh.sh_addr);
+
+    fn cpio(entries: Vec<(String, Vec<u8>)>) -> Cursor<Vec<u8>> {
+        let inputs = entries
+            .into_iter()
+            .map(|(name, data)| (NewcBuilder::new(&name), Cursor::new(data)));
+        let data = write_cpio(inputs, Cursor::new(Vec::new())).unwrap();
+        Cursor::new(data.into_inner())
     }
-    if !slot.contains(rodata_sh.sh_addr as u32) {
-        bail!("Bad {SH_RODATA} address 0x{:X}", rodata_sh.sh_addr);
+
+    #[test]
+    fn entries_in_any_order() {
+        let mut entries = images();
+        entries.insert(2, (MANIFEST_FILE_NAME.to_owned(), manifest(&images())));
+        entries.reverse();
+        entries.push(("README".to_owned(), b"ignored".to_vec()));
+
+        let archive = parse_archive(cpio(entries)).unwrap();
+        assert!(archive.manifest.is_some());
+        assert_eq!(archive.elf(BootSlot::Slot0), elf(BootSlot::Slot0));
+        assert_eq!(archive.elf(BootSlot::Slot1), elf(BootSlot::Slot1));
+        assert_eq!(archive.bin(BootSlot::Slot0).unwrap(), bin(BootSlot::Slot0));
+        assert_eq!(archive.bin(BootSlot::Slot1).unwrap(), bin(BootSlot::Slot1));
     }
 
-    let offset_to_address_alignments: BTreeMap<u64, u64> =
-        [&vector_table_sh, &text_sh, &rodata_sh, &data_sh]
-            .iter()
-            .map(|sh| (sh.sh_offset, sh.sh_addralign))
+    #[test]
+    fn without_manifest_or_bins() {
+        let entries = images()
+            .into_iter()
+            .filter(|(name, _)| name.ends_with(".elf"))
             .collect();
+        let archive = parse_archive(cpio(entries)).unwrap();
+        assert!(archive.manifest.is_none());
+        assert!(archive.bin_slot0.is_none());
+        assert!(archive.bin_slot1.is_none());
+    }
 
-    let program_headers: Vec<ProgramHeader> = elf
-        .segments()
-        .ok_or_else(|| anyhow!("Missing program headers"))?
-        .into_iter()
-        .collect();
-
-    let vector_table_ph = program_headers
-        .iter()
-        .find(|ph| ph.p_offset == vector_table_sh.sh_offset)
-        .ok_or_else(|| anyhow!("Missing {SH_VECTOR_TABLE} program header"))?;
-    if vector_table_ph.p_flags != abi::PF_R {
-        bail!("Bad {SH_VECTOR_TABLE} program header flags");
-    }
-
-    let text_ph = program_headers
-        .iter()
-        .find(|ph| ph.p_offset == text_sh.sh_offset)
-        .ok_or_else(|| anyhow!("Missing {SH_TEXT} program header"))?;
-    if text_ph.p_flags != abi::PF_R | abi::PF_X {
-        bail!("Bad {SH_TEXT} program header flags");
-    }
-
-    let rodata_ph = program_headers
-        .iter()
-        .find(|ph| ph.p_offset == rodata_sh.sh_offset)
-        .ok_or_else(|| anyhow!("Missing {SH_RODATA} program header"))?;
-    if rodata_ph.p_flags != abi::PF_R {
-        bail!("Bad {SH_RODATA} program header flags");
-    }
-
-    let data_ph = program_headers
-        .iter()
-        .find(|ph| ph.p_offset == data_sh.sh_offset)
-        .ok_or_else(|| anyhow!("Missing {SH_DATA} program header"))?;
-    if data_ph.p_flags != abi::PF_R | abi::PF_W {
-        bail!("Bad {SH_DATA} program header flags");
-    }
-
-    let mut combined_segment_data = Vec::new();
-    let mut bin_offset = 0_u64;
-    let segments_to_write = vec![vector_table_ph, text_ph, rodata_ph, data_ph];
-    for ph in segments_to_write.into_iter() {
-        let address_alignment = *offset_to_address_alignments
-            .get(&ph.p_offset)
-            .ok_or_else(|| anyhow!("Missing address alignment entry"))?;
-        let bin_offset_ptr = bin_offset as u32 as *const u32;
-        let bin_align_offset_in_words = bin_offset_ptr.align_offset(address_alignment as usize);
+    #[test]
+    fn missing_listed_entry() {
+        let mut entries = images();
+        entries.push((MANIFEST_FILE_NAME.to_owned(), manifest(&images())));
+        entries.retain(|(name, _)| name != "agp1.bin");
 
-        debug!(
-            "Adding segment to bin at 0x{:X} (+ {} align words), vaddr 0x{:X}, paddr 0x{:X}, memsz 0x{:X} ({})",
-            bin_offset, bin_align_offset_in_words, ph.p_vaddr, ph.p_paddr, ph.p_memsz, ph.p_memsz
+        let err = parse_archive(cpio(entries)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Bad archive, missing entry 'agp1.bin' listed in the manifest"
         );
+    }
 
-        if ph.p_type != abi::PT_LOAD {
-            bail!("Bad program header type");
-        }
-
-        if ph.p_filesz != ph.p_memsz {
-            bail!("FLASH segment sizes should match what's in the file");
-        }
+    #[test]
+    fn tampered_bin() {
+        let mut entries = images();
+        entries.push((MANIFEST_FILE_NAME.to_owned(), manifest(&images())));
+        entries[1].1[0] ^= 0xFF;
 
-        if !slot.contains(ph.p_paddr as u32) {
-            bail!("Bad p_paddr 0x{:X}", ph.p_paddr);
-        }
+        let err = parse_archive(cpio(entries)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Bad archive, 'agp0.bin' doesn't match the manifest's SHA-256"
+        );
+    }
 
-        let seg_data = elf.segment_data(ph)?;
+    #[test]
+    fn truncated_bin() {
+        let mut entries = images();
+        entries.push((MANIFEST_FILE_NAME.to_owned(), manifest(&images())));
+        entries[3].1.pop();
 
-        // Start with padding zero bytes to align the address
-        for _ in 0..bin_align_offset_in_words {
-            combined_segment_data.extend_from_slice(0_u32.to_le_bytes().as_slice());
-            bin_offset += 4;
-        }
+        let err = parse_archive(cpio(entries)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Bad archive, 'agp1.bin' is 63 bytes but the manifest says 64"
+        );
+    }
 
-        combined_segment_data.extend_from_slice(seg_data);
+    #[test]
+    fn duplicate_entry() {
+        let mut entries = images();
+        entries.push(entries[0].clone());
 
-        bin_offset += ph.p_memsz;
+        let err = parse_archive(cpio(entries)).unwrap_err();
+        assert_eq!(err.to_string(), "Bad archive, duplicate entry 'agp0.elf'");
     }
-
-    Ok(combined_segment_data)
 }
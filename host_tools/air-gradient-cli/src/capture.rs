@@ -0,0 +1,235 @@
+//! Capture files of raw broadcast datagrams, written by `record` and read by `replay`.
+//!
+//! The file starts with the magic `AGPCAP` and a little-endian u16 format version,
+//! followed by the records, all integers little-endian:
+//! * receive time, i64 nanoseconds since the UNIX epoch (UTC)
+//! * source IP version, u8 4 or 6
+//! * source IP address, 4 or 16 bytes
+//! * source port, u16
+//! * datagram length, u16
+//! * datagram
+
+use anyhow::{bail, Result};
+use chrono::prelude::*;
+use std::{
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+pub const MAGIC: &[u8; 6] = b"AGPCAP";
+pub const VERSION: u16 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub recv_time_utc: DateTime<Utc>,
+    pub src_addr: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(mut inner: W) -> Result<Self> {
+        inner.write_all(MAGIC)?;
+        inner.write_all(&VERSION.to_le_bytes())?;
+        Ok(Writer { inner })
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<()> {
+        let Ok(len) = u16::try_from(record.data.len()) else {
+            bail!("Datagram of {} bytes is too large", record.data.len());
+        };
+        let ts = record
+            .recv_time_utc
+            .timestamp_nanos_opt()
+            .expect("timestamp_nanos_opt failed");
+        self.inner.write_all(&ts.to_le_bytes())?;
+        match record.src_addr.ip() {
+            IpAddr::V4(ip) => {
+                self.inner.write_all(&[4])?;
+                self.inner.write_all(&ip.octets())?;
+            }
+            IpAddr::V6(ip) => {
+                self.inner.write_all(&[6])?;
+                self.inner.write_all(&ip.octets())?;
+            }
+        }
+        self.inner
+            .write_all(&record.src_addr.port().to_le_bytes())?;
+        self.inner.write_all(&len.to_le_bytes())?;
+        self.inner.write_all(&record.data)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+pub struct Reader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut inner: R) -> Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        let mut version = [0; 2];
+        if inner.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            bail!("Not a capture file");
+        }
+        inner.read_exact(&mut version)?;
+        let version = u16::from_le_bytes(version);
+        if version != VERSION {
+            bail!("Unsupported capture file version {version}");
+        }
+        Ok(Reader { inner })
+    }
+
+    /// Returns None at the end of the file
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut ts = [0; 8];
+        match self.inner.read_exact(&mut ts) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let res = self.read_rest(i64::from_le_bytes(ts));
+        match res {
+            Err(e) if is_eof(&e) => bail!("Truncated record at the end of the capture file"),
+            res => res.map(Some),
+        }
+    }
+
+    fn read_rest(&mut self, ts: i64) -> Result<Record> {
+        let ip = match self.read_array::<1>()? {
+            [4] => IpAddr::V4(Ipv4Addr::from(self.read_array::<4>()?)),
+            [6] => IpAddr::V6(Ipv6Addr::from(self.read_array::<16>()?)),
+            [v] => bail!("Invalid IP version {v} in the capture file"),
+        };
+        let port = u16::from_le_bytes(self.read_array()?);
+        let len = u16::from_le_bytes(self.read_array()?);
+        let mut data = vec![0; usize::from(len)];
+        self.inner.read_exact(&mut data)?;
+        Ok(Record {
+            recv_time_utc: Utc.timestamp_nanos(ts),
+            src_addr: SocketAddr::new(ip, port),
+            data,
+        })
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.inner.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+fn is_eof(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .map(|e| e.kind() == io::ErrorKind::UnexpectedEof)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn record(src_addr: &str, data: &[u8]) -> Record {
+        Record {
+            recv_time_utc: Utc.timestamp_nanos(1_700_000_000_123_456_789),
+            src_addr: src_addr.parse().unwrap(),
+            data: data.to_vec(),
+        }
+    }
+
+    fn capture(records: &[Record]) -> Vec<u8> {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        for r in records.iter() {
+            writer.write(r).unwrap();
+        }
+        writer.inner
+    }
+
+    fn read_all(bytes: &[u8]) -> Result<Vec<Record>> {
+        let mut reader = Reader::new(Cursor::new(bytes))?;
+        let mut records = Vec::new();
+        while let Some(r) = reader.next_record()? {
+            records.push(r);
+        }
+        Ok(records)
+    }
+
+    #[test]
+    fn empty() {
+        let bytes = capture(&[]);
+        assert_eq!(bytes, b"AGPCAP\x01\x00");
+        assert_eq!(read_all(&bytes).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn round_trip() {
+        let records = vec![
+            record("192.168.1.38:32100", &[1, 2, 3, 4]),
+            record("[fe80::1234:5678]:32100", &[0xAA; 60]),
+            record("10.0.0.1:1", &[]),
+        ];
+        let bytes = capture(&records);
+        assert_eq!(read_all(&bytes).unwrap(), records);
+    }
+
+    #[test]
+    fn truncated_trailing_record() {
+        let records = vec![
+            record("192.168.1.38:32100", &[1, 2, 3, 4]),
+            record("[fe80::1]:32100", &[5, 6, 7, 8]),
+        ];
+        let bytes = capture(&records);
+
+        for len in [bytes.len() - 1, bytes.len() - 6, bytes.len() - 20] {
+            let mut reader = Reader::new(Cursor::new(&bytes[..len])).unwrap();
+            assert_eq!(reader.next_record().unwrap(), Some(records[0].clone()));
+            let err = reader.next_record().unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Truncated record at the end of the capture file"
+            );
+        }
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut bytes = capture(&[record("192.168.1.38:32100", &[1, 2, 3, 4])]);
+        bytes[0] = b'X';
+        let err = Reader::new(Cursor::new(&bytes)).err().unwrap();
+        assert_eq!(err.to_string(), "Not a capture file");
+
+        let err = Reader::new(Cursor::new(&b"AGP"[..])).err().unwrap();
+        assert_eq!(err.to_string(), "Not a capture file");
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = capture(&[]);
+        bytes[MAGIC.len()] = 2;
+        let err = Reader::new(Cursor::new(&bytes)).err().unwrap();
+        assert_eq!(err.to_string(), "Unsupported capture file version 2");
+    }
+
+    #[test]
+    fn invalid_ip_version() {
+        let mut bytes = capture(&[record("192.168.1.38:32100", &[1, 2, 3, 4])]);
+        // After the header and timestamp
+        bytes[MAGIC.len() + 2 + 8] = 5;
+        let err = read_all(&bytes).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid IP version 5 in the capture file");
+    }
+
+    #[test]
+    fn datagram_too_large() {
+        let mut writer = Writer::new(Vec::new()).unwrap();
+        let err = writer
+            .write(&record("192.168.1.38:32100", &vec![0; 70_000]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Datagram of 70000 bytes is too large");
+    }
+}
@@ -0,0 +1,236 @@
+use crate::measurement::MessageExt;
+use anyhow::{anyhow, bail, Result};
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::{fmt, fs, path::Path, time::Duration};
+use wire_protocols::broadcast::Repr as Message;
+
+/// The alert rules and sinks, read from a TOML file
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(default, rename = "rule")]
+    pub rules: Vec<Rule>,
+
+    /// Events go to stdout when no sinks are configured
+    #[serde(default, rename = "sink")]
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read config file '{}'. {e}", path.display()))?;
+        let mut config: Config = toml::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid config file '{}'. {e}", path.display()))?;
+        for rule in config.rules.iter_mut() {
+            rule.validate()
+                .map_err(|e| anyhow!("Invalid rule '{}'. {e}", rule.name))?;
+            for sn in rule.devices.iter_mut() {
+                *sn = sn.to_uppercase();
+            }
+        }
+        if config.rules.is_empty() {
+            bail!("Config file '{}' has no rules", path.display());
+        }
+        for sink in config.sinks.iter() {
+            match sink {
+                SinkConfig::Stdout => (),
+                SinkConfig::Webhook { url } => {
+                    reqwest::Url::parse(url)
+                        .map_err(|e| anyhow!("Invalid webhook sink URL '{url}'. {e}"))?;
+                }
+                SinkConfig::Command { command } => {
+                    if command.is_empty() {
+                        bail!("The command sink needs a program to run");
+                    }
+                }
+            }
+        }
+        if config.sinks.is_empty() {
+            config.sinks.push(SinkConfig::Stdout);
+        }
+        Ok(config)
+    }
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    pub name: String,
+
+    /// Device serial numbers the rule applies to, all devices when empty
+    #[serde(default)]
+    pub devices: Vec<String>,
+
+    /// How long a threshold or AQI condition must hold before the rule fires
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default, rename = "for")]
+    pub for_duration: Option<humantime::Duration>,
+
+    #[serde(flatten)]
+    pub condition: Condition,
+}
+
+impl Rule {
+    pub fn applies_to(&self, device_serial_number: &str) -> bool {
+        self.devices.is_empty() || self.devices.iter().any(|sn| sn == device_serial_number)
+    }
+
+    pub fn for_duration(&self) -> Duration {
+        self.for_duration.map(Into::into).unwrap_or_default()
+    }
+
+    fn validate(&self) -> Result<()> {
+        match &self.condition {
+            Condition::Threshold {
+                above,
+                below,
+                clear,
+                ..
+            } => match (above, below, clear) {
+                (Some(_), Some(_), _) | (None, None, _) => {
+                    bail!("A threshold rule needs exactly one of 'above' or 'below'")
+                }
+                (Some(above), None, Some(clear)) if clear > above => {
+                    bail!("'clear' must not be more than 'above'")
+                }
+                (None, Some(below), Some(clear)) if clear < below => {
+                    bail!("'clear' must not be less than 'below'")
+                }
+                _ => (),
+            },
+            Condition::Aqi { level, clear } => {
+                if clear.map(|c| c > *level).unwrap_or(false) {
+                    bail!("'clear' must not be a worse level than 'level'");
+                }
+            }
+            Condition::Silent { .. } | Condition::ResetReason { .. } => {
+                if self.for_duration.is_some() {
+                    bail!("'for' only applies to threshold and AQI rules");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[serde_as]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// A field is above or below a value, and clears once it's back past `clear`
+    /// (defaults to the threshold itself)
+    Threshold {
+        field: Field,
+        above: Option<f64>,
+        below: Option<f64>,
+        clear: Option<f64>,
+    },
+
+    /// The PM2.5 US AQI is at or worse than `level`, and clears once it's better
+    /// than `clear` (defaults to `level`)
+    Aqi {
+        level: AqiLevel,
+        clear: Option<AqiLevel>,
+    },
+
+    /// No messages from a device for `after`
+    Silent {
+        #[serde_as(as = "DisplayFromStr")]
+        after: humantime::Duration,
+    },
+
+    /// A device restarted and its reset reason contains `reason`, e.g. "watchdog"
+    ResetReason { reason: String },
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    /// Degrees Celsius
+    Temperature,
+    /// Relative humidity percent
+    Humidity,
+    VocTicks,
+    NoxTicks,
+    VocIndex,
+    NoxIndex,
+    #[serde(rename = "pm2_5")]
+    Pm2_5,
+    /// PM2.5 US AQI
+    Aqi,
+    Co2,
+}
+
+impl Field {
+    /// The value of the field, None when the device doesn't report it as valid
+    pub fn value(&self, msg: &Message) -> Option<f64> {
+        match self {
+            Field::Temperature => msg.valid_temperature_c(),
+            Field::Humidity => msg.valid_relative_humidity(),
+            Field::VocTicks => msg.valid_voc_ticks().map(f64::from),
+            Field::NoxTicks => msg.valid_nox_ticks().map(f64::from),
+            Field::VocIndex => msg.valid_voc_index().map(f64::from),
+            Field::NoxIndex => msg.valid_nox_index().map(f64::from),
+            Field::Pm2_5 => msg.valid_pm2_5_atm().map(f64::from),
+            Field::Aqi => msg.valid_pm2_5_us_aqi().map(|a| a.aqi().into()),
+            Field::Co2 => msg.valid_co2().map(f64::from),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Temperature => f.write_str("Temperature"),
+            Field::Humidity => f.write_str("Humidity"),
+            Field::VocTicks => f.write_str("VOC ticks"),
+            Field::NoxTicks => f.write_str("NOx ticks"),
+            Field::VocIndex => f.write_str("VOC index"),
+            Field::NoxIndex => f.write_str("NOx index"),
+            Field::Pm2_5 => f.write_str("PM2.5"),
+            Field::Aqi => f.write_str("PM2.5 AQI"),
+            Field::Co2 => f.write_str("CO2"),
+        }
+    }
+}
+
+/// `aqi::AirQualityLevel`, ordered from best to worst
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AqiLevel {
+    Good,
+    Moderate,
+    UnhealthySensitive,
+    Unhealthy,
+    VeryUnhealthy,
+    Hazardous,
+}
+
+impl From<aqi::AirQualityLevel> for AqiLevel {
+    fn from(l: aqi::AirQualityLevel) -> Self {
+        use aqi::AirQualityLevel::*;
+        match l {
+            Good => AqiLevel::Good,
+            Moderate => AqiLevel::Moderate,
+            UnhealthySensitive => AqiLevel::UnhealthySensitive,
+            Unhealthy => AqiLevel::Unhealthy,
+            VeryUnhealthy => AqiLevel::VeryUnhealthy,
+            Hazardous => AqiLevel::Hazardous,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SinkConfig {
+    /// Prints each event as a line of text
+    Stdout,
+
+    /// POSTs each event as JSON to the URL
+    Webhook { url: String },
+
+    /// Runs the program with its arguments for each event, with the event as JSON
+    /// on stdin and in environment variables
+    Command { command: Vec<String> },
+}
@@ -0,0 +1,107 @@
+use crate::{datagram, device_util, interruptor::Interruptor, opts::Alert};
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+use tokio::{net::UdpSocket, sync::mpsc};
+use wire_protocols::broadcast::MESSAGE_LEN;
+
+use self::{config::Config, rules::Evaluator, sink::Sinks};
+
+mod config;
+mod rules;
+mod sink;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn alert(cmd: Alert, intr: Interruptor) -> Result<()> {
+    let config = Config::load(&cmd.config)?;
+
+    tracing::info!(
+        address = cmd.address,
+        port = cmd.port,
+        rules = config.rules.len(),
+        sinks = config.sinks.len(),
+        "Watching UDP broadcast messages for alerts",
+    );
+
+    let s = std::net::UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
+    s.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(s)?;
+
+    let mut evaluator = Evaluator::new(config.rules);
+    let mut sinks = Sinks::new(config.sinks)?;
+
+    // Reset reasons requested from restarted devices, by serial number
+    let (reset_reason_tx, mut reset_reason_rx) = mpsc::unbounded_channel::<(String, String)>();
+
+    let mut buf = vec![0; MESSAGE_LEN * 10];
+
+    while !intr.is_set() {
+        let now = Instant::now();
+        for event in evaluator.on_tick(now) {
+            sinks.send(&event);
+        }
+        while let Ok((sn, reset_reason)) = reset_reason_rx.try_recv() {
+            for event in evaluator.on_reset_reason(&sn, &reset_reason) {
+                sinks.send(&event);
+            }
+        }
+
+        let (bytes_recvd, src_addr) =
+            match tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await {
+                Ok(res) => res?,
+                Err(_) => continue,
+            };
+
+        tracing::debug!(
+            src = %src_addr,
+            bytes_recvd = bytes_recvd,
+            "Received message data"
+        );
+
+        let decoded = datagram::decode(&buf[..bytes_recvd]);
+        if let Some(e) = decoded.error {
+            tracing::error!(src = %src_addr, e = %e, "Failed to decode datagram");
+        }
+
+        for msg in decoded.messages {
+            let (events, wants_reset_reason) = evaluator.on_message(&msg, Instant::now());
+            for event in events.iter() {
+                sinks.send(event);
+            }
+
+            if wants_reset_reason {
+                let sn = format!("{:X}", msg.device_serial_number);
+                let address = src_addr.ip().to_string();
+                let port = cmd.device_port;
+                let tx = reset_reason_tx.clone();
+                tokio::spawn(async move {
+                    match request_reset_reason(address, port).await {
+                        Ok(reset_reason) => {
+                            let _ = tx.send((sn, reset_reason));
+                        }
+                        Err(e) => tracing::warn!(
+                            device_serial_number = sn,
+                            e = %e,
+                            "Failed to request the reset reason of a restarted device"
+                        ),
+                    }
+                });
+            }
+        }
+    }
+
+    tracing::debug!("Exiting alert loop");
+
+    sinks.finish().await;
+
+    Ok(())
+}
+
+async fn request_reset_reason(address: String, port: u16) -> Result<String> {
+    match tokio::time::timeout(PROBE_TIMEOUT, device_util::request_info(&address, port)).await {
+        Ok(res) => Ok(res?.reset_reason),
+        Err(_) => bail!("Timed out requesting device info"),
+    }
+}
@@ -0,0 +1,513 @@
+use super::config::{AqiLevel, Condition, Rule};
+use crate::measurement::MessageExt;
+use chrono::prelude::*;
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt, time::Instant};
+use wire_protocols::broadcast::Repr as Message;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// RFC 3339 UTC time
+    pub time: String,
+    pub rule: String,
+    pub state: EventState,
+    pub device_serial_number: String,
+    pub device_id: u16,
+    pub value: Option<f64>,
+    pub message: String,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventState {
+    Firing,
+    Resolved,
+}
+
+impl fmt::Display for EventState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventState::Firing => f.write_str("FIRING"),
+            EventState::Resolved => f.write_str("RESOLVED"),
+        }
+    }
+}
+
+/// Tracks the state of each rule per device and produces the events
+pub struct Evaluator {
+    rules: Vec<Rule>,
+    devices: BTreeMap<String, DeviceState>,
+}
+
+struct DeviceState {
+    device_id: u16,
+    last_seen: Instant,
+    uptime_seconds: u32,
+    sequence_number: u32,
+    /// Indexed like the rules
+    rules: Vec<RuleState>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct RuleState {
+    firing: bool,
+    /// When the condition started to hold, until the rule fires
+    pending_since: Option<Instant>,
+}
+
+/// A threshold or AQI condition evaluated on a message
+struct Check {
+    met: bool,
+    cleared: bool,
+    value: f64,
+    message: String,
+}
+
+impl Evaluator {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Evaluator {
+            rules,
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// Evaluates the rules on a message from a device.
+    ///
+    /// Also returns true when the device restarted and has reset reason rules, the
+    /// caller then requests its reset reason for `on_reset_reason`.
+    pub fn on_message(&mut self, msg: &Message, now: Instant) -> (Vec<Event>, bool) {
+        let sn = format!("{:X}", msg.device_serial_number);
+        let num_rules = self.rules.len();
+        let device = self.devices.entry(sn.clone()).or_insert(DeviceState {
+            device_id: msg.device_id.0,
+            last_seen: now,
+            uptime_seconds: msg.uptime_seconds,
+            sequence_number: msg.sequence_number,
+            rules: vec![RuleState::default(); num_rules],
+        });
+        // The uptime alone misses a restart when no messages arrive until the device
+        // has been up for longer than before
+        let restarted = msg.uptime_seconds < device.uptime_seconds
+            || msg.sequence_number < device.sequence_number;
+        device.device_id = msg.device_id.0;
+        device.last_seen = now;
+        device.uptime_seconds = msg.uptime_seconds;
+        device.sequence_number = msg.sequence_number;
+
+        let mut events = Vec::new();
+        let mut wants_reset_reason = false;
+        for (rule, state) in self.rules.iter().zip(device.rules.iter_mut()) {
+            if !rule.applies_to(&sn) {
+                continue;
+            }
+
+            match &rule.condition {
+                Condition::Silent { .. } => {
+                    if state.firing {
+                        state.firing = false;
+                        events.push(new_event(
+                            rule,
+                            EventState::Resolved,
+                            &sn,
+                            device.device_id,
+                            None,
+                            "Reporting again".to_owned(),
+                        ));
+                    }
+                }
+                Condition::ResetReason { .. } => wants_reset_reason |= restarted,
+                condition => {
+                    let Some(check) = check(condition, msg) else {
+                        // Keep the current state until the value is valid again
+                        continue;
+                    };
+                    if !state.firing {
+                        if check.met {
+                            let since = *state.pending_since.get_or_insert(now);
+                            if now.duration_since(since) >= rule.for_duration() {
+                                state.firing = true;
+                                state.pending_since = None;
+                                events.push(new_event(
+                                    rule,
+                                    EventState::Firing,
+                                    &sn,
+                                    device.device_id,
+                                    Some(check.value),
+                                    check.message,
+                                ));
+                            }
+                        } else {
+                            state.pending_since = None;
+                        }
+                    } else if check.cleared {
+                        state.firing = false;
+                        events.push(new_event(
+                            rule,
+                            EventState::Resolved,
+                            &sn,
+                            device.device_id,
+                            Some(check.value),
+                            check.message,
+                        ));
+                    }
+                }
+            }
+        }
+
+        (events, wants_reset_reason)
+    }
+
+    /// Fires the silent rules of the devices that haven't reported for long enough
+    pub fn on_tick(&mut self, now: Instant) -> Vec<Event> {
+        let mut events = Vec::new();
+        for (sn, device) in self.devices.iter_mut() {
+            let silent_for = now.duration_since(device.last_seen);
+            for (rule, state) in self.rules.iter().zip(device.rules.iter_mut()) {
+                let Condition::Silent { after } = &rule.condition else {
+                    continue;
+                };
+                if rule.applies_to(sn) && !state.firing && silent_for >= (*after).into() {
+                    state.firing = true;
+                    events.push(new_event(
+                        rule,
+                        EventState::Firing,
+                        sn,
+                        device.device_id,
+                        None,
+                        format!("No messages for {after}"),
+                    ));
+                }
+            }
+        }
+        events
+    }
+
+    /// Fires the reset reason rules matching the reason reported by a restarted device
+    pub fn on_reset_reason(&self, sn: &str, reset_reason: &str) -> Vec<Event> {
+        let Some(device) = self.devices.get(sn) else {
+            return Vec::new();
+        };
+        self.rules
+            .iter()
+            .filter(|rule| rule.applies_to(sn))
+            .filter(|rule| match &rule.condition {
+                Condition::ResetReason { reason } => reset_reason_matches(reason, reset_reason),
+                _ => false,
+            })
+            .map(|rule| {
+                new_event(
+                    rule,
+                    EventState::Firing,
+                    sn,
+                    device.device_id,
+                    None,
+                    format!("Restarted, reset reason is '{reset_reason}'"),
+                )
+            })
+            .collect()
+    }
+}
+
+fn check(condition: &Condition, msg: &Message) -> Option<Check> {
+    match condition {
+        Condition::Threshold {
+            field,
+            above,
+            below,
+            clear,
+        } => {
+            let value = field.value(msg)?;
+            let (met, cleared) = match (above, below) {
+                (Some(above), _) => (value > *above, value <= clear.unwrap_or(*above)),
+                (None, Some(below)) => (value < *below, value >= clear.unwrap_or(*below)),
+                (None, None) => return None,
+            };
+            Some(Check {
+                met,
+                cleared,
+                value,
+                message: format!("{field} is {}", (value * 100.0).round() / 100.0),
+            })
+        }
+        Condition::Aqi { level, clear } => {
+            let aqi = msg.valid_pm2_5_us_aqi()?;
+            let current = AqiLevel::from(aqi.level());
+            Some(Check {
+                met: current >= *level,
+                cleared: current < clear.unwrap_or(*level),
+                value: aqi.aqi().into(),
+                message: format!("PM2.5 {aqi}"),
+            })
+        }
+        Condition::Silent { .. } | Condition::ResetReason { .. } => None,
+    }
+}
+
+/// Case-insensitive substring match, with the watchdog abbreviations spelled out
+/// so e.g. "watchdog" matches "IWDG reset"
+fn reset_reason_matches(pattern: &str, reset_reason: &str) -> bool {
+    let reset_reason = reset_reason
+        .to_lowercase()
+        .replace("iwdg", "independent watchdog")
+        .replace("wwdg", "window watchdog");
+    reset_reason.contains(&pattern.to_lowercase())
+}
+
+fn new_event(
+    rule: &Rule,
+    state: EventState,
+    device_serial_number: &str,
+    device_id: u16,
+    value: Option<f64>,
+    message: String,
+) -> Event {
+    Event {
+        time: Utc::now().to_rfc3339(),
+        rule: rule.name.clone(),
+        state,
+        device_serial_number: device_serial_number.to_owned(),
+        device_id,
+        value,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{command::alert::config::Config, datagram::tests::repr};
+    use std::time::Duration;
+    use wire_protocols::StatusFlags;
+
+    const RULES: &str = r#"
+        [[rule]]
+        name = "hot"
+        type = "threshold"
+        field = "temperature"
+        above = 30.0
+        clear = 28.0
+        for = "1m"
+
+        [[rule]]
+        name = "silent"
+        type = "silent"
+        after = "5m"
+
+        [[rule]]
+        name = "watchdog"
+        type = "reset_reason"
+        reason = "watchdog"
+    "#;
+
+    fn evaluator(rules: &str) -> Evaluator {
+        let config: Config = toml::from_str(rules).unwrap();
+        Evaluator::new(config.rules)
+    }
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    /// A message with only the temperature valid, or nothing valid without a temperature
+    fn msg(sequence_number: u32, uptime_seconds: u32, temperature_c: Option<f64>) -> Message {
+        let mut msg = repr(sequence_number);
+        msg.uptime_seconds = uptime_seconds;
+        let mut flags = StatusFlags::empty();
+        if let Some(t) = temperature_c {
+            flags.set_temperature_valid(true);
+            msg.temperature = (t * 100.0) as i32;
+        }
+        msg.status_flags = flags;
+        msg
+    }
+
+    /// Feeds a message with the temperature, returns the rule name and state of the events
+    fn temperature(
+        evaluator: &mut Evaluator,
+        now: Instant,
+        seqnum: u32,
+        t: f64,
+    ) -> Vec<(String, EventState)> {
+        let (events, _) = evaluator.on_message(&msg(seqnum, seqnum, Some(t)), now);
+        events.into_iter().map(|e| (e.rule, e.state)).collect()
+    }
+
+    fn firing(rule: &str) -> Vec<(String, EventState)> {
+        vec![(rule.to_owned(), EventState::Firing)]
+    }
+
+    fn resolved(rule: &str) -> Vec<(String, EventState)> {
+        vec![(rule.to_owned(), EventState::Resolved)]
+    }
+
+    #[test]
+    fn fires_after_for() {
+        let mut e = evaluator(RULES);
+        let start = Instant::now();
+        assert_eq!(temperature(&mut e, start, 1, 31.0), vec![]);
+        assert_eq!(temperature(&mut e, secs(start, 30), 2, 32.0), vec![]);
+
+        let (events, _) = e.on_message(&msg(3, 3, Some(31.5)), secs(start, 60));
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.rule, "hot");
+        assert_eq!(event.state, EventState::Firing);
+        assert_eq!(event.value, Some(31.5));
+        assert_eq!(event.message, "Temperature is 31.5");
+        assert_eq!(
+            event.device_serial_number,
+            format!("{:X}", repr(0).device_serial_number)
+        );
+
+        // Already firing
+        assert_eq!(temperature(&mut e, secs(start, 120), 4, 35.0), vec![]);
+    }
+
+    #[test]
+    fn pending_restarts_when_the_condition_breaks() {
+        let mut e = evaluator(RULES);
+        let start = Instant::now();
+        assert_eq!(temperature(&mut e, start, 1, 31.0), vec![]);
+        assert_eq!(temperature(&mut e, secs(start, 30), 2, 25.0), vec![]);
+        assert_eq!(temperature(&mut e, secs(start, 60), 3, 31.0), vec![]);
+        assert_eq!(temperature(&mut e, secs(start, 90), 4, 31.0), vec![]);
+        assert_eq!(
+            temperature(&mut e, secs(start, 120), 5, 31.0),
+            firing("hot")
+        );
+    }
+
+    #[test]
+    fn no_refire_between_clear_and_threshold() {
+        let mut e = evaluator(RULES);
+        let start = Instant::now();
+        temperature(&mut e, start, 1, 31.0);
+        assert_eq!(temperature(&mut e, secs(start, 60), 2, 31.0), firing("hot"));
+
+        // Below the threshold but not past clear, still firing
+        assert_eq!(temperature(&mut e, secs(start, 70), 3, 29.0), vec![]);
+        assert_eq!(temperature(&mut e, secs(start, 80), 4, 31.0), vec![]);
+        assert_eq!(temperature(&mut e, secs(start, 90), 5, 28.5), vec![]);
+
+        // Invalid values keep the state
+        let (events, _) = e.on_message(&msg(6, 6, None), secs(start, 100));
+        assert_eq!(events.len(), 0);
+
+        let (events, _) = e.on_message(&msg(7, 7, Some(28.0)), secs(start, 110));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].state, EventState::Resolved);
+        assert_eq!(events[0].value, Some(28.0));
+
+        // Resolved, between clear and the threshold is fine now
+        assert_eq!(temperature(&mut e, secs(start, 120), 8, 29.0), vec![]);
+        // And the for duration applies again
+        assert_eq!(temperature(&mut e, secs(start, 130), 9, 31.0), vec![]);
+        assert_eq!(
+            temperature(&mut e, secs(start, 190), 10, 31.0),
+            firing("hot")
+        );
+    }
+
+    #[test]
+    fn resolves_past_clear_without_for() {
+        let mut e = evaluator(
+            r#"
+            [[rule]]
+            name = "cold"
+            type = "threshold"
+            field = "temperature"
+            below = 15.0
+            clear = 17.0
+            "#,
+        );
+        let start = Instant::now();
+        assert_eq!(temperature(&mut e, start, 1, 14.0), firing("cold"));
+        assert_eq!(temperature(&mut e, secs(start, 10), 2, 16.0), vec![]);
+        assert_eq!(
+            temperature(&mut e, secs(start, 20), 3, 17.5),
+            resolved("cold")
+        );
+        assert_eq!(
+            temperature(&mut e, secs(start, 30), 4, 14.5),
+            firing("cold")
+        );
+    }
+
+    #[test]
+    fn silent_fires_and_resolves() {
+        let mut e = evaluator(RULES);
+        let start = Instant::now();
+        temperature(&mut e, start, 1, 20.0);
+        assert_eq!(e.on_tick(secs(start, 299)).len(), 0);
+
+        let events = e.on_tick(secs(start, 300));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].rule, "silent");
+        assert_eq!(events[0].state, EventState::Firing);
+        assert_eq!(events[0].message, "No messages for 5m");
+        assert_eq!(e.on_tick(secs(start, 400)).len(), 0);
+
+        assert_eq!(
+            temperature(&mut e, secs(start, 500), 2, 20.0),
+            resolved("silent")
+        );
+        assert_eq!(temperature(&mut e, secs(start, 510), 3, 20.0), vec![]);
+        assert_eq!(e.on_tick(secs(start, 600)).len(), 0);
+    }
+
+    #[test]
+    fn detects_restarts() {
+        let mut e = evaluator(RULES);
+        let now = Instant::now();
+        assert!(!e.on_message(&msg(10, 100, None), now).1);
+        assert!(!e.on_message(&msg(11, 110, None), now).1);
+        // Duplicate
+        assert!(!e.on_message(&msg(11, 110, None), now).1);
+        // Uptime went backwards
+        assert!(e.on_message(&msg(0, 5, None), now).1);
+        assert!(!e.on_message(&msg(20, 200, None), now).1);
+        // The messages since the restart were missed, only the sequence number went backwards
+        assert!(e.on_message(&msg(2, 300, None), now).1);
+
+        // Not asked for without reset reason rules
+        let mut e = evaluator(
+            r#"
+            [[rule]]
+            name = "silent"
+            type = "silent"
+            after = "5m"
+            "#,
+        );
+        assert!(!e.on_message(&msg(10, 100, None), now).1);
+        assert!(!e.on_message(&msg(0, 5, None), now).1);
+    }
+
+    #[test]
+    fn reset_reason() {
+        let mut e = evaluator(RULES);
+        let sn = format!("{:X}", repr(0).device_serial_number);
+        assert_eq!(e.on_reset_reason(&sn, "IWDG reset").len(), 0);
+
+        e.on_message(&msg(1, 1, None), Instant::now());
+        let events = e.on_reset_reason(&sn, "IWDG reset");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].rule, "watchdog");
+        assert_eq!(events[0].message, "Restarted, reset reason is 'IWDG reset'");
+        assert_eq!(e.on_reset_reason(&sn, "Power-on reset").len(), 0);
+    }
+
+    #[test]
+    fn rule_devices() {
+        let mut e = evaluator(
+            r#"
+            [[rule]]
+            name = "hot"
+            devices = ["ABC"]
+            type = "threshold"
+            field = "temperature"
+            above = 30.0
+            "#,
+        );
+        assert_eq!(temperature(&mut e, Instant::now(), 1, 31.0), vec![]);
+    }
+}
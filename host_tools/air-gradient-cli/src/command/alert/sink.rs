@@ -0,0 +1,120 @@
+use super::{config::SinkConfig, rules::Event};
+use anyhow::{bail, Result};
+use std::{process::Stdio, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command, task::JoinSet};
+
+/// Timeout of each webhook request and command
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delivers the events to the configured sinks, webhooks and commands run in the background
+pub struct Sinks {
+    sinks: Vec<SinkConfig>,
+    client: reqwest::Client,
+    tasks: JoinSet<()>,
+}
+
+impl Sinks {
+    pub fn new(sinks: Vec<SinkConfig>) -> Result<Self> {
+        Ok(Sinks {
+            sinks,
+            client: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()?,
+            tasks: JoinSet::new(),
+        })
+    }
+
+    pub fn send(&mut self, event: &Event) {
+        while self.tasks.try_join_next().is_some() {}
+
+        for sink in self.sinks.iter() {
+            match sink {
+                SinkConfig::Stdout => println!(
+                    "{} {} [{}] {} ({}): {}",
+                    event.time,
+                    event.state,
+                    event.rule,
+                    event.device_serial_number,
+                    event.device_id,
+                    event.message
+                ),
+                SinkConfig::Webhook { url } => {
+                    let req = self.client.post(url).json(event);
+                    let url = url.clone();
+                    self.tasks.spawn(async move {
+                        let res = match req.send().await {
+                            Ok(resp) => resp.error_for_status().map(|_| ()),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = res {
+                            tracing::error!(url, e = %e, "Failed to deliver event to the webhook");
+                        }
+                    });
+                }
+                SinkConfig::Command { command } => {
+                    let command = command.clone();
+                    let event = event.clone();
+                    self.tasks.spawn(async move {
+                        let res =
+                            tokio::time::timeout(DELIVERY_TIMEOUT, run_command(&command, &event))
+                                .await
+                                .unwrap_or_else(|_| Err(anyhow::anyhow!("Timed out")));
+                        if let Err(e) = res {
+                            tracing::error!(
+                                command = command.join(" "),
+                                e = %e,
+                                "Failed to deliver event to the command"
+                            );
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    /// Waits for the deliveries still in progress
+    pub async fn finish(mut self) {
+        if tokio::time::timeout(DELIVERY_TIMEOUT, async {
+            while self.tasks.join_next().await.is_some() {}
+        })
+        .await
+        .is_err()
+        {
+            tracing::warn!("Timed out waiting for event deliveries");
+        }
+    }
+}
+
+/// Runs the command with the event as JSON on stdin and in the `AIR_GRADIENT_ALERT_*`
+/// environment variables
+async fn run_command(command: &[String], event: &Event) -> Result<()> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .env("AIR_GRADIENT_ALERT_TIME", &event.time)
+        .env("AIR_GRADIENT_ALERT_RULE", &event.rule)
+        .env("AIR_GRADIENT_ALERT_STATE", event.state.to_string())
+        .env(
+            "AIR_GRADIENT_ALERT_DEVICE_SERIAL_NUMBER",
+            &event.device_serial_number,
+        )
+        .env("AIR_GRADIENT_ALERT_DEVICE_ID", event.device_id.to_string())
+        .env(
+            "AIR_GRADIENT_ALERT_VALUE",
+            event.value.map(|v| v.to_string()).unwrap_or_default(),
+        )
+        .env("AIR_GRADIENT_ALERT_MESSAGE", &event.message)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The command doesn't have to read it
+        let _ = stdin.write_all(&serde_json::to_vec(event)?).await;
+    }
+
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("Command exited with {status}");
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use crate::{
+    archive_util,
+    interruptor::Interruptor,
+    opts::{ArchiveInspect, Format},
+};
+use agp_archive::{BootSlotExt, ImageDigest};
+use anyhow::{bail, Result};
+use bootloader_support::BootSlot;
+
+const NA: &str = "NA";
+
+pub async fn inspect(cmd: ArchiveInspect, _intr: Interruptor) -> Result<()> {
+    if !cmd.agp_images_cpio_file.exists() {
+        bail!(
+            "Image archive '{}' does not exist",
+            cmd.agp_images_cpio_file.display()
+        );
+    }
+
+    // Reading the archive checks the images against the manifest
+    let archive = archive_util::read_archive(&cmd.agp_images_cpio_file)?;
+
+    match cmd.format {
+        Format::Text => {
+            println!("Archive: '{}'", cmd.agp_images_cpio_file.display());
+            match archive.manifest.as_ref() {
+                Some(m) => {
+                    println!("Firmware version:       {}", m.firmware_version);
+                    println!("Min bootloader version: {}", m.min_bootloader_version);
+                    println!("Protocol version:       {}", m.protocol_version);
+                    println!("Hardware:               {}", m.hardware);
+                    println!(
+                        "Git commit:             {}",
+                        m.git_commit.as_deref().unwrap_or(NA)
+                    );
+                    println!("Built time (UTC):       {}", m.built_time_utc);
+                    println!(
+                        "Profile:                {}",
+                        m.profile.as_deref().unwrap_or(NA)
+                    );
+                }
+                None => println!("No manifest, the archive predates manifests"),
+            }
+            println!("Images:");
+            for slot in [BootSlot::Slot0, BootSlot::Slot1] {
+                let bin = match slot {
+                    BootSlot::Slot0 => archive.bin_slot0.as_deref(),
+                    BootSlot::Slot1 => archive.bin_slot1.as_deref(),
+                };
+                let images = [
+                    (slot.elf_file_name_and_ext(), Some(archive.elf(slot))),
+                    (slot.bin_file_name_and_ext(), bin),
+                ];
+                for (name, data) in images.into_iter() {
+                    if let Some(data) = data {
+                        let digest = ImageDigest::new(data);
+                        println!(
+                            "  {}: {} bytes, sha256 {}",
+                            name.display(),
+                            digest.size,
+                            digest.sha256
+                        );
+                    }
+                }
+            }
+        }
+        Format::Json => println!("{}", serde_json::to_string_pretty(&archive.manifest)?),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,11 @@
+use crate::{interruptor::Interruptor, opts::Archive};
+use anyhow::Result;
+
+mod inspect;
+
+pub async fn archive(cmd: Archive, intr: Interruptor) -> Result<()> {
+    match cmd {
+        Archive::Inspect(subcmd) => self::inspect::inspect(subcmd, intr).await?,
+    }
+    Ok(())
+}
@@ -1,5 +1,5 @@
 use crate::{
-    device_util::{self, DeviceInfo},
+    device_util::{self, BootHistoryRecord, DeviceInfo},
     interruptor::Interruptor,
     opts::{DeviceInfo as DeviceInfoOps, Format},
 };
@@ -24,6 +24,16 @@ pub async fn info(cmd: DeviceInfoOps, _intr: Interruptor) -> Result<()> {
     s.set_nonblocking(true)?;
     let mut stream = TcpStream::from_std(s)?;
 
+    // The info command closes the connection, so the history is requested first
+    let history = if cmd.history {
+        debug!("Requesting boot history");
+        device_util::write_command(Command::ReadBootHistory, &mut stream).await?;
+        let _status = device_util::read_status(&mut stream).await?;
+        Some(device_util::read_boot_history(&mut stream).await?)
+    } else {
+        None
+    };
+
     debug!("Requesting device info");
     device_util::write_command(Command::Info, &mut stream).await?;
     let status = device_util::read_status(&mut stream).await?;
@@ -50,9 +60,26 @@ pub async fn info(cmd: DeviceInfoOps, _intr: Interruptor) -> Result<()> {
                     }
                 }
             }
+            if let Some(h) = history.as_ref() {
+                println!("Boot history (newest first):");
+                for (idx, entry) in h.iter().enumerate() {
+                    println!("  {idx}: {entry}");
+                }
+            }
         }
         // TODO - handle field_names opts
-        Format::Json => println!("{}", serde_json::to_string_pretty(&info)?),
+        Format::Json => match history.as_ref() {
+            None => println!("{}", serde_json::to_string_pretty(&info)?),
+            Some(h) => {
+                let records: Vec<BootHistoryRecord> =
+                    h.iter().map(BootHistoryRecord::from).collect();
+                let value = serde_json::json!({
+                    "info": info,
+                    "boot_history": records,
+                });
+                println!("{}", serde_json::to_string_pretty(&value)?);
+            }
+        },
     }
 
     Ok(())
@@ -3,13 +3,15 @@ use anyhow::Result;
 
 mod info;
 mod reboot;
-mod update;
+mod rollback;
+pub mod update;
 
 pub async fn device(cmd: Device, intr: Interruptor) -> Result<()> {
     match cmd {
         Device::Info(subcmd) => self::info::info(subcmd, intr).await?,
         Device::Update(subcmd) => self::update::update(subcmd, intr).await?,
         Device::Reboot(subcmd) => self::reboot::reboot(subcmd, intr).await?,
+        Device::Rollback(subcmd) => self::rollback::rollback(subcmd, intr).await?,
     }
     Ok(())
 }
@@ -1,24 +1,52 @@
-use crate::{device_util, interruptor::Interruptor, opts::CommonDeviceOpts};
+use crate::{
+    device_util,
+    interruptor::Interruptor,
+    opts::{DeviceReboot, RebootSlot},
+};
 use anyhow::Result;
 use std::net;
-use tokio::net::TcpStream;
+use tokio::{io::AsyncWriteExt, net::TcpStream};
 use tracing::debug;
-use wire_protocols::device::Command;
+use wire_protocols::device::{Command, RebootTarget};
 
-pub async fn reboot(cmd: CommonDeviceOpts, _intr: Interruptor) -> Result<()> {
-    if cmd.format.is_text() {
-        println!("Rebooting device {}:{}", cmd.address, cmd.port);
+pub async fn reboot(cmd: DeviceReboot, _intr: Interruptor) -> Result<()> {
+    let target = if cmd.recovery {
+        Some(RebootTarget::Recovery)
+    } else {
+        cmd.slot.map(|s| match s {
+            RebootSlot::Current => RebootTarget::CurrentSlot,
+            RebootSlot::Other => RebootTarget::OtherSlot,
+        })
+    };
+
+    if cmd.common.format.is_text() {
+        match target {
+            Some(t) => println!(
+                "Rebooting device {}:{} into {t}",
+                cmd.common.address, cmd.common.port
+            ),
+            None => println!(
+                "Rebooting device {}:{}",
+                cmd.common.address, cmd.common.port
+            ),
+        }
     }
 
-    let s = net::TcpStream::connect((cmd.address.as_str(), cmd.port))?;
+    let s = net::TcpStream::connect((cmd.common.address.as_str(), cmd.common.port))?;
     s.set_nonblocking(true)?;
     let mut stream = TcpStream::from_std(s)?;
 
-    debug!("Requesting device reboot");
-    device_util::write_command(Command::CompleteAndReboot, &mut stream).await?;
+    if let Some(t) = target {
+        debug!("Requesting device reboot into {t}");
+        device_util::write_command(Command::RebootInto, &mut stream).await?;
+        stream.write_u32_le(t.into()).await?;
+    } else {
+        debug!("Requesting device reboot");
+        device_util::write_command(Command::CompleteAndReboot, &mut stream).await?;
+    }
     let status = device_util::read_status(&mut stream).await?;
 
-    if cmd.format.is_text() {
+    if cmd.common.format.is_text() {
         println!("Status: {status}");
     }
 
@@ -0,0 +1,141 @@
+use crate::{
+    archive_util::{self, Archive},
+    device_util::{self, DeviceInfo},
+    interruptor::Interruptor,
+    opts::DeviceRollback,
+};
+use anyhow::{bail, Result};
+use bootloader_support::BootSlot;
+use std::{
+    io::{self, Write},
+    net,
+};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::TcpStream,
+};
+use tracing::debug;
+use wire_protocols::device::{Command, MemoryHasher, MemoryRegion};
+
+pub async fn rollback(cmd: DeviceRollback, _intr: Interruptor) -> Result<()> {
+    if !cmd.common.format.is_text() && !cmd.yes {
+        bail!("Confirmation is required, use --yes with a non-text output format");
+    }
+
+    let mut stream = connect(&cmd)?;
+
+    // The info command closes the connection, so the history is requested first
+    debug!("Requesting boot history");
+    device_util::write_command(Command::ReadBootHistory, &mut stream).await?;
+    let _status = device_util::read_status(&mut stream).await?;
+    let history = device_util::read_boot_history(&mut stream).await?;
+
+    debug!("Requesting device info");
+    device_util::write_command(Command::Info, &mut stream).await?;
+    let _status = device_util::read_status(&mut stream).await?;
+    let mut info_str = String::new();
+    let _info_len = BufReader::new(stream).read_line(&mut info_str).await?;
+    let info = DeviceInfo::from_json(&info_str)?;
+
+    let active_slot = info.active_boot_slot;
+    let other_slot = active_slot.other();
+    // The other slot's version is only known if it was booted recently
+    let mut other_version = history
+        .last_firmware_version(other_slot)
+        .map(|v| v.to_string());
+
+    // A partially written or otherwise unknown image still has a valid vector table,
+    // so the other slot has to be known good, either from the boot history or by
+    // matching it against an image archive
+    let committed = history.has_committed_boot(other_slot);
+    let mut verified = false;
+    if let Some(archive_path) = cmd.archive.as_deref() {
+        if info.memory_hashing {
+            let archive = archive_util::read_archive(archive_path)?;
+            let mut stream = connect(&cmd)?;
+            if image_matches(other_slot, &archive, &mut stream).await? {
+                verified = true;
+                if let Some(manifest) = archive.manifest {
+                    other_version = Some(manifest.firmware_version.to_string());
+                }
+            } else if !cmd.force {
+                bail!(
+                    "Boot slot {other_slot} doesn't match image archive '{}', use --force to roll back anyway",
+                    archive_path.display()
+                );
+            }
+        } else if !cmd.force {
+            bail!("The device doesn't support memory hashing, can't check boot slot {other_slot} against the image archive, use --force to roll back anyway");
+        }
+    }
+    if !committed && !verified && !cmd.force {
+        bail!("The boot history doesn't show a committed boot of slot {other_slot}, verify its image with --archive or use --force to roll back anyway");
+    }
+
+    if cmd.common.format.is_text() {
+        println!(
+            "Active slot: {active_slot}, firmware {}",
+            info.firmware_version_str()
+        );
+        println!(
+            "Other slot:  {other_slot}, firmware {}",
+            other_version.as_deref().unwrap_or("unknown")
+        );
+    }
+
+    if !cmd.yes {
+        let confirmed = tokio::task::spawn_blocking(move || -> io::Result<bool> {
+            print!(
+                "Roll back device {} to {other_slot}? [y/N] ",
+                info.device_id
+            );
+            io::stdout().flush()?;
+            let mut answer = String::new();
+            io::stdin().read_line(&mut answer)?;
+            Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+        })
+        .await??;
+        if !confirmed {
+            println!("Rollback cancelled");
+            return Ok(());
+        }
+    }
+
+    let mut stream = connect(&cmd)?;
+
+    debug!("Requesting rollback");
+    device_util::write_command(Command::Rollback, &mut stream).await?;
+    let status = device_util::read_status(&mut stream).await?;
+
+    if cmd.common.format.is_text() {
+        println!("Status: {status}");
+        println!("The bootloader trial boots {other_slot} and only switches to it once the firmware starts up, check 'device info' once the device is back up");
+    } else {
+        let value = serde_json::json!({
+            "active_boot_slot": active_slot.to_string(),
+            "active_firmware_version": info.firmware_version,
+            "rollback_boot_slot": other_slot.to_string(),
+            "rollback_firmware_version": other_version,
+            "rollback_committed_boot": committed,
+            "rollback_image_verified": verified,
+            "status": status.to_string(),
+        });
+        println!("{}", serde_json::to_string_pretty(&value)?);
+    }
+
+    Ok(())
+}
+
+fn connect(cmd: &DeviceRollback) -> Result<TcpStream> {
+    let s = net::TcpStream::connect((cmd.common.address.as_str(), cmd.common.port))?;
+    s.set_nonblocking(true)?;
+    Ok(TcpStream::from_std(s)?)
+}
+
+/// Whether the image in the given slot matches the archive's image
+async fn image_matches(slot: BootSlot, archive: &Archive, stream: &mut TcpStream) -> Result<bool> {
+    let bin_data = archive.bin(slot)?;
+    let region = MemoryRegion::new_unchecked(slot.address(), bin_data.len() as u32);
+    debug!("Hashing boot slot {slot}");
+    Ok(device_util::hash_memory(region, stream).await? == MemoryHasher::hash(&bin_data))
+}
@@ -1,19 +1,55 @@
 use crate::{
-    archive_util::{self, BootSlotExt},
+    archive_util::{self, Archive},
     device_util::{self, DeviceInfo},
     interruptor::Interruptor,
     opts::DeviceUpdate,
 };
+use agp_archive::{ArchiveManifest, BootSlotExt};
 use anyhow::{anyhow, bail, Result};
 use bootloader_support::BootSlot;
-use elf::{endian::LittleEndian, ElfBytes};
-use std::{fs, io::Write, net};
+use std::{cmp::Ordering, collections::VecDeque, fs, io::Write, path::Path, time::Duration};
 use tokio::{
     io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::TcpStream,
 };
 use tracing::debug;
-use wire_protocols::device::{Command, MemoryRegion};
+use wire_protocols::{
+    compression,
+    device::{Command, MemoryHasher, MemoryRegion, RebootTarget, RequestId, StatusCode},
+    FirmwareVersion,
+};
+
+/// Number of tagged writes kept in flight, the device's TCP window throttles the rest
+const WRITE_WINDOW: usize = 4;
+
+/// Number of entries in the delta source index
+const DELTA_SOURCE_TABLE_LEN: usize = 1 << 16;
+
+/// How long to wait for the device to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay between connection attempts while the device isn't listening yet
+const CONNECT_RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How chunks are encoded for pipelined writes
+enum Encoding {
+    Raw,
+    Compressed,
+    /// Compressed, copying from the given active slot image where possible
+    Delta(Vec<u8>),
+}
+
+/// A chunk's encoded command and data, `None` when it's sent as is
+type EncodedChunk = Option<(Command, Vec<u8>)>;
+
+/// What `update_device` did
+#[derive(Clone, Debug)]
+pub enum UpdateOutcome {
+    /// The image was written and the device rebooted into it
+    Updated(DeviceInfo),
+    /// The device already runs the archive's firmware version, nothing was done
+    UpToDate(DeviceInfo),
+}
 
 pub async fn update(cmd: DeviceUpdate, _intr: Interruptor) -> Result<()> {
     if !cmd.agp_images_cpio_file.exists() {
@@ -35,28 +71,32 @@ pub async fn update(cmd: DeviceUpdate, _intr: Interruptor) -> Result<()> {
         fs::create_dir_all(c)?;
     }
 
-    let (elf_slot0_data, elf_slot1_data) =
-        archive_util::extract_elf_files_from_archive(&cmd.agp_images_cpio_file)?;
+    let archive = archive_util::read_archive(&cmd.agp_images_cpio_file)?;
 
     if let Some(c) = cmd.cache_dir.as_ref() {
-        let elf_path = c.join(BootSlot::Slot0.elf_file_name_and_ext());
-        debug!("Writing ELF '{}'", elf_path.display());
-        fs::write(elf_path, &elf_slot0_data)?;
-
-        let elf_path = c.join(BootSlot::Slot1.elf_file_name_and_ext());
-        debug!("Writing ELF '{}'", elf_path.display());
-        fs::write(elf_path, &elf_slot1_data)?;
+        for slot in [BootSlot::Slot0, BootSlot::Slot1] {
+            let elf_path = c.join(slot.elf_file_name_and_ext());
+            debug!("Writing ELF '{}'", elf_path.display());
+            fs::write(elf_path, archive.elf(slot))?;
+        }
     }
 
-    let elf_slot0 = ElfBytes::<LittleEndian>::minimal_parse(&elf_slot0_data)?;
-    let elf_slot1 = ElfBytes::<LittleEndian>::minimal_parse(&elf_slot1_data)?;
-
     // At this point the archive and image files look ok
 
-    let s = net::TcpStream::connect((cmd.common.address.as_str(), cmd.common.port))?;
-    s.set_nonblocking(true)?;
-    s.set_nodelay(true)?;
-    let mut stream = TcpStream::from_std(s)?;
+    update_device(&archive, &cmd, false).await?;
+
+    Ok(())
+}
+
+/// Updates the device at `cmd.common.address` with the archive's image for its inactive slot.
+/// When `skip_up_to_date` is set, devices already running the archive's firmware version
+/// are left alone.
+pub async fn update_device(
+    archive: &Archive,
+    cmd: &DeviceUpdate,
+    skip_up_to_date: bool,
+) -> Result<UpdateOutcome> {
+    let mut stream = connect(cmd).await?;
 
     debug!("Requesting device info");
     device_util::write_command(Command::Info, &mut stream).await?;
@@ -66,32 +106,35 @@ pub async fn update(cmd: DeviceUpdate, _intr: Interruptor) -> Result<()> {
     let mut info_str = String::new();
     let _info_len = buf_stream.read_line(&mut info_str).await?;
     let info = DeviceInfo::from_json(&info_str)?;
-    let stream = buf_stream.into_inner();
+    let mut stream = buf_stream.into_inner();
     if cmd.common.format.is_text() {
         println!("{info:#?}");
     }
 
-    let s = stream.into_std()?;
-    s.shutdown(net::Shutdown::Both)?;
-    drop(s);
+    if skip_up_to_date {
+        // Devices in recovery mode aren't running any firmware
+        if let (Some(m), Some(v)) = (archive.manifest.as_ref(), info.firmware_version.as_deref()) {
+            if parse_version("firmware", v)? == m.firmware_version {
+                debug!("Device already runs firmware {}", m.firmware_version);
+                return Ok(UpdateOutcome::UpToDate(info));
+            }
+        }
+    }
+
+    // Nothing has been modified on the device yet
+    check_update(archive.manifest.as_ref(), &info, cmd)?;
 
-    tokio::time::sleep(tokio::time::Duration::from_secs(8)).await;
+    stream.shutdown().await?;
+    drop(stream);
 
-    // Re-connect after info command
-    let s = net::TcpStream::connect((cmd.common.address.as_str(), cmd.common.port))?;
-    s.set_nonblocking(true)?;
-    s.set_nodelay(true)?;
-    let mut stream = TcpStream::from_std(s)?;
+    // Re-connect after info command, the device only listens again once it closed the previous connection
+    let mut stream = connect(cmd).await?;
 
     let current_boot_slot_from_info = info.active_boot_slot;
     let boot_slot_to_update = current_boot_slot_from_info.other();
 
-    let elf_to_use = match boot_slot_to_update {
-        BootSlot::Slot0 => elf_slot0,
-        BootSlot::Slot1 => elf_slot1,
-    };
-
-    let bin_data = archive_util::elf2bin(boot_slot_to_update, &elf_to_use)?;
+    // The exact bytes agp-linker built for the slot
+    let bin_data = archive.bin(boot_slot_to_update)?;
     if bin_data.len() > boot_slot_to_update.size() as usize {
         bail!(
             "Firmware must fit into boot slot size {}",
@@ -100,32 +143,329 @@ pub async fn update(cmd: DeviceUpdate, _intr: Interruptor) -> Result<()> {
     }
 
     if let Some(c) = cmd.cache_dir.as_ref() {
-        let bin_path = c.join(BootSlot::Slot1.bin_file_name_and_ext());
+        let bin_path = c.join(boot_slot_to_update.bin_file_name_and_ext());
         debug!("Writing bin '{}'", bin_path.display());
         fs::write(bin_path, &bin_data)?;
     }
 
-    if cmd.common.format.is_text() {
-        println!("Erasing sectors for boot slot {boot_slot_to_update}");
+    // Devices that support tagged writes report how large they can be
+    let pipelined = info.write_chunk_size.is_some();
+    let chunk_size = info
+        .write_chunk_size
+        .map(|s| s as usize)
+        .unwrap_or(MemoryRegion::MAX_CHUCK_SIZE);
+    let compress = cmd.compress && info.compressed_writes;
+    if cmd.compress && !compress && cmd.common.format.is_text() {
+        println!("Device doesn't support compressed writes, sending uncompressed chunks");
     }
-    let mem_region_to_erase =
-        MemoryRegion::new_unchecked(boot_slot_to_update.address(), boot_slot_to_update.size());
-    device_util::write_command(Command::EraseMemory, &mut stream).await?;
-    stream.write_all(&mem_region_to_erase.to_le_bytes()).await?;
-    let status = device_util::read_status(&mut stream).await?;
-    if cmd.common.format.is_text() {
-        println!("Erase status: {status}");
+    let delta = (cmd.delta || cmd.delta_from.is_some()) && info.delta_writes;
+    if (cmd.delta || cmd.delta_from.is_some()) && !delta && cmd.common.format.is_text() {
+        println!("Device doesn't support delta writes, sending the full image");
     }
+    debug!(
+        "Using chunk size {chunk_size}, pipelined writes: {pipelined}, compressed writes: {compress}, delta writes: {delta}"
+    );
 
-    if cmd.common.format.is_text() {
+    let delta_source_data = if delta {
+        let data = delta_source(
+            current_boot_slot_from_info,
+            cmd.delta_from.as_deref(),
+            cmd.common.format.is_text(),
+            &mut stream,
+        )
+        .await?;
+        Some(data)
+    } else {
+        None
+    };
+    let encoding = match delta_source_data {
+        Some(data) => Encoding::Delta(data),
+        None if compress => Encoding::Compressed,
+        None => Encoding::Raw,
+    };
+
+    let num_chunks = divide_round_up(bin_data.len(), chunk_size);
+    let image_region =
+        MemoryRegion::new_unchecked(boot_slot_to_update.address(), bin_data.len() as u32);
+    let image_hash = MemoryHasher::hash(&bin_data);
+
+    // Devices without hash support get the full erase/write/readback sequence
+    let first_chunk_to_write = if info.memory_hashing && !cmd.no_resume {
+        let mut slot = DeviceSlot {
+            slot: boot_slot_to_update,
+            stream: &mut stream,
+        };
+        find_resume_chunk(&bin_data, chunk_size, &mut slot).await?
+    } else {
+        None
+    };
+
+    if let Some(chunk_idx) = first_chunk_to_write {
+        if cmd.common.format.is_text() {
+            if chunk_idx == num_chunks {
+                println!("Image is already in boot slot {boot_slot_to_update}");
+            } else {
+                println!(
+                    "Resuming transfer to boot slot {boot_slot_to_update} at chunk {} of {num_chunks}",
+                    chunk_idx + 1
+                );
+            }
+        }
+    } else {
+        if cmd.common.format.is_text() {
+            println!("Erasing sectors for boot slot {boot_slot_to_update}");
+        }
+        let mem_region_to_erase =
+            MemoryRegion::new_unchecked(boot_slot_to_update.address(), boot_slot_to_update.size());
+        device_util::write_command(Command::EraseMemory, &mut stream).await?;
+        stream.write_all(&mem_region_to_erase.to_le_bytes()).await?;
+        let status = device_util::read_status(&mut stream).await?;
+        if cmd.common.format.is_text() {
+            println!("Erase status: {status}");
+        }
+    }
+    let first_chunk_to_write = first_chunk_to_write.unwrap_or(0);
+
+    if cmd.common.format.is_text() && first_chunk_to_write < num_chunks {
         println!(
             "Wrting bin to boot slot {boot_slot_to_update}, {} bytes",
-            bin_data.len()
+            bin_data.len() - (first_chunk_to_write * chunk_size)
+        );
+    }
+    if pipelined {
+        let encoded = !matches!(encoding, Encoding::Raw);
+        // Encoding is CPU heavy, keep it off the runtime's worker threads
+        let encoded_chunks = {
+            let bin_data = bin_data.clone();
+            tokio::task::spawn_blocking(move || {
+                encode_chunks(&bin_data, chunk_size, first_chunk_to_write, &encoding)
+            })
+            .await??
+        };
+        let bytes_sent = write_chunks_pipelined(
+            boot_slot_to_update,
+            &bin_data,
+            chunk_size,
+            first_chunk_to_write,
+            &encoded_chunks,
+            &mut stream,
+        )
+        .await?;
+        if encoded && cmd.common.format.is_text() {
+            println!("Sent {bytes_sent} encoded bytes");
+        }
+    } else {
+        write_chunks(
+            boot_slot_to_update,
+            &bin_data,
+            first_chunk_to_write,
+            &mut stream,
+        )
+        .await?;
+    }
+
+    if cmd.common.format.is_text() {
+        println!("Verifying image currently in {boot_slot_to_update}");
+    }
+
+    if info.memory_hashing {
+        let hash = device_util::hash_memory(image_region, &mut stream).await?;
+        if hash != image_hash {
+            bail!("Image hash 0x{hash:08X} does not match what we sent (0x{image_hash:08X}), aborting");
+        }
+    } else {
+        verify_by_readback(
+            boot_slot_to_update,
+            &bin_data,
+            cmd.cache_dir.as_deref(),
+            &mut stream,
+        )
+        .await?;
+    }
+
+    if first_chunk_to_write < num_chunks {
+        if cmd.common.format.is_text() {
+            println!("Update complete, issue reboot command");
+        }
+        device_util::write_command(Command::CompleteAndReboot, &mut stream).await?;
+    } else {
+        // Nothing was written, so the device has no update in progress to complete,
+        // trial boot the slot instead (same as completing an update)
+        if cmd.common.format.is_text() {
+            println!("Update complete, issue reboot into {boot_slot_to_update} command");
+        }
+        device_util::write_command(Command::RebootInto, &mut stream).await?;
+        stream
+            .write_u32_le(RebootTarget::TrialOtherSlot.into())
+            .await?;
+    }
+
+    Ok(UpdateOutcome::Updated(info))
+}
+
+/// Prints the from→to summary and refuses updates the device can't take
+fn check_update(
+    manifest: Option<&ArchiveManifest>,
+    info: &DeviceInfo,
+    cmd: &DeviceUpdate,
+) -> Result<()> {
+    let current_version = info
+        .firmware_version
+        .as_deref()
+        .map(|v| parse_version("firmware", v))
+        .transpose()?;
+    let bootloader_version = info
+        .bootloader_version
+        .as_deref()
+        .map(|v| parse_version("bootloader", v))
+        .transpose()?;
+
+    if cmd.common.format.is_text() {
+        match manifest {
+            Some(manifest) => {
+                println!(
+                    "Firmware:   {} -> {} ({}), boot slot {} -> {}",
+                    info.firmware_version_str(),
+                    manifest.firmware_version,
+                    match current_version.map(|v| manifest.firmware_version.cmp(&v)) {
+                        Some(Ordering::Greater) => "upgrade",
+                        Some(Ordering::Equal) => "reinstall",
+                        Some(Ordering::Less) => "downgrade",
+                        None => "recovery",
+                    },
+                    info.active_boot_slot,
+                    info.active_boot_slot.other()
+                );
+                println!(
+                    "Bootloader: {} (requires >= {})",
+                    bootloader_version
+                        .map(|v| v.to_string())
+                        .unwrap_or_else(|| "unknown".to_owned()),
+                    manifest.min_bootloader_version
+                );
+                println!(
+                    "Protocol:   {} -> {}",
+                    info.protocol_version, manifest.protocol_version
+                );
+            }
+            None => println!(
+                "Firmware:   {} -> unknown (archive has no manifest), boot slot {} -> {}",
+                info.firmware_version_str(),
+                info.active_boot_slot,
+                info.active_boot_slot.other()
+            ),
+        }
+    }
+
+    check_compatibility(
+        manifest,
+        current_version,
+        bootloader_version,
+        &info.protocol_version,
+        info.hardware.as_deref(),
+        CheckOverrides::from(cmd),
+    )
+}
+
+/// The checks --allow-* options turn off
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+struct CheckOverrides {
+    downgrade: bool,
+    unknown_bootloader: bool,
+    unverified_archive: bool,
+}
+
+impl From<&DeviceUpdate> for CheckOverrides {
+    fn from(cmd: &DeviceUpdate) -> Self {
+        CheckOverrides {
+            downgrade: cmd.allow_downgrade,
+            unknown_bootloader: cmd.allow_unknown_bootloader,
+            unverified_archive: cmd.allow_unverified_archive,
+        }
+    }
+}
+
+/// Refuses updates the device can't take: an archive without a manifest, other hardware,
+/// another protocol version, a too old or unreported bootloader version, or a downgrade,
+/// unless overridden.
+/// Devices in recovery mode have no current version, any version can be installed.
+fn check_compatibility(
+    manifest: Option<&ArchiveManifest>,
+    current_version: Option<FirmwareVersion>,
+    bootloader_version: Option<FirmwareVersion>,
+    protocol_version: &str,
+    hardware: Option<&str>,
+    overrides: CheckOverrides,
+) -> Result<()> {
+    let Some(manifest) = manifest else {
+        if !overrides.unverified_archive {
+            bail!(
+                "Image archive has no manifest, its firmware version and compatibility can't be checked. \
+                Use --allow-unverified-archive to update anyway"
+            );
+        }
+        return Ok(());
+    };
+
+    // Devices that predate the hardware field only came in one variant
+    if let Some(hardware) = hardware {
+        if hardware != manifest.hardware {
+            bail!(
+                "Image archive is for hardware '{}', the device is '{hardware}'",
+                manifest.hardware
+            );
+        }
+    }
+    if protocol_version != manifest.protocol_version.to_string() {
+        bail!(
+            "Image archive requires protocol version {}, the device speaks protocol version {protocol_version}",
+            manifest.protocol_version,
         );
     }
-    let mut write_address = boot_slot_to_update.address();
+    match bootloader_version {
+        Some(v) if v < manifest.min_bootloader_version => bail!(
+            "Image archive requires bootloader version {} or newer, the device has {v}",
+            manifest.min_bootloader_version
+        ),
+        Some(_) => (),
+        // Bootloaders that predate the version report can't take the current firmware
+        None if !overrides.unknown_bootloader => bail!(
+            "Image archive requires bootloader version {} or newer, the device doesn't report its bootloader version. \
+            Use --allow-unknown-bootloader to update anyway",
+            manifest.min_bootloader_version
+        ),
+        None => debug!("Device doesn't report its bootloader version, skipping the bootloader check"),
+    }
+    match current_version {
+        Some(v) if manifest.firmware_version < v && !overrides.downgrade => bail!(
+            "Refusing to downgrade firmware {v} to {}, use --allow-downgrade to force it",
+            manifest.firmware_version
+        ),
+        _ => (),
+    }
+
+    Ok(())
+}
+
+fn parse_version(what: &str, s: &str) -> Result<FirmwareVersion> {
+    s.parse()
+        .map_err(|_| anyhow!("Device reported an invalid {what} version '{s}'"))
+}
+
+/// One WriteMemory at a time, each waits for its status.
+async fn write_chunks(
+    boot_slot: BootSlot,
+    bin_data: &[u8],
+    first_chunk: usize,
+    stream: &mut TcpStream,
+) -> Result<()> {
     let num_chunks = divide_round_up(bin_data.len(), MemoryRegion::MAX_CHUCK_SIZE);
-    for (chunk_idx, chunk) in bin_data.chunks(MemoryRegion::MAX_CHUCK_SIZE).enumerate() {
+    for (chunk_idx, chunk) in bin_data
+        .chunks(MemoryRegion::MAX_CHUCK_SIZE)
+        .enumerate()
+        .skip(first_chunk)
+    {
+        let write_address = boot_slot.address() + (chunk_idx * MemoryRegion::MAX_CHUCK_SIZE) as u32;
         debug!(
             "Sending bin chunk address=0x{:X}, len=0x{:X}, {} of {}",
             write_address,
@@ -138,19 +478,273 @@ pub async fn update(cmd: DeviceUpdate, _intr: Interruptor) -> Result<()> {
         mem_region_to_write
             .check_length()
             .map_err(|sc| anyhow!("Memory region to write is invalid. {sc}"))?;
-        device_util::write_command(Command::WriteMemory, &mut stream).await?;
+        device_util::write_command(Command::WriteMemory, stream).await?;
         stream.write_all(&mem_region_to_write.to_le_bytes()).await?;
         stream.write_all(chunk).await?;
-        let _status = device_util::read_status(&mut stream).await?;
+        let _status = device_util::read_status(stream).await?;
+    }
+    Ok(())
+}
+
+/// Connects to the device, giving up after CONNECT_TIMEOUT
+async fn connect(cmd: &DeviceUpdate) -> Result<TcpStream> {
+    let addr = (cmd.common.address.as_str(), cmd.common.port);
+    let deadline = tokio::time::Instant::now() + CONNECT_TIMEOUT;
+    let stream = loop {
+        match tokio::time::timeout_at(deadline, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => break stream,
+            Ok(Err(e)) => {
+                debug!("Device not accepting connections yet. {e}");
+                tokio::time::sleep(CONNECT_RETRY_INTERVAL).await;
+            }
+            Err(_) => bail!(
+                "Timed out connecting to {}:{}",
+                cmd.common.address,
+                cmd.common.port
+            ),
+        }
+    };
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Encodes the chunks from `first_chunk` on, chunks that don't get smaller are sent as is.
+fn encode_chunks(
+    bin_data: &[u8],
+    chunk_size: usize,
+    first_chunk: usize,
+    encoding: &Encoding,
+) -> Result<Vec<EncodedChunk>> {
+    let mut delta_source_table = Vec::new();
+    let source = match encoding {
+        Encoding::Raw => {
+            return Ok(vec![
+                None;
+                divide_round_up(bin_data.len(), chunk_size)
+                    - first_chunk
+            ]);
+        }
+        Encoding::Compressed => None,
+        Encoding::Delta(data) => {
+            delta_source_table.resize(DELTA_SOURCE_TABLE_LEN, 0);
+            Some(compression::Source::new(data, &mut delta_source_table))
+        }
+    };
 
-        write_address += mem_region_to_write.length;
+    let mut compressed = vec![0_u8; compression::max_compressed_len(chunk_size)];
+    let mut encoded_chunks = Vec::new();
+    for (chunk_idx, chunk) in bin_data.chunks(chunk_size).enumerate().skip(first_chunk) {
+        let (encoded_cmd, compressed_len) = match source.as_ref() {
+            None => (
+                Command::WriteCompressed,
+                compression::compress(chunk, &mut compressed),
+            ),
+            Some(source) => (
+                Command::WriteDelta,
+                compression::compress_with_source(chunk, source, &mut compressed),
+            ),
+        };
+        let compressed_len =
+            compressed_len.map_err(|e| anyhow!("Failed to encode chunk {}. {e}", chunk_idx + 1))?;
+        if compressed_len < chunk.len() {
+            debug!("Chunk {} encoded to 0x{compressed_len:X}", chunk_idx + 1);
+            encoded_chunks.push(Some((encoded_cmd, compressed[..compressed_len].to_vec())));
+        } else {
+            encoded_chunks.push(None);
+        }
     }
+    Ok(encoded_chunks)
+}
 
-    if cmd.common.format.is_text() {
-        println!("Verifying image currently in {boot_slot_to_update}");
+/// Keeps up to WRITE_WINDOW tagged writes in flight, the chunk index is used
+/// as the request id. The device acknowledges them in order.
+/// `encoded_chunks` holds the `encode_chunks` output for the chunks from `first_chunk` on.
+/// Returns the number of chunk data bytes sent.
+async fn write_chunks_pipelined(
+    boot_slot: BootSlot,
+    bin_data: &[u8],
+    chunk_size: usize,
+    first_chunk: usize,
+    encoded_chunks: &[EncodedChunk],
+    stream: &mut TcpStream,
+) -> Result<usize> {
+    let mut bytes_sent = 0;
+    let num_chunks = divide_round_up(bin_data.len(), chunk_size);
+    let mut chunks = bin_data
+        .chunks(chunk_size)
+        .enumerate()
+        .skip(first_chunk)
+        .zip(encoded_chunks.iter());
+    let mut in_flight = VecDeque::with_capacity(WRITE_WINDOW);
+    loop {
+        while in_flight.len() < WRITE_WINDOW {
+            let Some(((chunk_idx, chunk), encoded_chunk)) = chunks.next() else {
+                break;
+            };
+            let write_address = boot_slot.address() + (chunk_idx * chunk_size) as u32;
+            debug!(
+                "Sending bin chunk address=0x{:X}, len=0x{:X}, {} of {}",
+                write_address,
+                chunk.len(),
+                chunk_idx + 1,
+                num_chunks,
+            );
+
+            let mem_region_to_write =
+                MemoryRegion::new_unchecked(write_address, chunk.len() as u32);
+            mem_region_to_write
+                .check_write_length()
+                .map_err(|sc| anyhow!("Memory region to write is invalid. {sc}"))?;
+            let id = chunk_idx as RequestId;
+            if let Some((encoded_cmd, data)) = encoded_chunk {
+                device_util::write_command(*encoded_cmd, stream).await?;
+                stream.write_u32_le(id).await?;
+                stream.write_all(&mem_region_to_write.to_le_bytes()).await?;
+                stream.write_u32_le(data.len() as u32).await?;
+                stream.write_all(data).await?;
+                bytes_sent += data.len();
+            } else {
+                device_util::write_command(Command::WriteMemoryTagged, stream).await?;
+                stream.write_u32_le(id).await?;
+                stream.write_all(&mem_region_to_write.to_le_bytes()).await?;
+                stream.write_all(chunk).await?;
+                bytes_sent += chunk.len();
+            }
+            in_flight.push_back(id);
+        }
+
+        let Some(expected_id) = in_flight.pop_front() else {
+            break;
+        };
+        let status = StatusCode::from(stream.read_u32_le().await?);
+        let id: RequestId = stream.read_u32_le().await?;
+        debug!("Write {id} acknowledged, status {status}");
+        if id != expected_id {
+            bail!("Got acknowledgement for write {id}, expected {expected_id}");
+        }
+        if !status.is_success() {
+            bail!("Write {id} failed, err status code = {status}");
+        }
+    }
+    Ok(bytes_sent)
+}
+
+/// The image in the active slot, from the given archive if it matches what's on the
+/// device, otherwise read back from the device
+async fn delta_source(
+    active_slot: BootSlot,
+    archive: Option<&Path>,
+    is_text: bool,
+    stream: &mut TcpStream,
+) -> Result<Vec<u8>> {
+    if let Some(archive) = archive {
+        let bin_data = archive_util::read_archive(archive)?.bin(active_slot)?;
+
+        let region = MemoryRegion::new_unchecked(active_slot.address(), bin_data.len() as u32);
+        if device_util::hash_memory(region, stream).await? == MemoryHasher::hash(&bin_data) {
+            return Ok(bin_data);
+        }
+        if is_text {
+            println!(
+                "Image archive '{}' doesn't match boot slot {active_slot}",
+                archive.display()
+            );
+        }
+    }
+
+    if is_text {
+        println!("Reading back boot slot {active_slot} for the delta");
+    }
+    let mut bin_data = Vec::with_capacity(active_slot.size() as usize);
+    let mut read_address = active_slot.address();
+    while bin_data.len() < active_slot.size() as usize {
+        let len = (active_slot.size() as usize - bin_data.len()).min(MemoryRegion::MAX_CHUCK_SIZE);
+        let mem_region_to_read = MemoryRegion::new_unchecked(read_address, len as u32);
+        device_util::write_command(Command::ReadMemory, stream).await?;
+        stream.write_all(&mem_region_to_read.to_le_bytes()).await?;
+        let _status = device_util::read_status(stream).await?;
+        let start = bin_data.len();
+        bin_data.resize(start + len, 0);
+        stream.read_exact(&mut bin_data[start..]).await?;
+        read_address += len as u32;
     }
 
-    let mut readback_file = match cmd.cache_dir.as_ref() {
+    // The rest of the slot is erased
+    let image_len = bin_data
+        .iter()
+        .rposition(|b| *b != 0xFF)
+        .map_or(0, |i| i + 1);
+    bin_data.truncate(image_len);
+    debug!("Boot slot {active_slot} image is {image_len} bytes");
+    Ok(bin_data)
+}
+
+/// Hashes the start of a boot slot
+trait SlotHash {
+    /// The hash of the first `len` bytes of the slot
+    async fn hash(&mut self, len: usize) -> Result<u32>;
+}
+
+/// A boot slot hashed by the device
+struct DeviceSlot<'a> {
+    slot: BootSlot,
+    stream: &'a mut TcpStream,
+}
+
+impl SlotHash for DeviceSlot<'_> {
+    async fn hash(&mut self, len: usize) -> Result<u32> {
+        let region = MemoryRegion::new_unchecked(self.slot.address(), len as u32);
+        device_util::hash_memory(region, self.stream).await
+    }
+}
+
+/// Returns the first chunk that doesn't match the image (the number of chunks if the
+/// entire image matches), if everything from there on is still erased, otherwise the
+/// slot needs to be erased.
+async fn find_resume_chunk<S: SlotHash>(
+    bin_data: &[u8],
+    chunk_size: usize,
+    slot: &mut S,
+) -> Result<Option<usize>> {
+    let num_chunks = divide_round_up(bin_data.len(), chunk_size);
+    if slot.hash(bin_data.len()).await? == MemoryHasher::hash(bin_data) {
+        return Ok(Some(num_chunks));
+    }
+
+    // Binary search for the number of leading chunks that match,
+    // the entire image doesn't
+    let mut matching = 0;
+    let mut mismatching = num_chunks;
+    while mismatching - matching > 1 {
+        let mid = (matching + mismatching) / 2;
+        let prefix = &bin_data[..mid * chunk_size];
+        if slot.hash(prefix.len()).await? == MemoryHasher::hash(prefix) {
+            matching = mid;
+        } else {
+            mismatching = mid;
+        }
+    }
+    debug!("{matching} of {num_chunks} chunks match");
+
+    // The matching chunks followed by erased flash
+    let offset = matching * chunk_size;
+    let mut expected = bin_data[..offset].to_vec();
+    expected.resize(bin_data.len(), 0xFF);
+    if slot.hash(bin_data.len()).await? == MemoryHasher::hash(&expected) {
+        Ok(Some(matching))
+    } else {
+        debug!("Remaining chunks are not erased");
+        Ok(None)
+    }
+}
+
+async fn verify_by_readback(
+    boot_slot: BootSlot,
+    bin_data: &[u8],
+    cache_dir: Option<&Path>,
+    stream: &mut TcpStream,
+) -> Result<()> {
+    let mut readback_file = match cache_dir {
         None => None,
         Some(c) => {
             let bin_path = c.join("mem_readback.bin");
@@ -158,7 +752,8 @@ pub async fn update(cmd: DeviceUpdate, _intr: Interruptor) -> Result<()> {
         }
     };
 
-    let mut read_address = boot_slot_to_update.address();
+    let num_chunks = divide_round_up(bin_data.len(), MemoryRegion::MAX_CHUCK_SIZE);
+    let mut read_address = boot_slot.address();
     for (chunk_idx, chunk) in bin_data.chunks(MemoryRegion::MAX_CHUCK_SIZE).enumerate() {
         debug!(
             "Read bin chunk address=0x{:X}, len=0x{:X}, {} of {}",
@@ -172,9 +767,9 @@ pub async fn update(cmd: DeviceUpdate, _intr: Interruptor) -> Result<()> {
         mem_region_to_read
             .check_length()
             .map_err(|sc| anyhow!("Memory region to read is invalid. {sc}"))?;
-        device_util::write_command(Command::ReadMemory, &mut stream).await?;
+        device_util::write_command(Command::ReadMemory, stream).await?;
         stream.write_all(&mem_region_to_read.to_le_bytes()).await?;
-        let _status = device_util::read_status(&mut stream).await?;
+        let _status = device_util::read_status(stream).await?;
         let mut bin_data_read_back_from_dev = vec![0_u8; chunk.len()];
         let num_bytes_read = stream.read_exact(&mut bin_data_read_back_from_dev).await?;
         if num_bytes_read != chunk.len() {
@@ -198,16 +793,222 @@ pub async fn update(cmd: DeviceUpdate, _intr: Interruptor) -> Result<()> {
         read_address += mem_region_to_read.length;
     }
 
-    if cmd.common.format.is_text() {
-        println!("Update complete, issue reboot command");
-    }
-
-    device_util::write_command(Command::CompleteAndReboot, &mut stream).await?;
-
     Ok(())
 }
 
-// a/b
 fn divide_round_up(a: usize, b: usize) -> usize {
     (a + (b - 1)) / b
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(major: u16, minor: u16, patch: u16) -> FirmwareVersion {
+        FirmwareVersion::new(major, minor, patch)
+    }
+
+    fn manifest() -> ArchiveManifest {
+        ArchiveManifest {
+            firmware_version: v(0, 5, 0),
+            min_bootloader_version: v(0, 5, 0),
+            protocol_version: 1,
+            hardware: bootloader_support::HARDWARE.to_owned(),
+            git_commit: None,
+            built_time_utc: "Mon, 24 Apr 2023 15:06:18 +0000".to_owned(),
+            profile: None,
+            images: Default::default(),
+        }
+    }
+
+    fn check(
+        manifest: Option<&ArchiveManifest>,
+        current: FirmwareVersion,
+        bootloader: Option<FirmwareVersion>,
+        overrides: CheckOverrides,
+    ) -> Result<()> {
+        check_compatibility(
+            manifest,
+            Some(current),
+            bootloader,
+            "1",
+            Some(bootloader_support::HARDWARE),
+            overrides,
+        )
+    }
+
+    #[test]
+    fn compatible() {
+        let m = manifest();
+        let bl = Some(v(0, 5, 0));
+        let none = CheckOverrides::default();
+        assert!(check(Some(&m), v(0, 4, 2), bl, none).is_ok());
+        assert!(check(Some(&m), v(0, 5, 0), bl, none).is_ok());
+        assert!(check(Some(&m), v(0, 4, 2), Some(v(0, 6, 0)), none).is_ok());
+        // Devices that predate the hardware field
+        assert!(check_compatibility(Some(&m), Some(v(0, 4, 2)), bl, "1", None, none).is_ok());
+    }
+
+    #[test]
+    fn unverified_archive() {
+        let bl = Some(v(0, 5, 0));
+        assert!(check(None, v(0, 4, 2), bl, CheckOverrides::default()).is_err());
+        let overrides = CheckOverrides {
+            unverified_archive: true,
+            ..Default::default()
+        };
+        assert!(check(None, v(0, 4, 2), bl, overrides).is_ok());
+        // Doesn't allow downgrades
+        assert!(check(Some(&manifest()), v(0, 6, 0), bl, overrides).is_err());
+    }
+
+    #[test]
+    fn hardware_mismatch() {
+        let m = manifest();
+        let all = CheckOverrides {
+            downgrade: true,
+            unknown_bootloader: true,
+            unverified_archive: true,
+        };
+        let res = check_compatibility(
+            Some(&m),
+            Some(v(0, 4, 2)),
+            Some(v(0, 5, 0)),
+            "1",
+            Some("other-hardware"),
+            all,
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn protocol_mismatch() {
+        let m = manifest();
+        let res = check_compatibility(
+            Some(&m),
+            Some(v(0, 4, 2)),
+            Some(v(0, 5, 0)),
+            "2",
+            Some(bootloader_support::HARDWARE),
+            CheckOverrides::default(),
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn bootloader_too_old() {
+        let m = manifest();
+        let all = CheckOverrides {
+            downgrade: true,
+            unknown_bootloader: true,
+            unverified_archive: true,
+        };
+        assert!(check(Some(&m), v(0, 4, 2), Some(v(0, 4, 1)), all).is_err());
+    }
+
+    #[test]
+    fn unknown_bootloader() {
+        let m = manifest();
+        assert!(check(Some(&m), v(0, 4, 2), None, CheckOverrides::default()).is_err());
+        let overrides = CheckOverrides {
+            unknown_bootloader: true,
+            ..Default::default()
+        };
+        assert!(check(Some(&m), v(0, 4, 2), None, overrides).is_ok());
+    }
+
+    #[test]
+    fn downgrade() {
+        let m = manifest();
+        let bl = Some(v(0, 5, 0));
+        assert!(check(Some(&m), v(0, 5, 1), bl, CheckOverrides::default()).is_err());
+        let overrides = CheckOverrides {
+            downgrade: true,
+            ..Default::default()
+        };
+        assert!(check(Some(&m), v(0, 5, 1), bl, overrides).is_ok());
+    }
+
+    #[test]
+    fn recovery_mode() {
+        let m = manifest();
+        let res = check_compatibility(
+            Some(&m),
+            None,
+            Some(v(0, 5, 0)),
+            "1",
+            Some(bootloader_support::HARDWARE),
+            CheckOverrides::default(),
+        );
+        assert!(res.is_ok());
+    }
+
+    const CHUNK_SIZE: usize = 16;
+
+    /// A boot slot's contents, hashed locally
+    struct TestSlot(Vec<u8>);
+
+    impl SlotHash for TestSlot {
+        async fn hash(&mut self, len: usize) -> Result<u32> {
+            Ok(MemoryHasher::hash(&self.0[..len]))
+        }
+    }
+
+    /// 4.5 chunks, without erased bytes
+    fn image() -> Vec<u8> {
+        (0..(4 * CHUNK_SIZE + CHUNK_SIZE / 2))
+            .map(|i| i as u8)
+            .collect()
+    }
+
+    /// The slot after writing the first `len` bytes of the image
+    fn slot(len: usize) -> TestSlot {
+        let mut data = vec![0xFF; 8 * CHUNK_SIZE];
+        data[..len].copy_from_slice(&image()[..len]);
+        TestSlot(data)
+    }
+
+    async fn resume(slot: &mut TestSlot) -> Option<usize> {
+        find_resume_chunk(&image(), CHUNK_SIZE, slot).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn resume_nothing_written() {
+        assert_eq!(resume(&mut slot(0)).await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn resume_after_whole_chunks() {
+        for n in 1..=4 {
+            assert_eq!(resume(&mut slot(n * CHUNK_SIZE)).await, Some(n));
+        }
+    }
+
+    #[tokio::test]
+    async fn resume_mid_chunk_stop_needs_erase() {
+        // The partially written chunk can't be written again without an erase
+        assert_eq!(resume(&mut slot(2 * CHUNK_SIZE + 3)).await, None);
+    }
+
+    #[tokio::test]
+    async fn resume_full_image() {
+        assert_eq!(resume(&mut slot(image().len())).await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn resume_stale_tail_needs_erase() {
+        let mut s = slot(2 * CHUNK_SIZE);
+        s.0[3 * CHUNK_SIZE + 1] = 0;
+        assert_eq!(resume(&mut s).await, None);
+
+        // A different image in the slot
+        let mut s = slot(0);
+        s.0[..CHUNK_SIZE].fill(0);
+        assert_eq!(resume(&mut s).await, None);
+
+        // Only the image's length has to be erased
+        let mut s = slot(2 * CHUNK_SIZE);
+        s.0[image().len()] = 0;
+        assert_eq!(resume(&mut s).await, Some(2));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::{
+    datagram, device_util,
+    interruptor::Interruptor,
+    opts::{Discover, Format},
+};
+use anyhow::{bail, Result};
+use futures::future;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::UdpSocket;
+use tracing::debug;
+use wire_protocols::{
+    broadcast::MESSAGE_LEN,
+    discovery::{self, Request, Response},
+    DeviceId, DeviceSerialNumber, ProtocolVersion,
+};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Requests are resent in case one got dropped
+const QUERY_INTERVAL: Duration = Duration::from_secs(5);
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NA: &str = "NA";
+
+/// A device found on the network.
+/// The `address` and `port` fields are what `fleet update --devices` reads.
+#[derive(Clone, Debug, Serialize)]
+struct DiscoveredDevice {
+    address: IpAddr,
+    /// Device protocol port
+    port: u16,
+    device_id: u16,
+    device_serial_number: String,
+    protocol_version: u8,
+    firmware_version: String,
+    /// Number of broadcast messages received
+    messages: u64,
+    /// Number of discovery responses received
+    responses: u64,
+    /// The following are only known when probed
+    active_boot_slot: Option<String>,
+    bootloader_version: Option<String>,
+    hardware: Option<String>,
+    probe_error: Option<String>,
+}
+
+pub async fn discover(cmd: Discover, intr: Interruptor) -> Result<()> {
+    if cmd.format.is_text() {
+        println!(
+            "Listening for UDP broadcast messages on {}:{} for {}",
+            cmd.address,
+            cmd.port,
+            humantime::format_duration(cmd.duration)
+        );
+    }
+
+    let s = std::net::UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
+    s.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(s)?;
+
+    let query = if cmd.query {
+        let request = Request {
+            protocol_version: ProtocolVersion::v1(),
+            request_id: new_request_id(),
+        };
+        let s = UdpSocket::bind((cmd.address.as_str(), 0)).await?;
+        s.set_broadcast(true)?;
+        if cmd.format.is_text() {
+            println!(
+                "Sending discovery requests to {}:{}",
+                cmd.query_address, cmd.discovery_port
+            );
+        }
+        Some((s, request))
+    } else {
+        None
+    };
+    let mut next_query = Instant::now();
+
+    let mut buf = vec![0; MESSAGE_LEN * 10];
+    let mut query_buf = vec![0; discovery::RESPONSE_LEN * 10];
+    let mut devices: BTreeMap<(DeviceSerialNumber, DeviceId, IpAddr), DiscoveredDevice> =
+        BTreeMap::new();
+
+    let deadline = Instant::now() + cmd.duration;
+    while !intr.is_set() {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+
+        if let Some((query_socket, request)) = query.as_ref() {
+            if Instant::now() >= next_query {
+                send_request(query_socket, request, &cmd).await?;
+                next_query = Instant::now() + QUERY_INTERVAL;
+            }
+        }
+
+        let recvd = tokio::time::timeout(remaining.min(TIMEOUT), async {
+            tokio::select! {
+                res = socket.recv_from(&mut buf) => res.map(|r| (r, false)),
+                res = recv_from_opt(query.as_ref().map(|(s, _)| s), &mut query_buf) => {
+                    res.map(|r| (r, true))
+                }
+            }
+        })
+        .await;
+        let ((bytes_recvd, src_addr), is_response) = match recvd {
+            Ok(res) => res?,
+            Err(_) => continue,
+        };
+        debug!("Received {bytes_recvd} bytes from {src_addr}");
+
+        if is_response {
+            let request_id = query
+                .as_ref()
+                .map(|(_, r)| r.request_id)
+                .unwrap_or_default();
+            match discovery::Message::new_checked(&query_buf[..bytes_recvd])
+                .and_then(|wire_msg| Response::parse(&wire_msg))
+            {
+                Ok(resp) if resp.request_id == request_id => {
+                    add_response(&mut devices, &resp);
+                }
+                Ok(resp) => debug!(
+                    "Ignoring response from {src_addr} to another request ID {}",
+                    resp.request_id
+                ),
+                Err(e) => debug!(
+                    "Ignoring message from {src_addr}, failed to parse as discovery response. {e}"
+                ),
+            }
+            continue;
+        }
+
+        let decoded = datagram::decode(&buf[..bytes_recvd]);
+        if let Some(e) = decoded.error {
+            debug!("Ignoring data from {src_addr}. {e}");
+        }
+
+        for msg in decoded.messages {
+            let key = (msg.device_serial_number, msg.device_id, src_addr.ip());
+            let device = devices.entry(key).or_insert_with(|| DiscoveredDevice {
+                address: src_addr.ip(),
+                port: cmd.device_port,
+                device_id: msg.device_id.0,
+                device_serial_number: format!("{:X}", msg.device_serial_number),
+                protocol_version: msg.protocol_version.0,
+                firmware_version: msg.firmware_version.to_string(),
+                messages: 0,
+                responses: 0,
+                active_boot_slot: None,
+                bootloader_version: None,
+                hardware: None,
+                probe_error: None,
+            });
+            device.messages += 1;
+        }
+    }
+
+    let mut devices: Vec<DiscoveredDevice> = devices.into_values().collect();
+
+    if cmd.probe && !devices.is_empty() {
+        if cmd.format.is_text() {
+            println!("Probing {} devices", devices.len());
+        }
+        let infos =
+            future::join_all(devices.iter().map(|d| probe(d.address.to_string(), d.port))).await;
+        for (device, info) in devices.iter_mut().zip(infos) {
+            match info {
+                Ok(info) => {
+                    // The device reports what it's running right now
+                    device.firmware_version = info.firmware_version_str().to_owned();
+                    device.active_boot_slot = Some(info.active_boot_slot.to_string());
+                    device.bootloader_version = info.bootloader_version;
+                    device.hardware = info.hardware;
+                }
+                Err(e) => device.probe_error = Some(format!("{e:#}")),
+            }
+        }
+    }
+
+    match cmd.format {
+        Format::Text => print_inventory(&devices),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&devices)?),
+    }
+
+    Ok(())
+}
+
+fn new_request_id() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.subsec_nanos() ^ (now.as_secs() as u32) ^ std::process::id()
+}
+
+async fn send_request(socket: &UdpSocket, request: &Request, cmd: &Discover) -> Result<()> {
+    let mut buf = vec![0; request.message_len()];
+    let mut wire_msg = discovery::Message::new_unchecked(&mut buf[..]);
+    request.emit(&mut wire_msg);
+    debug!("Sending discovery request ID {}", request.request_id);
+    socket
+        .send_to(&buf, (cmd.query_address.as_str(), cmd.discovery_port))
+        .await?;
+    Ok(())
+}
+
+async fn recv_from_opt(
+    socket: Option<&UdpSocket>,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(s) => s.recv_from(buf).await,
+        None => future::pending().await,
+    }
+}
+
+/// Responses carry everything a probe would, except the hardware
+fn add_response(
+    devices: &mut BTreeMap<(DeviceSerialNumber, DeviceId, IpAddr), DiscoveredDevice>,
+    resp: &Response,
+) {
+    let address = IpAddr::from(resp.ip_address);
+    let key = (resp.device_serial_number, resp.device_id, address);
+    let device = devices.entry(key).or_insert_with(|| DiscoveredDevice {
+        address,
+        port: resp.device_port,
+        device_id: resp.device_id.0,
+        device_serial_number: format!("{:X}", resp.device_serial_number),
+        protocol_version: resp.protocol_version.0,
+        firmware_version: resp.firmware_version.to_string(),
+        messages: 0,
+        responses: 0,
+        active_boot_slot: None,
+        bootloader_version: None,
+        hardware: None,
+        probe_error: None,
+    });
+    device.port = resp.device_port;
+    device.firmware_version = resp.firmware_version.to_string();
+    device.bootloader_version = resp.bootloader_version.map(|v| v.to_string());
+    device.active_boot_slot = Some(format!("SLOT{}", resp.active_boot_slot));
+    device.responses += 1;
+}
+
+async fn probe(address: String, port: u16) -> Result<device_util::DeviceInfo> {
+    match tokio::time::timeout(PROBE_TIMEOUT, device_util::request_info(&address, port)).await {
+        Ok(res) => res,
+        Err(_) => bail!("Timed out requesting device info"),
+    }
+}
+
+fn print_inventory(devices: &[DiscoveredDevice]) {
+    println!("Found {} devices", devices.len());
+    if devices.is_empty() {
+        return;
+    }
+
+    let addr_width = devices
+        .iter()
+        .map(|d| d.address.to_string().len())
+        .chain(Some("ADDRESS".len()))
+        .max()
+        .unwrap_or_default();
+    let sn_width = devices
+        .iter()
+        .map(|d| d.device_serial_number.len())
+        .chain(Some("SERIAL NUMBER".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!();
+    println!(
+        "{:<addr_width$}  {:<9}  {:<sn_width$}  {:<8}  {:<10}  {:<5}  {:>8}",
+        "ADDRESS", "DEVICE ID", "SERIAL NUMBER", "FIRMWARE", "BOOTLOADER", "SLOT", "MESSAGES"
+    );
+    for d in devices.iter() {
+        println!(
+            "{:<addr_width$}  {:<9}  {:<sn_width$}  {:<8}  {:<10}  {:<5}  {:>8}",
+            d.address.to_string(),
+            d.device_id,
+            d.device_serial_number,
+            d.firmware_version,
+            d.bootloader_version.as_deref().unwrap_or(NA),
+            d.active_boot_slot.as_deref().unwrap_or(NA),
+            d.messages
+        );
+    }
+    for d in devices.iter() {
+        if let Some(e) = d.probe_error.as_deref() {
+            println!("Failed to probe {}: {e}", d.address);
+        }
+    }
+}
@@ -0,0 +1,11 @@
+use crate::{interruptor::Interruptor, opts::Fleet};
+use anyhow::Result;
+
+mod update;
+
+pub async fn fleet(cmd: Fleet, intr: Interruptor) -> Result<()> {
+    match cmd {
+        Fleet::Update(subcmd) => self::update::update(subcmd, intr).await?,
+    }
+    Ok(())
+}
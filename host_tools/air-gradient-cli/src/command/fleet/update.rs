@@ -0,0 +1,412 @@
+use crate::{
+    archive_util::{self, Archive},
+    command::device::update::{update_device, UpdateOutcome},
+    device_util::{self, DeviceInfo},
+    interruptor::Interruptor,
+    opts::{CommonDeviceOpts, DeviceUpdate, FleetUpdate, Format},
+};
+use anyhow::{anyhow, bail, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs,
+    io::{self, Read},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::debug;
+use wire_protocols::FirmwareVersion;
+
+/// How long a device takes to reboot into the new image before it's asked for its info
+const REBOOT_DELAY: Duration = Duration::from_secs(8);
+
+/// How long to keep asking a rebooted device for its info
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Delay between the info requests while waiting for a device
+const REBOOT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a single info request may take
+const INFO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A device to update
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+struct Target {
+    address: String,
+    port: u16,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+/// An entry of the `discover --format json` output, only the fields needed here
+#[derive(Clone, Debug, Deserialize)]
+struct DiscoveredDevice {
+    address: String,
+    #[serde(default)]
+    port: Option<u16>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Status {
+    Updated,
+    UpToDate,
+    Failed,
+    /// Not attempted, the rollout was stopped
+    Skipped,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Updated => f.write_str("updated"),
+            Status::UpToDate => f.write_str("up-to-date"),
+            Status::Failed => f.write_str("failed"),
+            Status::Skipped => f.write_str("skipped"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct DeviceSummary {
+    address: String,
+    port: u16,
+    status: Status,
+    device_id: Option<u16>,
+    /// Firmware version the device was running
+    from_version: Option<String>,
+    /// Firmware version the device was updated to
+    to_version: Option<String>,
+    elapsed_secs: u64,
+    error: Option<String>,
+}
+
+pub async fn update(cmd: FleetUpdate, intr: Interruptor) -> Result<()> {
+    if !cmd.agp_images_cpio_file.exists() {
+        bail!(
+            "Image archive '{}' does not exist",
+            cmd.agp_images_cpio_file.display()
+        );
+    }
+
+    let targets = targets(&cmd)?;
+    if targets.is_empty() {
+        bail!("No devices to update, use --address or --devices");
+    }
+
+    let archive = archive_util::read_archive(&cmd.agp_images_cpio_file)?;
+    let to_version = match archive.manifest.as_ref() {
+        Some(m) => Some(m.firmware_version),
+        None if cmd.allow_unverified_archive => None,
+        None => bail!(
+            "Image archive '{}' has no manifest, its firmware version and compatibility can't be checked. \
+            Use --allow-unverified-archive to update anyway",
+            cmd.agp_images_cpio_file.display()
+        ),
+    };
+
+    if cmd.format.is_text() {
+        println!(
+            "Updating {} devices to firmware {} with image archive '{}', {} at a time",
+            targets.len(),
+            to_version
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| "unknown".to_owned()),
+            cmd.agp_images_cpio_file.display(),
+            cmd.concurrency
+        );
+    }
+
+    let concurrency = cmd.concurrency.get();
+    let rollout = Arc::new(Rollout {
+        cmd,
+        archive,
+        intr,
+        to_version,
+        failures: AtomicUsize::new(0),
+    });
+    // Each device gets its own task, so a stalled device doesn't hold up the others
+    let mut summaries: Vec<(usize, DeviceSummary)> = stream::iter(targets.into_iter().enumerate())
+        .map(|(idx, target)| {
+            let rollout = rollout.clone();
+            tokio::spawn(async move { rollout.update_target(idx, target).await })
+        })
+        .buffer_unordered(concurrency)
+        .try_collect()
+        .await?;
+    summaries.sort_by_key(|(idx, _)| *idx);
+    let summaries: Vec<DeviceSummary> = summaries.into_iter().map(|(_, s)| s).collect();
+
+    match rollout.cmd.format {
+        Format::Text => print_table(&summaries),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&summaries)?),
+    }
+
+    let num_failures = rollout.failures.load(SeqCst);
+    if num_failures != 0 {
+        bail!(
+            "{num_failures} of {} devices failed to update",
+            summaries.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// State shared by the concurrent device updates
+struct Rollout {
+    cmd: FleetUpdate,
+    archive: Archive,
+    intr: Interruptor,
+    to_version: Option<FirmwareVersion>,
+    failures: AtomicUsize,
+}
+
+impl Rollout {
+    /// Updates a device unless the rollout was stopped, returns the target index with its summary
+    async fn update_target(&self, idx: usize, target: Target) -> (usize, DeviceSummary) {
+        let text = self.cmd.format.is_text();
+        let mut summary = DeviceSummary {
+            address: target.address.clone(),
+            port: target.port,
+            status: Status::Skipped,
+            device_id: None,
+            from_version: None,
+            to_version: None,
+            elapsed_secs: 0,
+            error: None,
+        };
+
+        let num_failures = self.failures.load(SeqCst);
+        if num_failures >= self.cmd.max_failures.get() {
+            summary.error = Some(format!("Rollout stopped after {num_failures} failures"));
+            return (idx, summary);
+        }
+        if self.intr.is_set() {
+            summary.error = Some("Interrupted".to_owned());
+            return (idx, summary);
+        }
+
+        if text {
+            println!("[{target}] Updating");
+        }
+        let start = Instant::now();
+        let res = update_device(
+            &self.archive,
+            &device_update_cmd(&self.cmd, &target),
+            !self.cmd.reinstall,
+        )
+        .await;
+        let res = match res {
+            Ok(UpdateOutcome::Updated(info)) => {
+                summary.device_id = Some(info.device_id);
+                summary.from_version = Some(info.firmware_version_str().to_owned());
+                self.wait_for_reboot(&target).await.map(|info| {
+                    summary.to_version = Some(info.firmware_version_str().to_owned());
+                    Status::Updated
+                })
+            }
+            Ok(UpdateOutcome::UpToDate(info)) => {
+                summary.device_id = Some(info.device_id);
+                summary.from_version = Some(info.firmware_version_str().to_owned());
+                Ok(Status::UpToDate)
+            }
+            Err(e) => Err(e),
+        };
+        summary.elapsed_secs = start.elapsed().as_secs();
+        match res {
+            Ok(status) => summary.status = status,
+            Err(e) => {
+                self.failures.fetch_add(1, SeqCst);
+                summary.status = Status::Failed;
+                summary.error = Some(format!("{e:#}"));
+            }
+        }
+        if text {
+            match summary.error.as_deref() {
+                Some(e) => println!("[{target}] {}: {e}", summary.status),
+                None => println!("[{target}] {} in {}s", summary.status, summary.elapsed_secs),
+            }
+        }
+        (idx, summary)
+    }
+
+    /// Waits for the device to answer again after the update's reboot and checks it runs
+    /// the archive's firmware version, a device that rolled back reports the old one
+    async fn wait_for_reboot(&self, target: &Target) -> Result<DeviceInfo> {
+        let deadline = Instant::now() + REBOOT_TIMEOUT;
+        tokio::time::sleep(REBOOT_DELAY).await;
+        let info = loop {
+            let res = tokio::time::timeout(
+                INFO_TIMEOUT,
+                device_util::request_info(&target.address, target.port),
+            )
+            .await
+            .map_err(|_| anyhow!("Timed out requesting the device info"))
+            .and_then(|r| r);
+            match res {
+                Ok(info) => break info,
+                Err(e) if Instant::now() >= deadline => {
+                    bail!("Device didn't come back after the reboot. {e:#}")
+                }
+                Err(e) => {
+                    debug!("[{target}] Not back yet. {e:#}");
+                    tokio::time::sleep(REBOOT_POLL_INTERVAL).await;
+                }
+            }
+        };
+
+        if let Some(expected) = self.to_version {
+            let version = info
+                .firmware_version
+                .as_deref()
+                .and_then(|v| v.parse().ok());
+            if version != Some(expected) {
+                bail!(
+                    "Device came back with firmware {}, expected {expected}",
+                    info.firmware_version_str()
+                );
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// The single device update options for a target
+fn device_update_cmd(cmd: &FleetUpdate, target: &Target) -> DeviceUpdate {
+    DeviceUpdate {
+        common: CommonDeviceOpts {
+            verbose: false,
+            address: target.address.clone(),
+            port: target.port,
+            // The per-device output would be interleaved, the summary replaces it
+            format: Format::Json,
+        },
+        cache_dir: None,
+        no_resume: cmd.no_resume,
+        compress: cmd.compress,
+        delta: cmd.delta,
+        delta_from: None,
+        allow_downgrade: cmd.allow_downgrade,
+        allow_unknown_bootloader: cmd.allow_unknown_bootloader,
+        allow_unverified_archive: cmd.allow_unverified_archive,
+        agp_images_cpio_file: cmd.agp_images_cpio_file.clone(),
+    }
+}
+
+/// The --address targets followed by the --devices ones, without duplicates
+fn targets(cmd: &FleetUpdate) -> Result<Vec<Target>> {
+    let mut targets = Vec::new();
+    for a in cmd.addresses.iter() {
+        targets.push(parse_target(a, cmd.port)?);
+    }
+    if let Some(path) = cmd.devices_file.as_deref() {
+        targets.extend(read_devices_file(path, cmd.port)?);
+    }
+
+    let mut unique = Vec::with_capacity(targets.len());
+    for t in targets.into_iter() {
+        if unique.contains(&t) {
+            debug!("Ignoring duplicate device {t}");
+        } else {
+            unique.push(t);
+        }
+    }
+    Ok(unique)
+}
+
+fn read_devices_file(path: &Path, default_port: u16) -> Result<Vec<Target>> {
+    let contents = if path == Path::new("-") {
+        let mut s = String::new();
+        io::stdin().read_to_string(&mut s)?;
+        s
+    } else {
+        fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read devices file '{}'. {e}", path.display()))?
+    };
+
+    if contents.trim_start().starts_with('[') {
+        let devices: Vec<DiscoveredDevice> = serde_json::from_str(&contents)
+            .map_err(|e| anyhow!("Invalid devices file '{}'. {e}", path.display()))?;
+        Ok(devices
+            .into_iter()
+            .map(|d| Target {
+                address: d.address,
+                port: d.port.unwrap_or(default_port),
+            })
+            .collect())
+    } else {
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| parse_target(l, default_port))
+            .collect()
+    }
+}
+
+/// Parses 'host', 'host:port' or a socket address
+fn parse_target(s: &str, default_port: u16) -> Result<Target> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(Target {
+            address: addr.ip().to_string(),
+            port: addr.port(),
+        });
+    }
+    match s.rsplit_once(':') {
+        // Bare IPv6 addresses have colons too
+        Some((host, port)) if !host.contains(':') => Ok(Target {
+            address: host.to_owned(),
+            port: port
+                .parse()
+                .map_err(|_| anyhow!("Invalid port in device address '{s}'"))?,
+        }),
+        _ => Ok(Target {
+            address: s.to_owned(),
+            port: default_port,
+        }),
+    }
+}
+
+fn print_table(summaries: &[DeviceSummary]) {
+    let targets: Vec<String> = summaries
+        .iter()
+        .map(|s| format!("{}:{}", s.address, s.port))
+        .collect();
+    let addr_width = targets
+        .iter()
+        .map(String::len)
+        .chain(Some("ADDRESS".len()))
+        .max()
+        .unwrap_or_default();
+
+    println!();
+    println!(
+        "{:<addr_width$}  {:<9}  {:<10}  {:<16}  {:>6}  ERROR",
+        "ADDRESS", "DEVICE ID", "STATUS", "FIRMWARE", "TIME"
+    );
+    for (target, s) in targets.iter().zip(summaries.iter()) {
+        let device_id = s.device_id.map(|id| id.to_string()).unwrap_or_default();
+        let firmware = match (s.from_version.as_deref(), s.to_version.as_deref()) {
+            (Some(from), Some(to)) => format!("{from} -> {to}"),
+            (Some(from), None) => from.to_owned(),
+            (None, _) => String::new(),
+        };
+        let line = format!(
+            "{target:<addr_width$}  {device_id:<9}  {:<10}  {firmware:<16}  {:>5}s  {}",
+            s.status.to_string(),
+            s.elapsed_secs,
+            s.error.as_deref().unwrap_or_default()
+        );
+        println!("{}", line.trim_end());
+    }
+}
@@ -0,0 +1,76 @@
+use crate::{
+    database::{Database, FIELDS},
+    interruptor::Interruptor,
+    opts::History,
+};
+use anyhow::{bail, Result};
+use chrono::prelude::*;
+
+pub async fn history(cmd: History, _intr: Interruptor) -> Result<()> {
+    for field in cmd.field.iter() {
+        if !FIELDS.iter().any(|(f, _)| f == field) {
+            let fields: Vec<&str> = FIELDS.iter().map(|(f, _)| *f).collect();
+            bail!(
+                "Invalid field '{field}', must be one of {}",
+                fields.join(", ")
+            );
+        }
+    }
+
+    let end = cmd.end.unwrap_or_else(Utc::now);
+    let start = match cmd.start {
+        Some(start) => start,
+        None => end - chrono::Duration::from_std(cmd.since)?,
+    };
+    if start >= end {
+        bail!("The start of the time range must be before the end");
+    }
+
+    let db = Database::open_existing(&cmd.database)?;
+    let aggregates = db.query(cmd.device.as_deref(), start, end, cmd.interval)?;
+
+    println!(
+        "History from {} to {} in {} intervals",
+        start.format("%Y-%m-%d %H:%M:%S"),
+        end.format("%Y-%m-%d %H:%M:%S"),
+        humantime::format_duration(cmd.interval)
+    );
+
+    let mut device = None;
+    for a in aggregates
+        .iter()
+        .filter(|a| cmd.field.is_empty() || cmd.field.iter().any(|f| f == a.field))
+    {
+        if device != Some(&a.device_serial_number) {
+            device = Some(&a.device_serial_number);
+            println!();
+            println!("Device SN: {}", a.device_serial_number);
+            println!(
+                "  {:<19}  {:<11}  {:>8}  {:>10}  {:>10}  {:>10}",
+                "Interval (UTC)", "Field", "Count", "Min", "Avg", "Max"
+            );
+        }
+        let unit = FIELDS
+            .iter()
+            .find(|(f, _)| *f == a.field)
+            .map(|(_, u)| *u)
+            .unwrap_or_default();
+        let line = format!(
+            "  {:<19}  {:<11}  {:>8}  {:>10.2}  {:>10.2}  {:>10.2}  {unit}",
+            a.start_time.format("%Y-%m-%d %H:%M:%S"),
+            a.field,
+            a.count,
+            a.min,
+            a.avg,
+            a.max,
+        );
+        println!("{}", line.trim_end());
+    }
+
+    if device.is_none() {
+        println!();
+        println!("No history found");
+    }
+
+    Ok(())
+}
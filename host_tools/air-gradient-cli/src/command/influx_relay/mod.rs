@@ -0,0 +1,214 @@
+use crate::{
+    datagram,
+    interruptor::Interruptor,
+    measurement::{Measurement, MeasurementFields, MeasurementTags, MessageExt},
+    opts::InfluxRelay,
+};
+use anyhow::Result;
+use chrono::prelude::*;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use wire_protocols::broadcast::{Repr as Message, MESSAGE_LEN};
+
+use self::{output::Output, queue::Queue};
+
+mod output;
+mod queue;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+pub async fn influx_relay(cmd: InfluxRelay, intr: Interruptor) -> Result<()> {
+    tracing::info!(
+        address = cmd.address,
+        port = cmd.port,
+        output = %cmd.output,
+        host = cmd.host,
+        destination = cmd.destination,
+        "Relaying UDP broadcast messages to influx",
+    );
+
+    let mut buf = vec![0; MESSAGE_LEN * 10];
+
+    let s = std::net::UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
+    s.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(s)?;
+
+    let mut relay = Relay {
+        output: Output::new(&cmd).await?,
+        queue: Queue::new(cmd.max_queue_len.get(), cmd.spool_file.as_deref())?,
+        last_write: Instant::now(),
+        next_attempt: Instant::now(),
+        retries: 0,
+        written: 0,
+        cmd,
+    };
+    let mut last_stats = Instant::now();
+
+    loop {
+        if intr.is_set() {
+            break;
+        }
+
+        if relay.should_write() {
+            relay.write_batch().await?;
+        }
+
+        if last_stats.elapsed() >= STATS_INTERVAL {
+            relay.log_stats();
+            last_stats = Instant::now();
+        }
+
+        let (bytes_recvd, src_addr) =
+            match tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await {
+                Ok(res) => res?,
+                Err(_) => continue,
+            };
+        let recv_utc: DateTime<Utc> = Utc::now();
+
+        tracing::debug!(
+            src = %src_addr,
+            bytes_recvd = bytes_recvd,
+            "Received message data"
+        );
+
+        let decoded = datagram::decode(&buf[..bytes_recvd]);
+        if let Some(e) = decoded.error {
+            tracing::error!(src = %src_addr, e = %e, "Failed to decode datagram");
+        }
+
+        for msg in decoded.messages {
+            relay.queue.push(measurement(&msg, recv_utc))?;
+        }
+    }
+
+    tracing::debug!("Exiting relay loop");
+
+    // One last attempt, whatever is left stays in the spool file
+    if !relay.queue.is_empty() {
+        tracing::info!(queue_depth = relay.queue.len(), "Flushing queued points");
+        relay.next_attempt = Instant::now();
+        while !relay.queue.is_empty() && relay.write_batch().await? {}
+    }
+    relay.queue.compact()?;
+    relay.log_stats();
+    if !relay.queue.is_empty() && relay.cmd.spool_file.is_none() {
+        tracing::warn!(
+            points = relay.queue.len(),
+            "Dropping queued points, use --spool-file to keep them"
+        );
+    }
+
+    Ok(())
+}
+
+struct Relay {
+    cmd: InfluxRelay,
+    output: Output,
+    queue: Queue,
+    last_write: Instant,
+    /// Writes are held off until then after a failure
+    next_attempt: Instant,
+    /// Consecutive failed writes
+    retries: u32,
+    /// Points written since start
+    written: u64,
+}
+
+impl Relay {
+    fn should_write(&self) -> bool {
+        let now = Instant::now();
+        !self.queue.is_empty()
+            && now >= self.next_attempt
+            && (self.queue.len() >= self.cmd.batch_size.get()
+                || now.duration_since(self.last_write) >= self.cmd.batch_interval)
+    }
+
+    /// Writes the oldest batch of points, returns false when the write failed
+    async fn write_batch(&mut self) -> Result<bool> {
+        let batch_len = self.queue.len().min(self.cmd.batch_size.get());
+        let points = self
+            .queue
+            .front(batch_len)
+            .cloned()
+            .map(|m| m.into_data_point(&self.cmd.measurement_name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let res = tokio::time::timeout(WRITE_TIMEOUT, self.output.write(points)).await;
+        self.last_write = Instant::now();
+
+        let err = match res {
+            Ok(Ok(())) => {
+                self.queue.remove_front(batch_len)?;
+                self.written += batch_len as u64;
+                self.retries = 0;
+                tracing::debug!(
+                    points = batch_len,
+                    queue_depth = self.queue.len(),
+                    "Wrote batch"
+                );
+                return Ok(true);
+            }
+            Ok(Err(e)) => format!("{e:#}"),
+            Err(_) => "Timed out".to_owned(),
+        };
+
+        self.retries += 1;
+        let delay = INITIAL_RETRY_DELAY
+            .saturating_mul(2_u32.saturating_pow(self.retries - 1))
+            .min(self.cmd.max_retry_delay);
+        self.next_attempt = Instant::now() + delay;
+        tracing::warn!(
+            error = err,
+            points = batch_len,
+            queue_depth = self.queue.len(),
+            retries = self.retries,
+            dropped = self.queue.dropped(),
+            retry_in = %humantime::format_duration(delay),
+            "Failed to write batch"
+        );
+        Ok(false)
+    }
+
+    fn log_stats(&self) {
+        tracing::info!(
+            queue_depth = self.queue.len(),
+            written = self.written,
+            retries = self.retries,
+            dropped = self.queue.dropped(),
+            "Relay stats"
+        );
+    }
+}
+
+fn measurement(msg: &Message, recv_utc: DateTime<Utc>) -> Measurement {
+    let aqi = msg.valid_pm2_5_us_aqi();
+    Measurement {
+        recv_time_utc_ns: recv_utc
+            .timestamp_nanos_opt()
+            .expect("timestamp_nanos_opt failed"),
+        tags: MeasurementTags {
+            device_id: msg.device_id.to_string(),
+            device_serial_number: format!("{:X}", msg.device_serial_number),
+            firmware_version: msg.firmware_version.to_string(),
+        },
+        fields: MeasurementFields {
+            sequence_number: msg.sequence_number.into(),
+            temperature: msg.valid_temperature_f(),
+            humidity: msg.valid_relative_humidity(),
+            voc_ticks: msg.valid_voc_ticks().map(i64::from),
+            nox_ticks: msg.valid_nox_ticks().map(i64::from),
+            voc_index: msg.valid_voc_index().map(i64::from),
+            nox_index: msg.valid_nox_index().map(i64::from),
+            pm25: msg.valid_pm2_5_atm().map(i64::from),
+            aqi: aqi.as_ref().map(|a| i64::from(a.aqi())),
+            aqi_level: aqi.as_ref().map(|a| a.level().to_string()),
+            co2: msg.valid_co2().map(i64::from),
+        },
+    }
+}
@@ -0,0 +1,136 @@
+use crate::opts::{InfluxOutput, InfluxRelay};
+use anyhow::{anyhow, bail, Result};
+use futures::prelude::*;
+use influxdb2::{
+    models::{DataPoint, WriteDataPoint},
+    Client,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+};
+use tokio::net::UdpSocket;
+
+const UDP_SCHEME: &str = "udp://";
+
+/// Where the relay writes its points to
+pub enum Output {
+    /// InfluxDB 2.x write API
+    Influx2 { client: Client, bucket: String },
+    /// InfluxDB 1.x `/write` endpoint, with the database and retention policy in the URL
+    Influx1 {
+        client: reqwest::Client,
+        url: reqwest::Url,
+        credentials: Option<(String, String)>,
+    },
+    /// Line protocol to stdout
+    Stdout,
+    /// Line protocol appended to a file
+    File(File),
+    /// Line protocol datagrams, one point each, e.g. to a Telegraf socket_listener
+    Udp(UdpSocket),
+}
+
+impl Output {
+    pub async fn new(cmd: &InfluxRelay) -> Result<Self> {
+        Ok(match cmd.output {
+            InfluxOutput::Influx2 => {
+                let (Some(org), Some(bucket), Some(token)) = (&cmd.org, &cmd.bucket, &cmd.token)
+                else {
+                    bail!("The influx2 output requires --org, --bucket and --token");
+                };
+                Output::Influx2 {
+                    client: Client::new(&cmd.host, org, token),
+                    bucket: bucket.clone(),
+                }
+            }
+            InfluxOutput::Influx1 => {
+                let Some(database) = &cmd.database else {
+                    bail!("The influx1 output requires --database");
+                };
+                let mut url = reqwest::Url::parse(&cmd.host)
+                    .map_err(|e| anyhow!("Invalid host '{}'. {e}", cmd.host))?
+                    .join("write")?;
+                url.query_pairs_mut()
+                    .append_pair("db", database)
+                    .append_pair("precision", "ns");
+                if let Some(rp) = &cmd.retention_policy {
+                    url.query_pairs_mut().append_pair("rp", rp);
+                }
+                Output::Influx1 {
+                    client: reqwest::Client::new(),
+                    url,
+                    credentials: cmd.username.clone().zip(cmd.password.clone()),
+                }
+            }
+            InfluxOutput::LineProtocol => {
+                if cmd.destination == "-" {
+                    Output::Stdout
+                } else if let Some(addr) = cmd.destination.strip_prefix(UDP_SCHEME) {
+                    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                    socket
+                        .connect(addr)
+                        .await
+                        .map_err(|e| anyhow!("Invalid UDP destination '{addr}'. {e}"))?;
+                    Output::Udp(socket)
+                } else {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&cmd.destination)
+                        .map_err(|e| anyhow!("Failed to open '{}'. {e}", cmd.destination))?;
+                    Output::File(file)
+                }
+            }
+        })
+    }
+
+    pub async fn write(&mut self, points: Vec<DataPoint>) -> Result<()> {
+        match self {
+            Output::Influx2 { client, bucket } => {
+                client.write(bucket, stream::iter(points)).await?;
+            }
+            Output::Influx1 {
+                client,
+                url,
+                credentials,
+            } => {
+                let mut req = client.post(url.clone()).body(line_protocol(&points)?);
+                if let Some((username, password)) = credentials {
+                    req = req.basic_auth(username, Some(password));
+                }
+                let resp = req.send().await?;
+                let status = resp.status();
+                if !status.is_success() {
+                    let body = resp.text().await.unwrap_or_default();
+                    bail!("Write failed with status {status}. {}", body.trim());
+                }
+            }
+            Output::Stdout => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&line_protocol(&points)?)?;
+                stdout.flush()?;
+            }
+            Output::File(file) => {
+                file.write_all(&line_protocol(&points)?)?;
+            }
+            Output::Udp(socket) => {
+                for p in points.iter() {
+                    socket
+                        .send(&line_protocol(std::slice::from_ref(p))?)
+                        .await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Each point is terminated by a newline
+fn line_protocol(points: &[DataPoint]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for p in points.iter() {
+        p.write_data_point_to(&mut buf)?;
+    }
+    Ok(buf)
+}
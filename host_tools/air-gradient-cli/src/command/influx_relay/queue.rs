@@ -0,0 +1,383 @@
+use crate::measurement::Measurement;
+use anyhow::Result;
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Written or dropped points kept at the start of the spool file before it's compacted
+const MIN_STALE_LINES: usize = 1000;
+
+/// Points waiting to be written, oldest first.
+///
+/// With a spool file every queued point is also appended to the file as a line of JSON.
+/// The lines of written and dropped points stay at the start of the file until they
+/// outnumber the queued ones (and MIN_STALE_LINES), then the file is rewritten with only
+/// the queued points, so it stays bounded while points are dropped during an outage. It's emptied whenever the queue is, and compacted on exit.
+/// After a crash the stale lines are loaded and written again.
+#[derive(Debug)]
+pub struct Queue {
+    points: VecDeque<Measurement>,
+    max_len: usize,
+    spool: Option<Spool>,
+    dropped: u64,
+}
+
+impl Queue {
+    /// Creates the queue, loading the points left in the spool file by a previous run
+    pub fn new(max_len: usize, spool_path: Option<&Path>) -> Result<Self> {
+        let mut queue = Queue {
+            points: VecDeque::new(),
+            max_len,
+            spool: None,
+            dropped: 0,
+        };
+
+        if let Some(path) = spool_path {
+            if path.exists() {
+                for m in Spool::load(path)? {
+                    queue.push_in_memory(m);
+                }
+                if !queue.points.is_empty() {
+                    tracing::info!(
+                        spool_file = %path.display(),
+                        points = queue.points.len(),
+                        dropped = queue.dropped,
+                        "Loaded queued points from the spool file"
+                    );
+                }
+            }
+            let mut spool = Spool::new(path)?;
+            spool.rewrite(&queue.points)?;
+            queue.spool = Some(spool);
+        }
+
+        Ok(queue)
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Number of points dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    pub fn push(&mut self, m: Measurement) -> Result<()> {
+        if let Some(spool) = self.spool.as_mut() {
+            spool.append(&m)?;
+        }
+        self.push_in_memory(m);
+        self.rewrite_spool_if_stale()
+    }
+
+    /// The oldest `n` points
+    pub fn front(&self, n: usize) -> impl Iterator<Item = &Measurement> {
+        self.points.iter().take(n)
+    }
+
+    /// Removes the oldest `n` points once they've been written
+    pub fn remove_front(&mut self, n: usize) -> Result<()> {
+        let n = n.min(self.points.len());
+        self.points.drain(..n);
+        if let Some(spool) = self.spool.as_mut() {
+            spool.stale_lines += n;
+        }
+        self.rewrite_spool_if_stale()
+    }
+
+    /// Rewrites the spool file with only the queued points
+    pub fn compact(&mut self) -> Result<()> {
+        if let Some(spool) = self.spool.as_mut() {
+            if spool.stale_lines != 0 {
+                spool.rewrite(&self.points)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn rewrite_spool_if_stale(&mut self) -> Result<()> {
+        if let Some(spool) = self.spool.as_mut() {
+            if self.points.is_empty() || spool.stale_lines >= MIN_STALE_LINES.max(self.points.len())
+            {
+                spool.rewrite(&self.points)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn push_in_memory(&mut self, m: Measurement) {
+        if self.points.len() >= self.max_len {
+            self.points.pop_front();
+            self.dropped += 1;
+            if let Some(spool) = self.spool.as_mut() {
+                spool.stale_lines += 1;
+            }
+        }
+        self.points.push_back(m);
+    }
+}
+
+#[derive(Debug)]
+struct Spool {
+    path: PathBuf,
+    file: File,
+    /// Lines at the start of the file whose points were written or dropped
+    stale_lines: usize,
+}
+
+impl Spool {
+    fn new(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Spool {
+            path: path.to_owned(),
+            file,
+            stale_lines: 0,
+        })
+    }
+
+    fn load(path: &Path) -> Result<Vec<Measurement>> {
+        let mut points = Vec::new();
+        let reader = BufReader::new(File::open(path)?);
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(m) => points.push(m),
+                // Most likely a partial line from a crash mid-write
+                Err(e) => tracing::warn!(
+                    spool_file = %path.display(),
+                    line = idx + 1,
+                    e = %e,
+                    "Skipping invalid point in the spool file"
+                ),
+            }
+        }
+        Ok(points)
+    }
+
+    fn append(&mut self, m: &Measurement) -> Result<()> {
+        let mut line = serde_json::to_vec(m)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Replaces the contents of the file with the given points
+    fn rewrite<'a, I>(&mut self, points: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a Measurement>,
+    {
+        self.stale_lines = 0;
+        let mut points = points.into_iter().peekable();
+        if points.peek().is_none() {
+            self.file.set_len(0)?;
+            return Ok(());
+        }
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for m in points {
+            serde_json::to_writer(&mut writer, m)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measurement::{MeasurementFields, MeasurementTags};
+    use tempfile::TempDir;
+
+    fn point(sequence_number: i64) -> Measurement {
+        Measurement {
+            recv_time_utc_ns: sequence_number * 1_000_000_000,
+            tags: MeasurementTags {
+                device_id: "1".to_owned(),
+                device_serial_number: "AABBCC".to_owned(),
+                firmware_version: "0.4.1".to_owned(),
+            },
+            fields: MeasurementFields {
+                sequence_number,
+                temperature: Some(21.5),
+                humidity: None,
+                voc_ticks: None,
+                nox_ticks: None,
+                voc_index: None,
+                nox_index: None,
+                pm25: None,
+                aqi: None,
+                aqi_level: None,
+                co2: Some(800),
+            },
+        }
+    }
+
+    fn seqnums(queue: &Queue) -> Vec<i64> {
+        queue
+            .front(usize::MAX)
+            .map(|m| m.fields.sequence_number)
+            .collect()
+    }
+
+    fn spool_lines(path: &Path) -> usize {
+        fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    fn spool_path() -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spool.jsonl");
+        (dir, path)
+    }
+
+    #[test]
+    fn without_spool_drops_oldest() {
+        let mut queue = Queue::new(2, None).unwrap();
+        for seqnum in 1..=3 {
+            queue.push(point(seqnum)).unwrap();
+        }
+        assert_eq!(seqnums(&queue), vec![2, 3]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn reloads_queued_points() {
+        let (_dir, path) = spool_path();
+        let mut queue = Queue::new(10, Some(&path)).unwrap();
+        for seqnum in 1..=3 {
+            queue.push(point(seqnum)).unwrap();
+        }
+        drop(queue);
+
+        let queue = Queue::new(10, Some(&path)).unwrap();
+        assert_eq!(seqnums(&queue), vec![1, 2, 3]);
+        assert_eq!(queue.front(1).next(), Some(&point(1)));
+    }
+
+    #[test]
+    fn skips_partial_last_line() {
+        let (_dir, path) = spool_path();
+        let mut contents = String::new();
+        for seqnum in 1..=2 {
+            contents.push_str(&serde_json::to_string(&point(seqnum)).unwrap());
+            contents.push('\n');
+        }
+        let partial = serde_json::to_string(&point(3)).unwrap();
+        contents.push_str(&partial[..partial.len() / 2]);
+        fs::write(&path, contents).unwrap();
+
+        let mut queue = Queue::new(10, Some(&path)).unwrap();
+        assert_eq!(seqnums(&queue), vec![1, 2]);
+        // The partial line is gone, new points start on a line of their own
+        assert_eq!(spool_lines(&path), 2);
+        queue.push(point(4)).unwrap();
+        drop(queue);
+
+        let queue = Queue::new(10, Some(&path)).unwrap();
+        assert_eq!(seqnums(&queue), vec![1, 2, 4]);
+    }
+
+    #[test]
+    fn drops_oldest_on_load() {
+        let (_dir, path) = spool_path();
+        let mut queue = Queue::new(10, Some(&path)).unwrap();
+        for seqnum in 1..=5 {
+            queue.push(point(seqnum)).unwrap();
+        }
+        drop(queue);
+
+        let queue = Queue::new(3, Some(&path)).unwrap();
+        assert_eq!(seqnums(&queue), vec![3, 4, 5]);
+        assert_eq!(queue.dropped(), 2);
+        assert_eq!(spool_lines(&path), 3);
+    }
+
+    #[test]
+    fn remove_front_keeps_stale_lines_until_empty() {
+        let (_dir, path) = spool_path();
+        let mut queue = Queue::new(10, Some(&path)).unwrap();
+        for seqnum in 1..=5 {
+            queue.push(point(seqnum)).unwrap();
+        }
+
+        queue.remove_front(2).unwrap();
+        assert_eq!(seqnums(&queue), vec![3, 4, 5]);
+        assert_eq!(spool_lines(&path), 5);
+
+        queue.remove_front(10).unwrap();
+        assert!(queue.is_empty());
+        assert_eq!(spool_lines(&path), 0);
+
+        queue.push(point(6)).unwrap();
+        drop(queue);
+        let queue = Queue::new(10, Some(&path)).unwrap();
+        assert_eq!(seqnums(&queue), vec![6]);
+    }
+
+    #[test]
+    fn rewrites_once_stale_lines_outnumber_queued() {
+        let (_dir, path) = spool_path();
+        let num_points = 2 * MIN_STALE_LINES + 10;
+        let mut queue = Queue::new(num_points, Some(&path)).unwrap();
+        for seqnum in 0..num_points {
+            queue.push(point(seqnum as i64)).unwrap();
+        }
+
+        queue.remove_front(MIN_STALE_LINES).unwrap();
+        assert_eq!(spool_lines(&path), num_points);
+
+        queue.remove_front(10).unwrap();
+        assert_eq!(queue.len(), MIN_STALE_LINES);
+        assert_eq!(spool_lines(&path), MIN_STALE_LINES);
+        assert_eq!(
+            queue.front(1).next().map(|m| m.fields.sequence_number),
+            Some((MIN_STALE_LINES + 10) as i64)
+        );
+    }
+
+    #[test]
+    fn dropped_points_keep_spool_bounded() {
+        let (_dir, path) = spool_path();
+        let max_len = 3;
+        let mut queue = Queue::new(max_len, Some(&path)).unwrap();
+        for seqnum in 0..(3 * MIN_STALE_LINES) {
+            queue.push(point(seqnum as i64)).unwrap();
+            assert!(spool_lines(&path) <= MIN_STALE_LINES + max_len);
+        }
+        assert_eq!(queue.dropped(), (3 * MIN_STALE_LINES - max_len) as u64);
+        drop(queue);
+
+        let queue = Queue::new(max_len, Some(&path)).unwrap();
+        let last = 3 * MIN_STALE_LINES as i64 - 1;
+        assert_eq!(seqnums(&queue), vec![last - 2, last - 1, last]);
+    }
+
+    #[test]
+    fn compact_drops_written_points() {
+        let (_dir, path) = spool_path();
+        let mut queue = Queue::new(10, Some(&path)).unwrap();
+        for seqnum in 1..=4 {
+            queue.push(point(seqnum)).unwrap();
+        }
+        queue.remove_front(1).unwrap();
+        assert_eq!(spool_lines(&path), 4);
+
+        queue.compact().unwrap();
+        assert_eq!(spool_lines(&path), 3);
+        drop(queue);
+
+        let queue = Queue::new(10, Some(&path)).unwrap();
+        assert_eq!(seqnums(&queue), vec![2, 3, 4]);
+    }
+}
@@ -1,19 +1,41 @@
-use crate::{interruptor::Interruptor, measurement::MessageExt, opts::Listen};
+use crate::{
+    datagram,
+    device_stats::{DeviceStats, Sequence},
+    interruptor::Interruptor,
+    measurement::MessageExt,
+    opts::{Listen, ListenFormat},
+};
 use anyhow::Result;
 use chrono::prelude::*;
-use std::{collections::BTreeMap, net::UdpSocket, time::Duration};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
 use wire_protocols::{
-    broadcast::{Message as WireMessage, Repr as Message, MESSAGE_LEN},
-    DeviceId, ProtocolIdentifier,
+    broadcast::{Repr as Message, MESSAGE_LEN},
+    DeviceSerialNumber, ProtocolIdentifier,
 };
 
 const TIMEOUT: Duration = Duration::from_millis(100);
 
 pub async fn listen(cmd: Listen, intr: Interruptor) -> Result<()> {
-    println!(
-        "Listening for UDP broadcast messages on {}:{}",
-        cmd.address, cmd.port
-    );
+    let text = cmd.format.is_text();
+    if text {
+        println!(
+            "Listening for UDP broadcast messages on {}:{}",
+            cmd.address, cmd.port
+        );
+    } else {
+        tracing::info!(
+            address = cmd.address,
+            port = cmd.port,
+            format = %cmd.format,
+            "Listening for UDP broadcast messages"
+        );
+    }
 
     let socket = UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
     socket.set_read_timeout(TIMEOUT.into())?;
@@ -22,7 +44,12 @@ pub async fn listen(cmd: Listen, intr: Interruptor) -> Result<()> {
 
     let mut stats = BTreeMap::new();
 
-    println!();
+    let mut csv_writer =
+        matches!(cmd.format, ListenFormat::Csv).then(|| csv::Writer::from_writer(io::stdout()));
+
+    if text {
+        println!();
+    }
     loop {
         if intr.is_set() {
             break;
@@ -34,119 +61,129 @@ pub async fn listen(cmd: Listen, intr: Interruptor) -> Result<()> {
         };
         let recv_utc: DateTime<Utc> = Utc::now();
 
-        println!("Received {bytes_recvd} bytes from {src_addr}");
-        println!("UTC: {recv_utc}");
-
-        // TODO - walk entire buffer for possible multiple messages
+        if text {
+            println!("Received {bytes_recvd} bytes from {src_addr}");
+            println!("UTC: {recv_utc}");
+        }
 
-        let wire_msg = match WireMessage::new_checked(&buf) {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("Failed to parse as broadcast wire message. {e}");
-                continue;
-            }
-        };
+        let decoded = datagram::decode(&buf[..bytes_recvd]);
+        if let Some(e) = decoded.error {
+            eprintln!("{e}");
+        }
 
-        let msg = match Message::parse(&wire_msg) {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("Failed to parse as broadcast message. {e}");
-                continue;
-            }
-        };
+        for msg in decoded.messages {
+            let device_stats = stats
+                .entry(msg.device_serial_number)
+                .or_insert_with(|| DeviceStats::new(&msg));
 
-        let device_stats = stats
-            .entry(msg.device_serial_number)
-            .or_insert(DeviceStats {
-                device_id: msg.device_id,
-                last_seqnum: msg.sequence_number,
-                total_messages: 0,
-                missed_messages: 0,
-            });
-
-        if device_stats.total_messages != 0 {
-            if msg.sequence_number == device_stats.last_seqnum {
-                eprintln!(
-                    "** Duplicate message sequence number {}",
-                    msg.sequence_number
-                );
+            match device_stats.update(&msg) {
+                Sequence::Duplicate if text => {
+                    eprintln!(
+                        "** Duplicate message sequence number {}",
+                        msg.sequence_number
+                    );
+                }
+                Sequence::Missed { expected } if text => {
+                    eprintln!(
+                        "** Missed message sequence number {} (current {})",
+                        expected, msg.sequence_number
+                    );
+                }
+                Sequence::Restarted if text => {
+                    eprintln!("** Sequence number restarted at {}", msg.sequence_number);
+                }
+                _ => (),
             }
 
-            if msg.sequence_number != (device_stats.last_seqnum + 1) {
-                eprintln!(
-                    "** Missed message sequence number {} (current {})",
-                    device_stats.last_seqnum + 1,
-                    msg.sequence_number
-                );
-                device_stats.missed_messages += 1;
+            match cmd.format {
+                ListenFormat::Text => print_message(&msg),
+                ListenFormat::Jsonl => {
+                    let record = Record::new(recv_utc, src_addr, &msg);
+                    println!("{}", serde_json::to_string(&record)?);
+                }
+                ListenFormat::Csv => {
+                    if let Some(w) = csv_writer.as_mut() {
+                        w.serialize(Record::new(recv_utc, src_addr, &msg))?;
+                        w.flush()?;
+                    }
+                }
             }
         }
+    }
 
-        println!("Protocol: {}", ProtocolIdentifier::Broadcast);
-        println!("Protocol version: {}", msg.protocol_version);
-        println!("Firmware version: {}", msg.firmware_version);
-        println!("Device ID: 0x{:X} ({})", msg.device_id, msg.device_id);
-        println!("Device serial number: {:X}", msg.device_serial_number);
-        println!("Sequence number: {}", msg.sequence_number);
-        println!("Uptime seconds: {} | {}", msg.uptime_seconds, msg.uptime());
-        println!("Status flags: 0x{:X}", msg.status_flags.0);
-        println!("  initialized: {}", msg.status_flags.initialized());
-        println!("  datetime_valid: {}", msg.status_flags.datetime_valid());
-        println!(
-            "  temperature_valid: {}",
-            msg.status_flags.temperature_valid()
-        );
-        println!("  humidity_valid: {}", msg.status_flags.humidity_valid());
-        println!("  voc_ticks_valid: {}", msg.status_flags.voc_ticks_valid());
-        println!("  nox_ticks_valid: {}", msg.status_flags.nox_ticks_valid());
-        println!("  voc_index_valid: {}", msg.status_flags.voc_index_valid());
-        println!("  nox_index_valid: {}", msg.status_flags.nox_index_valid());
-        println!("  pm2_5_valid: {}", msg.status_flags.pm2_5_valid());
-        println!("  co2_valid: {}", msg.status_flags.co2_valid());
-
-        if msg.status_flags.datetime_valid() {
-            println!("DateTime: {}", msg.datetime);
-        }
-        if msg.status_flags.temperature_valid() {
-            println!(
-                "Temperature: {} cC | {:.02} °C | {:.02} °F",
-                msg.temperature,
-                msg.temperature_c(),
-                msg.temperature_f(),
-            );
-        }
-        if msg.status_flags.humidity_valid() {
-            println!(
-                "Humidity: {} c% | {:.02} %",
-                msg.humidity,
-                msg.relative_humidity(),
-            );
-        }
-        if msg.status_flags.voc_ticks_valid() {
-            println!("VOC ticks: {}", msg.voc_ticks);
-        }
-        if msg.status_flags.nox_ticks_valid() {
-            println!("NOx ticks: {}", msg.nox_ticks);
-        }
-        if msg.status_flags.voc_index_valid() {
-            println!("VOC index: {}", msg.voc_index);
-        }
-        if msg.status_flags.nox_index_valid() {
-            println!("NOx index: {}", msg.nox_index);
-        }
-        if msg.status_flags.pm2_5_valid() {
-            println!("PM2.5: {} | {}", msg.pm2_5_atm, msg.pm2_5_us_aqi());
-        }
-        if msg.status_flags.co2_valid() {
-            println!("CO2: {}", msg.co2);
-        }
+    if text {
+        print_summary(&stats);
+    } else {
+        eprintln!("{}", serde_json::to_string_pretty(&Summary::new(&stats))?);
+    }
 
-        println!();
+    Ok(())
+}
 
-        device_stats.total_messages += 1;
-        device_stats.last_seqnum = msg.sequence_number;
+fn print_message(msg: &Message) {
+    println!("Protocol: {}", ProtocolIdentifier::Broadcast);
+    println!("Protocol version: {}", msg.protocol_version);
+    println!("Firmware version: {}", msg.firmware_version);
+    println!("Device ID: 0x{:X} ({})", msg.device_id, msg.device_id);
+    println!("Device serial number: {:X}", msg.device_serial_number);
+    println!("Sequence number: {}", msg.sequence_number);
+    println!("Uptime seconds: {} | {}", msg.uptime_seconds, msg.uptime());
+    println!("Status flags: 0x{:X}", msg.status_flags.0);
+    println!("  initialized: {}", msg.status_flags.initialized());
+    println!("  datetime_valid: {}", msg.status_flags.datetime_valid());
+    println!(
+        "  temperature_valid: {}",
+        msg.status_flags.temperature_valid()
+    );
+    println!("  humidity_valid: {}", msg.status_flags.humidity_valid());
+    println!("  voc_ticks_valid: {}", msg.status_flags.voc_ticks_valid());
+    println!("  nox_ticks_valid: {}", msg.status_flags.nox_ticks_valid());
+    println!("  voc_index_valid: {}", msg.status_flags.voc_index_valid());
+    println!("  nox_index_valid: {}", msg.status_flags.nox_index_valid());
+    println!("  pm2_5_valid: {}", msg.status_flags.pm2_5_valid());
+    println!("  co2_valid: {}", msg.status_flags.co2_valid());
+
+    if msg.status_flags.datetime_valid() {
+        println!("DateTime: {}", msg.datetime);
+    }
+    if msg.status_flags.temperature_valid() {
+        println!(
+            "Temperature: {} cC | {:.02} °C | {:.02} °F",
+            msg.temperature,
+            msg.temperature_c(),
+            msg.temperature_f(),
+        );
+    }
+    if msg.status_flags.humidity_valid() {
+        println!(
+            "Humidity: {} c% | {:.02} %",
+            msg.humidity,
+            msg.relative_humidity(),
+        );
+    }
+    if msg.status_flags.voc_ticks_valid() {
+        println!("VOC ticks: {}", msg.voc_ticks);
+    }
+    if msg.status_flags.nox_ticks_valid() {
+        println!("NOx ticks: {}", msg.nox_ticks);
+    }
+    if msg.status_flags.voc_index_valid() {
+        println!("VOC index: {}", msg.voc_index);
+    }
+    if msg.status_flags.nox_index_valid() {
+        println!("NOx index: {}", msg.nox_index);
+    }
+    if msg.status_flags.pm2_5_valid() {
+        println!("PM2.5: {} | {}", msg.pm2_5_atm, msg.pm2_5_us_aqi());
+    }
+    if msg.status_flags.co2_valid() {
+        println!("CO2: {}", msg.co2);
     }
 
+    println!();
+}
+
+fn print_summary(stats: &BTreeMap<DeviceSerialNumber, DeviceStats>) {
     let total_messages: u64 = stats.values().map(|v| v.total_messages).sum();
     let missed_messages: u64 = stats.values().map(|v| v.missed_messages).sum();
 
@@ -155,21 +192,135 @@ pub async fn listen(cmd: Listen, intr: Interruptor) -> Result<()> {
     println!("Total messages: {total_messages}");
     println!("Missed messages {missed_messages}");
     println!("Devices: {}", stats.len());
-    for (dev_sn, dev_stats) in stats.into_iter() {
+    for (dev_sn, dev_stats) in stats.iter() {
         println!("  * Device SN: {:X}", dev_sn);
         println!("    Device ID: {}", dev_stats.device_id);
         println!("    Last message seqnum: {}", dev_stats.last_seqnum);
         println!("    Total messages: {}", dev_stats.total_messages);
         println!("    Missed messages {}", dev_stats.missed_messages);
+        println!("    Duplicate messages {}", dev_stats.duplicate_messages);
     }
+}
 
-    Ok(())
+/// A flat record of a message for the csv and jsonl formats.
+/// Values are empty (csv) or null (jsonl) when their validity flag isn't set.
+#[derive(Clone, Debug, Serialize)]
+struct Record {
+    recv_time_utc: String,
+    src_addr: SocketAddr,
+    protocol_version: String,
+    firmware_version: String,
+    device_id: u16,
+    device_serial_number: String,
+    sequence_number: u32,
+    uptime_seconds: u32,
+    status_flags: u16,
+    initialized: bool,
+    datetime_valid: bool,
+    temperature_valid: bool,
+    humidity_valid: bool,
+    voc_ticks_valid: bool,
+    nox_ticks_valid: bool,
+    voc_index_valid: bool,
+    nox_index_valid: bool,
+    pm2_5_valid: bool,
+    co2_valid: bool,
+    datetime: Option<String>,
+    /// Centi-degrees Celsius
+    temperature: Option<i32>,
+    temperature_c: Option<f64>,
+    temperature_f: Option<f64>,
+    /// Centi-percent
+    humidity: Option<u16>,
+    relative_humidity: Option<f64>,
+    voc_ticks: Option<u16>,
+    nox_ticks: Option<u16>,
+    voc_index: Option<u16>,
+    nox_index: Option<u16>,
+    pm2_5_atm: Option<u16>,
+    pm2_5_aqi: Option<u32>,
+    pm2_5_aqi_level: Option<String>,
+    co2: Option<u16>,
+}
+
+impl Record {
+    fn new(recv_time_utc: DateTime<Utc>, src_addr: SocketAddr, msg: &Message) -> Self {
+        let flags = msg.status_flags;
+        let aqi = msg.valid_pm2_5_us_aqi();
+        Record {
+            recv_time_utc: recv_time_utc.to_rfc3339(),
+            src_addr,
+            protocol_version: msg.protocol_version.to_string(),
+            firmware_version: msg.firmware_version.to_string(),
+            device_id: msg.device_id.0,
+            device_serial_number: format!("{:X}", msg.device_serial_number),
+            sequence_number: msg.sequence_number,
+            uptime_seconds: msg.uptime_seconds,
+            status_flags: flags.0,
+            initialized: flags.initialized(),
+            datetime_valid: flags.datetime_valid(),
+            temperature_valid: flags.temperature_valid(),
+            humidity_valid: flags.humidity_valid(),
+            voc_ticks_valid: flags.voc_ticks_valid(),
+            nox_ticks_valid: flags.nox_ticks_valid(),
+            voc_index_valid: flags.voc_index_valid(),
+            nox_index_valid: flags.nox_index_valid(),
+            pm2_5_valid: flags.pm2_5_valid(),
+            co2_valid: flags.co2_valid(),
+            datetime: msg.valid_datetime().map(|d| d.to_string()),
+            temperature: msg.valid_temperature(),
+            temperature_c: msg.valid_temperature_c(),
+            temperature_f: msg.valid_temperature_f(),
+            humidity: msg.valid_humidity(),
+            relative_humidity: msg.valid_relative_humidity(),
+            voc_ticks: msg.valid_voc_ticks(),
+            nox_ticks: msg.valid_nox_ticks(),
+            voc_index: msg.valid_voc_index(),
+            nox_index: msg.valid_nox_index(),
+            pm2_5_atm: msg.valid_pm2_5_atm(),
+            pm2_5_aqi: aqi.as_ref().map(|a| a.aqi()),
+            pm2_5_aqi_level: aqi.as_ref().map(|a| a.level().to_string()),
+            co2: msg.valid_co2(),
+        }
+    }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
-struct DeviceStats {
-    device_id: DeviceId,
-    last_seqnum: u32,
+/// The summary printed as JSON at exit for the csv and jsonl formats
+#[derive(Clone, Debug, Serialize)]
+struct Summary {
     total_messages: u64,
     missed_messages: u64,
+    duplicate_messages: u64,
+    devices: Vec<DeviceSummary>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct DeviceSummary {
+    device_serial_number: String,
+    device_id: u16,
+    last_sequence_number: u32,
+    total_messages: u64,
+    missed_messages: u64,
+    duplicate_messages: u64,
+}
+
+impl Summary {
+    fn new(stats: &BTreeMap<DeviceSerialNumber, DeviceStats>) -> Self {
+        Summary {
+            total_messages: stats.values().map(|v| v.total_messages).sum(),
+            missed_messages: stats.values().map(|v| v.missed_messages).sum(),
+            duplicate_messages: stats.values().map(|v| v.duplicate_messages).sum(),
+            devices: stats
+                .iter()
+                .map(|(sn, s)| DeviceSummary {
+                    device_serial_number: format!("{sn:X}"),
+                    device_id: s.device_id.0,
+                    last_sequence_number: s.last_seqnum,
+                    total_messages: s.total_messages,
+                    missed_messages: s.missed_messages,
+                    duplicate_messages: s.duplicate_messages,
+                })
+                .collect(),
+        }
+    }
 }
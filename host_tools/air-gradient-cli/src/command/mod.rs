@@ -1,9 +1,31 @@
+pub mod alert;
+pub mod archive;
 pub mod device;
+pub mod discover;
 pub mod extract_archive;
+pub mod fleet;
+pub mod history;
 pub mod influx_relay;
 pub mod listen;
+pub mod mqtt_relay;
+pub mod prometheus_exporter;
+pub mod record;
+pub mod replay;
+pub mod store;
+pub mod top;
 
+pub use self::alert::alert;
+pub use self::archive::archive;
 pub use self::device::device;
+pub use self::discover::discover;
 pub use self::extract_archive::extract_archive;
+pub use self::fleet::fleet;
+pub use self::history::history;
 pub use self::influx_relay::influx_relay;
 pub use self::listen::listen;
+pub use self::mqtt_relay::mqtt_relay;
+pub use self::prometheus_exporter::prometheus_exporter;
+pub use self::record::record;
+pub use self::replay::replay;
+pub use self::store::store;
+pub use self::top::top;
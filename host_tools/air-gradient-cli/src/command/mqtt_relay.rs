@@ -0,0 +1,342 @@
+use crate::{datagram, interruptor::Interruptor, measurement::MessageExt, opts::MqttRelay};
+use anyhow::Result;
+use rumqttc::{
+    AsyncClient, Event, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, Transport,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+use wire_protocols::{
+    broadcast::{Repr as Message, MESSAGE_LEN},
+    DeviceSerialNumber, FirmwareVersion,
+};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Capacity of the client's request channel, publishes are dropped when it's full
+const REQUEST_CAPACITY: usize = 64;
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Home Assistant sensor entities: field, name, device class, unit
+const SENSORS: &[(&str, &str, Option<&str>, Option<&str>)] = &[
+    (
+        "temperature",
+        "Temperature",
+        Some("temperature"),
+        Some("°C"),
+    ),
+    ("humidity", "Humidity", Some("humidity"), Some("%")),
+    ("co2", "CO2", Some("carbon_dioxide"), Some("ppm")),
+    ("pm25", "PM2.5", Some("pm25"), Some("µg/m³")),
+    ("aqi", "AQI", Some("aqi"), None),
+    ("voc_index", "VOC index", None, None),
+    ("nox_index", "NOx index", None, None),
+];
+
+/// The JSON state published for each message, fields are null until the
+/// device reports them as valid
+#[derive(Clone, Debug, Serialize)]
+struct State {
+    device_id: u16,
+    device_serial_number: String,
+    firmware_version: String,
+    sequence_number: u32,
+    uptime_seconds: u32,
+    /// Degrees Celsius
+    temperature: Option<f64>,
+    /// Relative humidity percent
+    humidity: Option<f64>,
+    voc_ticks: Option<u16>,
+    nox_ticks: Option<u16>,
+    voc_index: Option<u16>,
+    nox_index: Option<u16>,
+    pm25: Option<u16>,
+    aqi: Option<u32>,
+    aqi_level: Option<String>,
+    co2: Option<u16>,
+}
+
+impl State {
+    fn new(msg: &Message) -> Self {
+        let aqi = msg.valid_pm2_5_us_aqi();
+        State {
+            device_id: msg.device_id.0,
+            device_serial_number: format!("{:X}", msg.device_serial_number),
+            firmware_version: msg.firmware_version.to_string(),
+            sequence_number: msg.sequence_number,
+            uptime_seconds: msg.uptime_seconds,
+            temperature: msg.valid_temperature_c(),
+            humidity: msg.valid_relative_humidity(),
+            voc_ticks: msg.valid_voc_ticks(),
+            nox_ticks: msg.valid_nox_ticks(),
+            voc_index: msg.valid_voc_index(),
+            nox_index: msg.valid_nox_index(),
+            pm25: msg.valid_pm2_5_atm(),
+            aqi: aqi.as_ref().map(|a| a.aqi()),
+            aqi_level: aqi.as_ref().map(|a| a.level().to_string()),
+            co2: msg.valid_co2(),
+        }
+    }
+
+    /// The value of each field that has one, for the per-field topics
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("sequence_number", self.sequence_number.to_string()),
+            ("uptime_seconds", self.uptime_seconds.to_string()),
+        ];
+        let optional = [
+            ("temperature", self.temperature.map(|v| format!("{v:.2}"))),
+            ("humidity", self.humidity.map(|v| format!("{v:.2}"))),
+            ("voc_ticks", self.voc_ticks.map(|v| v.to_string())),
+            ("nox_ticks", self.nox_ticks.map(|v| v.to_string())),
+            ("voc_index", self.voc_index.map(|v| v.to_string())),
+            ("nox_index", self.nox_index.map(|v| v.to_string())),
+            ("pm25", self.pm25.map(|v| v.to_string())),
+            ("aqi", self.aqi.map(|v| v.to_string())),
+            ("aqi_level", self.aqi_level.clone()),
+            ("co2", self.co2.map(|v| v.to_string())),
+        ];
+        fields.extend(
+            optional
+                .into_iter()
+                .filter_map(|(name, v)| v.map(|v| (name, v))),
+        );
+        fields
+    }
+}
+
+pub async fn mqtt_relay(cmd: MqttRelay, intr: Interruptor) -> Result<()> {
+    tracing::info!(
+        address = cmd.address,
+        port = cmd.port,
+        host = cmd.host,
+        broker_port = cmd.broker_port,
+        tls = cmd.tls,
+        "Relaying UDP broadcast messages to MQTT",
+    );
+
+    let status_topic = format!("{}/status", cmd.topic_prefix);
+
+    let mut opts = MqttOptions::new(&cmd.client_id, &cmd.host, cmd.broker_port);
+    opts.set_keep_alive(KEEP_ALIVE);
+    opts.set_last_will(LastWill::new(
+        &status_topic,
+        OFFLINE,
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let (Some(username), Some(password)) = (&cmd.username, &cmd.password) {
+        opts.set_credentials(username, password);
+    }
+    if cmd.tls {
+        opts.set_transport(tls_transport(&cmd)?);
+    }
+
+    let s = std::net::UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
+    s.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(s)?;
+
+    let (client, eventloop) = AsyncClient::new(opts, REQUEST_CAPACITY);
+    let connected = Arc::new(AtomicBool::new(false));
+    let eventloop_handle = tokio::spawn(poll_eventloop(
+        eventloop,
+        client.clone(),
+        status_topic.clone(),
+        connected.clone(),
+    ));
+
+    let mut buf = vec![0; MESSAGE_LEN * 10];
+
+    // Firmware version each device was last announced with
+    let mut announced: BTreeMap<DeviceSerialNumber, FirmwareVersion> = BTreeMap::new();
+
+    while !intr.is_set() {
+        let (bytes_recvd, src_addr) =
+            match tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await {
+                Ok(res) => res?,
+                Err(_) => continue,
+            };
+
+        tracing::debug!(
+            src = %src_addr,
+            bytes_recvd = bytes_recvd,
+            "Received message data"
+        );
+
+        let decoded = datagram::decode(&buf[..bytes_recvd]);
+        if let Some(e) = decoded.error {
+            tracing::error!(src = %src_addr, e = %e, "Failed to decode datagram");
+        }
+
+        for msg in decoded.messages {
+            // Announce again after (re)connecting in case the broker lost the retained configs
+            if connected.swap(false, SeqCst) {
+                announced.clear();
+            }
+
+            let state = State::new(&msg);
+            let device_topic = format!("{}/{}", cmd.topic_prefix, state.device_serial_number);
+
+            if !cmd.no_discovery
+                && announced.get(&msg.device_serial_number) != Some(&msg.firmware_version)
+            {
+                let mut ok = true;
+                for (topic, config) in discovery_configs(&cmd, &state, &device_topic, &status_topic)
+                {
+                    ok &= publish(&client, topic, true, config.to_string());
+                }
+                if ok {
+                    announced.insert(msg.device_serial_number, msg.firmware_version);
+                }
+            }
+
+            publish(
+                &client,
+                format!("{device_topic}/state"),
+                false,
+                serde_json::to_string(&state)?,
+            );
+            for (field, value) in state.fields() {
+                publish(&client, format!("{device_topic}/{field}"), false, value);
+            }
+        }
+    }
+
+    tracing::debug!("Exiting relay loop");
+
+    publish(&client, status_topic, true, OFFLINE.to_owned());
+    if let Err(e) = client.try_disconnect() {
+        tracing::warn!(e = %e, "Failed to disconnect");
+    }
+    if tokio::time::timeout(DISCONNECT_TIMEOUT, eventloop_handle)
+        .await
+        .is_err()
+    {
+        tracing::warn!("Timed out disconnecting from the broker");
+    }
+
+    Ok(())
+}
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+fn tls_transport(cmd: &MqttRelay) -> Result<Transport> {
+    use rumqttc::TlsConfiguration;
+
+    let ca = cmd.ca_file.as_ref().map(std::fs::read).transpose()?;
+
+    #[cfg(feature = "native-tls")]
+    let config = match ca {
+        Some(ca) => TlsConfiguration::SimpleNative {
+            ca,
+            client_auth: None,
+        },
+        None => TlsConfiguration::Native,
+    };
+
+    #[cfg(not(feature = "native-tls"))]
+    let config = match ca {
+        Some(ca) => TlsConfiguration::Simple {
+            ca,
+            alpn: None,
+            client_auth: None,
+        },
+        None => TlsConfiguration::default(),
+    };
+
+    Ok(Transport::tls_with_config(config))
+}
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+fn tls_transport(_cmd: &MqttRelay) -> Result<Transport> {
+    anyhow::bail!("TLS support requires the native-tls or rustls feature")
+}
+
+/// Home Assistant MQTT discovery config topic and payload of each sensor entity
+fn discovery_configs(
+    cmd: &MqttRelay,
+    state: &State,
+    device_topic: &str,
+    status_topic: &str,
+) -> Vec<(String, serde_json::Value)> {
+    let object_id = format!("air_gradient_{}", state.device_serial_number);
+    SENSORS
+        .iter()
+        .map(|(field, name, device_class, unit)| {
+            let mut config = json!({
+                "name": name,
+                "unique_id": format!("{object_id}_{field}"),
+                "object_id": format!("{object_id}_{field}"),
+                "state_topic": format!("{device_topic}/state"),
+                "value_template": format!("{{{{ value_json.{field} }}}}"),
+                "state_class": "measurement",
+                "availability_topic": status_topic,
+                "device": {
+                    "identifiers": [object_id],
+                    "name": format!("AirGradient {}", state.device_id),
+                    "manufacturer": "AirGradient",
+                    "model": "Pro",
+                    "serial_number": state.device_serial_number,
+                    "sw_version": state.firmware_version,
+                },
+            });
+            if let Some(device_class) = device_class {
+                config["device_class"] = json!(device_class);
+            }
+            if let Some(unit) = unit {
+                config["unit_of_measurement"] = json!(unit);
+            }
+            let topic = format!("{}/sensor/{object_id}/{field}/config", cmd.discovery_prefix);
+            (topic, config)
+        })
+        .collect()
+}
+
+/// Queues a publish without waiting on the broker, returns false when it was dropped
+fn publish(client: &AsyncClient, topic: String, retain: bool, payload: String) -> bool {
+    match client.try_publish(&topic, QoS::AtLeastOnce, retain, payload) {
+        Ok(()) => true,
+        Err(e) => {
+            tracing::warn!(topic = topic, e = %e, "Failed to publish");
+            false
+        }
+    }
+}
+
+async fn poll_eventloop(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    status_topic: String,
+    connected: Arc<AtomicBool>,
+) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to the broker");
+                publish(&client, status_topic.clone(), true, ONLINE.to_owned());
+                connected.store(true, SeqCst);
+            }
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(event) => tracing::trace!(event = ?event, "MQTT event"),
+            Err(e) => {
+                tracing::error!(e = %e, "MQTT connection error");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use crate::{
+    datagram,
+    device_stats::{DeviceStats, Sequence},
+    interruptor::Interruptor,
+    measurement::MessageExt,
+    opts::PrometheusExporter,
+};
+use anyhow::{bail, Result};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+use wire_protocols::{
+    broadcast::{Repr as Message, MESSAGE_LEN},
+    DeviceSerialNumber,
+};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// The Prometheus text exposition format
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+const INDEX: &str = "<html><body><a href=\"/metrics\">Metrics</a></body></html>\n";
+
+type Getter = fn(&DeviceState) -> Option<f64>;
+
+/// Per-device gauges, a device only has a sample when the value is valid
+const GAUGES: &[(&str, &str, Getter)] = &[
+    (
+        "air_gradient_temperature_celsius",
+        "Temperature in degrees Celsius",
+        |d| d.msg.valid_temperature_c(),
+    ),
+    (
+        "air_gradient_relative_humidity_percent",
+        "Relative humidity in percent",
+        |d| d.msg.valid_relative_humidity(),
+    ),
+    ("air_gradient_voc_index", "VOC gas index", |d| {
+        d.msg.valid_voc_index().map(f64::from)
+    }),
+    ("air_gradient_nox_index", "NOx gas index", |d| {
+        d.msg.valid_nox_index().map(f64::from)
+    }),
+    ("air_gradient_voc_ticks", "Raw VOC sensor ticks", |d| {
+        d.msg.valid_voc_ticks().map(f64::from)
+    }),
+    ("air_gradient_nox_ticks", "Raw NOx sensor ticks", |d| {
+        d.msg.valid_nox_ticks().map(f64::from)
+    }),
+    (
+        "air_gradient_pm2_5_ug_per_m3",
+        "PM2.5 concentration in μg/m³ (atmospheric environment)",
+        |d| d.msg.valid_pm2_5_atm().map(f64::from),
+    ),
+    (
+        "air_gradient_pm2_5_aqi",
+        "US AQI of the PM2.5 concentration",
+        |d| d.msg.valid_pm2_5_us_aqi().map(|a| a.aqi().into()),
+    ),
+    ("air_gradient_co2_ppm", "CO2 concentration in ppm", |d| {
+        d.msg.valid_co2().map(f64::from)
+    }),
+    (
+        "air_gradient_uptime_seconds",
+        "Device uptime in seconds",
+        |d| Some(d.msg.uptime_seconds.into()),
+    ),
+    (
+        "air_gradient_sequence_number",
+        "Sequence number of the last broadcast message",
+        |d| Some(d.msg.sequence_number.into()),
+    ),
+];
+
+/// Per-device counters, reset when a device goes silent and its series are dropped
+const COUNTERS: &[(&str, &str, Getter)] = &[
+    (
+        "air_gradient_messages_total",
+        "Broadcast messages received",
+        |d| Some(d.stats.total_messages as f64),
+    ),
+    (
+        "air_gradient_missed_messages_total",
+        "Broadcast messages missed, from gaps in the sequence numbers",
+        |d| Some(d.stats.missed_messages as f64),
+    ),
+    (
+        "air_gradient_duplicate_messages_total",
+        "Broadcast messages received with a duplicate sequence number",
+        |d| Some(d.stats.duplicate_messages as f64),
+    ),
+];
+
+pub async fn prometheus_exporter(cmd: PrometheusExporter, intr: Interruptor) -> Result<()> {
+    tracing::info!(
+        address = cmd.address,
+        port = cmd.port,
+        listen_address = cmd.listen_address,
+        "Exporting UDP broadcast messages as Prometheus metrics",
+    );
+
+    let s = std::net::UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
+    s.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(s)?;
+
+    let listener = TcpListener::bind(cmd.listen_address.as_str()).await?;
+
+    let devices = Arc::new(Mutex::new(Devices::new(cmd.stale_after)));
+    let server = tokio::spawn(serve(listener, devices.clone()));
+
+    let mut buf = vec![0; MESSAGE_LEN * 10];
+
+    while !intr.is_set() {
+        let (bytes_recvd, src_addr) =
+            match tokio::time::timeout(TIMEOUT, socket.recv_from(&mut buf)).await {
+                Ok(res) => res?,
+                Err(_) => continue,
+            };
+
+        tracing::debug!(
+            src = %src_addr,
+            bytes_recvd = bytes_recvd,
+            "Received message data"
+        );
+
+        let decoded = datagram::decode(&buf[..bytes_recvd]);
+        if let Some(e) = decoded.error {
+            tracing::error!(src = %src_addr, e = %e, "Failed to decode datagram");
+        }
+
+        for msg in decoded.messages {
+            devices.lock().unwrap().update(msg, Instant::now());
+        }
+    }
+
+    tracing::debug!("Exiting exporter loop");
+    server.abort();
+
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+struct DeviceState {
+    last_seen: Instant,
+    /// The last message received
+    msg: Message,
+    stats: DeviceStats,
+}
+
+#[derive(Debug)]
+struct Devices {
+    stale_after: Duration,
+    devices: BTreeMap<DeviceSerialNumber, DeviceState>,
+}
+
+impl Devices {
+    fn new(stale_after: Duration) -> Self {
+        Self {
+            stale_after,
+            devices: BTreeMap::new(),
+        }
+    }
+
+    fn update(&mut self, msg: Message, now: Instant) {
+        let device = self
+            .devices
+            .entry(msg.device_serial_number)
+            .or_insert_with(|| DeviceState {
+                last_seen: now,
+                msg,
+                stats: DeviceStats::new(&msg),
+            });
+
+        let last_seqnum = device.stats.last_seqnum;
+        if device.stats.update(&msg) == Sequence::Restarted {
+            tracing::debug!(
+                device_serial_number = %format!("{:X}", msg.device_serial_number),
+                sequence_number = msg.sequence_number,
+                last_seqnum,
+                "Sequence number went backwards"
+            );
+        }
+
+        device.last_seen = now;
+        device.msg = msg;
+    }
+
+    /// Drops the devices that went silent and renders the rest in the text exposition format
+    fn render(&mut self, now: Instant) -> String {
+        let stale_after = self.stale_after;
+        self.devices.retain(|sn, d| {
+            let stale = now.saturating_duration_since(d.last_seen) > stale_after;
+            if stale {
+                tracing::info!(
+                    device_serial_number = %format!("{sn:X}"),
+                    "Dropping series of silent device"
+                );
+            }
+            !stale
+        });
+
+        let mut out = String::new();
+        let families = GAUGES
+            .iter()
+            .map(|m| (m, "gauge"))
+            .chain(COUNTERS.iter().map(|m| (m, "counter")));
+        for ((name, help, get), metric_type) in families {
+            let samples: Vec<(&DeviceState, f64)> = self
+                .devices
+                .values()
+                .filter_map(|d| get(d).map(|v| (d, v)))
+                .collect();
+            if samples.is_empty() {
+                continue;
+            }
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {metric_type}").unwrap();
+            for (d, value) in samples.into_iter() {
+                writeln!(
+                    out,
+                    "{name}{{device_id=\"{}\",device_serial_number=\"{:X}\",firmware_version=\"{}\"}} {value}",
+                    d.msg.device_id, d.msg.device_serial_number, d.msg.firmware_version
+                )
+                .unwrap();
+            }
+        }
+        out
+    }
+}
+
+async fn serve(listener: TcpListener, devices: Arc<Mutex<Devices>>) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::warn!(e = %e, "Failed to accept connection");
+                continue;
+            }
+        };
+        let devices = devices.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HTTP_TIMEOUT, handle_request(stream, &devices)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => tracing::warn!(peer = %peer, e = %e, "Failed to handle request"),
+                Err(_) => tracing::warn!(peer = %peer, "Timed out handling request"),
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, devices: &Mutex<Devices>) -> Result<()> {
+    let mut req = Vec::new();
+    let mut buf = [0; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+        let bytes_read = stream.read(&mut buf).await?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        req.extend_from_slice(&buf[..bytes_read]);
+        if req.len() > MAX_REQUEST_LEN {
+            bail!("Request too large");
+        }
+    }
+
+    let req = String::from_utf8_lossy(&req);
+    let mut request_line = req.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line
+        .next()
+        .unwrap_or_default()
+        .split('?')
+        .next()
+        .unwrap_or_default();
+    tracing::debug!(method, path, "HTTP request");
+
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/metrics") => (
+            "200 OK",
+            METRICS_CONTENT_TYPE,
+            devices.lock().unwrap().render(Instant::now()),
+        ),
+        ("GET", "/") => ("200 OK", "text/html", INDEX.to_owned()),
+        ("GET", _) => ("404 Not Found", "text/plain", "Not found\n".to_owned()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n".to_owned(),
+        ),
+    };
+
+    let header = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(header.as_bytes()).await?;
+    stream.write_all(body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datagram::tests::repr;
+
+    const STALE_AFTER: Duration = Duration::from_secs(60);
+
+    fn sample<'a>(metrics: &'a str, name: &str, serial_number: &str) -> Option<&'a str> {
+        let labels = format!("device_serial_number=\"{serial_number}\"");
+        metrics
+            .lines()
+            .find(|l| l.starts_with(&format!("{name}{{")) && l.contains(&labels))
+            .and_then(|l| l.rsplit(' ').next())
+    }
+
+    #[test]
+    fn counts_gaps_and_duplicates() {
+        let now = Instant::now();
+        let mut devices = Devices::new(STALE_AFTER);
+        for seqnum in [1, 2, 2, 6, 7] {
+            devices.update(repr(seqnum), now);
+        }
+        // Restarted, nothing missed
+        devices.update(repr(1), now);
+
+        let msg = repr(0);
+        let stats = devices.devices[&msg.device_serial_number].stats;
+        assert_eq!(stats.total_messages, 6);
+        assert_eq!(stats.missed_messages, 3);
+        assert_eq!(stats.duplicate_messages, 1);
+
+        let sn = format!("{:X}", msg.device_serial_number);
+        let metrics = devices.render(now);
+        assert!(metrics.contains("# TYPE air_gradient_messages_total counter\n"));
+        assert!(metrics.contains("# TYPE air_gradient_sequence_number gauge\n"));
+        assert_eq!(
+            sample(&metrics, "air_gradient_messages_total", &sn),
+            Some("6")
+        );
+        assert_eq!(
+            sample(&metrics, "air_gradient_missed_messages_total", &sn),
+            Some("3")
+        );
+        assert_eq!(
+            sample(&metrics, "air_gradient_duplicate_messages_total", &sn),
+            Some("1")
+        );
+        assert_eq!(
+            sample(&metrics, "air_gradient_sequence_number", &sn),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn drops_stale_series() {
+        let start = Instant::now();
+        let mut devices = Devices::new(STALE_AFTER);
+        let silent = repr(1);
+        let mut active = repr(1);
+        active.device_serial_number.word0 += 1;
+        let silent_sn = format!("{:X}", silent.device_serial_number);
+        let active_sn = format!("{:X}", active.device_serial_number);
+
+        devices.update(silent, start);
+        devices.update(active, start);
+        let metrics = devices.render(start);
+        assert!(sample(&metrics, "air_gradient_messages_total", &silent_sn).is_some());
+        assert!(sample(&metrics, "air_gradient_messages_total", &active_sn).is_some());
+
+        let now = start + STALE_AFTER + Duration::from_secs(1);
+        active.sequence_number = 2;
+        devices.update(active, now);
+        let metrics = devices.render(now);
+        assert_eq!(
+            sample(&metrics, "air_gradient_messages_total", &silent_sn),
+            None
+        );
+        assert_eq!(
+            sample(&metrics, "air_gradient_messages_total", &active_sn),
+            Some("2")
+        );
+        assert_eq!(devices.devices.len(), 1);
+
+        // The counters start over when the device comes back
+        let mut msg = silent;
+        msg.sequence_number = 10;
+        devices.update(msg, now);
+        let metrics = devices.render(now);
+        assert_eq!(
+            sample(&metrics, "air_gradient_messages_total", &silent_sn),
+            Some("1")
+        );
+        assert_eq!(
+            sample(&metrics, "air_gradient_missed_messages_total", &silent_sn),
+            Some("0")
+        );
+    }
+
+    #[test]
+    fn no_devices() {
+        let mut devices = Devices::new(STALE_AFTER);
+        assert_eq!(devices.render(Instant::now()), "");
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{
+    capture::{self, Record},
+    datagram,
+    interruptor::Interruptor,
+    opts::Record as RecordOps,
+};
+use anyhow::{bail, Result};
+use chrono::prelude::*;
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::BufWriter,
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+use wire_protocols::broadcast::MESSAGE_LEN;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+pub async fn record(cmd: RecordOps, intr: Interruptor) -> Result<()> {
+    if cmd.capture_file.exists() && !cmd.force {
+        bail!(
+            "Capture file '{}' already exists, use --force to overwrite it",
+            cmd.capture_file.display()
+        );
+    }
+
+    println!(
+        "Recording UDP broadcast messages on {}:{} to '{}'",
+        cmd.address,
+        cmd.port,
+        cmd.capture_file.display()
+    );
+
+    let socket = UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
+    socket.set_read_timeout(TIMEOUT.into())?;
+
+    let mut writer = capture::Writer::new(BufWriter::new(File::create(&cmd.capture_file)?))?;
+
+    let mut buf = vec![0; MESSAGE_LEN * 10];
+    let mut datagrams: u64 = 0;
+    let mut messages: u64 = 0;
+    let mut sources = BTreeSet::new();
+
+    let start = Instant::now();
+    loop {
+        if intr.is_set() {
+            break;
+        }
+        if let Some(d) = cmd.duration {
+            if start.elapsed() >= d {
+                break;
+            }
+        }
+        if let Some(n) = cmd.count {
+            if datagrams >= n {
+                break;
+            }
+        }
+
+        let (bytes_recvd, src_addr) = match socket.recv_from(&mut buf) {
+            Ok(ret) => ret,
+            Err(_e) => continue,
+        };
+        let recv_utc: DateTime<Utc> = Utc::now();
+
+        let data = &buf[..bytes_recvd];
+        let num_msgs = datagram::decode(data).messages.len();
+        tracing::debug!(
+            src = %src_addr,
+            bytes_recvd = bytes_recvd,
+            num_msgs,
+            "Recording datagram"
+        );
+
+        // Everything is recorded, invalid datagrams are useful to reproduce bugs too
+        writer.write(&Record {
+            recv_time_utc: recv_utc,
+            src_addr,
+            data: data.to_vec(),
+        })?;
+
+        datagrams += 1;
+        messages += num_msgs as u64;
+        sources.insert(src_addr.ip());
+    }
+
+    println!(
+        "Recorded {datagrams} datagrams ({messages} broadcast messages) from {} sources in {}",
+        sources.len(),
+        humantime::format_duration(Duration::from_secs(start.elapsed().as_secs()))
+    );
+
+    Ok(())
+}
@@ -0,0 +1,87 @@
+use crate::{capture, interruptor::Interruptor, opts::Replay};
+use anyhow::{anyhow, Result};
+use std::{
+    fs::File,
+    io::BufReader,
+    time::{Duration, Instant},
+};
+use tokio::net::UdpSocket;
+
+/// Sleep in short steps so the interruptor is noticed during long gaps
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+
+pub async fn replay(cmd: Replay, intr: Interruptor) -> Result<()> {
+    let mut reader = capture::Reader::new(BufReader::new(File::open(&cmd.capture_file)?))?;
+
+    let speed = if cmd.speed > 0.0 {
+        format!("at {}x speed", cmd.speed)
+    } else {
+        "without delays".to_owned()
+    };
+    println!(
+        "Replaying '{}' to {}:{} {speed}",
+        cmd.capture_file.display(),
+        cmd.address,
+        cmd.port,
+    );
+
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.set_broadcast(cmd.broadcast)?;
+    // Not connected, so nothing listening on the destination isn't an error
+    let dest = tokio::net::lookup_host((cmd.address.as_str(), cmd.port))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow!("Failed to resolve '{}'", cmd.address))?;
+
+    let mut datagrams: u64 = 0;
+    let start = Instant::now();
+    let mut first_recv_time = None;
+    // A corrupt record stops the replay, the datagrams sent so far are still reported
+    let mut res = Ok(());
+
+    while !intr.is_set() {
+        let record = match reader.next_record() {
+            Ok(Some(r)) => r,
+            Ok(None) => break,
+            Err(e) => {
+                res = Err(e);
+                break;
+            }
+        };
+
+        let first = *first_recv_time.get_or_insert(record.recv_time_utc);
+        if cmd.speed > 0.0 {
+            let offset = (record.recv_time_utc - first)
+                .to_std()
+                .unwrap_or_default()
+                .div_f64(cmd.speed);
+            let deadline = start + offset;
+            while !intr.is_set() {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                tokio::time::sleep(remaining.min(MAX_SLEEP)).await;
+            }
+            if intr.is_set() {
+                break;
+            }
+        }
+
+        tracing::debug!(
+            src = %record.src_addr,
+            recv_time_utc = %record.recv_time_utc,
+            len = record.data.len(),
+            "Sending datagram"
+        );
+        socket.send_to(&record.data, dest).await?;
+        datagrams += 1;
+    }
+
+    println!(
+        "Sent {datagrams} datagrams in {}",
+        humantime::format_duration(Duration::from_millis(start.elapsed().as_millis() as u64))
+    );
+
+    res
+}
@@ -0,0 +1,91 @@
+use crate::{database::Database, datagram, interruptor::Interruptor, opts::Store};
+use anyhow::{bail, Result};
+use chrono::prelude::*;
+use std::{
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+use wire_protocols::broadcast::MESSAGE_LEN;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How often the retention policies are applied
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+pub async fn store(cmd: Store, intr: Interruptor) -> Result<()> {
+    if let Some(retention) = cmd.retention {
+        if retention <= cmd.raw_retention {
+            bail!("--retention must be longer than --raw-retention");
+        }
+    }
+
+    tracing::info!(
+        address = cmd.address,
+        port = cmd.port,
+        database = %cmd.database.display(),
+        "Storing UDP broadcast messages",
+    );
+
+    let mut db = Database::open(&cmd.database)?;
+
+    let socket = UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
+    socket.set_read_timeout(TIMEOUT.into())?;
+
+    let mut buf = vec![0; MESSAGE_LEN * 10];
+    let mut messages: u64 = 0;
+    let mut last_maintenance: Option<Instant> = None;
+
+    while !intr.is_set() {
+        if last_maintenance
+            .map(|t| t.elapsed() >= MAINTENANCE_INTERVAL)
+            .unwrap_or(true)
+        {
+            if let Err(e) = apply_retention(&mut db, &cmd) {
+                tracing::error!(e = %e, "Failed to apply the retention policies");
+            }
+            last_maintenance = Some(Instant::now());
+        }
+
+        let (bytes_recvd, src_addr) = match socket.recv_from(&mut buf) {
+            Ok(ret) => ret,
+            Err(_e) => continue,
+        };
+        let recv_utc: DateTime<Utc> = Utc::now();
+
+        tracing::debug!(
+            src = %src_addr,
+            bytes_recvd = bytes_recvd,
+            "Received message data"
+        );
+
+        let decoded = datagram::decode(&buf[..bytes_recvd]);
+        if let Some(e) = decoded.error {
+            tracing::error!(src = %src_addr, e = %e, "Failed to decode datagram");
+        }
+
+        for msg in decoded.messages {
+            match db.insert(recv_utc, src_addr, &msg) {
+                Ok(()) => messages += 1,
+                Err(e) => tracing::error!(e = %e, "Failed to store message"),
+            }
+        }
+    }
+
+    tracing::info!(messages, "Stopped storing messages");
+
+    Ok(())
+}
+
+fn apply_retention(db: &mut Database, cmd: &Store) -> Result<()> {
+    let now = Utc::now();
+    let downsampled = db.downsample(
+        now - chrono::Duration::from_std(cmd.raw_retention)?,
+        cmd.downsample_interval,
+    )?;
+    let deleted = match cmd.retention {
+        Some(retention) => db.delete_samples(now - chrono::Duration::from_std(retention)?)?,
+        None => 0,
+    };
+    tracing::debug!(downsampled, deleted, "Applied the retention policies");
+    Ok(())
+}
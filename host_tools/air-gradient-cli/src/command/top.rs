@@ -0,0 +1,359 @@
+use crate::{
+    datagram, device_stats::DeviceStats, interruptor::Interruptor, measurement::MessageExt,
+    opts::Top,
+};
+use anyhow::Result;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Cell, Paragraph, Row, Sparkline, Table, TableState},
+    DefaultTerminal, Frame,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::UdpSocket,
+    time::{Duration, Instant},
+};
+use wire_protocols::{
+    broadcast::{Repr as Message, MESSAGE_LEN},
+    DeviceSerialNumber,
+};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Redraw at least this often so the last seen times stay current
+const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of recent messages kept per device for the sparklines
+const HISTORY_LEN: usize = 512;
+
+pub async fn top(cmd: Top, intr: Interruptor) -> Result<()> {
+    let socket = UdpSocket::bind((cmd.address.as_str(), cmd.port))?;
+    socket.set_read_timeout(TIMEOUT.into())?;
+
+    let mut app = App {
+        listen_address: format!("{}:{}", cmd.address, cmd.port),
+        devices: BTreeMap::new(),
+        table_state: TableState::default(),
+        total_messages: 0,
+        invalid_datagrams: 0,
+    };
+
+    let mut terminal = ratatui::init();
+    let res = run(&socket, &intr, &mut terminal, &mut app);
+    ratatui::restore();
+    res
+}
+
+fn run(
+    socket: &UdpSocket,
+    intr: &Interruptor,
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+) -> Result<()> {
+    let mut buf = vec![0; MESSAGE_LEN * 10];
+    let mut last_draw: Option<Instant> = None;
+    let mut dirty = true;
+
+    // The terminal is in raw mode, control-c arrives as a key event
+    while !intr.is_set() {
+        while event::poll(Duration::ZERO)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        return Ok(())
+                    }
+                    KeyCode::Down | KeyCode::Char('j') => app.select_next(),
+                    KeyCode::Up | KeyCode::Char('k') => app.select_previous(),
+                    _ => (),
+                }
+            }
+            dirty = true;
+        }
+
+        if dirty
+            || last_draw
+                .map(|t| t.elapsed() >= REDRAW_INTERVAL)
+                .unwrap_or(true)
+        {
+            terminal.draw(|frame| draw(frame, app))?;
+            last_draw = Some(Instant::now());
+            dirty = false;
+        }
+
+        let (bytes_recvd, _src_addr) = match socket.recv_from(&mut buf) {
+            Ok(ret) => ret,
+            Err(_e) => continue,
+        };
+
+        // Errors aren't logged, they'd mess up the terminal
+        let decoded = datagram::decode(&buf[..bytes_recvd]);
+        if decoded.error.is_some() {
+            app.invalid_datagrams += 1;
+        }
+        for msg in decoded.messages {
+            app.add_message(msg);
+        }
+        dirty = true;
+    }
+
+    Ok(())
+}
+
+struct App {
+    listen_address: String,
+    devices: BTreeMap<DeviceSerialNumber, Device>,
+    table_state: TableState,
+    total_messages: u64,
+    invalid_datagrams: u64,
+}
+
+struct Device {
+    stats: DeviceStats,
+    msg: Message,
+    last_seen: Instant,
+    history: VecDeque<Message>,
+}
+
+impl App {
+    fn add_message(&mut self, msg: Message) {
+        let device = self
+            .devices
+            .entry(msg.device_serial_number)
+            .or_insert_with(|| Device {
+                stats: DeviceStats::new(&msg),
+                msg,
+                last_seen: Instant::now(),
+                history: VecDeque::with_capacity(HISTORY_LEN),
+            });
+        device.stats.update(&msg);
+        device.msg = msg;
+        device.last_seen = Instant::now();
+        if device.history.len() >= HISTORY_LEN {
+            device.history.pop_front();
+        }
+        device.history.push_back(msg);
+        self.total_messages += 1;
+
+        if self.table_state.selected().is_none() {
+            self.table_state.select(Some(0));
+        }
+    }
+
+    fn select_next(&mut self) {
+        if !self.devices.is_empty() {
+            let i = self.table_state.selected().map(|i| i + 1).unwrap_or(0);
+            self.table_state.select(Some(i.min(self.devices.len() - 1)));
+        }
+    }
+
+    fn select_previous(&mut self) {
+        if !self.devices.is_empty() {
+            let i = self
+                .table_state
+                .selected()
+                .map(|i| i.saturating_sub(1))
+                .unwrap_or(0);
+            self.table_state.select(Some(i));
+        }
+    }
+
+    fn selected_device(&self) -> Option<&Device> {
+        self.table_state
+            .selected()
+            .and_then(|i| self.devices.values().nth(i))
+    }
+}
+
+type Getter = fn(&Message) -> Option<f64>;
+
+/// The series shown as sparklines in the detail pane: name, unit, value
+const SERIES: &[(&str, &str, Getter)] = &[
+    ("Temperature", "°C", |m| m.valid_temperature_c()),
+    ("Humidity", "%", |m| m.valid_relative_humidity()),
+    ("CO2", "ppm", |m| m.valid_co2().map(f64::from)),
+    ("PM2.5", "µg/m³", |m| m.valid_pm2_5_atm().map(f64::from)),
+    ("VOC index", "", |m| m.valid_voc_index().map(f64::from)),
+    ("NOx index", "", |m| m.valid_nox_index().map(f64::from)),
+];
+
+fn draw(frame: &mut Frame, app: &mut App) {
+    let [header_area, table_area, detail_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(4),
+        Constraint::Length(2 + 3 * SERIES.len() as u16),
+    ])
+    .areas(frame.area());
+
+    let header = Line::from(vec![
+        Span::from(format!("Listening on {}", app.listen_address)).bold(),
+        Span::from(format!(
+            " | devices: {} | messages: {} | invalid datagrams: {} | ↑/↓ select, q quit",
+            app.devices.len(),
+            app.total_messages,
+            app.invalid_datagrams
+        )),
+    ]);
+    frame.render_widget(header, header_area);
+
+    draw_table(frame, app, table_area);
+    draw_detail(frame, app, detail_area);
+}
+
+fn draw_table(frame: &mut Frame, app: &mut App, area: Rect) {
+    let header = Row::new([
+        "ID",
+        "Serial",
+        "Firmware",
+        "Uptime",
+        "Last seen",
+        "Msgs",
+        "Gaps",
+        "Temp °C",
+        "RH %",
+        "CO2",
+        "PM2.5",
+        "AQI",
+        "VOC",
+        "NOx",
+    ])
+    .style(Style::new().add_modifier(Modifier::BOLD | Modifier::REVERSED));
+
+    let rows = app.devices.iter().map(|(sn, d)| {
+        let m = &d.msg;
+        let flags = m.status_flags;
+        let aqi_cell = if let Some(aqi) = m.valid_pm2_5_us_aqi() {
+            Cell::from(format!("{} {}", aqi.aqi(), aqi.level()))
+                .style(Style::new().fg(aqi_color(aqi.level())))
+        } else {
+            Cell::from("-")
+        };
+        Row::new([
+            Cell::from(m.device_id.to_string()),
+            Cell::from(format!("{sn:X}")),
+            Cell::from(m.firmware_version.to_string()),
+            Cell::from(m.uptime().to_string()),
+            Cell::from(format!(
+                "{} ago",
+                humantime::format_duration(Duration::from_secs(d.last_seen.elapsed().as_secs()))
+            )),
+            Cell::from(d.stats.total_messages.to_string()),
+            Cell::from(d.stats.missed_messages.to_string()),
+            valid_cell(flags.temperature_valid(), || {
+                format!("{:.2}", m.temperature_c())
+            }),
+            valid_cell(flags.humidity_valid(), || {
+                format!("{:.2}", m.relative_humidity())
+            }),
+            valid_cell(flags.co2_valid(), || m.co2.to_string()),
+            valid_cell(flags.pm2_5_valid(), || m.pm2_5_atm.to_string()),
+            aqi_cell,
+            valid_cell(flags.voc_index_valid(), || m.voc_index.to_string()),
+            valid_cell(flags.nox_index_valid(), || m.nox_index.to_string()),
+        ])
+    });
+
+    let widths = [
+        Constraint::Length(5),
+        Constraint::Length(24),
+        Constraint::Length(9),
+        Constraint::Length(16),
+        Constraint::Length(10),
+        Constraint::Length(7),
+        Constraint::Length(5),
+        Constraint::Length(8),
+        Constraint::Length(6),
+        Constraint::Length(6),
+        Constraint::Length(6),
+        Constraint::Min(10),
+        Constraint::Length(4),
+        Constraint::Length(4),
+    ];
+
+    let table = Table::new(rows, widths)
+        .header(header)
+        .block(Block::bordered().title("Devices"))
+        .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED));
+    frame.render_stateful_widget(table, area, &mut app.table_state);
+}
+
+fn draw_detail(frame: &mut Frame, app: &App, area: Rect) {
+    let Some(device) = app.selected_device() else {
+        frame.render_widget(
+            Paragraph::new("Waiting for broadcast messages...").block(Block::bordered()),
+            area,
+        );
+        return;
+    };
+
+    let block = Block::bordered().title(format!(
+        "Device {:X} - last {} messages",
+        device.msg.device_serial_number,
+        device.history.len()
+    ));
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let areas = Layout::vertical(SERIES.iter().map(|_| Constraint::Length(3))).split(inner);
+    for ((name, unit, value), area) in SERIES.iter().zip(areas.iter()) {
+        let [label_area, spark_area] =
+            Layout::vertical([Constraint::Length(1), Constraint::Length(2)]).areas(*area);
+
+        // The most recent values that fit in the width
+        let values: Vec<Option<f64>> = device
+            .history
+            .iter()
+            .skip(device.history.len().saturating_sub(spark_area.width.into()))
+            .map(value)
+            .collect();
+        let min = values.iter().flatten().copied().reduce(f64::min);
+        let max = values.iter().flatten().copied().reduce(f64::max);
+
+        let label = match (values.last().copied().flatten(), min, max) {
+            (Some(current), Some(min), Some(max)) => {
+                format!("{name}: {current:.2} {unit} (min {min:.2}, max {max:.2})")
+            }
+            _ => format!("{name}: -"),
+        };
+        frame.render_widget(Line::from(label).bold(), label_area);
+
+        // Scaled from the minimum so small changes are visible, the minimum is a 1 unit bar
+        let data: Vec<Option<u64>> = values
+            .iter()
+            .map(|v| v.map(|v| ((v - min.unwrap_or(v)) * 100.0).round() as u64 + 1))
+            .collect();
+        frame.render_widget(
+            Sparkline::default()
+                .data(&data)
+                .style(Style::new().fg(Color::Cyan)),
+            spark_area,
+        );
+    }
+}
+
+fn valid_cell<'a, F: FnOnce() -> String>(valid: bool, f: F) -> Cell<'a> {
+    if valid {
+        Cell::from(f())
+    } else {
+        Cell::from("-")
+    }
+}
+
+/// The EPA AQI colors
+fn aqi_color(level: aqi::AirQualityLevel) -> Color {
+    use aqi::AirQualityLevel::*;
+    match level {
+        Good => Color::Rgb(0, 228, 0),
+        Moderate => Color::Rgb(255, 255, 0),
+        UnhealthySensitive => Color::Rgb(255, 126, 0),
+        Unhealthy => Color::Rgb(255, 0, 0),
+        VeryUnhealthy => Color::Rgb(143, 63, 151),
+        Hazardous => Color::Rgb(126, 0, 35),
+    }
+}
@@ -0,0 +1,470 @@
+//! SQLite history of the broadcast messages, written by `store` and queried by `history`.
+//!
+//! Every message is kept in the `messages` table, with a NULL for each value the device
+//! didn't report as valid. Messages older than the raw retention are downsampled into the
+//! `samples` table, one row per device, interval and field with the min, max, sum and count,
+//! so the aggregates can still be queried after the messages themselves are deleted.
+
+use crate::measurement::MessageExt;
+use anyhow::{anyhow, bail, Result};
+use chrono::prelude::*;
+use rusqlite::{params, Connection};
+use std::{net::SocketAddr, path::Path, time::Duration};
+use wire_protocols::broadcast::Repr as Message;
+
+/// Stored in the `user_version` pragma
+pub const SCHEMA_VERSION: i32 = 1;
+
+/// The aggregated fields and their units
+pub const FIELDS: &[(&str, &str)] = &[
+    ("temperature", "°C"),
+    ("humidity", "%"),
+    ("voc_ticks", ""),
+    ("nox_ticks", ""),
+    ("voc_index", ""),
+    ("nox_index", ""),
+    ("pm2_5_atm", "µg/m³"),
+    ("co2", "ppm"),
+];
+
+/// How long to wait on a lock held by another connection, e.g. `history` reading while
+/// `store` writes
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "
+CREATE TABLE messages (
+    recv_time INTEGER NOT NULL, -- milliseconds since the UNIX epoch
+    src_addr TEXT NOT NULL,
+    protocol_version INTEGER NOT NULL,
+    firmware_version TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    device_serial_number TEXT NOT NULL,
+    sequence_number INTEGER NOT NULL,
+    uptime_seconds INTEGER NOT NULL,
+    status_flags INTEGER NOT NULL,
+    datetime TEXT,
+    temperature REAL, -- degrees Celsius
+    humidity REAL, -- relative humidity percent
+    voc_ticks INTEGER,
+    nox_ticks INTEGER,
+    voc_index INTEGER,
+    nox_index INTEGER,
+    pm2_5_atm INTEGER,
+    co2 INTEGER
+);
+CREATE INDEX messages_device_time ON messages (device_serial_number, recv_time);
+
+CREATE TABLE samples (
+    device_serial_number TEXT NOT NULL,
+    start_time INTEGER NOT NULL, -- milliseconds since the UNIX epoch
+    field TEXT NOT NULL,
+    min REAL NOT NULL,
+    max REAL NOT NULL,
+    sum REAL NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (device_serial_number, start_time, field)
+);
+";
+
+/// A field aggregated over an interval
+#[derive(Clone, Debug, PartialEq)]
+pub struct Aggregate {
+    pub device_serial_number: String,
+    pub start_time: DateTime<Utc>,
+    pub field: &'static str,
+    pub count: u64,
+    pub min: f64,
+    pub avg: f64,
+    pub max: f64,
+}
+
+pub struct Database {
+    conn: Connection,
+}
+
+impl Database {
+    /// Opens the database, creating it if it doesn't exist
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .map_err(|e| anyhow!("Failed to open database '{}'. {e}", path.display()))?;
+        Self::with_connection(conn, path)
+    }
+
+    /// Sets up the connection, creating the schema in a new database
+    fn with_connection(conn: Connection, path: &Path) -> Result<Self> {
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // Lets readers query while the store is writing
+        let _mode: String =
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |r| r.get(0))?;
+
+        let version: i32 = conn.pragma_query_value(None, "user_version", |r| r.get(0))?;
+        match version {
+            0 => {
+                conn.execute_batch(SCHEMA)?;
+                conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+            }
+            SCHEMA_VERSION => (),
+            v => bail!(
+                "Database '{}' has unsupported schema version {v}",
+                path.display()
+            ),
+        }
+
+        Ok(Database { conn })
+    }
+
+    /// Opens an existing database
+    pub fn open_existing(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            bail!("Database '{}' doesn't exist", path.display());
+        }
+        Self::open(path)
+    }
+
+    pub fn insert(
+        &self,
+        recv_time_utc: DateTime<Utc>,
+        src_addr: SocketAddr,
+        msg: &Message,
+    ) -> Result<()> {
+        let flags = msg.status_flags;
+        self.conn
+            .prepare_cached(
+                "INSERT INTO messages VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+            )?
+            .execute(params![
+                recv_time_utc.timestamp_millis(),
+                src_addr.to_string(),
+                msg.protocol_version.0,
+                msg.firmware_version.to_string(),
+                msg.device_id.0,
+                format!("{:X}", msg.device_serial_number),
+                msg.sequence_number,
+                msg.uptime_seconds,
+                flags.0,
+                msg.valid_datetime().map(|d| d.to_string()),
+                msg.valid_temperature_c(),
+                msg.valid_relative_humidity(),
+                msg.valid_voc_ticks(),
+                msg.valid_nox_ticks(),
+                msg.valid_voc_index(),
+                msg.valid_nox_index(),
+                msg.valid_pm2_5_atm(),
+                msg.valid_co2(),
+            ])?;
+        Ok(())
+    }
+
+    /// Aggregates the messages received before `before` into samples of `interval` and
+    /// deletes them, returns the number of messages downsampled.
+    ///
+    /// `before` is rounded down to an interval boundary so an interval is never split
+    /// between the messages and the samples.
+    pub fn downsample(&mut self, before: DateTime<Utc>, interval: Duration) -> Result<usize> {
+        let interval = interval_millis(interval)?;
+        let before = (before.timestamp_millis() / interval) * interval;
+
+        let tx = self.conn.transaction()?;
+        for (field, _unit) in FIELDS.iter() {
+            tx.execute(
+                &format!(
+                    "INSERT INTO samples
+                    SELECT device_serial_number, (recv_time / ?1) * ?1, '{field}',
+                        min({field}), max({field}), sum({field}), count({field})
+                    FROM messages
+                    WHERE recv_time < ?2 AND {field} IS NOT NULL
+                    GROUP BY 1, 2
+                    ON CONFLICT (device_serial_number, start_time, field) DO UPDATE SET
+                        min = min(min, excluded.min),
+                        max = max(max, excluded.max),
+                        sum = sum + excluded.sum,
+                        count = count + excluded.count"
+                ),
+                params![interval, before],
+            )?;
+        }
+        let n = tx.execute("DELETE FROM messages WHERE recv_time < ?1", [before])?;
+        tx.commit()?;
+        Ok(n)
+    }
+
+    /// Deletes the samples older than `before`, returns the number deleted
+    pub fn delete_samples(&self, before: DateTime<Utc>) -> Result<usize> {
+        Ok(self.conn.execute(
+            "DELETE FROM samples WHERE start_time < ?1",
+            [before.timestamp_millis()],
+        )?)
+    }
+
+    /// Aggregates each field per device and interval over both the messages and the samples,
+    /// ordered by device, interval and field.
+    ///
+    /// The intervals of the samples are kept whole, so the query interval should be a
+    /// multiple of the downsample interval.
+    pub fn query(
+        &self,
+        device_serial_number: Option<&str>,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        interval: Duration,
+    ) -> Result<Vec<Aggregate>> {
+        let interval = interval_millis(interval)?;
+
+        let mut sources: Vec<String> = FIELDS
+            .iter()
+            .map(|(field, _unit)| {
+                format!(
+                    "SELECT device_serial_number, recv_time AS t, '{field}' AS field,
+                        {field} AS min, {field} AS max, {field} AS sum, 1 AS count
+                    FROM messages WHERE {field} IS NOT NULL"
+                )
+            })
+            .collect();
+        sources.push(
+            "SELECT device_serial_number, start_time, field, min, max, sum, count FROM samples"
+                .to_owned(),
+        );
+
+        let sql = format!(
+            "SELECT device_serial_number, (t / ?1) * ?1, field, min(min), max(max), sum(sum), sum(count)
+            FROM ({})
+            WHERE t >= ?2 AND t < ?3 AND (?4 IS NULL OR device_serial_number = ?4)
+            GROUP BY 1, 2, 3",
+            sources.join(" UNION ALL ")
+        );
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(
+            params![
+                interval,
+                start.timestamp_millis(),
+                end.timestamp_millis(),
+                device_serial_number.map(|sn| sn.to_uppercase()),
+            ],
+            |r| {
+                Ok((
+                    r.get::<_, String>(0)?,
+                    r.get::<_, i64>(1)?,
+                    r.get::<_, String>(2)?,
+                    r.get::<_, f64>(3)?,
+                    r.get::<_, f64>(4)?,
+                    r.get::<_, f64>(5)?,
+                    r.get::<_, i64>(6)?,
+                ))
+            },
+        )?;
+
+        let mut aggregates = Vec::new();
+        for row in rows {
+            let (device_serial_number, start_time, field, min, max, sum, count) = row?;
+            let Some((field, _unit)) = FIELDS.iter().find(|(f, _)| *f == field) else {
+                continue;
+            };
+            let Some(start_time) = Utc.timestamp_millis_opt(start_time).single() else {
+                continue;
+            };
+            aggregates.push(Aggregate {
+                device_serial_number,
+                start_time,
+                field,
+                count: count as u64,
+                min,
+                avg: sum / count as f64,
+                max,
+            });
+        }
+
+        aggregates.sort_by_key(|a| {
+            (
+                a.device_serial_number.clone(),
+                a.start_time,
+                FIELDS.iter().position(|(f, _)| *f == a.field),
+            )
+        });
+
+        Ok(aggregates)
+    }
+}
+
+fn interval_millis(interval: Duration) -> Result<i64> {
+    match i64::try_from(interval.as_millis()) {
+        Ok(ms) if ms > 0 => Ok(ms),
+        _ => bail!(
+            "Invalid interval '{}'",
+            humantime::format_duration(interval)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datagram::tests::repr;
+    use wire_protocols::StatusFlags;
+
+    /// On an hour boundary
+    const BASE_SECS: i64 = 1_699_999_200;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    fn db() -> Database {
+        Database::with_connection(Connection::open_in_memory().unwrap(), Path::new(":memory:"))
+            .unwrap()
+    }
+
+    fn time(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(BASE_SECS + secs, 0).unwrap()
+    }
+
+    /// A message with only the temperature and CO2 valid
+    fn msg(temperature_c: i32, co2: u16) -> Message {
+        let mut msg = repr(1);
+        let mut flags = StatusFlags::empty();
+        flags.set_temperature_valid(true);
+        flags.set_co2_valid(true);
+        msg.status_flags = flags;
+        msg.temperature = temperature_c * 100;
+        msg.co2 = co2;
+        msg
+    }
+
+    fn insert(db: &Database, secs: i64, temperature_c: i32, co2: u16) {
+        let src_addr = "192.168.1.38:32100".parse().unwrap();
+        db.insert(time(secs), src_addr, &msg(temperature_c, co2))
+            .unwrap();
+    }
+
+    fn field<'a>(aggregates: &'a [Aggregate], secs: i64, field: &str) -> &'a Aggregate {
+        aggregates
+            .iter()
+            .find(|a| a.start_time == time(secs) && a.field == field)
+            .unwrap()
+    }
+
+    fn count(db: &Database, table: &str) -> i64 {
+        db.conn
+            .query_row(&format!("SELECT count(*) FROM {table}"), [], |r| r.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn query_messages() {
+        let db = db();
+        insert(&db, 0, 20, 400);
+        insert(&db, 10, 24, 600);
+        insert(&db, 70, 30, 800);
+
+        let aggregates = db.query(None, time(0), time(120), MINUTE).unwrap();
+        // Only the valid fields, ordered by interval and field
+        let keys: Vec<(DateTime<Utc>, &str)> =
+            aggregates.iter().map(|a| (a.start_time, a.field)).collect();
+        assert_eq!(
+            keys,
+            vec![
+                (time(0), "temperature"),
+                (time(0), "co2"),
+                (time(60), "temperature"),
+                (time(60), "co2"),
+            ]
+        );
+
+        let a = field(&aggregates, 0, "temperature");
+        assert_eq!(
+            a.device_serial_number,
+            format!("{:X}", repr(1).device_serial_number)
+        );
+        assert_eq!((a.count, a.min, a.avg, a.max), (2, 20.0, 22.0, 24.0));
+        let a = field(&aggregates, 0, "co2");
+        assert_eq!((a.count, a.min, a.avg, a.max), (2, 400.0, 500.0, 600.0));
+    }
+
+    #[test]
+    fn downsample_and_query_across_the_boundary() {
+        let mut db = db();
+        insert(&db, 0, 20, 400);
+        insert(&db, 10, 22, 500);
+        insert(&db, 20, 24, 600);
+        insert(&db, 70, 30, 800);
+        insert(&db, 130, 40, 1000);
+
+        // Rounded down to the start of the second minute
+        assert_eq!(db.downsample(time(65), MINUTE).unwrap(), 3);
+        assert_eq!(count(&db, "messages"), 2);
+        assert_eq!(count(&db, "samples"), 2);
+
+        // A late message for the already downsampled minute is merged into its samples
+        insert(&db, 30, 18, 300);
+        assert_eq!(db.downsample(time(125), MINUTE).unwrap(), 2);
+        assert_eq!(count(&db, "messages"), 1);
+        assert_eq!(count(&db, "samples"), 4);
+
+        let aggregates = db.query(None, time(0), time(180), MINUTE).unwrap();
+        assert_eq!(aggregates.len(), 6);
+        let a = field(&aggregates, 0, "temperature");
+        assert_eq!((a.count, a.min, a.avg, a.max), (4, 18.0, 21.0, 24.0));
+        let a = field(&aggregates, 0, "co2");
+        assert_eq!((a.count, a.min, a.avg, a.max), (4, 300.0, 450.0, 600.0));
+        let a = field(&aggregates, 60, "temperature");
+        assert_eq!((a.count, a.min, a.avg, a.max), (1, 30.0, 30.0, 30.0));
+        // Still a raw message
+        let a = field(&aggregates, 120, "temperature");
+        assert_eq!((a.count, a.min, a.avg, a.max), (1, 40.0, 40.0, 40.0));
+
+        // One interval over both the samples and the raw message
+        let aggregates = db.query(None, time(0), time(180), 3 * MINUTE).unwrap();
+        assert_eq!(aggregates.len(), 2);
+        let a = field(&aggregates, 0, "temperature");
+        assert_eq!((a.count, a.min, a.max), (6, 18.0, 40.0));
+        assert_eq!(a.avg, 154.0 / 6.0);
+        let a = field(&aggregates, 0, "co2");
+        assert_eq!((a.count, a.min, a.avg, a.max), (6, 300.0, 600.0, 1000.0));
+
+        // The range keeps the sample intervals whole
+        let aggregates = db.query(None, time(60), time(180), MINUTE).unwrap();
+        let start_times: Vec<DateTime<Utc>> = aggregates.iter().map(|a| a.start_time).collect();
+        assert_eq!(start_times, vec![time(60), time(60), time(120), time(120)]);
+    }
+
+    #[test]
+    fn query_device() {
+        let db = db();
+        insert(&db, 0, 20, 400);
+        let mut other = msg(30, 800);
+        other.device_serial_number.word0 += 1;
+        db.insert(time(0), "192.168.1.39:32100".parse().unwrap(), &other)
+            .unwrap();
+
+        let sn = format!("{:X}", repr(1).device_serial_number);
+        let aggregates = db
+            .query(Some(&sn.to_lowercase()), time(0), time(60), MINUTE)
+            .unwrap();
+        assert_eq!(aggregates.len(), 2);
+        assert!(aggregates.iter().all(|a| a.device_serial_number == sn));
+        assert_eq!(field(&aggregates, 0, "temperature").max, 20.0);
+
+        let aggregates = db.query(None, time(0), time(60), MINUTE).unwrap();
+        assert_eq!(aggregates.len(), 4);
+    }
+
+    #[test]
+    fn delete_samples() {
+        let mut db = db();
+        insert(&db, 0, 20, 400);
+        insert(&db, 70, 30, 800);
+        insert(&db, 130, 40, 1000);
+        assert_eq!(db.downsample(time(120), MINUTE).unwrap(), 2);
+
+        assert_eq!(db.delete_samples(time(60)).unwrap(), 2);
+        let aggregates = db.query(None, time(0), time(180), MINUTE).unwrap();
+        let start_times: Vec<DateTime<Utc>> = aggregates.iter().map(|a| a.start_time).collect();
+        assert_eq!(start_times, vec![time(60), time(60), time(120), time(120)]);
+    }
+
+    #[test]
+    fn invalid_interval() {
+        let mut db = db();
+        assert!(db.downsample(time(0), Duration::ZERO).is_err());
+        assert!(db.query(None, time(0), time(60), Duration::ZERO).is_err());
+    }
+}
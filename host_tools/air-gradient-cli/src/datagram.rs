@@ -0,0 +1,189 @@
+//! Decoding of received broadcast datagrams.
+//!
+//! A datagram can hold several concatenated messages. Each one is validated against
+//! the received length, so only the bytes actually received are ever parsed.
+
+use std::fmt;
+use wire_protocols::broadcast::{Message as WireMessage, Repr as Message, MESSAGE_LEN};
+
+/// The messages decoded from a datagram, and the error that stopped the decoding, if any
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Decoded {
+    pub messages: Vec<Message>,
+    pub error: Option<DecodeError>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The message at `offset` is invalid, the remaining `len` bytes of the datagram are skipped
+    Invalid { offset: usize, len: usize },
+    /// The remaining `len` bytes at `offset` are too short for a message
+    Truncated { offset: usize, len: usize },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Invalid { offset, len } => write!(
+                f,
+                "Invalid broadcast message at offset {offset}, skipped the remaining {len} bytes"
+            ),
+            DecodeError::Truncated { offset: 0, len } => write!(
+                f,
+                "Datagram of {len} bytes is too short for a broadcast message"
+            ),
+            DecodeError::Truncated { offset, len } => write!(
+                f,
+                "Truncated broadcast message, {len} trailing bytes at offset {offset}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes the messages of a datagram, `data` must be only the bytes received
+pub fn decode(data: &[u8]) -> Decoded {
+    let mut decoded = Decoded::default();
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        if rest.len() < MESSAGE_LEN {
+            decoded.error = Some(DecodeError::Truncated {
+                offset,
+                len: rest.len(),
+            });
+            break;
+        }
+
+        match WireMessage::new_checked(&rest[..MESSAGE_LEN])
+            .and_then(|wire_msg| Message::parse(&wire_msg))
+        {
+            Ok(msg) => decoded.messages.push(msg),
+            Err(_) => {
+                // There's no way to find the start of the next message
+                decoded.error = Some(DecodeError::Invalid {
+                    offset,
+                    len: rest.len(),
+                });
+                break;
+            }
+        }
+        offset += MESSAGE_LEN;
+    }
+    decoded
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    static MSG_BYTES: [u8; MESSAGE_LEN] = [
+        0x42, 0x52, 0x44, 0x43, 0x01, 0x03, 0x00, 0x02, 0x00, 0x01, 0x00, 0x0D, 0x00, 0xAA, 0xAA,
+        0xAA, 0xAA, 0xBB, 0xBB, 0xBB, 0xBB, 0xCC, 0xCC, 0xCC, 0xCC, 0x01, 0x00, 0x00, 0xFF, 0x44,
+        0x33, 0x22, 0x11, 0xBB, 0xAA, 0xE7, 0x07, 0x02, 0x15, 0x10, 0x28, 0x37, 0xEA, 0xFF, 0xFF,
+        0xFF, 0xE8, 0x03, 0xAB, 0x00, 0xCD, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
+    ];
+
+    /// The MSG_BYTES message with the given sequence number, also used by the other modules' tests
+    pub(crate) fn repr(sequence_number: u32) -> Message {
+        let mut repr = Message::parse(&WireMessage::new_checked(&MSG_BYTES[..]).unwrap()).unwrap();
+        repr.sequence_number = sequence_number;
+        repr
+    }
+
+    fn msg(sequence_number: u32) -> Vec<u8> {
+        let repr = repr(sequence_number);
+        let mut bytes = vec![0; MESSAGE_LEN];
+        repr.emit(&mut WireMessage::new_unchecked(&mut bytes[..]));
+        bytes
+    }
+
+    fn seqnums(decoded: &Decoded) -> Vec<u32> {
+        decoded.messages.iter().map(|m| m.sequence_number).collect()
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(decode(&[]), Decoded::default());
+    }
+
+    #[test]
+    fn single_message() {
+        let decoded = decode(&msg(1));
+        assert_eq!(seqnums(&decoded), vec![1]);
+        assert_eq!(decoded.error, None);
+    }
+
+    #[test]
+    fn concatenated_messages() {
+        let data = [msg(1), msg(2), msg(3)].concat();
+        let decoded = decode(&data);
+        assert_eq!(seqnums(&decoded), vec![1, 2, 3]);
+        assert_eq!(decoded.error, None);
+    }
+
+    #[test]
+    fn too_short() {
+        let decoded = decode(b"junk");
+        assert!(decoded.messages.is_empty());
+        assert_eq!(
+            decoded.error,
+            Some(DecodeError::Truncated { offset: 0, len: 4 })
+        );
+
+        let decoded = decode(&msg(1)[..MESSAGE_LEN - 1]);
+        assert!(decoded.messages.is_empty());
+        assert_eq!(
+            decoded.error,
+            Some(DecodeError::Truncated {
+                offset: 0,
+                len: MESSAGE_LEN - 1
+            })
+        );
+    }
+
+    #[test]
+    fn truncated_trailing_data() {
+        let data = [msg(1), msg(2)].concat();
+        let decoded = decode(&data[..MESSAGE_LEN + 10]);
+        assert_eq!(seqnums(&decoded), vec![1]);
+        assert_eq!(
+            decoded.error,
+            Some(DecodeError::Truncated {
+                offset: MESSAGE_LEN,
+                len: 10
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_message_stops_decoding() {
+        let mut bad = msg(2);
+        bad[0] = 0;
+        let data = [msg(1), bad, msg(3)].concat();
+        let decoded = decode(&data);
+        assert_eq!(seqnums(&decoded), vec![1]);
+        assert_eq!(
+            decoded.error,
+            Some(DecodeError::Invalid {
+                offset: MESSAGE_LEN,
+                len: MESSAGE_LEN * 2
+            })
+        );
+    }
+
+    #[test]
+    fn stale_buffer_bytes_are_not_decoded() {
+        // A receive buffer holding two messages from an earlier datagram, then a
+        // shorter datagram overwriting the start of it
+        let mut buf = [msg(1), msg(2)].concat();
+        buf[..4].copy_from_slice(b"junk");
+        let decoded = decode(&buf[..4]);
+        assert!(decoded.messages.is_empty());
+        assert_eq!(
+            decoded.error,
+            Some(DecodeError::Truncated { offset: 0, len: 4 })
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use wire_protocols::{broadcast::Repr as Message, DeviceId};
+
+/// Message counts of a device, from its sequence numbers
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct DeviceStats {
+    pub device_id: DeviceId,
+    pub last_seqnum: u32,
+    pub total_messages: u64,
+    pub missed_messages: u64,
+    pub duplicate_messages: u64,
+}
+
+/// How a message's sequence number relates to the previous one from the device
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Sequence {
+    First,
+    Next,
+    Duplicate,
+    /// There's a gap, `expected` is the first missed sequence number
+    Missed {
+        expected: u32,
+    },
+    /// The sequence number went backwards, the device restarted
+    Restarted,
+}
+
+impl DeviceStats {
+    pub fn new(msg: &Message) -> Self {
+        DeviceStats {
+            device_id: msg.device_id,
+            last_seqnum: msg.sequence_number,
+            total_messages: 0,
+            missed_messages: 0,
+            duplicate_messages: 0,
+        }
+    }
+
+    pub fn update(&mut self, msg: &Message) -> Sequence {
+        let expected = self.last_seqnum.wrapping_add(1);
+        let seq = if self.total_messages == 0 {
+            Sequence::First
+        } else if msg.sequence_number == self.last_seqnum {
+            self.duplicate_messages += 1;
+            Sequence::Duplicate
+        } else if msg.sequence_number == expected {
+            Sequence::Next
+        } else if msg.sequence_number > expected {
+            self.missed_messages += u64::from(msg.sequence_number - expected);
+            Sequence::Missed { expected }
+        } else {
+            // Sequence numbers start over when the device reboots
+            Sequence::Restarted
+        };
+
+        self.device_id = msg.device_id;
+        self.total_messages += 1;
+        self.last_seqnum = msg.sequence_number;
+        seq
+    }
+}
@@ -1,19 +1,28 @@
 use anyhow::{bail, Result};
-use bootloader_support::BootSlot;
+use bootloader_support::{BootHistory, BootHistoryEntry, BootSlot};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::TcpStream,
 };
 use tracing::debug;
-use wire_protocols::device::{Command, StatusCode};
+use wire_protocols::device::{Command, MemoryRegion, StatusCode};
+
+/// Shown instead of the firmware version for devices in recovery mode
+pub const NO_FIRMWARE_VERSION: &str = "none (recovery)";
 
 #[serde_as]
 #[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize, Serialize)]
 pub struct DeviceInfo {
     pub protocol_version: String,
-    pub firmware_version: String,
+    /// None in the bootloader's recovery mode, no firmware is running
+    pub firmware_version: Option<String>,
+    /// Only reported by devices whose bootloader records its version
+    #[serde(default)]
+    pub bootloader_version: Option<String>,
+    #[serde(default)]
+    pub hardware: Option<String>,
     pub device_id: u16,
     pub device_serial_number: String,
     pub mac_address: [u8; 6],
@@ -22,6 +31,18 @@ pub struct DeviceInfo {
     pub reset_reason: String,
     pub built_time_utc: String,
     pub git_commit: String,
+    /// Only reported by devices that support `Command::HashMemory`
+    #[serde(default)]
+    pub memory_hashing: bool,
+    /// Only reported by devices that support `Command::WriteMemoryTagged`
+    #[serde(default)]
+    pub write_chunk_size: Option<u32>,
+    /// Only reported by devices that support `Command::WriteCompressed`
+    #[serde(default)]
+    pub compressed_writes: bool,
+    /// Only reported by devices that support `Command::WriteDelta`
+    #[serde(default)]
+    pub delta_writes: bool,
 }
 
 impl DeviceInfo {
@@ -29,11 +50,23 @@ impl DeviceInfo {
         serde_json::from_str(s)
     }
 
+    /// The firmware version, or a marker for devices in recovery mode
+    pub fn firmware_version_str(&self) -> &str {
+        self.firmware_version
+            .as_deref()
+            .unwrap_or(NO_FIRMWARE_VERSION)
+    }
+
     // TODO - use serde_transcode to do this instead of manually
     pub fn into_field_names_and_values(self) -> serde_json::Map<String, serde_json::Value> {
         vec![
             ("protocol_version".to_owned(), self.protocol_version.into()),
             ("firmware_version".to_owned(), self.firmware_version.into()),
+            (
+                "bootloader_version".to_owned(),
+                self.bootloader_version.into(),
+            ),
+            ("hardware".to_owned(), self.hardware.into()),
             ("device_id".to_owned(), self.device_id.into()),
             (
                 "device_serial_number".to_owned(),
@@ -50,12 +83,55 @@ impl DeviceInfo {
             ("reset_reason".to_owned(), self.reset_reason.into()),
             ("built_time_utc".to_owned(), self.built_time_utc.into()),
             ("git_commit".to_owned(), self.git_commit.into()),
+            ("memory_hashing".to_owned(), self.memory_hashing.into()),
+            ("write_chunk_size".to_owned(), self.write_chunk_size.into()),
+            (
+                "compressed_writes".to_owned(),
+                self.compressed_writes.into(),
+            ),
+            ("delta_writes".to_owned(), self.delta_writes.into()),
         ]
         .into_iter()
         .collect()
     }
 }
 
+#[derive(Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Deserialize, Serialize)]
+pub struct BootHistoryRecord {
+    pub slot: String,
+    pub reset_reason: String,
+    pub update_attempted: bool,
+    pub update_committed: bool,
+    pub rolled_back: bool,
+    pub booted_once: bool,
+    pub firmware_version: Option<String>,
+}
+
+impl From<&BootHistoryEntry> for BootHistoryRecord {
+    fn from(e: &BootHistoryEntry) -> Self {
+        BootHistoryRecord {
+            slot: e.slot.to_string(),
+            reset_reason: e.reset_reason.to_string(),
+            update_attempted: e.update_attempted,
+            update_committed: e.update_committed,
+            rolled_back: e.rolled_back,
+            booted_once: e.booted_once,
+            firmware_version: e.firmware_version.map(|v| v.to_string()),
+        }
+    }
+}
+
+/// Connects to the device and requests its info, the device closes the connection afterwards
+pub async fn request_info(address: &str, port: u16) -> Result<DeviceInfo> {
+    let mut stream = TcpStream::connect((address, port)).await?;
+    debug!("Requesting device info from {address}:{port}");
+    write_command(Command::Info, &mut stream).await?;
+    let _status = read_status(&mut stream).await?;
+    let mut info_str = String::new();
+    let _info_len = BufReader::new(stream).read_line(&mut info_str).await?;
+    Ok(DeviceInfo::from_json(&info_str)?)
+}
+
 pub async fn write_command(cmd: Command, s: &mut TcpStream) -> Result<()> {
     s.write_u32_le(cmd.into()).await?;
     Ok(())
@@ -71,6 +147,19 @@ pub async fn read_status(s: &mut TcpStream) -> Result<StatusCode> {
     }
 }
 
+pub async fn hash_memory(region: MemoryRegion, s: &mut TcpStream) -> Result<u32> {
+    write_command(Command::HashMemory, s).await?;
+    s.write_all(&region.to_le_bytes()).await?;
+    let _status = read_status(s).await?;
+    Ok(s.read_u32_le().await?)
+}
+
+pub async fn read_boot_history(s: &mut TcpStream) -> Result<BootHistory> {
+    let mut bytes = [0_u8; BootHistory::WIRE_SIZE];
+    s.read_exact(&mut bytes).await?;
+    Ok(BootHistory::from_le_bytes(&bytes))
+}
+
 fn fmt_mac_addr(bytes: &[u8; 6]) -> String {
     format!(
         "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
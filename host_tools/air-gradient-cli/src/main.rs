@@ -1,14 +1,22 @@
 use crate::{interruptor::Interruptor, opts::Command};
 use anyhow::Result;
 use clap::Parser;
+use std::time::Duration;
 
 mod archive_util;
+mod capture;
 mod command;
+mod database;
+mod datagram;
+mod device_stats;
 mod device_util;
 mod interruptor;
 mod measurement;
 mod opts;
 
+/// How long to wait for the command to return after the user signals shutdown
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(15);
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let opts = opts::Opts::parse();
@@ -36,8 +44,19 @@ async fn main() -> Result<()> {
     let mut join_handle = tokio::spawn(async move {
         match opts.command {
             Command::Listen(c) => command::listen(c, interruptor).await,
+            Command::Top(c) => command::top(c, interruptor).await,
             Command::InfluxRelay(c) => command::influx_relay(c, interruptor).await,
+            Command::PrometheusExporter(c) => command::prometheus_exporter(c, interruptor).await,
+            Command::MqttRelay(c) => command::mqtt_relay(c, interruptor).await,
+            Command::Alert(c) => command::alert(c, interruptor).await,
+            Command::Store(c) => command::store(c, interruptor).await,
+            Command::History(c) => command::history(c, interruptor).await,
+            Command::Record(c) => command::record(c, interruptor).await,
+            Command::Replay(c) => command::replay(c, interruptor).await,
+            Command::Discover(c) => command::discover(c, interruptor).await,
             Command::Device(c) => command::device(c, interruptor).await,
+            Command::Fleet(c) => command::fleet(c, interruptor).await,
+            Command::Archive(c) => command::archive(c, interruptor).await,
             Command::ExtractArchive(c) => command::extract_archive(c, interruptor).await,
         }
     });
@@ -45,6 +64,12 @@ async fn main() -> Result<()> {
     tokio::select! {
         _ = tokio::signal::ctrl_c() => {
             tracing::debug!("User signaled shutdown");
+            // Commands watching the interruptor get a chance to wind down (e.g. influx-relay
+            // flushing its queue), a second control-c exits right away
+            match tokio::time::timeout(SHUTDOWN_GRACE_PERIOD, &mut join_handle).await {
+                Ok(res) => res??,
+                Err(_) => tracing::debug!("Command didn't exit in time"),
+            }
         }
         res = &mut join_handle => {
             let inner_res = res?;
@@ -52,14 +77,13 @@ async fn main() -> Result<()> {
         }
     };
 
-    // TODO - add shutdown signaling
-
     Ok(())
 }
 
 fn try_init_tracing_subscriber() -> Result<()> {
     use tracing_subscriber::util::SubscriberInitExt;
-    let builder = tracing_subscriber::fmt::Subscriber::builder();
+    // Logs go to stderr, stdout is for command output (e.g. influx-relay line protocol)
+    let builder = tracing_subscriber::fmt::Subscriber::builder().with_writer(std::io::stderr);
     let env_filter = std::env::var(tracing_subscriber::EnvFilter::DEFAULT_ENV)
         .map(tracing_subscriber::EnvFilter::new)
         .unwrap_or_else(|_| {
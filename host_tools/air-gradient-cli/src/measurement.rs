@@ -1,21 +1,22 @@
 use influxdb2::models::{data_point::DataPointError, DataPoint};
-use wire_protocols::broadcast::Repr as Message;
+use serde::{Deserialize, Serialize};
+use wire_protocols::{broadcast::Repr as Message, DateTime};
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Measurement {
     pub recv_time_utc_ns: i64,
     pub tags: MeasurementTags,
     pub fields: MeasurementFields,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct MeasurementTags {
     pub device_id: String,
     pub device_serial_number: String,
     pub firmware_version: String,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct MeasurementFields {
     pub sequence_number: i64,
     pub temperature: Option<f64>,
@@ -84,6 +85,42 @@ pub trait MessageExt {
     fn pm2_5_us_aqi(&self) -> aqi::AirQuality;
 
     fn uptime(&self) -> humantime::FormattedDuration;
+
+    // The valid_* accessors are None when the status flags mark the value as invalid
+
+    fn valid_datetime(&self) -> Option<DateTime>;
+
+    fn valid_temperature(&self) -> Option<i32>;
+
+    fn valid_temperature_c(&self) -> Option<f64> {
+        self.valid_temperature().map(|_| self.temperature_c())
+    }
+
+    fn valid_temperature_f(&self) -> Option<f64> {
+        self.valid_temperature().map(|_| self.temperature_f())
+    }
+
+    fn valid_humidity(&self) -> Option<u16>;
+
+    fn valid_relative_humidity(&self) -> Option<f64> {
+        self.valid_humidity().map(|_| self.relative_humidity())
+    }
+
+    fn valid_voc_ticks(&self) -> Option<u16>;
+
+    fn valid_nox_ticks(&self) -> Option<u16>;
+
+    fn valid_voc_index(&self) -> Option<u16>;
+
+    fn valid_nox_index(&self) -> Option<u16>;
+
+    fn valid_pm2_5_atm(&self) -> Option<u16>;
+
+    fn valid_pm2_5_us_aqi(&self) -> Option<aqi::AirQuality> {
+        self.valid_pm2_5_atm().map(|_| self.pm2_5_us_aqi())
+    }
+
+    fn valid_co2(&self) -> Option<u16>;
 }
 
 impl MessageExt for Message {
@@ -104,4 +141,50 @@ impl MessageExt for Message {
     fn uptime(&self) -> humantime::FormattedDuration {
         humantime::format_duration(std::time::Duration::from_secs(self.uptime_seconds.into()))
     }
+
+    fn valid_datetime(&self) -> Option<DateTime> {
+        self.status_flags.datetime_valid().then_some(self.datetime)
+    }
+
+    fn valid_temperature(&self) -> Option<i32> {
+        self.status_flags
+            .temperature_valid()
+            .then_some(self.temperature)
+    }
+
+    fn valid_humidity(&self) -> Option<u16> {
+        self.status_flags.humidity_valid().then_some(self.humidity)
+    }
+
+    fn valid_voc_ticks(&self) -> Option<u16> {
+        self.status_flags
+            .voc_ticks_valid()
+            .then_some(self.voc_ticks)
+    }
+
+    fn valid_nox_ticks(&self) -> Option<u16> {
+        self.status_flags
+            .nox_ticks_valid()
+            .then_some(self.nox_ticks)
+    }
+
+    fn valid_voc_index(&self) -> Option<u16> {
+        self.status_flags
+            .voc_index_valid()
+            .then_some(self.voc_index)
+    }
+
+    fn valid_nox_index(&self) -> Option<u16> {
+        self.status_flags
+            .nox_index_valid()
+            .then_some(self.nox_index)
+    }
+
+    fn valid_pm2_5_atm(&self) -> Option<u16> {
+        self.status_flags.pm2_5_valid().then_some(self.pm2_5_atm)
+    }
+
+    fn valid_co2(&self) -> Option<u16> {
+        self.status_flags.co2_valid().then_some(self.co2)
+    }
 }
@@ -1,6 +1,9 @@
+use chrono::{DateTime, Utc};
 use clap::Parser;
-use std::{fmt, path::PathBuf, str::FromStr};
-use wire_protocols::{broadcast as broadcast_proto, device as device_proto};
+use std::{fmt, num::NonZeroUsize, path::PathBuf, str::FromStr, time::Duration};
+use wire_protocols::{
+    broadcast as broadcast_proto, device as device_proto, discovery as discovery_proto,
+};
 
 /// Command line tool for interacting with the air-gradient-pro firmware
 #[derive(Parser, Debug, Clone)]
@@ -15,14 +18,49 @@ pub enum Command {
     /// Listen for broadcast messages
     Listen(Listen),
 
+    /// Live terminal dashboard of the devices and their broadcast messages
+    Top(Top),
+
     /// Relay the broadcast messages to InfluxDB
     InfluxRelay(InfluxRelay),
 
+    /// Serve the broadcast messages as Prometheus metrics
+    PrometheusExporter(PrometheusExporter),
+
+    /// Relay the broadcast messages to an MQTT broker, with Home Assistant discovery
+    MqttRelay(MqttRelay),
+
+    /// Watch the broadcast messages and send events when alert rules match
+    Alert(Alert),
+
+    /// Store the broadcast messages in a local SQLite database
+    Store(Store),
+
+    /// Query the history stored by the store command
+    History(History),
+
+    /// Capture the raw broadcast datagrams to a file
+    Record(Record),
+
+    /// Send the datagrams of a capture file to a UDP endpoint
+    Replay(Replay),
+
+    /// Find the devices on the network from their broadcast messages
+    Discover(Discover),
+
     /// Subcommands for interacting with a device over the network
     #[command(subcommand)]
     Device(Device),
 
-    /// Extract firmware ELF files from an archive file
+    /// Subcommands for operating on multiple devices
+    #[command(subcommand)]
+    Fleet(Fleet),
+
+    /// Subcommands for working with firmware image archives
+    #[command(subcommand)]
+    Archive(Archive),
+
+    /// Extract the firmware ELF and bin files from an archive file
     ExtractArchive(ExtractArchive),
 }
 
@@ -35,6 +73,23 @@ pub struct Listen {
     /// UDP port number
     #[arg(long, short = 'p', default_value = broadcast_proto::DEFAULT_PORT.to_string())]
     pub port: u16,
+
+    /// Output format: text, csv or jsonl.
+    /// With csv and jsonl each message is a record on stdout and the summary
+    /// is printed to stderr as JSON
+    #[arg(long, short = 'f', default_value_t = ListenFormat::Text)]
+    pub format: ListenFormat,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct Top {
+    /// Address
+    #[arg(long, short = 'a', default_value = "0.0.0.0")]
+    pub address: String,
+
+    /// UDP port number
+    #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
+    pub port: u16,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -47,25 +102,333 @@ pub struct InfluxRelay {
     #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
     pub port: u16,
 
+    /// Output backend: influx2, influx1 or line-protocol
+    #[arg(long, short = 'o', default_value_t = InfluxOutput::Influx2)]
+    pub output: InfluxOutput,
+
     /// InfluxDB host
     #[arg(long, default_value = "http://localhost:8086", env = "INFLUX_HOST")]
     pub host: String,
 
-    /// InfluxDB organization
+    /// InfluxDB organization, required for influx2
     #[arg(long, short = 'g', env = "INFLUX_ORG")]
-    pub org: String,
+    pub org: Option<String>,
 
-    /// InfluxDB bucket
+    /// InfluxDB bucket, required for influx2
     #[arg(long, short = 'b', env = "INFLUX_BUCKET_NAME")]
-    pub bucket: String,
+    pub bucket: Option<String>,
 
-    /// InfluxDB auth token
+    /// InfluxDB auth token, required for influx2
     #[arg(long, short = 't', env = "INFLUX_TOKEN")]
-    pub token: String,
+    pub token: Option<String>,
+
+    /// InfluxDB database, required for influx1
+    #[arg(long, short = 'd', env = "INFLUX_DATABASE")]
+    pub database: Option<String>,
+
+    /// InfluxDB retention policy, for influx1
+    #[arg(long, short = 'r', env = "INFLUX_RETENTION_POLICY")]
+    pub retention_policy: Option<String>,
+
+    /// InfluxDB username, for influx1
+    #[arg(long, short = 'u', env = "INFLUX_USERNAME", requires = "password")]
+    pub username: Option<String>,
+
+    /// InfluxDB password, for influx1
+    #[arg(long, env = "INFLUX_PASSWORD", requires = "username")]
+    pub password: Option<String>,
+
+    /// Where line-protocol is written to: '-' for stdout, udp://<host>:<port>,
+    /// or a file path which is appended to
+    #[arg(long, default_value = "-")]
+    pub destination: String,
 
     /// InfluxDB measurement name
     #[arg(long, short = 'm', default_value = "measurement")]
     pub measurement_name: String,
+
+    /// Maximum number of points per write
+    #[arg(long, default_value = "100")]
+    pub batch_size: NonZeroUsize,
+
+    /// Write the queued points at least this often, even when there's less than a batch
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    pub batch_interval: Duration,
+
+    /// Maximum number of queued points, the oldest are dropped when the queue is full
+    #[arg(long, default_value = "100000")]
+    pub max_queue_len: NonZeroUsize,
+
+    /// Upper bound of the exponential backoff between failed writes
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    pub max_retry_delay: Duration,
+
+    /// Keep the queued points in this file so they survive restarts
+    #[arg(long, env = "INFLUX_SPOOL_FILE")]
+    pub spool_file: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct PrometheusExporter {
+    /// Address
+    #[arg(long, short = 'a', default_value = "0.0.0.0")]
+    pub address: String,
+
+    /// UDP port number
+    #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// Address and port the HTTP server listens on, metrics are served at /metrics
+    #[arg(
+        long,
+        short = 'l',
+        default_value = "0.0.0.0:9871",
+        env = "AIR_GRADIENT_EXPORTER_ADDRESS"
+    )]
+    pub listen_address: String,
+
+    /// Drop the series of a device after not hearing from it for this long,
+    /// devices broadcast every 5 seconds
+    #[arg(long, default_value = "1m", value_parser = humantime::parse_duration)]
+    pub stale_after: Duration,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct Alert {
+    /// Address
+    #[arg(long, short = 'a', default_value = "0.0.0.0")]
+    pub address: String,
+
+    /// UDP port number
+    #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// Device protocol TCP port number, used to request the reset reason of restarted devices
+    #[arg(long, default_value_t = device_proto::DEFAULT_PORT)]
+    pub device_port: u16,
+
+    /// The TOML file with the alert rules and sinks
+    #[arg(long, short = 'c', env = "AIR_GRADIENT_ALERT_CONFIG")]
+    pub config: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct Store {
+    /// Address
+    #[arg(long, short = 'a', default_value = "0.0.0.0")]
+    pub address: String,
+
+    /// UDP port number
+    #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// The SQLite database file, created if it doesn't exist
+    #[arg(
+        long,
+        short = 'd',
+        default_value = "air-gradient.db",
+        env = "AIR_GRADIENT_DATABASE"
+    )]
+    pub database: PathBuf,
+
+    /// Keep every message for this long, older messages are downsampled
+    #[arg(long, default_value = "7d", value_parser = humantime::parse_duration)]
+    pub raw_retention: Duration,
+
+    /// Interval the older messages are downsampled to, as the min, average and max of each field
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    pub downsample_interval: Duration,
+
+    /// Delete the downsampled history older than this, it's kept forever by default
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub retention: Option<Duration>,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct History {
+    /// The SQLite database file written by the store command
+    #[arg(
+        long,
+        short = 'd',
+        default_value = "air-gradient.db",
+        env = "AIR_GRADIENT_DATABASE"
+    )]
+    pub database: PathBuf,
+
+    /// Only show this device serial number
+    #[arg(long, short = 's')]
+    pub device: Option<String>,
+
+    /// Only show these fields, all of them by default.
+    /// One of temperature, humidity, voc_ticks, nox_ticks, voc_index, nox_index, pm2_5_atm or co2
+    #[arg(long, short = 'f')]
+    pub field: Vec<String>,
+
+    /// Start of the time range, as a duration before now
+    #[arg(long, default_value = "1d", value_parser = humantime::parse_duration, conflicts_with = "start")]
+    pub since: Duration,
+
+    /// Start of the time range, as an RFC 3339 UTC time, e.g. 2023-04-24T15:00:00Z
+    #[arg(long)]
+    pub start: Option<DateTime<Utc>>,
+
+    /// End of the time range, as an RFC 3339 UTC time, defaults to now
+    #[arg(long)]
+    pub end: Option<DateTime<Utc>>,
+
+    /// Aggregate the values over intervals of this length.
+    /// Should be a multiple of the store's downsample interval for the downsampled history
+    #[arg(long, short = 'i', default_value = "1h", value_parser = humantime::parse_duration)]
+    pub interval: Duration,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct Record {
+    /// Address
+    #[arg(long, short = 'a', default_value = "0.0.0.0")]
+    pub address: String,
+
+    /// UDP port number
+    #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// Stop recording after this long, records until control-c by default
+    #[arg(long, short = 'd', value_parser = humantime::parse_duration)]
+    pub duration: Option<Duration>,
+
+    /// Stop recording after this many datagrams
+    #[arg(long, short = 'n')]
+    pub count: Option<u64>,
+
+    /// Overwrite the capture file if it exists
+    #[arg(long)]
+    pub force: bool,
+
+    /// The capture file to write
+    pub capture_file: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct Replay {
+    /// Destination address, use --broadcast for a broadcast address
+    #[arg(long, short = 'a', default_value = "127.0.0.1")]
+    pub address: String,
+
+    /// Destination UDP port number
+    #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// Allow sending to a broadcast address
+    #[arg(long)]
+    pub broadcast: bool,
+
+    /// Playback speed relative to the recording, e.g. 10 for ten times faster,
+    /// 0 sends everything without delays
+    #[arg(long, short = 's', default_value_t = 1.0, value_parser = parse_speed)]
+    pub speed: f64,
+
+    /// The capture file to read
+    pub capture_file: PathBuf,
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    let speed: f64 = s.parse().map_err(|e| format!("Invalid speed '{s}'. {e}"))?;
+    if !speed.is_finite() || speed < 0.0 {
+        return Err(format!("Invalid speed '{s}', must be zero or positive"));
+    }
+    Ok(speed)
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct MqttRelay {
+    /// Address
+    #[arg(long, short = 'a', default_value = "0.0.0.0")]
+    pub address: String,
+
+    /// UDP port number
+    #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// MQTT broker host
+    #[arg(long, default_value = "localhost", env = "MQTT_HOST")]
+    pub host: String,
+
+    /// MQTT broker port, usually 8883 with --tls
+    #[arg(long, default_value_t = 1883, env = "MQTT_PORT")]
+    pub broker_port: u16,
+
+    /// MQTT client ID
+    #[arg(long, default_value = "air-gradient-relay")]
+    pub client_id: String,
+
+    /// MQTT username
+    #[arg(long, short = 'u', env = "MQTT_USERNAME", requires = "password")]
+    pub username: Option<String>,
+
+    /// MQTT password
+    #[arg(long, env = "MQTT_PASSWORD", requires = "username")]
+    pub password: Option<String>,
+
+    /// Connect to the broker over TLS
+    #[arg(long)]
+    pub tls: bool,
+
+    /// PEM file of the CA certificate to verify the broker with instead of the
+    /// platform's root certificates
+    #[arg(long, requires = "tls")]
+    pub ca_file: Option<PathBuf>,
+
+    /// Prefix of the device topics
+    #[arg(long, default_value = "air-gradient")]
+    pub topic_prefix: String,
+
+    /// Home Assistant MQTT discovery prefix
+    #[arg(long, default_value = "homeassistant")]
+    pub discovery_prefix: String,
+
+    /// Don't publish Home Assistant MQTT discovery configs
+    #[arg(long)]
+    pub no_discovery: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct Discover {
+    /// Address
+    #[arg(long, short = 'a', default_value = "0.0.0.0")]
+    pub address: String,
+
+    /// UDP port number
+    #[arg(long, short = 'p', default_value_t = broadcast_proto::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// How long to listen for broadcast messages, devices broadcast every few seconds
+    #[arg(long, short = 'd', default_value = "15s", value_parser = humantime::parse_duration)]
+    pub duration: Duration,
+
+    /// Also send discovery requests, devices answer right away
+    /// even before they start broadcasting measurements
+    #[arg(long)]
+    pub query: bool,
+
+    /// Address discovery requests are sent to
+    #[arg(long, default_value = "255.255.255.255")]
+    pub query_address: String,
+
+    /// Discovery protocol UDP port number
+    #[arg(long, default_value_t = discovery_proto::DEFAULT_PORT)]
+    pub discovery_port: u16,
+
+    /// Request the device info from each device found
+    #[arg(long)]
+    pub probe: bool,
+
+    /// Device protocol TCP port number, used for probing
+    #[arg(long, default_value_t = device_proto::DEFAULT_PORT)]
+    pub device_port: u16,
+
+    /// Output format
+    #[arg(long, short = 'f', default_value_t = Format::Text)]
+    pub format: Format,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -74,10 +437,13 @@ pub enum Device {
     Info(DeviceInfo),
 
     /// Reboot a device
-    Reboot(CommonDeviceOpts),
+    Reboot(DeviceReboot),
 
     /// Perform a firmware update
     Update(DeviceUpdate),
+
+    /// Roll back to the firmware image in the other slot
+    Rollback(DeviceRollback),
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -90,10 +456,80 @@ pub struct DeviceUpdate {
     #[arg(long = "cache")]
     pub cache_dir: Option<PathBuf>,
 
+    /// Always erase the slot and write the whole image instead of resuming
+    /// a previous transfer
+    #[arg(long)]
+    pub no_resume: bool,
+
+    /// Compress the image chunks, the device decompresses them before writing
+    /// to FLASH. Ignored if the device doesn't support compressed writes.
+    #[arg(long)]
+    pub compress: bool,
+
+    /// Send the image as a delta against the image in the active slot,
+    /// which is read back from the device unless --delta-from is given.
+    /// Ignored if the device doesn't support delta writes.
+    #[arg(long)]
+    pub delta: bool,
+
+    /// Image archive the active slot's image was built from, used as the
+    /// delta source instead of reading it back from the device. Implies --delta.
+    #[arg(long, value_name = "ARCHIVE")]
+    pub delta_from: Option<PathBuf>,
+
+    /// Allow installing an older firmware version than the one running on the device
+    #[arg(long)]
+    pub allow_downgrade: bool,
+
+    /// Allow installing an archive without a manifest, its firmware version and
+    /// compatibility with the device can't be checked
+    #[arg(long)]
+    pub allow_unverified_archive: bool,
+
+    /// Allow updating a device that doesn't report its bootloader version,
+    /// older bootloaders don't
+    #[arg(long)]
+    pub allow_unknown_bootloader: bool,
+
     /// Path to the 'agp_images.cpio' archive file
     pub agp_images_cpio_file: PathBuf,
 }
 
+#[derive(Parser, Debug, Clone)]
+pub struct DeviceReboot {
+    #[clap(flatten)]
+    pub common: CommonDeviceOpts,
+
+    /// Reboot into the given slot, 'current' or 'other'.
+    /// The other slot is only booted once, the active slot is unchanged
+    /// and the next reset returns to it.
+    #[arg(long, conflicts_with = "recovery")]
+    pub slot: Option<RebootSlot>,
+
+    /// Reboot into the bootloader's network recovery mode
+    #[arg(long)]
+    pub recovery: bool,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct DeviceRollback {
+    #[clap(flatten)]
+    pub common: CommonDeviceOpts,
+
+    /// Don't ask for confirmation
+    #[arg(long, short = 'y')]
+    pub yes: bool,
+
+    /// Image archive the other slot's image was built from, the rollback is only
+    /// done if the slot matches it or the boot history shows a committed boot of it
+    #[arg(long, value_name = "ARCHIVE")]
+    pub archive: Option<PathBuf>,
+
+    /// Roll back even if the other slot's image can't be verified
+    #[arg(long)]
+    pub force: bool,
+}
+
 #[derive(Parser, Debug, Clone)]
 pub struct DeviceInfo {
     #[clap(flatten)]
@@ -103,6 +539,10 @@ pub struct DeviceInfo {
     /// Can be supplied multiple times.
     #[arg(long = "field-name", short = 'F')]
     pub field_names: Vec<String>,
+
+    /// Also request and print the boot history recorded by the bootloader
+    #[arg(long)]
+    pub history: bool,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -124,6 +564,92 @@ pub struct CommonDeviceOpts {
     pub format: Format,
 }
 
+#[derive(Parser, Debug, Clone)]
+pub enum Fleet {
+    /// Perform a firmware update on multiple devices
+    Update(FleetUpdate),
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct FleetUpdate {
+    /// Device address, optionally with the port ('host:port').
+    /// Can be supplied multiple times.
+    #[arg(long = "address", short = 'a')]
+    pub addresses: Vec<String>,
+
+    /// File listing the devices to update, either the JSON output of
+    /// 'discover --format json' or one address per line. Use '-' for stdin.
+    #[arg(long = "devices", short = 'd', value_name = "FILE")]
+    pub devices_file: Option<PathBuf>,
+
+    /// Device protocol TCP port number, used for addresses without one
+    #[arg(long, short = 'p', default_value_t = device_proto::DEFAULT_PORT)]
+    pub port: u16,
+
+    /// Maximum number of devices updated at the same time
+    #[arg(long, short = 'j', default_value = "4")]
+    pub concurrency: NonZeroUsize,
+
+    /// Stop starting new updates once this many devices failed
+    #[arg(long, default_value = "1")]
+    pub max_failures: NonZeroUsize,
+
+    /// Also update devices already running the archive's firmware version
+    #[arg(long)]
+    pub reinstall: bool,
+
+    /// Always erase the slot and write the whole image instead of resuming
+    /// a previous transfer
+    #[arg(long)]
+    pub no_resume: bool,
+
+    /// Compress the image chunks, see 'device update --compress'
+    #[arg(long)]
+    pub compress: bool,
+
+    /// Send the image as a delta against the image in the active slot,
+    /// see 'device update --delta'
+    #[arg(long)]
+    pub delta: bool,
+
+    /// Allow installing an older firmware version than the one running on a device
+    #[arg(long)]
+    pub allow_downgrade: bool,
+
+    /// Allow installing an archive without a manifest, its firmware version and
+    /// compatibility with a device can't be checked
+    #[arg(long)]
+    pub allow_unverified_archive: bool,
+
+    /// Allow updating a device that doesn't report its bootloader version,
+    /// older bootloaders don't
+    #[arg(long)]
+    pub allow_unknown_bootloader: bool,
+
+    /// Output format
+    #[arg(long, short = 'f', default_value_t = Format::Text)]
+    pub format: Format,
+
+    /// Path to the 'agp_images.cpio' archive file
+    pub agp_images_cpio_file: PathBuf,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub enum Archive {
+    /// Validate an archive and print its manifest
+    Inspect(ArchiveInspect),
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct ArchiveInspect {
+    /// Output format
+    #[arg(long, short = 'f', default_value_t = Format::Text)]
+    pub format: Format,
+
+    /// Path to the 'agp_images.cpio' archive file
+    pub agp_images_cpio_file: PathBuf,
+}
+
 #[derive(Parser, Debug, Clone)]
 pub struct ExtractArchive {
     /// Output directory to extract to
@@ -134,6 +660,104 @@ pub struct ExtractArchive {
     pub agp_images_cpio_file: PathBuf,
 }
 
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum RebootSlot {
+    Current,
+    Other,
+}
+
+impl FromStr for RebootSlot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "current" => RebootSlot::Current,
+            "other" => RebootSlot::Other,
+            _ => return Err(format!("Invalid slot '{s}'")),
+        })
+    }
+}
+
+impl fmt::Display for RebootSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RebootSlot::Current => f.write_str("current"),
+            RebootSlot::Other => f.write_str("other"),
+        }
+    }
+}
+
+#[derive(Parser, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum ListenFormat {
+    #[default]
+    Text,
+    Csv,
+    Jsonl,
+}
+
+impl ListenFormat {
+    pub fn is_text(&self) -> bool {
+        matches!(self, ListenFormat::Text)
+    }
+}
+
+impl FromStr for ListenFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "text" => ListenFormat::Text,
+            "csv" => ListenFormat::Csv,
+            "jsonl" => ListenFormat::Jsonl,
+            _ => return Err(format!("Invalid format '{s}'")),
+        })
+    }
+}
+
+impl fmt::Display for ListenFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenFormat::Text => f.write_str("text"),
+            ListenFormat::Csv => f.write_str("csv"),
+            ListenFormat::Jsonl => f.write_str("jsonl"),
+        }
+    }
+}
+
+#[derive(Parser, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum InfluxOutput {
+    /// InfluxDB 2.x HTTP API
+    #[default]
+    Influx2,
+    /// InfluxDB 1.x HTTP API, also spoken by VictoriaMetrics
+    Influx1,
+    /// Raw line protocol
+    LineProtocol,
+}
+
+impl FromStr for InfluxOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "influx2" => InfluxOutput::Influx2,
+            "influx1" => InfluxOutput::Influx1,
+            "line-protocol" => InfluxOutput::LineProtocol,
+            _ => return Err(format!("Invalid output '{s}'")),
+        })
+    }
+}
+
+impl fmt::Display for InfluxOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InfluxOutput::Influx2 => f.write_str("influx2"),
+            InfluxOutput::Influx1 => f.write_str("influx1"),
+            InfluxOutput::LineProtocol => f.write_str("line-protocol"),
+        }
+    }
+}
+
 #[derive(Parser, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
 pub enum Format {
     #[default]
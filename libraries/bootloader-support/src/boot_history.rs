@@ -0,0 +1,343 @@
+use crate::{BootSlot, ResetReason};
+use core::fmt;
+use wire_protocols::FirmwareVersion;
+
+/// Number of boots kept in the boot history.
+pub const BOOT_HISTORY_LEN: usize = 8;
+
+/// A single boot, recorded by the bootloader.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct BootHistoryEntry {
+    /// The slot that was selected for boot
+    pub slot: BootSlot,
+    /// The reset reason seen by the bootloader
+    pub reset_reason: ResetReason,
+    /// The boot was a trial boot of a pending update
+    pub update_attempted: bool,
+    /// The boot committed a previously attempted update
+    pub update_committed: bool,
+    /// The boot was a trial boot of the other slot for a rollback
+    pub rolled_back: bool,
+    /// The boot was a one-off boot of the other slot, the active slot didn't change
+    pub booted_once: bool,
+    /// The firmware version, reported by the application once it has started
+    pub firmware_version: Option<FirmwareVersion>,
+}
+
+impl BootHistoryEntry {
+    /// Size of an entry in the boot configuration.
+    /// * byte 0: slot
+    /// * byte 1: reset reason code
+    /// * byte 2: flags
+    /// * byte 3: reserved
+    /// * bytes 4..8: raw reset reason RCC CSR bits
+    /// * bytes 8..14: firmware version major, minor, patch (0xFFFF when not reported)
+    /// * bytes 14..16: reserved
+    pub const WIRE_SIZE: usize = 16;
+
+    const EMPTY_SLOT: u8 = 0xFF;
+    const FLAG_UPDATE_ATTEMPTED: u8 = 1 << 0;
+    const FLAG_UPDATE_COMMITTED: u8 = 1 << 1;
+    const FLAG_ROLLED_BACK: u8 = 1 << 2;
+    const FLAG_BOOTED_ONCE: u8 = 1 << 3;
+    const VERSION_NOT_REPORTED: u16 = 0xFFFF;
+
+    pub const fn new(slot: BootSlot, reset_reason: ResetReason) -> Self {
+        BootHistoryEntry {
+            slot,
+            reset_reason,
+            update_attempted: false,
+            update_committed: false,
+            rolled_back: false,
+            booted_once: false,
+            firmware_version: None,
+        }
+    }
+
+    pub fn to_le_bytes(self) -> [u8; Self::WIRE_SIZE] {
+        let mut bytes = [0_u8; Self::WIRE_SIZE];
+        let (code, rcc_csr) = self.reset_reason.into_parts();
+        bytes[0] = match self.slot {
+            BootSlot::Slot0 => 0,
+            BootSlot::Slot1 => 1,
+        };
+        bytes[1] = code;
+        if self.update_attempted {
+            bytes[2] |= Self::FLAG_UPDATE_ATTEMPTED;
+        }
+        if self.update_committed {
+            bytes[2] |= Self::FLAG_UPDATE_COMMITTED;
+        }
+        if self.rolled_back {
+            bytes[2] |= Self::FLAG_ROLLED_BACK;
+        }
+        if self.booted_once {
+            bytes[2] |= Self::FLAG_BOOTED_ONCE;
+        }
+        bytes[4..8].copy_from_slice(&rcc_csr.to_le_bytes());
+        let (major, minor, patch) = match self.firmware_version {
+            Some(v) => (v.major, v.minor, v.patch),
+            None => (
+                Self::VERSION_NOT_REPORTED,
+                Self::VERSION_NOT_REPORTED,
+                Self::VERSION_NOT_REPORTED,
+            ),
+        };
+        bytes[8..10].copy_from_slice(&major.to_le_bytes());
+        bytes[10..12].copy_from_slice(&minor.to_le_bytes());
+        bytes[12..14].copy_from_slice(&patch.to_le_bytes());
+        bytes
+    }
+
+    /// Returns `None` if the bytes don't contain an entry (unused or erased).
+    pub fn from_le_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::WIRE_SIZE {
+            return None;
+        }
+        let slot = match bytes[0] {
+            0 => BootSlot::Slot0,
+            1 => BootSlot::Slot1,
+            _ => return None,
+        };
+        let rcc_csr = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        let major = u16::from_le_bytes([bytes[8], bytes[9]]);
+        let minor = u16::from_le_bytes([bytes[10], bytes[11]]);
+        let patch = u16::from_le_bytes([bytes[12], bytes[13]]);
+        let firmware_version = if major == Self::VERSION_NOT_REPORTED
+            && minor == Self::VERSION_NOT_REPORTED
+            && patch == Self::VERSION_NOT_REPORTED
+        {
+            None
+        } else {
+            Some(FirmwareVersion::new(major, minor, patch))
+        };
+        Some(BootHistoryEntry {
+            slot,
+            reset_reason: ResetReason::from_parts(bytes[1], rcc_csr),
+            update_attempted: (bytes[2] & Self::FLAG_UPDATE_ATTEMPTED) != 0,
+            update_committed: (bytes[2] & Self::FLAG_UPDATE_COMMITTED) != 0,
+            rolled_back: (bytes[2] & Self::FLAG_ROLLED_BACK) != 0,
+            booted_once: (bytes[2] & Self::FLAG_BOOTED_ONCE) != 0,
+            firmware_version,
+        })
+    }
+}
+
+impl fmt::Display for BootHistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}", self.slot, self.reset_reason)?;
+        match self.firmware_version {
+            Some(v) => write!(f, ", firmware {v}")?,
+            None => f.write_str(", firmware unknown")?,
+        }
+        if self.update_attempted {
+            f.write_str(", update attempted")?;
+        }
+        if self.update_committed {
+            f.write_str(", update committed")?;
+        }
+        if self.rolled_back {
+            f.write_str(", rolled back")?;
+        }
+        if self.booted_once {
+            f.write_str(", booted once")?;
+        }
+        Ok(())
+    }
+}
+
+/// The most recent boots, newest first.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub struct BootHistory {
+    entries: [Option<BootHistoryEntry>; BOOT_HISTORY_LEN],
+}
+
+impl BootHistory {
+    pub const WIRE_SIZE: usize = BOOT_HISTORY_LEN * BootHistoryEntry::WIRE_SIZE;
+
+    pub const fn new() -> Self {
+        BootHistory {
+            entries: [None; BOOT_HISTORY_LEN],
+        }
+    }
+
+    /// Add a new boot, dropping the oldest entry if the history is full.
+    pub fn push(&mut self, entry: BootHistoryEntry) {
+        self.entries.copy_within(..BOOT_HISTORY_LEN - 1, 1);
+        self.entries[0] = Some(entry);
+    }
+
+    pub fn latest(&self) -> Option<&BootHistoryEntry> {
+        self.entries[0].as_ref()
+    }
+
+    pub fn latest_mut(&mut self) -> Option<&mut BootHistoryEntry> {
+        self.entries[0].as_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries[0].is_none()
+    }
+
+    /// Iterate over the entries, newest first.
+    pub fn iter(&self) -> impl Iterator<Item = &BootHistoryEntry> {
+        self.entries.iter().map_while(|e| e.as_ref())
+    }
+
+    /// The most recent firmware version reported by the application in the given slot.
+    pub fn last_firmware_version(&self, slot: BootSlot) -> Option<FirmwareVersion> {
+        self.iter()
+            .filter(|e| e.slot == slot)
+            .find_map(|e| e.firmware_version)
+    }
+
+    /// Whether the given slot was booted as the active slot, as opposed to a trial
+    /// or one-off boot, or became the active slot by committing an update.
+    pub fn has_committed_boot(&self, slot: BootSlot) -> bool {
+        self.iter().any(|e| {
+            e.slot == slot && (e.update_committed || !(e.update_attempted || e.booted_once))
+        })
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; Self::WIRE_SIZE] {
+        let mut bytes = [0_u8; Self::WIRE_SIZE];
+        for (idx, entry) in self.entries.iter().enumerate() {
+            let offset = idx * BootHistoryEntry::WIRE_SIZE;
+            let chunk = &mut bytes[offset..offset + BootHistoryEntry::WIRE_SIZE];
+            match entry {
+                Some(e) => chunk.copy_from_slice(&e.to_le_bytes()),
+                None => chunk[0] = BootHistoryEntry::EMPTY_SLOT,
+            }
+        }
+        bytes
+    }
+
+    pub fn from_le_bytes(bytes: &[u8]) -> Self {
+        let mut history = Self::new();
+        for (idx, entry) in history.entries.iter_mut().enumerate() {
+            let offset = idx * BootHistoryEntry::WIRE_SIZE;
+            *entry = bytes
+                .get(offset..offset + BootHistoryEntry::WIRE_SIZE)
+                .and_then(BootHistoryEntry::from_le_bytes);
+            if entry.is_none() {
+                break;
+            }
+        }
+        history
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(n: u16) -> BootHistoryEntry {
+        BootHistoryEntry {
+            slot: if n & 1 == 0 {
+                BootSlot::Slot0
+            } else {
+                BootSlot::Slot1
+            },
+            reset_reason: ResetReason::Unknown(0xAB00 + u32::from(n)),
+            update_attempted: n & 2 != 0,
+            update_committed: n & 4 != 0,
+            rolled_back: n & 8 != 0,
+            booted_once: n & 16 != 0,
+            firmware_version: Some(FirmwareVersion::new(n, n + 1, n + 2)),
+        }
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let e = entry(3);
+        assert_eq!(BootHistoryEntry::from_le_bytes(&e.to_le_bytes()), Some(e));
+
+        let e = BootHistoryEntry::new(BootSlot::Slot1, ResetReason::IndependentWatchdogReset);
+        assert_eq!(BootHistoryEntry::from_le_bytes(&e.to_le_bytes()), Some(e));
+
+        assert_eq!(BootHistoryEntry::from_le_bytes(&[0xFF; 16]), None);
+        assert_eq!(BootHistoryEntry::from_le_bytes(&[0x00; 15]), None);
+    }
+
+    #[test]
+    fn push_drops_oldest() {
+        let mut h = BootHistory::new();
+        assert!(h.is_empty());
+        assert_eq!(h.len(), 0);
+        for n in 0..(BOOT_HISTORY_LEN as u16 + 2) {
+            h.push(entry(n));
+        }
+        assert_eq!(h.len(), BOOT_HISTORY_LEN);
+        assert_eq!(h.latest(), Some(&entry(BOOT_HISTORY_LEN as u16 + 1)));
+        assert_eq!(h.iter().last(), Some(&entry(2)));
+    }
+
+    #[test]
+    fn history_round_trip() {
+        let mut h = BootHistory::new();
+        assert_eq!(BootHistory::from_le_bytes(&h.to_le_bytes()), h);
+        h.push(entry(1));
+        h.push(entry(2));
+        h.latest_mut().unwrap().firmware_version = None;
+        assert_eq!(BootHistory::from_le_bytes(&h.to_le_bytes()), h);
+        assert_eq!(
+            BootHistory::from_le_bytes(&[0xFF; BootHistory::WIRE_SIZE]).len(),
+            0
+        );
+    }
+
+    #[test]
+    fn last_firmware_version() {
+        let mut h = BootHistory::new();
+        assert_eq!(h.last_firmware_version(BootSlot::Slot0), None);
+        h.push(entry(0));
+        h.push(entry(1));
+        h.push(entry(2));
+        h.latest_mut().unwrap().firmware_version = None;
+        assert_eq!(
+            h.last_firmware_version(BootSlot::Slot0),
+            entry(0).firmware_version
+        );
+        assert_eq!(
+            h.last_firmware_version(BootSlot::Slot1),
+            entry(1).firmware_version
+        );
+    }
+
+    #[test]
+    fn has_committed_boot() {
+        let mut h = BootHistory::new();
+        assert!(!h.has_committed_boot(BootSlot::Slot0));
+
+        let mut trial = BootHistoryEntry::new(BootSlot::Slot1, ResetReason::SoftwareReset);
+        trial.update_attempted = true;
+        h.push(trial);
+        let mut once = BootHistoryEntry::new(BootSlot::Slot1, ResetReason::SoftwareReset);
+        once.booted_once = true;
+        h.push(once);
+        h.push(BootHistoryEntry::new(
+            BootSlot::Slot0,
+            ResetReason::PowerOnReset,
+        ));
+        assert!(h.has_committed_boot(BootSlot::Slot0));
+        assert!(!h.has_committed_boot(BootSlot::Slot1));
+
+        let mut commit = BootHistoryEntry::new(BootSlot::Slot1, ResetReason::SoftwareReset);
+        commit.update_committed = true;
+        h.push(commit);
+        assert!(h.has_committed_boot(BootSlot::Slot1));
+    }
+
+    #[test]
+    fn reset_reason_parts() {
+        for code in 0..=8_u8 {
+            let r = ResetReason::from_parts(code, 0x1234);
+            let (c, raw) = r.into_parts();
+            assert_eq!(ResetReason::from_parts(c, raw), r);
+        }
+    }
+}
@@ -3,10 +3,16 @@
 
 use core::{fmt, str};
 
+mod boot_history;
 mod reset_reason;
 
+pub use self::boot_history::{BootHistory, BootHistoryEntry, BOOT_HISTORY_LEN};
 pub use self::reset_reason::ResetReason;
 
+/// Hardware variant the bootloader and firmware are built for, firmware
+/// images are only compatible with devices reporting the same variant
+pub const HARDWARE: &str = "air-gradient-pro-stm32f411";
+
 pub const FLASH_BASE_ADDRESS: u32 = 0x0800_0000;
 
 pub const FLASH_SLOT0_ADDRESS: u32 = FLASH_BASE_ADDRESS + FLASH_SLOT0_SECTOR_OFFSET;
@@ -75,6 +81,20 @@ impl fmt::Display for BootSlot {
     }
 }
 
+/// Whether the full boot config sector can be erased to write a record for `new_slot`.
+///
+/// A power loss between the erase and the program leaves no valid record, and the
+/// bootloader then boots `default_slot`. So the erase is only safe when that's either
+/// the slot on flash or the new one, which always holds for a slot change.
+/// Otherwise the record can't be written until the next slot change.
+pub fn boot_config_erase_is_safe(
+    default_slot: BootSlot,
+    stored_slot: Option<BootSlot>,
+    new_slot: BootSlot,
+) -> bool {
+    new_slot == default_slot || stored_slot.map(|s| s == default_slot).unwrap_or(true)
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct ParseBootSlotError;
 
@@ -131,6 +151,19 @@ mod tests {
         assert_eq!(BootSlot::Slot1.contains(0x0804_0000 + (194 * 1024)), false);
     }
 
+    #[test]
+    fn boot_config_erase() {
+        use BootSlot::*;
+        // Plain boots
+        assert!(boot_config_erase_is_safe(Slot0, Some(Slot0), Slot0));
+        assert!(!boot_config_erase_is_safe(Slot0, Some(Slot1), Slot1));
+        // Slot changes
+        assert!(boot_config_erase_is_safe(Slot0, Some(Slot0), Slot1));
+        assert!(boot_config_erase_is_safe(Slot0, Some(Slot1), Slot0));
+        // No valid record, the default slot is already in use
+        assert!(boot_config_erase_is_safe(Slot0, None, Slot1));
+    }
+
     #[test]
     fn from_str() {
         assert_eq!(BootSlot::from_str(" SLOT0  "), Ok(BootSlot::Slot0));
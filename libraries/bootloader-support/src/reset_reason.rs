@@ -38,3 +38,36 @@ impl fmt::Display for ResetReason {
         }
     }
 }
+
+impl ResetReason {
+    /// Returns the storage representation of the reset reason, a reason code
+    /// and the raw RCC CSR bits (only non-zero for `ResetReason::Unknown`).
+    pub fn into_parts(self) -> (u8, u32) {
+        use ResetReason::*;
+        match self {
+            LowPowerReset => (1, 0),
+            WindowWatchdogReset => (2, 0),
+            IndependentWatchdogReset => (3, 0),
+            SoftwareReset => (4, 0),
+            PowerOnReset => (5, 0),
+            PinReset => (6, 0),
+            BrownoutReset => (7, 0),
+            Unknown(rcc_csr) => (0, rcc_csr),
+        }
+    }
+
+    /// Inverse of `ResetReason::into_parts`.
+    pub fn from_parts(code: u8, rcc_csr: u32) -> Self {
+        use ResetReason::*;
+        match code {
+            1 => LowPowerReset,
+            2 => WindowWatchdogReset,
+            3 => IndependentWatchdogReset,
+            4 => SoftwareReset,
+            5 => PowerOnReset,
+            6 => PinReset,
+            7 => BrownoutReset,
+            _ => Unknown(rcc_csr),
+        }
+    }
+}
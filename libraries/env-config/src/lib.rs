@@ -1,7 +1,7 @@
 use log::LevelFilter;
 use smoltcp::wire::EthernetAddress;
 use std::{env, fs, io::Write, net::Ipv4Addr, path::PathBuf, str::FromStr};
-use wire_protocols::{broadcast, device, DeviceId};
+use wire_protocols::{broadcast, device, discovery, DeviceId};
 
 const DEFAULT_IP_ADDRESS: &str = "192.168.1.38";
 const DEFAULT_MAC_ADDRESS: &str = "02:00:04:03:07:02";
@@ -9,6 +9,7 @@ const DEFAULT_DEVICE_ID: u16 = DeviceId::DEFAULT.0;
 const DEFAULT_BROADCAST_PORT: u16 = broadcast::DEFAULT_PORT;
 const DEFAULT_BROADCAST_ADDRESS: &str = "255.255.255.255";
 const DEFAULT_DEVICE_PORT: u16 = device::DEFAULT_PORT;
+const DEFAULT_DISCOVERY_PORT: u16 = discovery::DEFAULT_PORT;
 const DEFAULT_LOG_LEVEL: &str = "INFO";
 
 pub fn generate_env_config_constants() {
@@ -101,6 +102,19 @@ pub fn generate_env_config_constants() {
     writeln!(&mut config_file, "pub const DEVICE_PORT: u16 = {dev_port};").unwrap();
     println!("cargo:rerun-if-env-changed=AIR_GRADIENT_DEVICE_PORT");
 
+    let disc_port: u16 = get_env_or_default(
+        "AIR_GRADIENT_DISCOVERY_PORT",
+        DEFAULT_DISCOVERY_PORT.to_string(),
+    )
+    .parse()
+    .unwrap();
+    writeln!(
+        &mut config_file,
+        "pub const DISCOVERY_PORT: u16 = {disc_port};"
+    )
+    .unwrap();
+    println!("cargo:rerun-if-env-changed=AIR_GRADIENT_DISCOVERY_PORT");
+
     let max_log_level =
         LevelFilter::from_str(get_env_or_default("AIR_GRADIENT_LOG", DEFAULT_LOG_LEVEL).as_str())
             .unwrap();
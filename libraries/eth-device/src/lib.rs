@@ -1,3 +1,9 @@
+//! smoltcp network device for the ENC28J60 connected to SPI2.
+//!
+//! Shared by the firmware and the bootloader's recovery mode.
+
+#![no_std]
+
 use enc28j60::Enc28j60;
 use log::{debug, error, warn};
 use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
@@ -8,10 +14,10 @@ use stm32f4xx_hal::{
     spi::Spi,
 };
 
-type CsPin = PB12<Output<PushPull>>;
-type IntPin = PA8<Input>;
+pub type CsPin = PB12<Output<PushPull>>;
+pub type IntPin = PA8<Input>;
 //type ResetPin = PB1<Output<PushPull>>;
-type ResetPin = enc28j60::Unconnected;
+pub type ResetPin = enc28j60::Unconnected;
 
 pub type SpiSckPin = PB13<AF5>;
 pub type SpiMisoPin = PB14<AF5>;
@@ -19,7 +25,7 @@ pub type SpiMosiPin = PB15<AF5>;
 pub type SpiPins = (SpiSckPin, SpiMisoPin, SpiMosiPin);
 pub type EthSpi = Spi<SPI2>;
 
-type Drv = Enc28j60<EthSpi, CsPin, IntPin, ResetPin>;
+pub type Drv = Enc28j60<EthSpi, CsPin, IntPin, ResetPin>;
 
 // TODO - add some rx/tx error counters
 /// An ENC28J60 connected to SPI2
@@ -53,8 +59,14 @@ impl<'buf> Eth<'buf> {
 }
 
 impl<'buf> Device for Eth<'buf> {
-    type RxToken<'a> = RxToken<'a> where Self: 'a;
-    type TxToken<'a> = TxToken<'a> where Self: 'a;
+    type RxToken<'a>
+        = RxToken<'a>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = TxToken<'a>
+    where
+        Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
         match self.drv.next_packet() {
@@ -1,21 +1,26 @@
 #![no_std]
 #![forbid(unsafe_code)]
 
-use bootloader_support::{BootSlot, ResetReason};
+use bootloader_support::{BootHistory, BootSlot, ResetReason};
 use core::fmt::{self, Write};
 use log::{debug, warn};
 use smoltcp::socket::tcp::{self, Socket as TcpSocket};
 use wire_protocols::{
+    compression::{self, Decoder},
     device::{
-        Command, MemoryEraseRequest, MemoryReadRequest, MemoryRegion, MemoryWriteRequest,
-        StatusCode,
+        Command, MemoryEraseRequest, MemoryHashRequest, MemoryHasher, MemoryReadRequest,
+        MemoryRegion, MemoryWriteRequest, RebootTarget, RequestId, StatusCode,
+        REQUEST_ID_WIRE_SIZE,
     },
     DeviceId, DeviceSerialNumber, FirmwareVersion, ProtocolVersion,
 };
 
 pub trait Device {
     fn info(&self) -> &DeviceInfo;
+    fn boot_history(&self) -> &BootHistory;
     fn perform_reboot(&mut self) -> !;
+    fn perform_reboot_into(&mut self, target: RebootTarget) -> !;
+    fn perform_rollback(&mut self) -> !;
     fn complete_update_and_perform_reboot(&mut self) -> !;
     fn update_progress_changed(&mut self, _status: FirmwareUpdateStatus, _bytes_written: usize) {}
     // TODO
@@ -41,7 +46,11 @@ pub enum Error {
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct DeviceInfo {
     pub protocol_version: ProtocolVersion,
-    pub firmware_version: FirmwareVersion,
+    /// None in the bootloader's recovery mode, no firmware is running
+    pub firmware_version: Option<FirmwareVersion>,
+    /// None when the bootloader doesn't report its version
+    pub bootloader_version: Option<FirmwareVersion>,
+    pub hardware: &'static str,
     pub device_id: DeviceId,
     pub device_serial_number: DeviceSerialNumber,
     pub mac_address: [u8; 6],
@@ -51,6 +60,18 @@ pub struct DeviceInfo {
     pub git_commit: &'static str,
 }
 
+/// Formats an optional version as a JSON string or null
+struct JsonVersion(Option<FirmwareVersion>);
+
+impl fmt::Display for JsonVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(v) => write!(f, "\"{v}\""),
+            None => f.write_str("null"),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub enum FirmwareUpdateStatus {
     InProgress,
@@ -62,17 +83,35 @@ pub enum FirmwareUpdateStatus {
 pub const UPDATE_TICKS_TO_REBOOT: usize = 10;
 pub const UPDATE_TICKS_TO_CLOSE: usize = UPDATE_TICKS_TO_REBOOT / 2;
 
+/// Upper bound on the pipelined tagged writes processed in a single update
+pub const MAX_TAGGED_WRITES_PER_UPDATE: usize = 4;
+
 type RemainingMemoryWriteRegion = MemoryRegion;
 
+struct CompressedWrite {
+    /// Where the decompressed data goes
+    region: MemoryRegion,
+    /// Compressed bytes yet to be received
+    remaining: usize,
+    /// Delta blocks can copy from the active slot
+    delta: bool,
+}
+
 pub struct UpdateManager {
     port: u16,
     update_complete: bool,
     update_in_progress: bool,
     write_in_progress: Option<RemainingMemoryWriteRegion>,
+    // Request id of the in-progress tagged write, sent back with its status
+    write_tag: Option<RequestId>,
+    compressed_write_in_progress: Option<CompressedWrite>,
+    decoder: Decoder,
     bytes_written: usize,
     // Only used to send a progress update callback on write->read/verify state change
     last_cmd: Option<Command>,
     ticks_until_reboot: Option<usize>,
+    reboot_target: Option<RebootTarget>,
+    rollback: bool,
 }
 
 impl UpdateManager {
@@ -82,9 +121,14 @@ impl UpdateManager {
             update_complete: false,
             update_in_progress: false,
             write_in_progress: None,
+            write_tag: None,
+            compressed_write_in_progress: None,
+            decoder: Decoder::new(),
             bytes_written: 0,
             last_cmd: None,
             ticks_until_reboot: None,
+            reboot_target: None,
+            rollback: false,
         }
     }
 
@@ -97,10 +141,29 @@ impl UpdateManager {
 
         self.manage_socket(socket)?;
 
-        if let Some(remaining_region) = self.write_in_progress.take() {
-            self.manage_in_progress_write(remaining_region, device, socket)?;
-        } else if let Some(cmd) = self.recv_cmd(socket)? {
-            self.process_cmd(cmd, device, socket)?;
+        for _ in 0..MAX_TAGGED_WRITES_PER_UPDATE {
+            if let Some(remaining_region) = self.write_in_progress.take() {
+                self.manage_in_progress_write(remaining_region, device, socket)?;
+            } else if let Some(write) = self.compressed_write_in_progress.take() {
+                self.handle_compressed_write_data(write, device, socket)?;
+            } else if let Some(cmd) = self.recv_cmd(socket)? {
+                self.process_cmd(cmd, device, socket)?;
+            } else {
+                break;
+            }
+
+            // Only completed tagged writes are followed by the next queued command,
+            // everything else gets an update cycle to itself
+            if self.write_is_in_progress()
+                || !matches!(
+                    self.last_cmd,
+                    Some(
+                        Command::WriteMemoryTagged | Command::WriteCompressed | Command::WriteDelta
+                    )
+                )
+            {
+                break;
+            }
         }
 
         Ok(())
@@ -117,6 +180,10 @@ impl UpdateManager {
 
                 if self.update_complete {
                     device.complete_update_and_perform_reboot();
+                } else if self.rollback {
+                    device.perform_rollback();
+                } else if let Some(target) = self.reboot_target {
+                    device.perform_reboot_into(target);
                 } else {
                     device.perform_reboot();
                 }
@@ -126,13 +193,15 @@ impl UpdateManager {
 
     // TODO - doing this means we drop the tx queue, so error/status will never
     // reach the client, they just see a dropped connection
+    // NOTE: the update state (update_in_progress and bytes_written) is kept so the
+    // client can resume the transfer on a new connection, it's reset by the next erase
     fn abort_in_progress(&mut self, socket: &mut TcpSocket) {
-        if self.write_in_progress.is_some() {
+        if self.write_is_in_progress() {
             warn!("In-progress write will be aborted");
         }
 
         if self.update_in_progress && !self.update_complete {
-            warn!("In-progress update will be aborted");
+            warn!("In-progress update will be paused");
 
             self.ticks_until_reboot = None;
             self.update_complete = false;
@@ -148,19 +217,19 @@ impl UpdateManager {
             socket.recv_queue(),
             socket.recv_capacity(),
         );
-        self.update_in_progress = false;
         self.write_in_progress = None;
+        self.write_tag = None;
+        self.compressed_write_in_progress = None;
         // Don't clear update_complete, it's needed by manage_reboot_schedule
         // in case the connection drops after all is done, that's ok
         //self.update_complete = false;
-        self.bytes_written = 0;
         self.last_cmd = None;
         socket.abort();
     }
 
     fn manage_socket(&mut self, socket: &mut TcpSocket) -> Result<()> {
         if !socket.is_open() {
-            if self.update_in_progress || self.write_in_progress.is_some() {
+            if self.write_is_in_progress() {
                 self.abort_in_progress(socket);
             }
 
@@ -187,6 +256,33 @@ impl UpdateManager {
         Ok(())
     }
 
+    fn write_is_in_progress(&self) -> bool {
+        self.write_in_progress.is_some() || self.compressed_write_in_progress.is_some()
+    }
+
+    /// Tagged writes have their request id follow the status
+    fn send_write_status(&mut self, status: StatusCode, socket: &mut TcpSocket) -> Result<()> {
+        self.send_status(status, socket)?;
+        if let Some(id) = self.write_tag.take() {
+            if socket.can_send() {
+                socket.send_slice(&id.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The largest tagged write that fits in half of the recv buffer, so the
+    /// next write can be received while the current one is written
+    fn write_chunk_size(socket: &TcpSocket) -> usize {
+        const HEADER_SIZE: usize =
+            Command::WIRE_SIZE + REQUEST_ID_WIRE_SIZE + MemoryRegion::WIRE_SIZE;
+        let size = (socket.recv_capacity() / 2).saturating_sub(HEADER_SIZE) & !0x3;
+        size.clamp(
+            MemoryRegion::MAX_CHUCK_SIZE,
+            MemoryRegion::MAX_WRITE_CHUNK_SIZE,
+        )
+    }
+
     fn manage_in_progress_write<D: Device>(
         &mut self,
         remaining_region: MemoryRegion,
@@ -228,6 +324,16 @@ impl UpdateManager {
             match Command::from_le_bytes(peeked_data) {
                 Some(Command::ReadMemory) => return Ok(CMD_AND_REGION_SIZE),
                 Some(Command::WriteMemory) => return Ok(CMD_AND_REGION_SIZE),
+                Some(Command::WriteMemoryTagged) => {
+                    return Ok(CMD_AND_REGION_SIZE + REQUEST_ID_WIRE_SIZE)
+                }
+                Some(Command::WriteCompressed | Command::WriteDelta) => {
+                    return Ok(CMD_AND_REGION_SIZE + REQUEST_ID_WIRE_SIZE + 4)
+                }
+                Some(Command::HashMemory) => return Ok(CMD_AND_REGION_SIZE),
+                Some(Command::RebootInto) => {
+                    return Ok(Command::WIRE_SIZE + RebootTarget::WIRE_SIZE)
+                }
                 _ => (),
             }
         }
@@ -246,9 +352,11 @@ impl UpdateManager {
             Command::Info => {
                 let dev_info = device.info();
                 self.send_status(StatusCode::Success, socket)?;
-                writeln!(socket, "{{\"protocol_version\": \"{}\", \"firmware_version\": \"{}\", \"device_id\": {}, \"device_serial_number\": \"{:X}\", \"mac_address\": {:?}, \"active_boot_slot\": \"{}\", \"reset_reason\": \"{}\", \"built_time_utc\": \"{}\", \"git_commit\": \"{}\"}}",
+                writeln!(socket, "{{\"protocol_version\": \"{}\", \"firmware_version\": {}, \"bootloader_version\": {}, \"hardware\": \"{}\", \"device_id\": {}, \"device_serial_number\": \"{:X}\", \"mac_address\": {:?}, \"active_boot_slot\": \"{}\", \"reset_reason\": \"{}\", \"built_time_utc\": \"{}\", \"git_commit\": \"{}\", \"write_chunk_size\": {}, \"memory_hashing\": true, \"compressed_writes\": true, \"delta_writes\": true}}",
                     dev_info.protocol_version,
-                    dev_info.firmware_version,
+                    JsonVersion(dev_info.firmware_version),
+                    JsonVersion(dev_info.bootloader_version),
+                    dev_info.hardware,
                     dev_info.device_id,
                     dev_info.device_serial_number,
                     dev_info.mac_address,
@@ -256,11 +364,12 @@ impl UpdateManager {
                     dev_info.reset_reason,
                     dev_info.built_time_utc,
                     dev_info.git_commit,
+                    Self::write_chunk_size(socket),
                 )?;
 
                 socket.close();
 
-                if self.update_in_progress || self.write_in_progress.is_some() {
+                if self.write_is_in_progress() {
                     device
                         .update_progress_changed(FirmwareUpdateStatus::Aborted, self.bytes_written);
                     self.abort_in_progress(socket);
@@ -293,6 +402,56 @@ impl UpdateManager {
 
                 self.handle_write_req_data(mem_region, device, socket)?;
             }
+            Command::WriteMemoryTagged => {
+                let id = self.read_u32(socket)?;
+                let mem_region = self.read_mem_region(socket)?;
+                debug!(
+                    "Tagged write {id} region address=0x{:X}, len=0x{:X}",
+                    mem_region.address, mem_region.length
+                );
+
+                self.write_tag = Some(id);
+                if let Err(code) = mem_region.check_write_length() {
+                    warn!("Invalid tagged write region, status {code}");
+                    self.send_write_status(code, socket)?;
+                    self.abort_in_progress(socket);
+                } else {
+                    self.handle_write_req_data(mem_region, device, socket)?;
+                }
+            }
+            Command::WriteCompressed | Command::WriteDelta => {
+                let id = self.read_u32(socket)?;
+                let mem_region = self.read_mem_region(socket)?;
+                let compressed_len = self.read_u32(socket)? as usize;
+                debug!(
+                    "{cmd} {id} region address=0x{:X}, len=0x{:X}, compressed len=0x{:X}",
+                    mem_region.address, mem_region.length, compressed_len
+                );
+
+                self.write_tag = Some(id);
+                let checked_region = mem_region.check_write_length().and_then(|()| {
+                    let max_len = compression::max_compressed_len(mem_region.length as usize);
+                    if compressed_len == 0 || compressed_len > max_len {
+                        Err(StatusCode::DataLengthIncorrect)
+                    } else {
+                        self.decoder
+                            .reset(mem_region.length as usize)
+                            .map_err(|_| StatusCode::LengthTooLong)
+                    }
+                });
+                if let Err(code) = checked_region {
+                    warn!("Invalid compressed write region, status {code}");
+                    self.send_write_status(code, socket)?;
+                    self.abort_in_progress(socket);
+                } else {
+                    let write = CompressedWrite {
+                        region: mem_region,
+                        remaining: compressed_len,
+                        delta: matches!(cmd, Command::WriteDelta),
+                    };
+                    self.handle_compressed_write_data(write, device, socket)?;
+                }
+            }
             Command::EraseMemory => {
                 let mem_region = self.read_mem_region(socket)?;
                 debug!(
@@ -302,6 +461,10 @@ impl UpdateManager {
 
                 match device.erase_memory(mem_region) {
                     Ok(()) => {
+                        // Start of a new update
+                        self.update_in_progress = false;
+                        self.update_complete = false;
+                        self.bytes_written = 0;
                         self.send_status(StatusCode::Success, socket)?;
                     }
                     Err(code) => {
@@ -331,13 +494,88 @@ impl UpdateManager {
                     );
                 }
             }
+            Command::ReadBootHistory => {
+                let history = device.boot_history().to_le_bytes();
+                self.send_status(StatusCode::Success, socket)?;
+                socket.send_slice(&history)?;
+            }
+            Command::RebootInto => {
+                let target = self.read_reboot_target(socket)?;
+                if let RebootTarget::Unknown(t) = target {
+                    warn!("Unknown reboot target {t}");
+                    self.send_status(StatusCode::InvalidArgument, socket)?;
+                } else {
+                    debug!(
+                        "UM: scheduling a reboot into {target} {} update cycles from now",
+                        UPDATE_TICKS_TO_REBOOT
+                    );
+
+                    if self.update_in_progress {
+                        warn!("In-progress update will be aborted");
+                        device.update_progress_changed(
+                            FirmwareUpdateStatus::Aborted,
+                            self.bytes_written,
+                        );
+                        self.update_in_progress = false;
+                    }
+                    self.update_complete = false;
+
+                    self.send_status(StatusCode::Success, socket)?;
+                    self.reboot_target = Some(target);
+                    self.ticks_until_reboot = Some(UPDATE_TICKS_TO_REBOOT);
+                }
+            }
+            Command::Rollback => {
+                debug!(
+                    "UM: scheduling a rollback reboot {} update cycles from now",
+                    UPDATE_TICKS_TO_REBOOT
+                );
+
+                if self.update_in_progress {
+                    warn!("In-progress update will be aborted");
+                    device
+                        .update_progress_changed(FirmwareUpdateStatus::Aborted, self.bytes_written);
+                    self.update_in_progress = false;
+                }
+                self.update_complete = false;
+
+                self.send_status(StatusCode::Success, socket)?;
+                self.rollback = true;
+                self.ticks_until_reboot = Some(UPDATE_TICKS_TO_REBOOT);
+            }
+            Command::HashMemory => {
+                let mem_region = self.read_mem_region(socket)?;
+                debug!(
+                    "Hash region address=0x{:X}, len=0x{:X}",
+                    mem_region.address, mem_region.length
+                );
+
+                match Self::hash_memory(mem_region, device) {
+                    Ok(hash) => {
+                        self.send_status(StatusCode::Success, socket)?;
+                        socket.send_slice(&hash.to_le_bytes())?;
+                    }
+                    Err(code) => {
+                        warn!("Device returned status {code}");
+                        self.send_status(code, socket)?
+                    }
+                }
+            }
             Command::Unknown(_c) => {
                 self.send_status(StatusCode::UnknownCommand, socket)?;
             }
         }
 
         if self.update_in_progress
-            && matches!(self.last_cmd, Some(Command::WriteMemory))
+            && matches!(
+                self.last_cmd,
+                Some(
+                    Command::WriteMemory
+                        | Command::WriteMemoryTagged
+                        | Command::WriteCompressed
+                        | Command::WriteDelta
+                )
+            )
             && matches!(cmd, Command::ReadMemory)
         {
             device.update_progress_changed(FirmwareUpdateStatus::Verifying, self.bytes_written);
@@ -369,6 +607,58 @@ impl UpdateManager {
         }
     }
 
+    /// Hash the region in MemoryRegion::MAX_CHUCK_SIZE reads, each read is
+    /// checked by the device
+    fn hash_memory<D: Device>(req: MemoryHashRequest, device: &mut D) -> StatusCodeResult<u32> {
+        if req.length == 0 {
+            return Err(StatusCode::DataLengthIncorrect);
+        }
+        let end = req
+            .address
+            .checked_add(req.length)
+            .ok_or(StatusCode::DataLengthIncorrect)?;
+
+        let mut hasher = MemoryHasher::new();
+        let mut address = req.address;
+        while address < end {
+            let length = (end - address).min(MemoryRegion::MAX_CHUCK_SIZE as u32);
+            let data = device.read_memory(MemoryRegion { address, length })?;
+            hasher.update(data);
+            address += length;
+        }
+        Ok(hasher.finalize())
+    }
+
+    fn read_u32(&mut self, socket: &mut TcpSocket) -> Result<u32> {
+        let mut bytes = [0_u8; 4];
+        match socket.recv_slice(&mut bytes) {
+            Ok(4) => Ok(u32::from_le_bytes(bytes)),
+            Ok(_) => {
+                self.send_status(StatusCode::CommandLengthIncorrect, socket)?;
+                Err(Error::Protocol)
+            }
+            Err(e) => {
+                self.send_status(StatusCode::NetworkError, socket)?;
+                Err(e.into())
+            }
+        }
+    }
+
+    fn read_reboot_target(&mut self, socket: &mut TcpSocket) -> Result<RebootTarget> {
+        let mut target = [0_u8; RebootTarget::WIRE_SIZE];
+        match socket.recv_slice(&mut target) {
+            Ok(RebootTarget::WIRE_SIZE) => Ok(RebootTarget::from_le_bytes_unchecked(&target)),
+            Ok(_) => {
+                self.send_status(StatusCode::CommandLengthIncorrect, socket)?;
+                Err(Error::Protocol)
+            }
+            Err(e) => {
+                self.send_status(StatusCode::NetworkError, socket)?;
+                Err(e.into())
+            }
+        }
+    }
+
     // TODO - check for 16-byte (128 bit) alignment?
     fn handle_write_req_data<D: Device>(
         &mut self,
@@ -420,7 +710,7 @@ impl UpdateManager {
 
                 // Don't send status until the write request is fulfilled
                 if self.write_in_progress.is_none() {
-                    self.send_status(StatusCode::Success, socket)?;
+                    self.send_write_status(StatusCode::Success, socket)?;
                 }
 
                 device
@@ -428,11 +718,124 @@ impl UpdateManager {
             }
             Ok(Err(code)) => {
                 warn!("Device returned status {code}");
-                self.send_status(code, socket)?;
+                self.send_write_status(code, socket)?;
                 self.abort_in_progress(socket);
             }
             Err(_) => {
-                self.send_status(StatusCode::NetworkError, socket)?;
+                self.send_write_status(StatusCode::NetworkError, socket)?;
+                self.abort_in_progress(socket);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Fill `dst` with the active slot's data at `offset`, for delta blocks.
+    /// Device reads must be 4-byte aligned, so each read covers the aligned
+    /// region around the piece that's needed.
+    fn read_source<D: Device>(
+        device: &mut D,
+        offset: u32,
+        dst: &mut [u8],
+    ) -> core::result::Result<(), compression::Error> {
+        const PIECE_SIZE: usize = MemoryRegion::MAX_CHUCK_SIZE - 8;
+
+        let active_slot = device.info().active_boot_slot;
+        let mut address = active_slot
+            .address()
+            .checked_add(offset)
+            .ok_or(compression::Error::Source)?;
+        for piece in dst.chunks_mut(PIECE_SIZE) {
+            let end = address
+                .checked_add(piece.len() as u32)
+                .ok_or(compression::Error::Source)?;
+            if !active_slot.contains(address) || !active_slot.contains(end - 1) {
+                warn!("Delta source address 0x{address:X} is outside the active slot");
+                return Err(compression::Error::Source);
+            }
+
+            let aligned_address = address & !0x3;
+            let aligned_end = (end + 0x3) & !0x3;
+            let data = device
+                .read_memory(MemoryRegion {
+                    address: aligned_address,
+                    length: aligned_end - aligned_address,
+                })
+                .map_err(|code| {
+                    warn!("Device returned status {code} reading the delta source");
+                    compression::Error::Source
+                })?;
+            let skip = (address - aligned_address) as usize;
+            piece.copy_from_slice(&data[skip..skip + piece.len()]);
+            address = end;
+        }
+        Ok(())
+    }
+
+    fn handle_compressed_write_data<D: Device>(
+        &mut self,
+        mut write: CompressedWrite,
+        device: &mut D,
+        socket: &mut TcpSocket,
+    ) -> Result<()> {
+        // The recv buffer can wrap, so it can take more than one recv
+        while write.remaining != 0 && socket.can_recv() {
+            let decoder = &mut self.decoder;
+            let remaining = write.remaining;
+            let delta = write.delta;
+            let res = socket.recv(|buf| {
+                let n = buf.len().min(remaining);
+                let res = if delta {
+                    decoder.decode_with_source(&buf[..n], |offset, dst| {
+                        Self::read_source(device, offset, dst)
+                    })
+                } else {
+                    decoder.decode(&buf[..n])
+                };
+                (n, res.map(|()| n))
+            });
+            match res {
+                Ok(Ok(n)) => write.remaining -= n,
+                Ok(Err(e)) => {
+                    warn!("Decompress error {e}");
+                    self.send_write_status(StatusCode::DecompressError, socket)?;
+                    self.abort_in_progress(socket);
+                    return Ok(());
+                }
+                Err(_) => {
+                    self.send_write_status(StatusCode::NetworkError, socket)?;
+                    self.abort_in_progress(socket);
+                    return Ok(());
+                }
+            }
+        }
+
+        if write.remaining != 0 {
+            // Keep the write in-progress, read the rest as it comes in
+            self.compressed_write_in_progress = Some(write);
+            return Ok(());
+        }
+
+        let res = match self.decoder.finish() {
+            Ok(data) => device.write_memory(write.region, data),
+            Err(e) => {
+                warn!("Decompress error {e}");
+                Err(StatusCode::DecompressError)
+            }
+        };
+        match res {
+            Ok(()) => {
+                self.update_in_progress = true;
+                self.bytes_written = self
+                    .bytes_written
+                    .saturating_add(write.region.length as usize);
+                self.send_write_status(StatusCode::Success, socket)?;
+                device
+                    .update_progress_changed(FirmwareUpdateStatus::InProgress, self.bytes_written);
+            }
+            Err(code) => {
+                warn!("Device returned status {code}");
+                self.send_write_status(code, socket)?;
                 self.abort_in_progress(socket);
             }
         }
@@ -481,3 +884,116 @@ impl fmt::Display for FirmwareUpdateStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the progress callbacks, nothing else is used by these tests
+    #[derive(Default)]
+    struct TestDevice {
+        progress: Option<(FirmwareUpdateStatus, usize)>,
+    }
+
+    impl Device for TestDevice {
+        fn info(&self) -> &DeviceInfo {
+            unimplemented!()
+        }
+        fn boot_history(&self) -> &BootHistory {
+            unimplemented!()
+        }
+        fn perform_reboot(&mut self) -> ! {
+            unimplemented!()
+        }
+        fn perform_reboot_into(&mut self, _target: RebootTarget) -> ! {
+            unimplemented!()
+        }
+        fn perform_rollback(&mut self) -> ! {
+            unimplemented!()
+        }
+        fn complete_update_and_perform_reboot(&mut self) -> ! {
+            unimplemented!()
+        }
+        fn update_progress_changed(&mut self, status: FirmwareUpdateStatus, bytes_written: usize) {
+            self.progress = Some((status, bytes_written));
+        }
+        fn read_memory(&mut self, _req: MemoryReadRequest) -> StatusCodeResult<&[u8]> {
+            unimplemented!()
+        }
+        fn write_memory(&mut self, _req: MemoryWriteRequest, _data: &[u8]) -> StatusCodeResult<()> {
+            unimplemented!()
+        }
+        fn erase_memory(&mut self, _req: MemoryEraseRequest) -> StatusCodeResult<()> {
+            unimplemented!()
+        }
+    }
+
+    /// A socket that isn't connected, sends fail and the command is aborted
+    fn closed_socket<'a>(rx: &'a mut [u8], tx: &'a mut [u8]) -> TcpSocket<'a> {
+        TcpSocket::new(tcp::SocketBuffer::new(rx), tcp::SocketBuffer::new(tx))
+    }
+
+    /// An update that was interrupted in the middle of a write
+    fn interrupted_update() -> UpdateManager {
+        let mut um = UpdateManager::new(1234);
+        um.update_in_progress = true;
+        um.bytes_written = 4096;
+        um.write_in_progress = Some(MemoryRegion::new_unchecked(
+            BootSlot::Slot1.address() + 4096,
+            1024,
+        ));
+        um.write_tag = Some(7);
+        um.last_cmd = Some(Command::WriteMemoryTagged);
+        um
+    }
+
+    #[test]
+    fn abort_keeps_update_state() {
+        let (mut rx, mut tx) = ([0_u8; 64], [0_u8; 64]);
+        let mut socket = closed_socket(&mut rx, &mut tx);
+        let mut um = interrupted_update();
+
+        um.reset(&mut socket);
+        assert!(!um.write_is_in_progress());
+        assert_eq!(um.write_tag, None);
+        assert_eq!(um.last_cmd, None);
+        // The client resumes the transfer on a new connection
+        assert!(um.update_in_progress);
+        assert!(!um.update_complete);
+        assert_eq!(um.bytes_written, 4096);
+    }
+
+    #[test]
+    fn resumed_update_completes() {
+        let (mut rx, mut tx) = ([0_u8; 64], [0_u8; 64]);
+        let mut socket = closed_socket(&mut rx, &mut tx);
+        let mut device = TestDevice::default();
+        let mut um = interrupted_update();
+        um.reset(&mut socket);
+
+        um.process_cmd(Command::CompleteAndReboot, &mut device, &mut socket)
+            .unwrap();
+        assert!(um.update_complete);
+        assert_eq!(um.ticks_until_reboot, Some(UPDATE_TICKS_TO_REBOOT));
+        assert_eq!(
+            device.progress,
+            Some((FirmwareUpdateStatus::Complete, 4096))
+        );
+    }
+
+    #[test]
+    fn rollback_ends_paused_update() {
+        let (mut rx, mut tx) = ([0_u8; 64], [0_u8; 64]);
+        let mut socket = closed_socket(&mut rx, &mut tx);
+        let mut device = TestDevice::default();
+        let mut um = interrupted_update();
+        um.reset(&mut socket);
+
+        um.process_cmd(Command::Rollback, &mut device, &mut socket)
+            .unwrap();
+        assert!(!um.update_in_progress);
+        assert!(!um.update_complete);
+        assert!(um.rollback);
+        assert_eq!(device.progress, Some((FirmwareUpdateStatus::Aborted, 4096)));
+    }
+}
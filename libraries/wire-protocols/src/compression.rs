@@ -0,0 +1,614 @@
+//! Block compression used by `device::Command::WriteCompressed` and `device::Command::WriteDelta`.
+//!
+//! An LZ4 style block format. Blocks are compressed independently and
+//! decompress to at most `MAX_BLOCK_SIZE` bytes, so the decoder only needs the
+//! block's own output as its window and can be fed the input in pieces as
+//! it arrives.
+//!
+//! A block is a sequence of:
+//! * token: literal length (high nibble), match length minus `MIN_MATCH_LEN` (low nibble),
+//!   a nibble of 15 is extended by the bytes that follow, each one is added until one isn't 255
+//! * literals
+//! * match offset (u16, little endian) back into the output, followed by the match length extension
+//!
+//! A match offset of 0 is a source match instead, it's followed by the u32 (little endian)
+//! offset into the source image to copy from. Delta blocks use these to copy from the
+//! image that's already on the device, plain compressed blocks have no source.
+//!
+//! The block ends as soon as the output reaches the decompressed length.
+
+use crate::device::MemoryRegion;
+use core::fmt;
+
+pub const MAX_BLOCK_SIZE: usize = MemoryRegion::MAX_WRITE_CHUNK_SIZE;
+pub const MIN_MATCH_LEN: usize = 4;
+
+const HASH_BITS: u32 = 12;
+const NIBBLE_MAX: usize = 15;
+
+/// Upper bound on the compressed size of `len` bytes of input.
+/// This also holds for delta blocks, a source match is only used when it's smaller
+/// than the literals it replaces.
+pub const fn max_compressed_len(len: usize) -> usize {
+    len + (len / 255) + 16
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum Error {
+    /// The block is larger than `MAX_BLOCK_SIZE`
+    BlockTooLarge,
+    /// The output buffer is too small
+    OutputTooSmall,
+    /// A match refers to data before the start of the block
+    InvalidOffset,
+    /// The block decompresses to more than the expected length
+    Overflow,
+    /// The block ended before the expected length was reached
+    Truncated,
+    /// A source match couldn't be read
+    Source,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// An image delta blocks can copy from, indexed for finding matches.
+pub struct Source<'a> {
+    data: &'a [u8],
+    table: &'a mut [u32],
+    hash_bits: u32,
+}
+
+impl<'a> Source<'a> {
+    /// `table` holds the index, its length must be a power of two,
+    /// larger tables find more matches.
+    pub fn new(data: &'a [u8], table: &'a mut [u32]) -> Self {
+        assert!(table.len().is_power_of_two() && table.len() > 1);
+        assert!(data.len() < u32::MAX as usize);
+        let hash_bits = table.len().trailing_zeros();
+        table.fill(0);
+        for (i, seq) in data.windows(MIN_MATCH_LEN).enumerate() {
+            table[hash(seq, hash_bits)] = (i + 1) as u32;
+        }
+        Self {
+            data,
+            table,
+            hash_bits,
+        }
+    }
+
+    /// Length and source offset of the longest match for the start of `input`
+    /// found via the index
+    fn find(&self, input: &[u8]) -> Option<(usize, usize)> {
+        let seq = &input[..MIN_MATCH_LEN];
+        let candidate = self.table[hash(seq, self.hash_bits)] as usize;
+        if candidate == 0 {
+            return None;
+        }
+        let start = candidate - 1;
+        let len = input
+            .iter()
+            .zip(&self.data[start..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        (len >= MIN_MATCH_LEN).then_some((len, start))
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Match {
+    /// Offset back into the output
+    Output { offset: usize, len: usize },
+    /// Offset into the source
+    Source { offset: usize, len: usize },
+}
+
+impl Match {
+    fn len(&self) -> usize {
+        match self {
+            Match::Output { len, .. } | Match::Source { len, .. } => *len,
+        }
+    }
+}
+
+/// Compresses `input` into `output`, returns the compressed length.
+/// `output` should be at least `max_compressed_len(input.len())` bytes.
+pub fn compress(input: &[u8], output: &mut [u8]) -> Result<usize, Error> {
+    compress_impl(input, None, output)
+}
+
+/// Same as `compress`, but the block can also copy from `source`,
+/// which must be what the decoder's source reads return.
+pub fn compress_with_source(
+    input: &[u8],
+    source: &Source<'_>,
+    output: &mut [u8],
+) -> Result<usize, Error> {
+    compress_impl(input, Some(source), output)
+}
+
+fn compress_impl(
+    input: &[u8],
+    source: Option<&Source<'_>>,
+    output: &mut [u8],
+) -> Result<usize, Error> {
+    if input.len() > MAX_BLOCK_SIZE {
+        return Err(Error::BlockTooLarge);
+    }
+
+    // Most recent position + 1 for each hash, 0 is empty
+    let mut table = [0_u16; 1 << HASH_BITS];
+    let mut out = Writer {
+        buf: output,
+        pos: 0,
+    };
+    let mut anchor = 0;
+    let mut i = 0;
+    while i + MIN_MATCH_LEN <= input.len() {
+        let seq = &input[i..i + MIN_MATCH_LEN];
+        let h = hash(seq, HASH_BITS);
+        let candidate = table[h] as usize;
+        table[h] = (i + 1) as u16;
+
+        let mut best = None;
+        if candidate != 0 && &input[candidate - 1..candidate - 1 + MIN_MATCH_LEN] == seq {
+            let start = candidate - 1;
+            let mut len = MIN_MATCH_LEN;
+            while i + len < input.len() && input[start + len] == input[i + len] {
+                len += 1;
+            }
+            best = Some(Match::Output {
+                offset: i - start,
+                len,
+            });
+        }
+        if let Some((len, offset)) = source.and_then(|s| s.find(&input[i..])) {
+            // Source matches take up another 4 bytes for the offset, and
+            // must be longer than their encoding to keep within max_compressed_len
+            let output_len = best.map(|m| m.len()).unwrap_or(MIN_MATCH_LEN);
+            if len >= output_len + 4 {
+                best = Some(Match::Source { offset, len });
+            }
+        }
+
+        if let Some(m) = best {
+            out.sequence(&input[anchor..i], Some(m))?;
+            i += m.len();
+            anchor = i;
+        } else {
+            i += 1;
+        }
+    }
+    if anchor < input.len() {
+        out.sequence(&input[anchor..], None)?;
+    }
+
+    Ok(out.pos)
+}
+
+fn hash(seq: &[u8], bits: u32) -> usize {
+    let v = u32::from_le_bytes([seq[0], seq[1], seq[2], seq[3]]);
+    (v.wrapping_mul(2_654_435_761) >> (32 - bits)) as usize
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn sequence(&mut self, literals: &[u8], m: Option<Match>) -> Result<(), Error> {
+        let match_len = m.map(|m| m.len() - MIN_MATCH_LEN).unwrap_or(0);
+        let token = (literals.len().min(NIBBLE_MAX) << 4) | match_len.min(NIBBLE_MAX);
+        self.push(token as u8)?;
+        if literals.len() >= NIBBLE_MAX {
+            self.length_extension(literals.len() - NIBBLE_MAX)?;
+        }
+        self.extend(literals)?;
+        if let Some(m) = m {
+            match m {
+                Match::Output { offset, .. } => self.extend(&(offset as u16).to_le_bytes())?,
+                Match::Source { offset, .. } => {
+                    self.extend(&[0, 0])?;
+                    self.extend(&(offset as u32).to_le_bytes())?;
+                }
+            }
+            if match_len >= NIBBLE_MAX {
+                self.length_extension(match_len - NIBBLE_MAX)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn length_extension(&mut self, mut len: usize) -> Result<(), Error> {
+        while len >= 0xFF {
+            self.push(0xFF)?;
+            len -= 0xFF;
+        }
+        self.push(len as u8)
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        for b in bytes {
+            self.push(*b)?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, b: u8) -> Result<(), Error> {
+        let dst = self.buf.get_mut(self.pos).ok_or(Error::OutputTooSmall)?;
+        *dst = b;
+        self.pos += 1;
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum State {
+    Token,
+    LiteralLength {
+        len: usize,
+        match_nibble: usize,
+    },
+    Literals {
+        remaining: usize,
+        match_nibble: usize,
+    },
+    OffsetLow {
+        match_nibble: usize,
+    },
+    OffsetHigh {
+        low: u8,
+        match_nibble: usize,
+    },
+    SourceOffset {
+        bytes: [u8; 4],
+        received: usize,
+        match_nibble: usize,
+    },
+    MatchLength {
+        from: MatchFrom,
+        len: usize,
+    },
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum MatchFrom {
+    Output(usize),
+    Source(u32),
+}
+
+/// Streaming decoder for a single block, see the module docs.
+pub struct Decoder {
+    buf: [u8; MAX_BLOCK_SIZE],
+    len: usize,
+    expected_len: usize,
+    state: State,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_BLOCK_SIZE],
+            len: 0,
+            expected_len: 0,
+            state: State::Token,
+        }
+    }
+
+    /// Start a new block that decompresses to `expected_len` bytes
+    pub fn reset(&mut self, expected_len: usize) -> Result<(), Error> {
+        if expected_len > MAX_BLOCK_SIZE {
+            return Err(Error::BlockTooLarge);
+        }
+        self.len = 0;
+        self.expected_len = expected_len;
+        self.state = State::Token;
+        Ok(())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.len == self.expected_len
+    }
+
+    /// Decompress the next piece of a block without a source
+    pub fn decode(&mut self, input: &[u8]) -> Result<(), Error> {
+        self.decode_with_source(input, |_offset, _dst| Err(Error::Source))
+    }
+
+    /// Decompress the next piece of the block, `source` fills the given buffer
+    /// with the source image data at the given offset
+    pub fn decode_with_source<F>(&mut self, mut input: &[u8], mut source: F) -> Result<(), Error>
+    where
+        F: FnMut(u32, &mut [u8]) -> Result<(), Error>,
+    {
+        while let Some((&b, rest)) = input.split_first() {
+            if self.is_done() {
+                return Err(Error::Overflow);
+            }
+
+            match self.state {
+                State::Token => {
+                    input = rest;
+                    let lit_nibble = usize::from(b >> 4);
+                    let match_nibble = usize::from(b & 0x0F);
+                    self.state = if lit_nibble == NIBBLE_MAX {
+                        State::LiteralLength {
+                            len: lit_nibble,
+                            match_nibble,
+                        }
+                    } else {
+                        self.literals(lit_nibble, match_nibble)?
+                    };
+                }
+                State::LiteralLength { len, match_nibble } => {
+                    input = rest;
+                    let len = len + usize::from(b);
+                    self.state = if b == 0xFF {
+                        self.check_remaining(len)?;
+                        State::LiteralLength { len, match_nibble }
+                    } else {
+                        self.literals(len, match_nibble)?
+                    };
+                }
+                State::Literals {
+                    remaining,
+                    match_nibble,
+                } => {
+                    let n = remaining.min(input.len());
+                    self.buf[self.len..self.len + n].copy_from_slice(&input[..n]);
+                    self.len += n;
+                    input = &input[n..];
+                    self.state = if n < remaining {
+                        State::Literals {
+                            remaining: remaining - n,
+                            match_nibble,
+                        }
+                    } else {
+                        State::OffsetLow { match_nibble }
+                    };
+                }
+                State::OffsetLow { match_nibble } => {
+                    input = rest;
+                    self.state = State::OffsetHigh {
+                        low: b,
+                        match_nibble,
+                    };
+                }
+                State::OffsetHigh { low, match_nibble } => {
+                    input = rest;
+                    let offset = usize::from(u16::from_le_bytes([low, b]));
+                    self.state = if offset == 0 {
+                        State::SourceOffset {
+                            bytes: [0; 4],
+                            received: 0,
+                            match_nibble,
+                        }
+                    } else if offset > self.len {
+                        return Err(Error::InvalidOffset);
+                    } else {
+                        self.match_length(MatchFrom::Output(offset), match_nibble, &mut source)?
+                    };
+                }
+                State::SourceOffset {
+                    mut bytes,
+                    received,
+                    match_nibble,
+                } => {
+                    input = rest;
+                    bytes[received] = b;
+                    self.state = if received + 1 < bytes.len() {
+                        State::SourceOffset {
+                            bytes,
+                            received: received + 1,
+                            match_nibble,
+                        }
+                    } else {
+                        let from = MatchFrom::Source(u32::from_le_bytes(bytes));
+                        self.match_length(from, match_nibble, &mut source)?
+                    };
+                }
+                State::MatchLength { from, len } => {
+                    input = rest;
+                    let len = len + usize::from(b);
+                    self.state = if b == 0xFF {
+                        self.check_remaining(len)?;
+                        State::MatchLength { from, len }
+                    } else {
+                        self.copy_match(from, len, &mut source)?
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The decompressed block, once all of it has been decoded
+    pub fn finish(&self) -> Result<&[u8], Error> {
+        if self.is_done() {
+            Ok(&self.buf[..self.len])
+        } else {
+            Err(Error::Truncated)
+        }
+    }
+
+    fn check_remaining(&self, len: usize) -> Result<(), Error> {
+        if len > self.expected_len - self.len {
+            Err(Error::Overflow)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn literals(&mut self, len: usize, match_nibble: usize) -> Result<State, Error> {
+        self.check_remaining(len)?;
+        Ok(if len == 0 {
+            State::OffsetLow { match_nibble }
+        } else {
+            State::Literals {
+                remaining: len,
+                match_nibble,
+            }
+        })
+    }
+
+    fn match_length<F>(
+        &mut self,
+        from: MatchFrom,
+        match_nibble: usize,
+        source: &mut F,
+    ) -> Result<State, Error>
+    where
+        F: FnMut(u32, &mut [u8]) -> Result<(), Error>,
+    {
+        let len = match_nibble + MIN_MATCH_LEN;
+        if match_nibble == NIBBLE_MAX {
+            Ok(State::MatchLength { from, len })
+        } else {
+            self.copy_match(from, len, source)
+        }
+    }
+
+    fn copy_match<F>(&mut self, from: MatchFrom, len: usize, source: &mut F) -> Result<State, Error>
+    where
+        F: FnMut(u32, &mut [u8]) -> Result<(), Error>,
+    {
+        self.check_remaining(len)?;
+        match from {
+            MatchFrom::Output(offset) => {
+                for _ in 0..len {
+                    self.buf[self.len] = self.buf[self.len - offset];
+                    self.len += 1;
+                }
+            }
+            MatchFrom::Source(offset) => {
+                source(offset, &mut self.buf[self.len..self.len + len])?;
+                self.len += len;
+            }
+        }
+        Ok(State::Token)
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(input: &[u8], piece_size: usize) -> usize {
+        let mut compressed = [0_u8; max_compressed_len(MAX_BLOCK_SIZE)];
+        let compressed_len = compress(input, &mut compressed).unwrap();
+        assert!(compressed_len <= max_compressed_len(input.len()));
+
+        let mut dec = Decoder::new();
+        dec.reset(input.len()).unwrap();
+        for piece in compressed[..compressed_len].chunks(piece_size) {
+            dec.decode(piece).unwrap();
+        }
+        assert_eq!(dec.finish().unwrap(), input);
+        compressed_len
+    }
+
+    #[test]
+    fn round_trip_blocks() {
+        let mut data = [0xFF_u8; MAX_BLOCK_SIZE];
+        assert!(round_trip(&data, MAX_BLOCK_SIZE) < 32);
+
+        // Pseudo-random, incompressible
+        let mut x = 0x1234_5678_u32;
+        for b in data.iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        round_trip(&data, 1);
+        round_trip(&data, 7);
+
+        // Repeated runs with some variation
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = if i % 64 < 40 { (i % 7) as u8 } else { *b };
+        }
+        round_trip(&data, 1);
+        round_trip(&data, MAX_BLOCK_SIZE);
+
+        round_trip(&[], 1);
+        round_trip(b"abc", 1);
+        round_trip(b"abcdabcdabcdabcdabcd", 3);
+    }
+
+    #[test]
+    fn round_trip_delta_blocks() {
+        let mut old = [0_u8; 4 * MAX_BLOCK_SIZE];
+        let mut x = 0xCAFE_F00D_u32;
+        for b in old.iter_mut() {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        // The new block is shifted old data with a few changes
+        let mut new = [0_u8; MAX_BLOCK_SIZE];
+        new.copy_from_slice(&old[1000..1000 + MAX_BLOCK_SIZE]);
+        new[100..110].fill(0xAA);
+        new[1500] ^= 0xFF;
+
+        let mut table = [0_u32; 1 << 14];
+        let source = Source::new(&old, &mut table);
+        let mut compressed = [0_u8; max_compressed_len(MAX_BLOCK_SIZE)];
+        let compressed_len = compress_with_source(&new, &source, &mut compressed).unwrap();
+        assert!(compressed_len < 64);
+
+        let mut dec = Decoder::new();
+        dec.reset(new.len()).unwrap();
+        for piece in compressed[..compressed_len].chunks(3) {
+            dec.decode_with_source(piece, |offset, dst| {
+                let offset = offset as usize;
+                let src = old.get(offset..offset + dst.len()).ok_or(Error::Source)?;
+                dst.copy_from_slice(src);
+                Ok(())
+            })
+            .unwrap();
+        }
+        assert_eq!(dec.finish().unwrap(), &new[..]);
+
+        // Source matches need a source
+        dec.reset(new.len()).unwrap();
+        assert_eq!(
+            dec.decode(&compressed[..compressed_len]),
+            Err(Error::Source)
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut dec = Decoder::new();
+        assert_eq!(dec.reset(MAX_BLOCK_SIZE + 1), Err(Error::BlockTooLarge));
+
+        // 4 literals, then a match reaching back too far
+        dec.reset(16).unwrap();
+        assert_eq!(
+            dec.decode(&[0x40, 1, 2, 3, 4, 5, 0]),
+            Err(Error::InvalidOffset)
+        );
+
+        // More literals than the expected length
+        dec.reset(2).unwrap();
+        assert_eq!(dec.decode(&[0x30, 1, 2, 3]), Err(Error::Overflow));
+
+        dec.reset(4).unwrap();
+        dec.decode(&[0x40, 1, 2]).unwrap();
+        assert_eq!(dec.finish(), Err(Error::Truncated));
+        dec.decode(&[3, 4]).unwrap();
+        assert_eq!(dec.finish(), Ok(&[1_u8, 2, 3, 4][..]));
+        assert_eq!(dec.decode(&[0]), Err(Error::Overflow));
+    }
+}
@@ -4,9 +4,16 @@
 
 use byteorder::{ByteOrder, LittleEndian};
 use core::fmt;
+use crc::{Crc, Digest, CRC_32_ISO_HDLC};
 
 pub const DEFAULT_PORT: u16 = 32101;
-pub const SOCKET_BUFFER_LEN: usize = MemoryRegion::MAX_CHUCK_SIZE + 256;
+/// Room for two maximum size tagged writes, so the next one can arrive while
+/// the device is writing the current one.
+pub const SOCKET_BUFFER_LEN: usize = (2 * MemoryRegion::MAX_WRITE_CHUNK_SIZE) + 256;
+
+/// Identifies a tagged request, chosen by the client and echoed back in the response.
+pub type RequestId = u32;
+pub const REQUEST_ID_WIRE_SIZE: usize = 4;
 
 /// Commands are received by the device.
 /// The device always responds with a `StatusCode`, possibly
@@ -15,7 +22,8 @@ pub const SOCKET_BUFFER_LEN: usize = MemoryRegion::MAX_CHUCK_SIZE + 256;
 pub enum Command {
     /// Request device information.
     /// This command also causes the device to reset its connection after sending a response.
-    /// It can be used to abort an in-progress update too.
+    /// It can be used to abort an in-progress write too, the update itself can be resumed
+    /// on a new connection.
     /// Request type: None
     /// Response type: json string
     Info,
@@ -42,6 +50,56 @@ pub enum Command {
     /// Response type: None
     CompleteAndReboot,
 
+    /// Read the boot history recorded by the bootloader, newest boot first.
+    /// Request type: None
+    /// Response type: [u8] (bootloader_support::BootHistory, little endian)
+    ReadBootHistory,
+
+    /// Schedule a system reboot into the given target.
+    /// An in-progress update is aborted.
+    /// Request type: RebootTarget
+    /// Response type: None
+    RebootInto,
+
+    /// Roll back to the image in the other slot and schedule a system reboot.
+    /// The bootloader validates the other slot's image before making it the
+    /// active slot, the request is ignored if it's invalid.
+    /// An in-progress update is aborted.
+    /// Request type: None
+    /// Response type: None
+    Rollback,
+
+    /// Compute the hash of a region of FLASH memory, see `MemoryHasher`.
+    /// The region isn't limited to `MemoryRegion::MAX_CHUCK_SIZE`, but must be
+    /// within one of the boot slots.
+    /// Devices that support this command report `memory_hashing` in the info response.
+    /// Request type: MemoryHashRequest
+    /// Response type: u32
+    HashMemory,
+
+    /// Write a region of FLASH memory, tagged with a request id.
+    /// The client can have several tagged writes in flight, they're processed
+    /// and acknowledged in order. The status is always followed by the request id.
+    /// Devices that support this command report `write_chunk_size` in the info
+    /// response, regions can be up to that size.
+    /// Request type: RequestId, MemoryWriteRequest followed by [u8] data
+    /// Response type: RequestId
+    WriteMemoryTagged,
+
+    /// Same as `WriteMemoryTagged`, but the data is a compressed block, see
+    /// `compression`. The region is where the decompressed data is written.
+    /// Devices that support this command report `compressed_writes` in the info response.
+    /// Request type: RequestId, MemoryWriteRequest, u32 compressed length followed by [u8] data
+    /// Response type: RequestId
+    WriteCompressed,
+
+    /// Same as `WriteCompressed`, but the block can also copy from the image in
+    /// the active boot slot, source offsets are relative to the start of the slot.
+    /// Devices that support this command report `delta_writes` in the info response.
+    /// Request type: RequestId, MemoryWriteRequest, u32 compressed length followed by [u8] data
+    /// Response type: RequestId
+    WriteDelta,
+
     /// Unknown command.
     /// The device will always response with StatusCode::UnknownCommand.
     /// Request type: None
@@ -74,6 +132,13 @@ impl From<u32> for Command {
             3 => WriteMemory,
             4 => EraseMemory,
             5 => CompleteAndReboot,
+            6 => ReadBootHistory,
+            7 => RebootInto,
+            8 => Rollback,
+            9 => HashMemory,
+            10 => WriteMemoryTagged,
+            11 => WriteCompressed,
+            12 => WriteDelta,
             _ => Unknown(value),
         }
     }
@@ -88,6 +153,13 @@ impl From<Command> for u32 {
             WriteMemory => 3,
             EraseMemory => 4,
             CompleteAndReboot => 5,
+            ReadBootHistory => 6,
+            RebootInto => 7,
+            Rollback => 8,
+            HashMemory => 9,
+            WriteMemoryTagged => 10,
+            WriteCompressed => 11,
+            WriteDelta => 12,
             Unknown(v) => v,
         }
     }
@@ -111,15 +183,25 @@ pub struct MemoryRegion {
 impl MemoryRegion {
     pub const WIRE_SIZE: usize = 8;
     pub const MAX_CHUCK_SIZE: usize = 1024;
+    /// Writes can be larger than reads, see `Command::WriteMemoryTagged`
+    pub const MAX_WRITE_CHUNK_SIZE: usize = 2048;
 
     pub fn new_unchecked(address: u32, length: u32) -> Self {
         Self { address, length }
     }
 
     pub fn check_length(&self) -> Result<(), StatusCode> {
+        self.check_length_max(Self::MAX_CHUCK_SIZE)
+    }
+
+    pub fn check_write_length(&self) -> Result<(), StatusCode> {
+        self.check_length_max(Self::MAX_WRITE_CHUNK_SIZE)
+    }
+
+    fn check_length_max(&self, max: usize) -> Result<(), StatusCode> {
         if self.length % 4 != 0 {
             Err(StatusCode::LengthNotMultiple4)
-        } else if self.length > Self::MAX_CHUCK_SIZE as u32 {
+        } else if self.length > max as u32 {
             Err(StatusCode::LengthTooLong)
         } else if self.length == 0 {
             Err(StatusCode::DataLengthIncorrect)
@@ -137,9 +219,111 @@ impl MemoryRegion {
     }
 }
 
+/// Where the device should boot after a `Command::RebootInto`.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum RebootTarget {
+    /// Reboot into the active boot slot.
+    CurrentSlot,
+
+    /// Boot the image in the other slot once, without changing the active slot.
+    /// The next reset returns to the active slot.
+    OtherSlot,
+
+    /// Enter the bootloader's network recovery mode.
+    /// Bootloaders built without recovery support ignore the request.
+    Recovery,
+
+    /// Trial boot the image in the other slot, like a newly written update.
+    /// It becomes the active slot once the application marks it valid,
+    /// otherwise the bootloader falls back to the current slot.
+    TrialOtherSlot,
+
+    /// Unknown target.
+    /// The device will always respond with StatusCode::InvalidArgument.
+    Unknown(u32),
+}
+
+impl RebootTarget {
+    pub const WIRE_SIZE: usize = 4;
+
+    pub fn from_le_bytes_unchecked(value: &[u8]) -> Self {
+        RebootTarget::from(LittleEndian::read_u32(value))
+    }
+
+    pub fn from_le_bytes(value: &[u8]) -> Option<Self> {
+        if value.len() >= 4 {
+            Some(Self::from_le_bytes_unchecked(value))
+        } else {
+            None
+        }
+    }
+}
+
+impl From<u32> for RebootTarget {
+    fn from(value: u32) -> Self {
+        use RebootTarget::*;
+        match value {
+            0 => CurrentSlot,
+            1 => OtherSlot,
+            2 => Recovery,
+            3 => TrialOtherSlot,
+            _ => Unknown(value),
+        }
+    }
+}
+
+impl From<RebootTarget> for u32 {
+    fn from(value: RebootTarget) -> Self {
+        use RebootTarget::*;
+        match value {
+            CurrentSlot => 0,
+            OtherSlot => 1,
+            Recovery => 2,
+            TrialOtherSlot => 3,
+            Unknown(v) => v,
+        }
+    }
+}
+
+impl fmt::Display for RebootTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
 pub type MemoryReadRequest = MemoryRegion;
 pub type MemoryWriteRequest = MemoryRegion;
 pub type MemoryEraseRequest = MemoryRegion;
+pub type MemoryHashRequest = MemoryRegion;
+
+static MEMORY_HASH: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// The hash used by `Command::HashMemory`, CRC-32/ISO-HDLC (same as zlib).
+pub struct MemoryHasher(Digest<'static, u32>);
+
+impl MemoryHasher {
+    pub fn new() -> Self {
+        MemoryHasher(MEMORY_HASH.digest())
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finalize(self) -> u32 {
+        self.0.finalize()
+    }
+
+    pub fn hash(data: &[u8]) -> u32 {
+        MEMORY_HASH.checksum(data)
+    }
+}
+
+impl Default for MemoryHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub enum StatusCode {
@@ -155,6 +339,8 @@ pub enum StatusCode {
     NetworkError,
     InternalError,
     CommandLengthIncorrect,
+    InvalidArgument,
+    DecompressError,
     Unknown(u32),
 }
 
@@ -192,6 +378,8 @@ impl From<u32> for StatusCode {
             9 => NetworkError,
             10 => InternalError,
             11 => CommandLengthIncorrect,
+            12 => InvalidArgument,
+            13 => DecompressError,
             _ => Unknown(value),
         }
     }
@@ -213,6 +401,8 @@ impl From<StatusCode> for u32 {
             NetworkError => 9,
             InternalError => 10,
             CommandLengthIncorrect => 11,
+            InvalidArgument => 12,
+            DecompressError => 13,
             Unknown(v) => v,
         }
     }
@@ -237,6 +427,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn write_length_limits() {
+        let read_max = MemoryRegion::new_unchecked(0, MemoryRegion::MAX_CHUCK_SIZE as u32);
+        let write_max = MemoryRegion::new_unchecked(0, MemoryRegion::MAX_WRITE_CHUNK_SIZE as u32);
+        assert_eq!(read_max.check_length(), Ok(()));
+        assert_eq!(read_max.check_write_length(), Ok(()));
+        assert_eq!(write_max.check_length(), Err(StatusCode::LengthTooLong));
+        assert_eq!(write_max.check_write_length(), Ok(()));
+        assert_eq!(
+            MemoryRegion::new_unchecked(0, 6).check_write_length(),
+            Err(StatusCode::LengthNotMultiple4)
+        );
+    }
+
+    #[test]
+    fn round_trip_wire_reboot_target() {
+        for in_t in 0..0xFF_u32 {
+            let in_t_bytes = in_t.to_le_bytes();
+            let t = RebootTarget::from_le_bytes(&in_t_bytes).unwrap();
+            assert_eq!(in_t, u32::from(t));
+        }
+    }
+
+    #[test]
+    fn memory_hash() {
+        assert_eq!(MemoryHasher::hash(b"123456789"), 0xCBF4_3926);
+
+        let mut h = MemoryHasher::new();
+        h.update(b"1234");
+        h.update(b"56789");
+        assert_eq!(h.finalize(), 0xCBF4_3926);
+    }
+
     #[test]
     fn round_trip_status_code() {
         for in_c in 0..0xFF_u32 {
@@ -0,0 +1,650 @@
+//! Active device discovery.
+//!
+//! A host sends a request datagram (usually to the broadcast address) and every
+//! device answers the sender with a response describing itself, including the
+//! address and port its device protocol socket listens on.
+
+use crate::{
+    DeviceId, DeviceSerialNumber, Error, FirmwareVersion, ProtocolIdentifier, ProtocolVersion,
+    Result,
+};
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt;
+
+pub const DEFAULT_PORT: u16 = 32102;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum MessageType {
+    /// "Who is there", sent by the host
+    Request,
+    /// Sent by a device in reply to a request
+    Response,
+    /// Unknown
+    Unknown(u8),
+}
+
+impl From<u8> for MessageType {
+    fn from(value: u8) -> Self {
+        use MessageType::*;
+        match value {
+            1 => Request,
+            2 => Response,
+            _ => Unknown(value),
+        }
+    }
+}
+
+impl From<MessageType> for u8 {
+    fn from(value: MessageType) -> Self {
+        use MessageType::*;
+        match value {
+            Request => 1,
+            Response => 2,
+            Unknown(t) => t,
+        }
+    }
+}
+
+impl fmt::Display for MessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageType::Request => f.write_str("request"),
+            MessageType::Response => f.write_str("response"),
+            MessageType::Unknown(t) => t.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Message<T: AsRef<[u8]>> {
+    buffer: T,
+}
+
+mod field {
+    use crate::field::*;
+
+    pub const PROTOCOL: Field = 0..4;
+    pub const PROTOCOL_VERSION: usize = 4;
+    pub const MESSAGE_TYPE: usize = 5;
+    pub const REQUEST_ID: Field = 6..10;
+
+    // Response only
+    pub const FIRMWARE_VERSION_PATCH: Field = 10..12;
+    pub const FIRMWARE_VERSION_MINOR: Field = 12..14;
+    pub const FIRMWARE_VERSION_MAJOR: Field = 14..16;
+    pub const BOOTLOADER_VERSION_PATCH: Field = 16..18;
+    pub const BOOTLOADER_VERSION_MINOR: Field = 18..20;
+    pub const BOOTLOADER_VERSION_MAJOR: Field = 20..22;
+
+    pub const DEVICE_ID: Field = 22..24;
+    pub const DEVICE_SERIAL_NUMBER0: Field = 24..28;
+    pub const DEVICE_SERIAL_NUMBER1: Field = 28..32;
+    pub const DEVICE_SERIAL_NUMBER2: Field = 32..36;
+
+    pub const MAC_ADDRESS: Field = 36..42;
+    pub const IP_ADDRESS: Field = 42..46;
+    pub const DEVICE_PORT: Field = 46..48;
+    pub const ACTIVE_BOOT_SLOT: usize = 48;
+
+    pub const REST: Rest = 49..;
+}
+
+/// The request message length.
+pub const REQUEST_LEN: usize = field::FIRMWARE_VERSION_PATCH.start;
+
+/// The response message length.
+pub const RESPONSE_LEN: usize = field::REST.start;
+
+/// Bootloader version field value when the bootloader doesn't report its version
+const VERSION_NOT_REPORTED: u16 = 0xFFFF;
+
+impl<T: AsRef<[u8]>> Message<T> {
+    /// Imbue a raw octet buffer with message structure.
+    pub const fn new_unchecked(buffer: T) -> Message<T> {
+        Message { buffer }
+    }
+
+    /// Shorthand for a combination of [new_unchecked] and [check_len].
+    ///
+    /// [new_unchecked]: #method.new_unchecked
+    /// [check_len]: #method.check_len
+    pub fn new_checked(buffer: T) -> Result<Message<T>> {
+        let packet = Self::new_unchecked(buffer);
+        packet.check_len()?;
+        packet.check_protocol()?;
+        Ok(packet)
+    }
+
+    /// Ensure that no accessor method will panic if called.
+    /// Returns `Err(Error)` if the buffer is too short for the message type.
+    /// The response fields are only accessible on response messages.
+    pub fn check_len(&self) -> Result<()> {
+        let len = self.buffer.as_ref().len();
+        if len < REQUEST_LEN || (self.message_type() == MessageType::Response && len < RESPONSE_LEN)
+        {
+            Err(Error)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that the message protocol matches the
+    /// expected discovery protocol identifier.
+    pub fn check_protocol(&self) -> Result<()> {
+        if self.protocol() != ProtocolIdentifier::Discovery {
+            Err(Error)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Consumes the message, returning the underlying buffer.
+    pub fn into_inner(self) -> T {
+        self.buffer
+    }
+
+    /// Return the length of the message, based on its type.
+    pub fn message_len(&self) -> usize {
+        match self.message_type() {
+            MessageType::Response => RESPONSE_LEN,
+            _ => REQUEST_LEN,
+        }
+    }
+
+    /// Return the protocol field.
+    #[inline]
+    pub fn protocol(&self) -> ProtocolIdentifier {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u32(&data[field::PROTOCOL]).into()
+    }
+
+    /// Return the protocol version field.
+    #[inline]
+    pub fn protocol_version(&self) -> u8 {
+        let data = self.buffer.as_ref();
+        data[field::PROTOCOL_VERSION]
+    }
+
+    /// Return the message type field.
+    #[inline]
+    pub fn message_type(&self) -> MessageType {
+        let data = self.buffer.as_ref();
+        data[field::MESSAGE_TYPE].into()
+    }
+
+    /// Return the request ID field.
+    #[inline]
+    pub fn request_id(&self) -> u32 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u32(&data[field::REQUEST_ID])
+    }
+
+    /// Return the firmware version patch field.
+    #[inline]
+    pub fn firmware_version_patch(&self) -> u16 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u16(&data[field::FIRMWARE_VERSION_PATCH])
+    }
+
+    /// Return the firmware version minor field.
+    #[inline]
+    pub fn firmware_version_minor(&self) -> u16 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u16(&data[field::FIRMWARE_VERSION_MINOR])
+    }
+
+    /// Return the firmware version major field.
+    #[inline]
+    pub fn firmware_version_major(&self) -> u16 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u16(&data[field::FIRMWARE_VERSION_MAJOR])
+    }
+
+    /// Return the bootloader version patch field.
+    #[inline]
+    pub fn bootloader_version_patch(&self) -> u16 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u16(&data[field::BOOTLOADER_VERSION_PATCH])
+    }
+
+    /// Return the bootloader version minor field.
+    #[inline]
+    pub fn bootloader_version_minor(&self) -> u16 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u16(&data[field::BOOTLOADER_VERSION_MINOR])
+    }
+
+    /// Return the bootloader version major field.
+    #[inline]
+    pub fn bootloader_version_major(&self) -> u16 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u16(&data[field::BOOTLOADER_VERSION_MAJOR])
+    }
+
+    /// Return the device ID field.
+    #[inline]
+    pub fn device_id(&self) -> u16 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u16(&data[field::DEVICE_ID])
+    }
+
+    /// Return the device serial number word 0 field.
+    #[inline]
+    pub fn device_serial_number_word0(&self) -> u32 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u32(&data[field::DEVICE_SERIAL_NUMBER0])
+    }
+
+    /// Return the device serial number word 1 field.
+    #[inline]
+    pub fn device_serial_number_word1(&self) -> u32 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u32(&data[field::DEVICE_SERIAL_NUMBER1])
+    }
+
+    /// Return the device serial number word 2 field.
+    #[inline]
+    pub fn device_serial_number_word2(&self) -> u32 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u32(&data[field::DEVICE_SERIAL_NUMBER2])
+    }
+
+    /// Return the MAC address field.
+    #[inline]
+    pub fn mac_address(&self) -> [u8; 6] {
+        let data = self.buffer.as_ref();
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&data[field::MAC_ADDRESS]);
+        mac
+    }
+
+    /// Return the IPv4 address field.
+    #[inline]
+    pub fn ip_address(&self) -> [u8; 4] {
+        let data = self.buffer.as_ref();
+        let mut ip = [0; 4];
+        ip.copy_from_slice(&data[field::IP_ADDRESS]);
+        ip
+    }
+
+    /// Return the device protocol port field.
+    #[inline]
+    pub fn device_port(&self) -> u16 {
+        let data = self.buffer.as_ref();
+        LittleEndian::read_u16(&data[field::DEVICE_PORT])
+    }
+
+    /// Return the active boot slot field.
+    #[inline]
+    pub fn active_boot_slot(&self) -> u8 {
+        let data = self.buffer.as_ref();
+        data[field::ACTIVE_BOOT_SLOT]
+    }
+}
+
+impl<T: AsRef<[u8]> + AsMut<[u8]>> Message<T> {
+    /// Set the protocol field.
+    #[inline]
+    pub fn set_protocol(&mut self, value: ProtocolIdentifier) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u32(&mut data[field::PROTOCOL], value.into())
+    }
+
+    /// Set the protocol version field.
+    #[inline]
+    pub fn set_protocol_version(&mut self, value: u8) {
+        let data = self.buffer.as_mut();
+        data[field::PROTOCOL_VERSION] = value;
+    }
+
+    /// Set the message type field.
+    #[inline]
+    pub fn set_message_type(&mut self, value: MessageType) {
+        let data = self.buffer.as_mut();
+        data[field::MESSAGE_TYPE] = value.into();
+    }
+
+    /// Set the request ID field.
+    #[inline]
+    pub fn set_request_id(&mut self, value: u32) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u32(&mut data[field::REQUEST_ID], value)
+    }
+
+    /// Set the firmware version patch field.
+    #[inline]
+    pub fn set_firmware_version_patch(&mut self, value: u16) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u16(&mut data[field::FIRMWARE_VERSION_PATCH], value)
+    }
+
+    /// Set the firmware version minor field.
+    #[inline]
+    pub fn set_firmware_version_minor(&mut self, value: u16) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u16(&mut data[field::FIRMWARE_VERSION_MINOR], value)
+    }
+
+    /// Set the firmware version major field.
+    #[inline]
+    pub fn set_firmware_version_major(&mut self, value: u16) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u16(&mut data[field::FIRMWARE_VERSION_MAJOR], value)
+    }
+
+    /// Set the bootloader version patch field.
+    #[inline]
+    pub fn set_bootloader_version_patch(&mut self, value: u16) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u16(&mut data[field::BOOTLOADER_VERSION_PATCH], value)
+    }
+
+    /// Set the bootloader version minor field.
+    #[inline]
+    pub fn set_bootloader_version_minor(&mut self, value: u16) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u16(&mut data[field::BOOTLOADER_VERSION_MINOR], value)
+    }
+
+    /// Set the bootloader version major field.
+    #[inline]
+    pub fn set_bootloader_version_major(&mut self, value: u16) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u16(&mut data[field::BOOTLOADER_VERSION_MAJOR], value)
+    }
+
+    /// Set the device ID field.
+    #[inline]
+    pub fn set_device_id(&mut self, value: u16) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u16(&mut data[field::DEVICE_ID], value)
+    }
+
+    /// Set the device serial number word 0 field.
+    #[inline]
+    pub fn set_device_serial_number_word0(&mut self, value: u32) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u32(&mut data[field::DEVICE_SERIAL_NUMBER0], value)
+    }
+
+    /// Set the device serial number word 1 field.
+    #[inline]
+    pub fn set_device_serial_number_word1(&mut self, value: u32) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u32(&mut data[field::DEVICE_SERIAL_NUMBER1], value)
+    }
+
+    /// Set the device serial number word 2 field.
+    #[inline]
+    pub fn set_device_serial_number_word2(&mut self, value: u32) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u32(&mut data[field::DEVICE_SERIAL_NUMBER2], value)
+    }
+
+    /// Set the MAC address field.
+    #[inline]
+    pub fn set_mac_address(&mut self, value: [u8; 6]) {
+        let data = self.buffer.as_mut();
+        data[field::MAC_ADDRESS].copy_from_slice(&value);
+    }
+
+    /// Set the IPv4 address field.
+    #[inline]
+    pub fn set_ip_address(&mut self, value: [u8; 4]) {
+        let data = self.buffer.as_mut();
+        data[field::IP_ADDRESS].copy_from_slice(&value);
+    }
+
+    /// Set the device protocol port field.
+    #[inline]
+    pub fn set_device_port(&mut self, value: u16) {
+        let data = self.buffer.as_mut();
+        LittleEndian::write_u16(&mut data[field::DEVICE_PORT], value)
+    }
+
+    /// Set the active boot slot field.
+    #[inline]
+    pub fn set_active_boot_slot(&mut self, value: u8) {
+        let data = self.buffer.as_mut();
+        data[field::ACTIVE_BOOT_SLOT] = value;
+    }
+}
+
+impl<T: AsRef<[u8]>> AsRef<[u8]> for Message<T> {
+    fn as_ref(&self) -> &[u8] {
+        self.buffer.as_ref()
+    }
+}
+
+impl<T: AsRef<[u8]>> fmt::Display for Message<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Message proto={}, type={}, request_id={}",
+            self.protocol_version(),
+            self.message_type(),
+            self.request_id(),
+        )
+    }
+}
+
+/// A high-level representation of a request message.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Request {
+    pub protocol_version: ProtocolVersion,
+    /// Chosen by the host, echoed back in the responses
+    pub request_id: u32,
+}
+
+impl Request {
+    /// Parse a request message and return a high-level representation.
+    pub fn parse<T: AsRef<[u8]> + ?Sized>(msg: &Message<&T>) -> Result<Request> {
+        msg.check_len()?;
+        msg.check_protocol()?;
+        if msg.message_type() != MessageType::Request {
+            return Err(Error);
+        }
+        Ok(Request {
+            protocol_version: ProtocolVersion(msg.protocol_version()),
+            request_id: msg.request_id(),
+        })
+    }
+
+    /// Return the length of a message that will be emitted from this high-level representation.
+    pub const fn message_len(&self) -> usize {
+        REQUEST_LEN
+    }
+
+    /// Emit a high-level representation into a message.
+    pub fn emit<T: AsRef<[u8]> + AsMut<[u8]>>(&self, msg: &mut Message<T>) {
+        msg.set_protocol(ProtocolIdentifier::Discovery);
+        msg.set_protocol_version(self.protocol_version.0);
+        msg.set_message_type(MessageType::Request);
+        msg.set_request_id(self.request_id);
+    }
+}
+
+/// A high-level representation of a response message.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Response {
+    pub protocol_version: ProtocolVersion,
+    /// The request ID of the request being answered
+    pub request_id: u32,
+    pub firmware_version: FirmwareVersion,
+    /// None when the bootloader doesn't report its version
+    pub bootloader_version: Option<FirmwareVersion>,
+    pub device_id: DeviceId,
+    pub device_serial_number: DeviceSerialNumber,
+    pub mac_address: [u8; 6],
+    /// The device's current IPv4 address
+    pub ip_address: [u8; 4],
+    /// The port the device protocol socket listens on
+    pub device_port: u16,
+    /// Boot slot index (0 or 1) the firmware is running from
+    pub active_boot_slot: u8,
+}
+
+impl Response {
+    /// Parse a response message and return a high-level representation.
+    pub fn parse<T: AsRef<[u8]> + ?Sized>(msg: &Message<&T>) -> Result<Response> {
+        msg.check_len()?;
+        msg.check_protocol()?;
+        if msg.message_type() != MessageType::Response {
+            return Err(Error);
+        }
+        let bootloader_version = FirmwareVersion {
+            major: msg.bootloader_version_major(),
+            minor: msg.bootloader_version_minor(),
+            patch: msg.bootloader_version_patch(),
+        };
+        let not_reported = FirmwareVersion::new(
+            VERSION_NOT_REPORTED,
+            VERSION_NOT_REPORTED,
+            VERSION_NOT_REPORTED,
+        );
+        Ok(Response {
+            protocol_version: ProtocolVersion(msg.protocol_version()),
+            request_id: msg.request_id(),
+            firmware_version: FirmwareVersion {
+                major: msg.firmware_version_major(),
+                minor: msg.firmware_version_minor(),
+                patch: msg.firmware_version_patch(),
+            },
+            bootloader_version: (bootloader_version != not_reported).then_some(bootloader_version),
+            device_id: DeviceId(msg.device_id()),
+            device_serial_number: DeviceSerialNumber {
+                word0: msg.device_serial_number_word0(),
+                word1: msg.device_serial_number_word1(),
+                word2: msg.device_serial_number_word2(),
+            },
+            mac_address: msg.mac_address(),
+            ip_address: msg.ip_address(),
+            device_port: msg.device_port(),
+            active_boot_slot: msg.active_boot_slot(),
+        })
+    }
+
+    /// Return the length of a message that will be emitted from this high-level representation.
+    pub const fn message_len(&self) -> usize {
+        RESPONSE_LEN
+    }
+
+    /// Emit a high-level representation into a message.
+    pub fn emit<T: AsRef<[u8]> + AsMut<[u8]>>(&self, msg: &mut Message<T>) {
+        msg.set_protocol(ProtocolIdentifier::Discovery);
+        msg.set_protocol_version(self.protocol_version.0);
+        msg.set_message_type(MessageType::Response);
+        msg.set_request_id(self.request_id);
+        msg.set_firmware_version_patch(self.firmware_version.patch);
+        msg.set_firmware_version_minor(self.firmware_version.minor);
+        msg.set_firmware_version_major(self.firmware_version.major);
+        let bootloader_version = self.bootloader_version.unwrap_or(FirmwareVersion::new(
+            VERSION_NOT_REPORTED,
+            VERSION_NOT_REPORTED,
+            VERSION_NOT_REPORTED,
+        ));
+        msg.set_bootloader_version_patch(bootloader_version.patch);
+        msg.set_bootloader_version_minor(bootloader_version.minor);
+        msg.set_bootloader_version_major(bootloader_version.major);
+        msg.set_device_id(self.device_id.0);
+        msg.set_device_serial_number_word0(self.device_serial_number.word0);
+        msg.set_device_serial_number_word1(self.device_serial_number.word1);
+        msg.set_device_serial_number_word2(self.device_serial_number.word2);
+        msg.set_mac_address(self.mac_address);
+        msg.set_ip_address(self.ip_address);
+        msg.set_device_port(self.device_port);
+        msg.set_active_boot_slot(self.active_boot_slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static REQUEST_BYTES: [u8; 10] = [0x44, 0x49, 0x53, 0x43, 0x01, 0x01, 0x44, 0x33, 0x22, 0x11];
+
+    static RESPONSE_BYTES: [u8; 49] = [
+        0x44, 0x49, 0x53, 0x43, 0x01, 0x02, 0x44, 0x33, 0x22, 0x11, 0x03, 0x00, 0x02, 0x00, 0x01,
+        0x00, 0x01, 0x00, 0x04, 0x00, 0x00, 0x00, 0x0D, 0x00, 0xAA, 0xAA, 0xAA, 0xAA, 0xBB, 0xBB,
+        0xBB, 0xBB, 0xCC, 0xCC, 0xCC, 0xCC, 0x02, 0x00, 0x04, 0x03, 0x07, 0x02, 0xC0, 0xA8, 0x01,
+        0x26, 0x65, 0x7D, 0x01,
+    ];
+
+    fn response() -> Response {
+        Response {
+            protocol_version: ProtocolVersion::v1(),
+            request_id: 0x11_22_33_44,
+            firmware_version: FirmwareVersion::new(1, 2, 3),
+            bootloader_version: Some(FirmwareVersion::new(0, 4, 1)),
+            device_id: DeviceId(13),
+            device_serial_number: DeviceSerialNumber::new(0xAAAA_AAAA, 0xBBBB_BBBB, 0xCCCC_CCCC),
+            mac_address: [0x02, 0x00, 0x04, 0x03, 0x07, 0x02],
+            ip_address: [192, 168, 1, 38],
+            device_port: 32101,
+            active_boot_slot: 1,
+        }
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let bytes = [0xFF; 6];
+        assert!(Message::new_checked(&bytes[..]).is_err());
+        let msg = Message::new_unchecked(&bytes[..]);
+        assert_eq!(msg.check_len(), Err(Error));
+
+        // A response needs the response fields
+        assert!(Message::new_checked(&RESPONSE_BYTES[..REQUEST_LEN + 1]).is_err());
+        assert!(Message::new_checked(&RESPONSE_BYTES[..]).is_ok());
+    }
+
+    #[test]
+    fn request_roundtrip() {
+        let msg = Message::new_checked(&REQUEST_BYTES[..]).unwrap();
+        assert_eq!(msg.protocol(), ProtocolIdentifier::Discovery);
+        assert_eq!(msg.message_type(), MessageType::Request);
+        assert_eq!(msg.message_len(), REQUEST_LEN);
+        let req = Request::parse(&msg).unwrap();
+        assert_eq!(
+            req,
+            Request {
+                protocol_version: ProtocolVersion::v1(),
+                request_id: 0x11_22_33_44,
+            }
+        );
+        assert!(Response::parse(&msg).is_err());
+
+        let mut bytes_out = [0xFF; REQUEST_LEN];
+        let mut msg_out = Message::new_unchecked(&mut bytes_out);
+        req.emit(&mut msg_out);
+        assert_eq!(msg_out.into_inner(), &REQUEST_BYTES);
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let mut bytes = [0xFF; RESPONSE_LEN];
+        let mut msg = Message::new_unchecked(&mut bytes);
+        response().emit(&mut msg);
+        assert_eq!(msg.into_inner(), &RESPONSE_BYTES);
+
+        let msg = Message::new_checked(&RESPONSE_BYTES[..]).unwrap();
+        assert_eq!(msg.message_len(), RESPONSE_LEN);
+        assert_eq!(msg.ip_address(), [192, 168, 1, 38]);
+        assert_eq!(msg.device_port(), 32101);
+        assert_eq!(Response::parse(&msg).unwrap(), response());
+        assert!(Request::parse(&msg).is_err());
+    }
+
+    #[test]
+    fn bootloader_version_not_reported() {
+        let resp = Response {
+            bootloader_version: None,
+            ..response()
+        };
+        let mut bytes = [0; RESPONSE_LEN];
+        let mut msg = Message::new_unchecked(&mut bytes);
+        resp.emit(&mut msg);
+        assert_eq!(msg.bootloader_version_major(), 0xFFFF);
+        let msg = Message::new_checked(&bytes[..]).unwrap();
+        assert_eq!(Response::parse(&msg).unwrap(), resp);
+    }
+}
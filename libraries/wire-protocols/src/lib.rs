@@ -2,10 +2,12 @@
 #![forbid(unsafe_code)]
 
 use bitfield::bitfield;
-use core::fmt;
+use core::{fmt, str};
 
 pub mod broadcast;
+pub mod compression;
 pub mod device;
+pub mod discovery;
 
 // TODO - add error variants
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
@@ -25,6 +27,8 @@ pub enum ProtocolIdentifier {
     Broadcast,
     /// Device protocol ("DEVC")
     Device,
+    /// Discovery protocol ("DISC")
+    Discovery,
     /// Unknown
     Unknown(u32),
 }
@@ -34,6 +38,7 @@ impl fmt::Display for ProtocolIdentifier {
         match self {
             ProtocolIdentifier::Broadcast => "broadcast".fmt(f),
             ProtocolIdentifier::Device => "device".fmt(f),
+            ProtocolIdentifier::Discovery => "discovery".fmt(f),
             ProtocolIdentifier::Unknown(p) => p.fmt(f),
         }
     }
@@ -45,6 +50,7 @@ impl From<u32> for ProtocolIdentifier {
         match value {
             0x43_44_52_42 => Broadcast,
             0x43_56_45_44 => Device,
+            0x43_53_49_44 => Discovery,
             _ => Unknown(value),
         }
     }
@@ -56,6 +62,7 @@ impl From<ProtocolIdentifier> for u32 {
         match value {
             Broadcast => 0x43_44_52_42,
             Device => 0x43_56_45_44,
+            Discovery => 0x43_53_49_44,
             Unknown(id) => id,
         }
     }
@@ -97,6 +104,47 @@ impl FirmwareVersion {
             patch,
         }
     }
+
+    /// Parses a "major.minor.patch" version string
+    pub const fn parse(s: &str) -> Option<Self> {
+        let bytes = s.as_bytes();
+        let mut parts = [0_u16; 3];
+        let mut part = 0;
+        let mut digits = 0;
+        let mut i = 0;
+        while i < bytes.len() {
+            let b = bytes[i];
+            if b == b'.' {
+                if digits == 0 || part == 2 {
+                    return None;
+                }
+                part += 1;
+                digits = 0;
+            } else if b.is_ascii_digit() {
+                let v = (parts[part] as u32 * 10) + (b - b'0') as u32;
+                if v > u16::MAX as u32 {
+                    return None;
+                }
+                parts[part] = v as u16;
+                digits += 1;
+            } else {
+                return None;
+            }
+            i += 1;
+        }
+        if digits == 0 || part != 2 {
+            return None;
+        }
+        Some(FirmwareVersion::new(parts[0], parts[1], parts[2]))
+    }
+}
+
+impl str::FromStr for FirmwareVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        FirmwareVersion::parse(s).ok_or(Error)
+    }
 }
 
 impl fmt::Display for FirmwareVersion {
@@ -249,3 +297,34 @@ mod field {
     pub type Field = ops::Range<usize>;
     pub type Rest = ops::RangeFrom<usize>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firmware_version_parse() {
+        assert_eq!(
+            FirmwareVersion::parse("0.4.2"),
+            Some(FirmwareVersion::new(0, 4, 2))
+        );
+        assert_eq!(
+            "12.0.65535".parse::<FirmwareVersion>(),
+            Ok(FirmwareVersion::new(12, 0, 65535))
+        );
+        for bad in [
+            "",
+            "1",
+            "1.2",
+            "1.2.",
+            ".1.2",
+            "1..2",
+            "1.2.3.4",
+            "1.2.x",
+            "1.2.65536",
+        ] {
+            assert_eq!(FirmwareVersion::parse(bad), None, "{bad}");
+        }
+        assert!(FirmwareVersion::new(0, 4, 2) < FirmwareVersion::new(0, 10, 0));
+    }
+}